@@ -0,0 +1,247 @@
+use std::io::{self, Read, Write};
+
+use crate::checkpoint::{read_color, read_f32, read_u32, write_color, write_f32, write_u32};
+use crate::exr_output::ExrChannel;
+use crate::pixel_buffer::Color;
+use crate::traits::Zero;
+use crate::vec::f32x3;
+
+
+// Arbitrary output variables written together with beauty image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AovType {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    ShapeId,
+    MaterialId,
+    Variance,
+    SampleCount
+}
+
+impl AovType {
+    pub fn from_name(name: &str) -> Option<AovType> {
+        match name {
+            "albedo" => Some(AovType::Albedo),
+            "normal" => Some(AovType::Normal),
+            "depth" => Some(AovType::Depth),
+            "position" => Some(AovType::Position),
+            "shape_id" => Some(AovType::ShapeId),
+            "material_id" => Some(AovType::MaterialId),
+            "variance" => Some(AovType::Variance),
+            "samples" => Some(AovType::SampleCount),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AovType::Albedo => "albedo",
+            AovType::Normal => "normal",
+            AovType::Depth => "depth",
+            AovType::Position => "position",
+            AovType::ShapeId => "shape_id",
+            AovType::MaterialId => "material_id",
+            AovType::Variance => "variance",
+            AovType::SampleCount => "samples"
+        }
+    }
+
+    // Variance and sample count come from pixel statistics, others from first hit of camera ray
+    pub fn is_geometric(&self) -> bool {
+        !matches!(self, AovType::Variance | AovType::SampleCount)
+    }
+}
+
+// Values at first hit of camera ray. Ids are stored incremented by one, zero is background.
+#[derive(Debug, Clone, Copy)]
+pub struct AovSample {
+    pub albedo: Color,
+    pub normal: f32x3,
+    pub depth: f32,
+    pub position: f32x3,
+    pub shape_id: u32,
+    pub material_id: u32
+}
+
+impl AovSample {
+    pub fn write_raw(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_color(w, &self.albedo)?;
+        for v in [self.normal.0, self.normal.1, self.normal.2, self.depth, self.position.0, self.position.1, self.position.2] {
+            write_f32(w, v)?;
+        }
+        write_u32(w, self.shape_id)?;
+        write_u32(w, self.material_id)
+    }
+
+    pub fn read_raw(r: &mut dyn Read) -> io::Result<AovSample> {
+        let albedo = read_color(r)?;
+        let normal = f32x3(read_f32(r)?, read_f32(r)?, read_f32(r)?);
+        let depth = read_f32(r)?;
+        let position = f32x3(read_f32(r)?, read_f32(r)?, read_f32(r)?);
+        let shape_id = read_u32(r)?;
+        let material_id = read_u32(r)?;
+        Ok(AovSample { albedo, normal, depth, position, shape_id, material_id })
+    }
+}
+
+impl Zero for AovSample {
+    fn zero() -> Self {
+        let zero = f32x3(0.0, 0.0, 0.0);
+        AovSample { albedo: Color::zero(), normal: zero, depth: 0.0, position: zero, shape_id: 0, material_id: 0 }
+    }
+}
+
+// Accumulated AOVs of pixel. Albedo is averaged over all samples, so it is weighted by
+// coverage like beauty. Normal, depth and position are averaged over samples that hit
+// geometry, otherwise silhouette pixels would be pulled towards zero by missed samples.
+// Ids are taken from the first hit, because average of ids has no meaning.
+#[derive(Debug, Clone, Copy)]
+pub struct AovPixel {
+    sum: AovSample,
+    weight: f32,
+    hits: f32
+}
+
+impl AovPixel {
+    pub fn add(&mut self, sample: &AovSample) {
+        self.sum.albedo += sample.albedo;
+        self.weight += 1.0;
+        if sample.shape_id == 0 {
+            return;
+        }
+        if self.hits == 0.0 {
+            self.sum.shape_id = sample.shape_id;
+            self.sum.material_id = sample.material_id;
+        }
+        self.sum.normal = self.sum.normal + sample.normal;
+        self.sum.depth += sample.depth;
+        self.sum.position = self.sum.position + sample.position;
+        self.hits += 1.0;
+    }
+
+    // Accumulation of other render of the same pixel is added, ids of this one are kept
+    pub fn merge(&mut self, other: &AovPixel) {
+        if self.hits == 0.0 {
+            self.sum.shape_id = other.sum.shape_id;
+            self.sum.material_id = other.sum.material_id;
+        }
+        self.sum.albedo += other.sum.albedo;
+        self.sum.normal = self.sum.normal + other.sum.normal;
+        self.sum.depth += other.sum.depth;
+        self.sum.position = self.sum.position + other.sum.position;
+        self.weight += other.weight;
+        self.hits += other.hits;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weight == 0.0
+    }
+
+    pub fn get(&self) -> AovSample {
+        if self.weight == 0.0 {
+            return AovSample::zero()
+        }
+        let albedo = self.sum.albedo * (1.0 / self.weight);
+        if self.hits == 0.0 {
+            return AovSample { albedo, ..AovSample::zero() }
+        }
+        let inv = 1.0 / self.hits;
+        let normal = self.sum.normal * inv;
+        let normal = if normal.length_sqr() > 0.0 { normal.normalize() } else { normal };
+        AovSample {
+            albedo,
+            normal,
+            depth: self.sum.depth * inv,
+            position: self.sum.position * inv,
+            shape_id: self.sum.shape_id,
+            material_id: self.sum.material_id
+        }
+    }
+
+    pub fn write_raw(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.sum.write_raw(w)?;
+        write_f32(w, self.weight)?;
+        write_f32(w, self.hits)
+    }
+
+    pub fn read_raw(r: &mut dyn Read) -> io::Result<AovPixel> {
+        let sum = AovSample::read_raw(r)?;
+        let weight = read_f32(r)?;
+        let hits = read_f32(r)?;
+        Ok(AovPixel { sum, weight, hits })
+    }
+}
+
+impl Zero for AovPixel {
+    fn zero() -> Self {
+        AovPixel { sum: AovSample::zero(), weight: 0.0, hits: 0.0 }
+    }
+}
+
+// EXR channels of geometric AOV, layer name is prefix of channel names
+pub fn aov_channels(aov_type: AovType, pixels: &[AovPixel]) -> Vec<ExrChannel> {
+    let values: Vec<AovSample> = pixels.iter().map(|pixel| pixel.get()).collect();
+    let layer = aov_type.name();
+    let float = |channel: &str, f: &dyn Fn(&AovSample) -> f32| {
+        ExrChannel::float(&format!("{}.{}", layer, channel), values.iter().map(f).collect())
+    };
+    match aov_type {
+        AovType::Albedo => vec![
+            float("R", &|v| v.albedo.red), float("G", &|v| v.albedo.green), float("B", &|v| v.albedo.blue)
+        ],
+        AovType::Normal => vec![
+            float("X", &|v| v.normal.0), float("Y", &|v| v.normal.1), float("Z", &|v| v.normal.2)
+        ],
+        AovType::Position => vec![
+            float("X", &|v| v.position.0), float("Y", &|v| v.position.1), float("Z", &|v| v.position.2)
+        ],
+        AovType::Depth => vec![float("Z", &|v| v.depth)],
+        AovType::ShapeId => vec![
+            ExrChannel::uint(&format!("{}.id", layer), values.iter().map(|v| v.shape_id).collect())
+        ],
+        AovType::MaterialId => vec![
+            ExrChannel::uint(&format!("{}.id", layer), values.iter().map(|v| v.material_id).collect())
+        ],
+        AovType::Variance | AovType::SampleCount => Vec::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulate_aovs() {
+        let mut pixel = AovPixel::zero();
+        let mut sample = AovSample::zero();
+        sample.depth = 2.0;
+        sample.normal = f32x3(0.0, 0.0, 1.0);
+        sample.shape_id = 3;
+        pixel.add(&sample);
+        sample.depth = 4.0;
+        sample.normal = f32x3(0.0, 1.0, 0.0);
+        sample.shape_id = 5;
+        pixel.add(&sample);
+
+        let value = pixel.get();
+        assert_eq!(value.depth, 3.0);
+        assert_eq!(value.shape_id, 3);
+        assert!((value.normal.length_sqr() - 1.0).abs() < 1e-5);
+
+        // misses of silhouette pixel count only in albedo, ids come from the first hit
+        let mut pixel = AovPixel::zero();
+        let miss = AovSample::zero();
+        pixel.add(&miss);
+        sample.albedo = Color { red: 1.0, green: 1.0, blue: 1.0 };
+        pixel.add(&sample);
+        pixel.add(&miss);
+        let value = pixel.get();
+        assert_eq!(value.depth, 4.0);
+        assert_eq!(value.shape_id, 5);
+        assert!((value.albedo.red - 1.0 / 3.0).abs() < 1e-5);
+        assert_eq!(AovType::from_name("samples"), Some(AovType::SampleCount));
+    }
+}
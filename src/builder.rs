@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::checkpoint::fnv1a_hash;
+use crate::filter::Filter;
+use crate::lights::PointLight;
+use crate::materials::{MatteEmissiveMaterial, MatteMaterial};
+use crate::pixel_buffer::Color;
+use crate::sampler::SamplerType;
+use crate::scene::{BSDFInterface, LightInterface, RenderingAlgorithm, SceneData};
+use crate::shapes::{GeometryInterface, Shape, Sphere, Triangle};
+use crate::tonemap::ToneMapping;
+use crate::vec::f32x3;
+
+
+// Programmatic construction of scene. Materials are referenced by name from shapes,
+// the first error (e.g. unknown material) is reported by build. Scene and image hashes
+// for checkpoints are computed from description of builder calls, materials, shapes and
+// lights given as trait objects are described by what they expose and settings changed
+// with configure are not hashed.
+//
+//     let scene_data = SceneBuilder::new()
+//         .resolution(640, 480)
+//         .samples_per_pixel(16)
+//         .camera(f32x3(0.0, 1.0, -5.0), f32x3(0.0, 0.0, 0.0), 60.0)
+//         .matte("red", Color { red: 0.8, green: 0.1, blue: 0.1 })
+//         .sphere(f32x3(0.0, 0.0, 0.0), 1.0, "red")
+//         .point_light(Color { red: 50.0, green: 50.0, blue: 50.0 }, f32x3(0.0, 5.0, -2.0))
+//         .build()?;
+pub struct SceneBuilder {
+    scene_data: SceneData,
+    materials: HashMap<String, usize>,
+    horizontal_fov: Option<f32>,
+    error: Option<String>,
+    // calls that change image and calls that only change sampling of it, like global keys of
+    // scene file that are part of scene hash and image hash
+    image_description: String,
+    sampling_description: String
+}
+
+impl Default for SceneBuilder {
+    fn default() -> Self {
+        SceneBuilder::new()
+    }
+}
+
+impl SceneBuilder {
+    pub fn new() -> SceneBuilder {
+        SceneBuilder {
+            scene_data: SceneData::default(),
+            materials: HashMap::new(),
+            horizontal_fov: None,
+            error: None,
+            image_description: String::new(),
+            sampling_description: String::new()
+        }
+    }
+
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        self.scene_data.set_image_size(width, height);
+        self.describe(format!("resolution {} {}", width, height));
+        self
+    }
+
+    pub fn samples_per_pixel(mut self, spp: usize) -> Self {
+        self.scene_data.set_samples_per_pixel(spp);
+        self.describe_sampling(format!("spp {}", spp));
+        self
+    }
+
+    pub fn threads(mut self, nthreads: usize) -> Self {
+        self.scene_data.set_nthreads(nthreads);
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: RenderingAlgorithm) -> Self {
+        self.describe(format!("algorithm {:?}", algorithm));
+        self.scene_data.set_rendering_algorithm(algorithm);
+        self
+    }
+
+    pub fn sampler(mut self, sampler_type: SamplerType) -> Self {
+        self.scene_data.set_sampler_type(sampler_type);
+        self.describe_sampling(format!("sampler {:?}", sampler_type));
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.scene_data.set_seed(seed);
+        self.describe_sampling(format!("seed {}", seed));
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.scene_data.set_filter(filter);
+        self.describe(format!("filter {:?}", filter));
+        self
+    }
+
+    pub fn tone_mapping(mut self, tone_mapping: ToneMapping) -> Self {
+        self.scene_data.set_tone_mapping(tone_mapping);
+        self
+    }
+
+    // Field of view depends on image width, so it is applied in build
+    pub fn camera(mut self, position: f32x3, look_at: f32x3, horizontal_fov: f32) -> Self {
+        self.scene_data.set_camera_pos(position);
+        self.scene_data.set_camera_look_at(look_at);
+        self.horizontal_fov = Some(horizontal_fov);
+        self.describe(format!("camera {:?} {:?} {}", position, look_at, horizontal_fov));
+        self
+    }
+
+    // Access to settings that have no builder method
+    pub fn configure<F: FnOnce(&mut SceneData)>(mut self, configure: F) -> Self {
+        configure(&mut self.scene_data);
+        self
+    }
+
+    pub fn material(mut self, name: &str, material: Box<dyn BSDFInterface + Send + Sync>) -> Self {
+        if self.materials.contains_key(name) {
+            self.set_error(format!("Material {} is defined twice.", name));
+            return self
+        }
+        self.describe(format!("material {} {:?} {:?} {} {}", name, material.albedo(), material.emssion(),
+            material.is_specular(), material.is_emissive()));
+        let material_id = self.scene_data.add_material(material);
+        self.scene_data.set_material_name(material_id, name);
+        self.materials.insert(name.to_string(), material_id);
+        self
+    }
+
+    pub fn matte(self, name: &str, reflectance: Color) -> Self {
+        self.material(name, Box::new(MatteMaterial::new(reflectance)))
+    }
+
+    pub fn emissive(self, name: &str, reflectance: Color, emission: Color) -> Self {
+        self.material(name, Box::new(MatteEmissiveMaterial::new(reflectance, emission)))
+    }
+
+    pub fn shape(mut self, geometry: Box<dyn GeometryInterface + Send + Sync>, material: &str) -> Self {
+        self.describe(format!("shape {} {:?} {} {}", geometry.name(), geometry.bbox(), geometry.area(), material));
+        match self.materials.get(material) {
+            Some(material_id) => self.scene_data.add_shape(Shape::new(geometry, *material_id)),
+            None => self.set_error(format!("Unknown material {}.", material))
+        }
+        self
+    }
+
+    pub fn sphere(self, center: f32x3, radius: f32, material: &str) -> Self {
+        self.shape(Box::new(Sphere::new(center, radius)), material)
+    }
+
+    pub fn triangle(self, v0: f32x3, v1: f32x3, v2: f32x3, material: &str) -> Self {
+        self.shape(Box::new(Triangle::new(v0, v1, v2)), material)
+    }
+
+    pub fn light(mut self, light: Box<dyn LightInterface + Send + Sync>) -> Self {
+        self.describe(format!("light {}", light.name()));
+        self.scene_data.add_light(light);
+        self
+    }
+
+    pub fn point_light(mut self, intensity: Color, position: f32x3) -> Self {
+        self.describe(format!("point light {:?} {:?}", intensity, position));
+        self.light(Box::new(PointLight::new(intensity, position)))
+    }
+
+    fn describe(&mut self, call: String) {
+        self.image_description.push_str(&call);
+        self.image_description.push('\n');
+    }
+
+    fn describe_sampling(&mut self, call: String) {
+        self.sampling_description.push_str(&call);
+        self.sampling_description.push('\n');
+    }
+
+    fn set_error(&mut self, error: String) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    // Emissive shapes become area lights
+    pub fn build(mut self) -> Result<SceneData, Box<dyn Error>> {
+        if let Some(error) = self.error {
+            return Err(error.into())
+        }
+        if let Some(fov) = self.horizontal_fov {
+            self.scene_data.set_camera_horizontal_fov(fov);
+        }
+        self.scene_data.create_area_lights();
+        let image_hash = fnv1a_hash(self.image_description.as_bytes());
+        self.scene_data.set_image_hash(image_hash);
+        self.scene_data.set_scene_hash(image_hash ^ fnv1a_hash(self.sampling_description.as_bytes()).rotate_left(1));
+        Ok(self.scene_data)
+    }
+}
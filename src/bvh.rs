@@ -14,6 +14,7 @@ struct BVHNode {
 }
 
 impl BVHNode {
+    #[allow(clippy::assign_op_pattern)]
     pub fn new(bbox: AABB, is_leaf: bool, left_or_prim: u32, right_or_count: u32) -> BVHNode {
         if left_or_prim & 0x80000000 != 0 {
             panic!("Only 31-bit are used to index BVH nodes. 32 bit is used to distinguish leaf nodes.")
@@ -40,6 +41,7 @@ impl BVHNode {
         (self.left_or_prim & 0x7FFFFFFF) as usize
     }
 
+    #[allow(dead_code)]
     pub fn count(&self) -> usize {
         self.right_or_count as usize
     }
@@ -53,14 +55,71 @@ pub struct BVH {
     nodes: Vec<BVHNode>
 }
 
+// Relative costs of bounding box test and primitive intersection used for SAH cost
+pub const TRAVERSAL_COST: f32 = 1.0;
+pub const INTERSECTION_COST: f32 = 1.0;
+
+// SAH cost is expected work of ray that crosses root bounding box, in units of bounding box tests
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BVHStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub depth: usize,
+    pub sah_cost: f32
+}
+
+// Probability that ray through root box also crosses the box, flat root has all boxes hit
+pub fn hit_probability(bbox: &AABB, root: &AABB) -> f32 {
+    let root_area = root.area();
+    if root_area > 0.0 { (bbox.area() / root_area).min(1.0) } else { 1.0 }
+}
+
+// Bounding volume tests and shape intersection tests done by one ray
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraversalStats {
+    pub node_visits: u32,
+    pub primitive_tests: u32
+}
+
 pub struct IsectInfo {
     pub primitive: usize,
     pub t: f64
 }
 
 impl BVH {
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats { nodes: self.nodes.len(), ..BVHStats::default() };
+        if self.nodes.is_empty() {
+            return stats
+        }
+        let root = self.nodes.len() - 1;
+        let mut stack = vec![(root, 1)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index];
+            let probability = hit_probability(node.bbox(), self.nodes[root].bbox());
+            stats.depth = stats.depth.max(depth);
+            stats.sah_cost += TRAVERSAL_COST * probability;
+            if node.is_leaf() {
+                stats.leaves += 1;
+                stats.sah_cost += INTERSECTION_COST * probability;
+            } else {
+                stack.push((node.left_child(), depth + 1));
+                stack.push((node.right_child(), depth + 1));
+            }
+        }
+        stats
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn memory(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<BVHNode>()
+    }
+
     pub fn intersection(&self, ray: &Ray, tmax: f32,
-        isect: &dyn Fn(usize, f64x3, f64x3, f64) -> Option<f64>) -> Option<IsectInfo> {
+        isect: &dyn Fn(usize, f64x3, f64x3, f64) -> Option<f64>, stats: &mut TraversalStats) -> Option<IsectInfo> {
 
         let root_node = self.nodes.len() - 1;
         let mut cur_t = tmax as f64;
@@ -79,10 +138,12 @@ impl BVH {
 
             let node = &self.nodes[stack[stack_pointer - 1]];
             stack_pointer -= 1;
+            stats.node_visits += 1;
 
             if node.bbox.intersection(ray) {
                 if node.is_leaf() {
                     let prim = node.primitive();
+                    stats.primitive_tests += 1;
                     if let Some(t) = isect(prim, origin, direction, cur_t) {
                         cur_t = t;
                         cur_primitive = prim;
@@ -101,6 +162,7 @@ impl BVH {
         None
     }
 
+    #[allow(clippy::needless_return)]
     pub fn visible(&self, ray: &Ray, tmax: f32,
         isect: &dyn Fn(usize, f64x3, f64x3, f64) -> Option<f64>) -> bool {
 
@@ -140,6 +202,7 @@ impl BVH {
 }
 
 
+#[allow(clippy::needless_return, clippy::needless_borrow)]
 pub fn build_bottom_up_bvh(primitives: &Vec<BVHPrimitive>) -> BVH {
     let mut nodes = Vec::with_capacity(primitives.len()*2);
     let mut active_nodes = HashSet::new();
@@ -195,6 +258,43 @@ pub fn build_bottom_up_bvh(primitives: &Vec<BVHPrimitive>) -> BVH {
 }
 
 
+// Primitives are split at median of their box centers along the axis where centers spread
+// the most, so the tree is balanced and its depth stays within the traversal stack.
+// Children are stored before their parent, root is the last node as in bottom up build.
+pub fn build_median_split_bvh(primitives: &[BVHPrimitive]) -> BVH {
+    let mut nodes = Vec::with_capacity(primitives.len() * 2);
+    let mut prims: Vec<&BVHPrimitive> = primitives.iter().collect();
+    if !prims.is_empty() {
+        median_split(&mut prims, &mut nodes);
+    }
+    BVH { nodes }
+}
+
+fn median_split(prims: &mut [&BVHPrimitive], nodes: &mut Vec<BVHNode>) -> usize {
+    let bbox = prims.iter().skip(1).fold(prims[0].bbox, |bbox, prim| bbox.merge(&prim.bbox));
+    if prims.len() == 1 {
+        nodes.push(BVHNode::new(bbox, true, prims[0].primitive as u32, 1));
+        return nodes.len() - 1
+    }
+    let center = |prim: &BVHPrimitive| (prim.bbox.min + prim.bbox.max) * 0.5;
+    let first = center(prims[0]);
+    let (low, high) = prims.iter().fold((first, first), |(low, high), prim| (low.min(center(prim)), high.max(center(prim))));
+    let extent = high - low;
+    let axis = if extent.0 >= extent.1 && extent.0 >= extent.2 { 0 } else if extent.1 >= extent.2 { 1 } else { 2 };
+    let key = |prim: &BVHPrimitive| {
+        let c = center(prim);
+        [c.0, c.1, c.2][axis]
+    };
+    let mid = prims.len() / 2;
+    prims.select_nth_unstable_by(mid, |a, b| key(a).total_cmp(&key(b)));
+    let (left, right) = prims.split_at_mut(mid);
+    let left = median_split(left, nodes);
+    let right = median_split(right, nodes);
+    nodes.push(BVHNode::new(bbox, false, left as u32, right as u32));
+    nodes.len() - 1
+}
+
+
 #[cfg(test)]
 mod tests {
 
@@ -204,9 +304,36 @@ mod tests {
     use std::mem;
 
     # [test]
+    #[allow(clippy::print_with_newline)]
     fn bvh_test() {
         print!("Bvh node size {}\n", mem::size_of::<BVHNode>());
         let bbox = AABB::new(f32x3(0.0, 0.5, 0.3), f32x3(0.99, 2.2, 3.3));
-        let bvh = BVHNode::new(bbox, true, 2000000000, 0);
+        let _bvh = BVHNode::new(bbox, true, 2000000000, 0);
+
+        let unit = |x: f32| AABB::new(f32x3(x, 0.0, 0.0), f32x3(x + 1.0, 1.0, 1.0));
+        let prims = vec![
+            BVHPrimitive{bbox: unit(0.0), primitive: 0},
+            BVHPrimitive{bbox: unit(1.0), primitive: 1},
+            BVHPrimitive{bbox: unit(5.0), primitive: 2}
+        ];
+        let stats = build_bottom_up_bvh(&prims).stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.depth), (5, 3, 3));
+        // root is always visited, leaves are hit with probability of their area
+        assert!(stats.sah_cost > 1.0 && stats.sah_cost < 5.0);
+
+        // balanced tree, ray along the row visits every node, ray above the boxes only the root
+        let prims: Vec<BVHPrimitive> = (0..8).map(|i| BVHPrimitive{bbox: unit(2.0 * i as f32), primitive: i}).collect();
+        let bvh = build_median_split_bvh(&prims);
+        let stats = bvh.stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.depth), (15, 8, 4));
+        let isect = |prim: usize, _origin: f64x3, _direction: f64x3, _tmax: f64| if prim == 3 { Some(1.0) } else { None };
+        let mut visits = TraversalStats::default();
+        let ray = Ray::new(f32x3(-1.0, 0.5, 0.5), f32x3(1.0, 0.0, 0.0), 0.0);
+        let hit = bvh.intersection(&ray, 100.0, &isect, &mut visits).unwrap();
+        assert_eq!((hit.primitive, visits.node_visits, visits.primitive_tests), (3, 15, 8));
+        let mut visits = TraversalStats::default();
+        let ray = Ray::new(f32x3(-1.0, 5.0, 0.5), f32x3(1.0, 0.0, 0.0), 0.0);
+        assert!(bvh.intersection(&ray, 100.0, &isect, &mut visits).is_none());
+        assert_eq!((visits.node_visits, visits.primitive_tests), (1, 0));
     }
 }
@@ -1,5 +1,6 @@
 use std::default::Default;
 use crate::{vec::f32x3, ray::Ray};
+use crate::transform::{Interpolation, interpolate_values};
 
 
 pub struct PinholeCamera {
@@ -7,6 +8,10 @@ pub struct PinholeCamera {
     look_at: f32x3,
     up: f32x3,
     view_plane_distance: f32,
+    shutter_open: f32,
+    shutter_close: f32,
+    // start of frame in animation, shutter is relative to it
+    time: f32,
 
     u: f32x3,
     v: f32x3,
@@ -18,7 +23,7 @@ impl PinholeCamera {
         let up = f32x3(0.0, 1.0, 0.0);
 
         let (u, v, w) = PinholeCamera::calculate_uvw(eye, look_at, up);
-        PinholeCamera { eye, look_at, up, view_plane_distance, u, v, w }
+        PinholeCamera { eye, look_at, up, view_plane_distance, shutter_open: 0.0, shutter_close: 0.0, time: 0.0, u, v, w }
     }
 
     fn calculate_uvw(eye: f32x3, look_at: f32x3, up: f32x3) -> (f32x3, f32x3, f32x3) {
@@ -66,9 +71,41 @@ impl PinholeCamera {
         self.calculate_and_set_uvw();
     }
 
-    pub fn generate_ray(&self, x: f32, y: f32) -> Ray {
+    pub fn position(&self) -> f32x3 {
+        self.eye
+    }
+
+    pub fn look_at(&self) -> f32x3 {
+        self.look_at
+    }
+
+    pub fn view_plane_distance(&self) -> f32 {
+        self.view_plane_distance
+    }
+
+    pub fn set_shutter(&mut self, shutter_open: f32, shutter_close: f32) {
+        self.shutter_open = shutter_open;
+        self.shutter_close = shutter_close;
+    }
+
+    pub fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+    }
+
+    // Interval of ray times in scene time
+    pub fn shutter_interval(&self) -> (f32, f32) {
+        (self.time + self.shutter_open, self.time + self.shutter_close)
+    }
+
+    // tp is uniform random number used to pick time inside shutter interval
+    pub fn generate_ray(&self, x: f32, y: f32, tp: f32) -> Ray {
         let direction = (x * self.u + y * self.v - self.view_plane_distance * self.w).normalize();
-        Ray::new(self.eye, direction)
+        let time = self.time + self.shutter_open + tp * (self.shutter_close - self.shutter_open);
+        Ray::new(self.eye, direction, time)
     }
 }
 
@@ -80,3 +117,55 @@ impl Default for PinholeCamera {
     }
 }
 
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraKeyframe {
+    pub time: f32,
+    pub eye: f32x3,
+    pub look_at: f32x3,
+    // horizontal field of view in degrees, track keeps field of view of camera when not set
+    pub hfov: Option<f32>
+}
+
+// Keyframed camera of animation. Camera is placed once per frame, so moving camera
+// doesn't produce motion blur.
+#[derive(Debug, Clone)]
+pub struct CameraTrack {
+    keyframes: Vec<CameraKeyframe>,
+    interpolation: Interpolation
+}
+
+impl CameraTrack {
+    // Field of view is animated only when all keyframes have it
+    pub fn new(mut keyframes: Vec<CameraKeyframe>, interpolation: Interpolation) -> CameraTrack {
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        if keyframes.iter().any(|k| k.hfov.is_none()) {
+            keyframes.iter_mut().for_each(|k| k.hfov = None);
+        }
+        CameraTrack { keyframes, interpolation }
+    }
+
+    pub fn evaluate(&self, time: f32) -> CameraKeyframe {
+        let n = self.keyframes.len();
+        if n == 1 || time <= self.keyframes[0].time {
+            return CameraKeyframe { time, ..self.keyframes[0] }
+        }
+        if time >= self.keyframes[n - 1].time {
+            return CameraKeyframe { time, ..self.keyframes[n - 1] }
+        }
+        let index = self.keyframes.partition_point(|k| k.time <= time) - 1;
+        let t = (time - self.keyframes[index].time) / (self.keyframes[index + 1].time - self.keyframes[index].time);
+        let times: Vec<f32> = self.keyframes.iter().map(|k| k.time).collect();
+        let track = |value: &dyn Fn(&CameraKeyframe) -> f32x3| -> f32x3 {
+            let values: Vec<f32x3> = self.keyframes.iter().map(value).collect();
+            interpolate_values(&times, &values, index, t, self.interpolation)
+        };
+        let eye = track(&|k| k.eye);
+        let look_at = track(&|k| k.look_at);
+        let hfov = self.keyframes[0].hfov.map(|_| track(&|k| {
+            let hfov = k.hfov.unwrap_or(0.0);
+            f32x3(hfov, hfov, hfov)
+        }).0);
+        CameraKeyframe { time, eye, look_at, hfov }
+    }
+}
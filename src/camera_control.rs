@@ -0,0 +1,142 @@
+use serde_json::json;
+
+use crate::scene::SceneData;
+use crate::vec::f32x3;
+
+
+const UP: f32x3 = f32x3(0.0, 1.0, 0.0);
+// camera is never turned exactly up or down, where its orientation is not defined
+const MAX_PITCH: f32 = 89.0;
+
+// Interactive navigation of camera. Orbit, pan and dolly move around the look at point,
+// fly moves eye and look at point together. Angles are in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraControl {
+    pub eye: f32x3,
+    pub look_at: f32x3,
+    pub hfov: f32
+}
+
+impl CameraControl {
+    pub fn from_scene(scene_data: &SceneData) -> CameraControl {
+        CameraControl {
+            eye: scene_data.get_camera_pos(),
+            look_at: scene_data.get_camera_look_at(),
+            hfov: scene_data.get_camera_horizontal_fov()
+        }
+    }
+
+    pub fn apply(&self, scene_data: &mut SceneData) {
+        scene_data.set_camera_pos(self.eye);
+        scene_data.set_camera_look_at(self.look_at);
+        scene_data.set_camera_horizontal_fov(self.hfov);
+    }
+
+    pub fn distance(&self) -> f32 {
+        (self.eye - self.look_at).length()
+    }
+
+    // Unit vectors forward, right and up of view
+    fn basis(&self) -> (f32x3, f32x3, f32x3) {
+        let forward = (self.look_at - self.eye).normalize();
+        let right = forward.cross(UP).normalize();
+        let up = right.cross(forward);
+        (forward, right, up)
+    }
+
+    // Rotates eye around look at point, yaw around vertical axis and pitch up and down
+    pub fn orbit(&mut self, yaw: f32, pitch: f32) {
+        let offset = self.eye - self.look_at;
+        let distance = offset.length();
+        let horizontal = (offset.0 * offset.0 + offset.2 * offset.2).sqrt();
+        let azimuth = offset.0.atan2(offset.2) + yaw.to_radians();
+        let elevation = (offset.1.atan2(horizontal).to_degrees() + pitch).clamp(-MAX_PITCH, MAX_PITCH).to_radians();
+        let dir = f32x3(elevation.cos() * azimuth.sin(), elevation.sin(), elevation.cos() * azimuth.cos());
+        self.eye = self.look_at + distance * dir;
+    }
+
+    // Moves camera in view plane, dx and dy are fractions of view width
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        let (_forward, right, up) = self.basis();
+        let view_width = 2.0 * self.distance() * (0.5 * self.hfov).to_radians().tan();
+        let offset = (dx * view_width) * right + (dy * view_width) * up;
+        self.eye = self.eye + offset;
+        self.look_at = self.look_at + offset;
+    }
+
+    // Moves eye towards look at point, distance is multiplied by factor
+    pub fn dolly(&mut self, factor: f32) {
+        let distance = (self.distance() * factor).max(1e-3);
+        let (forward, _right, _up) = self.basis();
+        self.eye = self.look_at - distance * forward;
+    }
+
+    // Moves camera along its view, distances are in scene units
+    pub fn fly(&mut self, forward: f32, right: f32, up: f32) {
+        let (f, r, _u) = self.basis();
+        let offset = forward * f + right * r + up * UP;
+        self.eye = self.eye + offset;
+        self.look_at = self.look_at + offset;
+    }
+
+    pub fn zoom(&mut self, delta_fov: f32) {
+        self.hfov = (self.hfov + delta_fov).clamp(1.0, 170.0);
+    }
+
+    // Camera block of scene file
+    pub fn to_json(&self, scene_data: &SceneData) -> String {
+        let round = |v: f32| (v as f64 * 1e4).round() / 1e4;
+        let vector = |v: f32x3| json!([round(v.0), round(v.1), round(v.2)]);
+        let mut camera = json!({
+            "eye": vector(self.eye),
+            "lookat": vector(self.look_at),
+            "hfov": round(self.hfov)
+        });
+        let (shutter_open, shutter_close) = scene_data.get_camera_shutter();
+        if shutter_close > shutter_open {
+            camera["shutter_open"] = json!(round(shutter_open));
+            camera["shutter_close"] = json!(round(shutter_close));
+        }
+        format!("\"camera\": {}", serde_json::to_string_pretty(&camera).unwrap_or_default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_navigation() {
+        let mut scene_data = SceneData::default();
+        scene_data.set_image_size(64, 48);
+        scene_data.set_camera_pos(f32x3(0.0, 0.0, -5.0));
+        scene_data.set_camera_look_at(f32x3(0.0, 0.0, 0.0));
+        scene_data.set_camera_horizontal_fov(60.0);
+        let mut control = CameraControl::from_scene(&scene_data);
+        assert!((control.hfov - 60.0).abs() < 1e-3);
+
+        control.orbit(90.0, 0.0);
+        assert!((control.eye - f32x3(-5.0, 0.0, 0.0)).length() < 1e-4);
+        control.orbit(0.0, 200.0);
+        assert!((control.distance() - 5.0).abs() < 1e-4);
+        assert!(control.eye.1 < 5.0 && control.eye.1 > 4.99);
+
+        let mut control = CameraControl::from_scene(&scene_data);
+        control.pan(0.5, 0.0);
+        assert!((control.look_at - control.eye - f32x3(0.0, 0.0, 5.0)).length() < 1e-4);
+        assert!(control.look_at.0.abs() > 1.0);
+        control.dolly(0.5);
+        assert!((control.distance() - 2.5).abs() < 1e-4);
+        control.fly(1.0, 0.0, 1.0);
+        assert!((control.distance() - 2.5).abs() < 1e-4);
+        control.zoom(-100.0);
+        assert_eq!(control.hfov, 1.0);
+
+        control.apply(&mut scene_data);
+        assert_eq!(CameraControl::from_scene(&scene_data).eye, control.eye);
+        let camera: serde_json::Value = serde_json::from_str(&control.to_json(&scene_data)[10..]).unwrap();
+        assert_eq!(camera["hfov"], json!(1.0));
+        assert!(camera["eye"].as_array().unwrap().len() == 3);
+    }
+}
@@ -0,0 +1,219 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::pixel_buffer::{Color, PixelBuffer};
+use crate::scene::CropWindow;
+
+
+const MAGIC: &[u8; 8] = b"RSTCKPT\0";
+const VERSION: u32 = 3;
+
+// Progress of renderer at the moment when no tiles are being rendered. Tiles that were
+// dispatched but not written to pixel buffer, e.g. jobs of lost workers or tiles waiting
+// for them, are stored as (tile, sample index) in dispatch order and rendered again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderProgress {
+    pub n_tiles_processed: usize,
+    pub current_pass: usize,
+    pub pass_tiles: Vec<usize>,
+    pub pass_position: usize,
+    pub unfinished_jobs: Vec<(usize, usize)>,
+    pub all_passes_dispatched: bool,
+    pub render_time: Duration,
+    pub samples: u64,
+    pub rays: u64
+}
+
+// Seed and window of rendered pixels tell which samples merged checkpoints would share
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointHeader {
+    pub scene_hash: u64,
+    pub image_hash: u64,
+    pub seed: u64,
+    pub window: CropWindow,
+    pub progress: RenderProgress
+}
+
+// Checkpoint file stores header with scene and image hashes and progress counters and raw accumulation
+// of pixel buffer. File is written to temporary file first, so crash during writing keeps previous checkpoint.
+pub fn write_checkpoint<P: AsRef<Path>>(path: P, header: &CheckpointHeader,
+                                        pixel_buffer: &PixelBuffer) -> Result<(), Box<dyn Error>> {
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let w: &mut dyn Write = &mut writer;
+        w.write_all(MAGIC)?;
+        write_u32(w, VERSION)?;
+        let progress = &header.progress;
+        write_u64(w, header.scene_hash)?;
+        write_u64(w, header.image_hash)?;
+        write_u64(w, header.seed)?;
+        let window = &header.window;
+        for value in [window.x0, window.y0, window.x1, window.y1] {
+            write_u32(w, value as u32)?;
+        }
+        write_u64(w, progress.n_tiles_processed as u64)?;
+        write_u64(w, progress.current_pass as u64)?;
+        write_u64(w, progress.pass_position as u64)?;
+        write_u32(w, progress.all_passes_dispatched as u32)?;
+        write_f64(w, progress.render_time.as_secs_f64())?;
+        write_u64(w, progress.samples)?;
+        write_u64(w, progress.rays)?;
+        write_u64(w, progress.pass_tiles.len() as u64)?;
+        for tile in progress.pass_tiles.iter() {
+            write_u32(w, *tile as u32)?;
+        }
+        write_u64(w, progress.unfinished_jobs.len() as u64)?;
+        for (tile, sample_index) in progress.unfinished_jobs.iter() {
+            write_u32(w, *tile as u32)?;
+            write_u32(w, *sample_index as u32)?;
+        }
+        pixel_buffer.write_raw(w)?;
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Restores pixel buffer from checkpoint, checkpoint of different scene is refused
+pub fn read_checkpoint<P: AsRef<Path>>(path: P, scene_hash: u64,
+                                       pixel_buffer: &mut PixelBuffer) -> Result<RenderProgress, Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    let r: &mut dyn Read = &mut reader;
+    let header = read_header(r, path)?;
+    if header.scene_hash != scene_hash {
+        return Err(format!("Checkpoint {} was rendered from different scene.", path.display()).into())
+    }
+    pixel_buffer.read_raw(r)?;
+    Ok(header.progress)
+}
+
+// Reads accumulation of checkpoint that is merged with other renders of the same image,
+// seed, samples per pixel and crop window of checkpoint may differ
+pub fn read_checkpoint_pixels<P: AsRef<Path>>(path: P, image_hash: u64,
+                                              pixel_buffer: &mut PixelBuffer) -> Result<CheckpointHeader, Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    let r: &mut dyn Read = &mut reader;
+    let header = read_header(r, path)?;
+    if header.image_hash != image_hash {
+        return Err(format!("Checkpoint {} was rendered from different scene.", path.display()).into())
+    }
+    pixel_buffer.read_raw(r)?;
+    Ok(header)
+}
+
+pub fn read_checkpoint_header<P: AsRef<Path>>(path: P) -> Result<CheckpointHeader, Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    read_header(&mut reader, path)
+}
+
+fn read_header(r: &mut dyn Read, path: &Path) -> Result<CheckpointHeader, Box<dyn Error>> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(format!("File {} is not a checkpoint.", path.display()).into())
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(format!("Unsupported checkpoint version {} in {}.", version, path.display()).into())
+    }
+    let scene_hash = read_u64(r)?;
+    let image_hash = read_u64(r)?;
+    let seed = read_u64(r)?;
+    let window = CropWindow {
+        x0: read_u32(r)? as usize,
+        y0: read_u32(r)? as usize,
+        x1: read_u32(r)? as usize,
+        y1: read_u32(r)? as usize
+    };
+    let n_tiles_processed = read_u64(r)? as usize;
+    let current_pass = read_u64(r)? as usize;
+    let pass_position = read_u64(r)? as usize;
+    let all_passes_dispatched = read_u32(r)? != 0;
+    let render_time = Duration::from_secs_f64(read_f64(r)?);
+    let samples = read_u64(r)?;
+    let rays = read_u64(r)?;
+    let n_pass_tiles = read_u64(r)? as usize;
+    let mut pass_tiles = Vec::with_capacity(n_pass_tiles.min(1 << 20));
+    for _ in 0..n_pass_tiles {
+        pass_tiles.push(read_u32(r)? as usize);
+    }
+    let n_unfinished_jobs = read_u64(r)? as usize;
+    let mut unfinished_jobs = Vec::with_capacity(n_unfinished_jobs.min(1 << 16));
+    for _ in 0..n_unfinished_jobs {
+        unfinished_jobs.push((read_u32(r)? as usize, read_u32(r)? as usize));
+    }
+    let progress = RenderProgress {
+        n_tiles_processed, current_pass, pass_tiles, pass_position, unfinished_jobs, all_passes_dispatched,
+        render_time, samples, rays
+    };
+    Ok(CheckpointHeader { scene_hash, image_hash, seed, window, progress })
+}
+
+// FNV-1a, stable across platforms and compiler versions unlike std hasher
+pub fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Values are stored in little endian
+pub fn write_u32(w: &mut dyn Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64(w: &mut dyn Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f32(w: &mut dyn Write, value: f32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f64(w: &mut dyn Write, value: f64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_color(w: &mut dyn Write, color: &Color) -> io::Result<()> {
+    write_f32(w, color.red)?;
+    write_f32(w, color.green)?;
+    write_f32(w, color.blue)
+}
+
+pub fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f32(r: &mut dyn Read) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+pub fn read_f64(r: &mut dyn Read) -> io::Result<f64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+pub fn read_color(r: &mut dyn Read) -> io::Result<Color> {
+    Ok(Color { red: read_f32(r)?, green: read_f32(r)?, blue: read_f32(r)? })
+}
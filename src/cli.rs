@@ -0,0 +1,240 @@
+use std::error::Error;
+use std::fs;
+
+use serde_json::Value;
+
+use crate::json::apply_json_overrides;
+
+
+pub const USAGE: &str = "Usage:
+    rs_tracer [render] scene.json [--console] [--resume checkpoint.ckpt] [--workers host:port,...] [overrides]
+    rs_tracer info scene.json [overrides]
+    rs_tracer validate scene.json [overrides]
+    rs_tracer convert scene.json checkpoint.ckpt [overrides]
+    rs_tracer bench scene.json [--runs n] [overrides]
+    rs_tracer merge scene.json part1.ckpt part2.ckpt ... [overrides]
+    rs_tracer worker port [--listen address]
+
+Overrides:
+    --spp n  --resolution WxH  --threads n  --output file  --algorithm name  --seed n
+    --crop x0,y0,x1,y1  --set path=value (e.g. camera.hfov=40 or shapes[2].radius=0.5)";
+
+// Scene file with command line overrides, overrides are applied to JSON before parsing
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneArgs {
+    pub file: String,
+    pub overrides: Vec<(String, Value)>
+}
+
+impl SceneArgs {
+    // Contents of scene file after overrides
+    pub fn load(&self) -> Result<String, Box<dyn Error>> {
+        let contents = match fs::read_to_string(&self.file) {
+            Ok(contents) => contents,
+            Err(err) => return Err(format!("Problem reading input file {}: {}", self.file, err).into())
+        };
+        if self.overrides.is_empty() {
+            return Ok(contents)
+        }
+        apply_json_overrides(&contents, &self.overrides)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Render { scene: SceneArgs, console: bool, resume: Option<String>, workers: Vec<String> },
+    Info(SceneArgs),
+    Validate(SceneArgs),
+    // writes image of checkpoint, e.g. of interrupted render
+    Convert { scene: SceneArgs, checkpoint: String },
+    Bench { scene: SceneArgs, runs: usize },
+    Merge { scene: SceneArgs, checkpoints: Vec<String> },
+    Worker { port: u16, listen: String },
+    Help
+}
+
+fn parse_number(value: &str, option: &str) -> Result<Value, Box<dyn Error>> {
+    match value.parse::<u64>() {
+        Ok(number) => Ok(Value::from(number)),
+        Err(_) => Err(format!("Option {} expects non negative integer, got {}.", option, value).into())
+    }
+}
+
+// Value of --set is JSON, anything that is not valid JSON is taken as string
+fn parse_set(value: &str) -> Result<(String, Value), Box<dyn Error>> {
+    match value.split_once('=') {
+        Some((path, value)) if !path.is_empty() => {
+            let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+            Ok((path.to_string(), value))
+        },
+        _ => Err(format!("Option --set expects path=value, got {}.", value).into())
+    }
+}
+
+fn parse_resolution(value: &str) -> Result<Value, Box<dyn Error>> {
+    let size = value.split_once('x').and_then(|(w, h)| Some((w.parse::<u64>().ok()?, h.parse::<u64>().ok()?)));
+    match size {
+        Some((width, height)) => Ok(Value::from(vec![width, height])),
+        None => Err(format!("Option --resolution expects WxH, got {}.", value).into())
+    }
+}
+
+// Integers are pixels and fractions are relative to resolution, like in scene file
+fn parse_crop(value: &str) -> Result<Value, Box<dyn Error>> {
+    let mut values = Vec::new();
+    for item in value.split(',') {
+        let item = item.trim();
+        match (item.parse::<u64>(), item.parse::<f64>()) {
+            (Ok(pixel), _) => values.push(Value::from(pixel)),
+            (_, Ok(fraction)) => values.push(Value::from(fraction)),
+            _ => return Err(format!("Option --crop expects x0,y0,x1,y1, got {}.", value).into())
+        }
+    }
+    Ok(Value::Array(values))
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, Box<dyn Error>> {
+    let (command, rest) = match args.first().map(|arg| arg.as_str()) {
+        None => return Err("Missing scene description file.".into()),
+        Some("help") | Some("--help") | Some("-h") => return Ok(Command::Help),
+        Some("worker") | Some("--worker") => {
+            // worker is reachable only from this machine unless listen address is given
+            let listen = match &args[args.len().min(2)..] {
+                [] => "127.0.0.1".to_string(),
+                [option, address] if option == "--listen" => address.clone(),
+                _ => return Err("Command worker expects port number and optional --listen address.".into())
+            };
+            return match args.get(1).map(|port| port.parse::<u16>()) {
+                Some(Ok(port)) => Ok(Command::Worker { port, listen }),
+                _ => Err("Command worker expects port number.".into())
+            }
+        },
+        Some(command @ ("render" | "info" | "validate" | "convert" | "bench" | "merge")) => (command, &args[1..]),
+        // scene file without command is rendered
+        Some(_) => ("render", args)
+    };
+
+    let mut files = Vec::new();
+    let mut overrides = Vec::new();
+    let (mut console, mut resume, mut workers, mut runs) = (false, None, Vec::new(), 1);
+    let mut index = 0;
+    while index < rest.len() {
+        let arg = rest[index].as_str();
+        if !arg.starts_with("--") {
+            files.push(arg.to_string());
+            index += 1;
+            continue
+        }
+        if arg == "--console" && command == "render" {
+            console = true;
+            index += 1;
+            continue
+        }
+        let value = match rest.get(index + 1) {
+            Some(value) => value.as_str(),
+            None => return Err(format!("Missing value after {}.", arg).into())
+        };
+        match (arg, command) {
+            ("--spp", _) => overrides.push(("global.spp".to_string(), parse_number(value, arg)?)),
+            ("--threads", _) => overrides.push(("global.nthreads".to_string(), parse_number(value, arg)?)),
+            ("--seed", _) => overrides.push(("global.seed".to_string(), parse_number(value, arg)?)),
+            ("--resolution", _) => overrides.push(("global.resolution".to_string(), parse_resolution(value)?)),
+            ("--output", _) => overrides.push(("global.output".to_string(), Value::from(value))),
+            ("--algorithm", _) => overrides.push(("global.rendering".to_string(), Value::from(value))),
+            ("--crop", _) => overrides.push(("global.crop".to_string(), parse_crop(value)?)),
+            ("--set", _) => overrides.push(parse_set(value)?),
+            ("--resume", "render") => resume = Some(value.to_string()),
+            ("--workers", "render") => workers.extend(value.split(',').filter(|a| !a.is_empty()).map(|a| a.to_string())),
+            ("--runs", "bench") => runs = parse_number(value, arg)?.as_u64().unwrap_or(1).max(1) as usize,
+            _ => return Err(format!("Unknown option {} for command {}.", arg, command).into())
+        }
+        index += 2;
+    }
+
+    if files.is_empty() {
+        return Err(format!("Command {} expects scene description file.", command).into())
+    }
+    let scene = SceneArgs { file: files.remove(0), overrides };
+    let expect_files = |count: usize| -> Result<(), Box<dyn Error>> {
+        if files.len() != count {
+            return Err(format!("Unexpected arguments for command {}: {}", command, files.join(" ")).into())
+        }
+        Ok(())
+    };
+    match command {
+        "render" => {
+            expect_files(0)?;
+            Ok(Command::Render { scene, console, resume, workers })
+        },
+        "info" => {
+            expect_files(0)?;
+            Ok(Command::Info(scene))
+        },
+        "validate" => {
+            expect_files(0)?;
+            Ok(Command::Validate(scene))
+        },
+        "convert" => {
+            expect_files(1)?;
+            Ok(Command::Convert { scene, checkpoint: files.remove(0) })
+        },
+        "bench" => {
+            expect_files(0)?;
+            Ok(Command::Bench { scene, runs })
+        },
+        _ => {
+            if files.is_empty() {
+                return Err("Command merge expects checkpoint files.".into())
+            }
+            Ok(Command::Merge { scene, checkpoints: files })
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn command_line() {
+        let command = parse_args(&args("scene.json --console --spp 16 --set camera.hfov=40 --set global.output=a.exr")).unwrap();
+        let scene = SceneArgs { file: "scene.json".to_string(), overrides: vec![
+            ("global.spp".to_string(), json!(16)),
+            ("camera.hfov".to_string(), json!(40)),
+            ("global.output".to_string(), json!("a.exr"))
+        ]};
+        assert_eq!(command, Command::Render { scene, console: true, resume: None, workers: Vec::new() });
+
+        match parse_args(&args("bench s.json --runs 3 --resolution 64x48 --crop 0,0,0.5,1")).unwrap() {
+            Command::Bench { scene, runs } => {
+                assert_eq!(runs, 3);
+                assert_eq!(scene.overrides[0].1, json!([64, 48]));
+                assert_eq!(scene.overrides[1].1, json!([0, 0, 0.5, 1]));
+            },
+            command => panic!("Unexpected command {:?}", command)
+        }
+        assert!(matches!(parse_args(&args("merge s.json a.ckpt b.ckpt")).unwrap(), Command::Merge { checkpoints, .. } if checkpoints.len() == 2));
+        assert_eq!(parse_args(&args("worker 7000")).unwrap(), Command::Worker { port: 7000, listen: "127.0.0.1".to_string() });
+        assert_eq!(parse_args(&args("worker 7000 --listen 0.0.0.0")).unwrap(), Command::Worker { port: 7000, listen: "0.0.0.0".to_string() });
+        assert!(parse_args(&args("worker 7000 0.0.0.0")).is_err());
+        assert_eq!(parse_args(&args("--help")).unwrap(), Command::Help);
+        assert!(parse_args(&[]).is_err());
+        assert!(parse_args(&args("info s.json --console")).is_err());
+        assert!(parse_args(&args("render s.json --spp many")).is_err());
+        assert!(parse_args(&args("convert s.json")).is_err());
+        assert!(parse_args(&args("validate s.json --seed")).is_err());
+
+        let json = apply_json_overrides(r#"{"shapes": [{"radius": 1}, {"radius": 2}]}"#,
+            &[("shapes[1].radius".to_string(), json!(5)), ("global.spp".to_string(), json!(8))]).unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["shapes"][1]["radius"], json!(5));
+        assert_eq!(value["global"]["spp"], json!(8));
+        assert!(apply_json_overrides("{}", &[("shapes[0].radius".to_string(), json!(1))]).is_err());
+    }
+}
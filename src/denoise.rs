@@ -0,0 +1,177 @@
+use crate::pixel_buffer::Color;
+use crate::traits::Zero;
+use crate::vec::f32x3;
+
+
+// Feature buffers used to guide denoising, all buffers have one value per pixel
+pub struct DenoiseFeatures<'a> {
+    pub albedo: &'a [Color],
+    pub normal: &'a [f32x3],
+    pub depth: &'a [f32],
+    // variance of pixel estimate (luminance)
+    pub variance: &'a [f32]
+}
+
+const ITERATIONS: usize = 5;
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+const SIGMA_LUMINANCE: f32 = 4.0;
+const SIGMA_NORMAL: f32 = 64.0;
+const SIGMA_DEPTH: f32 = 0.05;
+const SIGMA_ALBEDO: f32 = 0.1;
+
+// Edge avoiding a-trous wavelet filter (Dammertz et al., SVGF). Each iteration is 5x5
+// B-spline kernel with holes of size 2^i. Weights are reduced across edges in albedo,
+// normal and depth and where luminance difference is large compared to its standard deviation.
+// Lighting is filtered without albedo (demodulated), so texture detail is kept.
+pub fn denoise(width: usize, height: usize, color: &[Color], features: &DenoiseFeatures) -> Vec<Color> {
+    let mut lighting: Vec<Color> = color.iter().zip(features.albedo)
+        .map(|(c, a)| Color {
+            red: demodulate(c.red, a.red),
+            green: demodulate(c.green, a.green),
+            blue: demodulate(c.blue, a.blue)
+        }).collect();
+    // variance is given for color, it is scaled to demodulated lighting. With few samples
+    // per pixel the estimate is unreliable, so spatial variance of neighbourhood is its lower bound.
+    let spatial = spatial_variance(width, height, &lighting, features);
+    let mut variance: Vec<f32> = features.variance.iter().zip(features.albedo).zip(spatial)
+        .map(|((v, a), spatial_var)| {
+            let lum = a.luminance();
+            let var = if lum > 0.01 { v / (lum * lum) } else { *v };
+            var.max(spatial_var)
+        }).collect();
+
+    let mut next_lighting = vec![Color::zero(); lighting.len()];
+    let mut next_variance = vec![0.0; variance.len()];
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        for y in 0..height {
+            for x in 0..width {
+                let center = y * width + x;
+                let lum_c = lighting[center].luminance();
+                let std_dev = SIGMA_LUMINANCE * variance[center].max(0.0).sqrt() + 1e-4;
+                let mut sum = Color::zero();
+                let mut sum_var = 0.0;
+                let mut sum_weight = 0.0;
+                for j in -2i32..=2 {
+                    let py = y as i32 + j * step;
+                    if py < 0 || py >= height as i32 {
+                        continue;
+                    }
+                    for i in -2i32..=2 {
+                        let px = x as i32 + i * step;
+                        if px < 0 || px >= width as i32 {
+                            continue;
+                        }
+                        let index = py as usize * width + px as usize;
+                        let kernel = KERNEL[j.unsigned_abs() as usize] * KERNEL[i.unsigned_abs() as usize];
+                        let weight = kernel * edge_weight(center, index, features) *
+                            (-(lighting[index].luminance() - lum_c).abs() / std_dev).exp();
+                        sum += lighting[index] * weight;
+                        sum_var += weight * weight * variance[index];
+                        sum_weight += weight;
+                    }
+                }
+                next_lighting[center] = sum * (1.0 / sum_weight);
+                next_variance[center] = sum_var / (sum_weight * sum_weight);
+            }
+        }
+        std::mem::swap(&mut lighting, &mut next_lighting);
+        std::mem::swap(&mut variance, &mut next_variance);
+    }
+
+    lighting.iter().zip(features.albedo)
+        .map(|(l, a)| Color {
+            red: remodulate(l.red, a.red),
+            green: remodulate(l.green, a.green),
+            blue: remodulate(l.blue, a.blue)
+        }).collect()
+}
+
+// Pixels without albedo (background, lights) are filtered as they are
+fn demodulate(value: f32, albedo: f32) -> f32 {
+    if albedo > 1e-3 { value / albedo } else { value }
+}
+
+fn remodulate(value: f32, albedo: f32) -> f32 {
+    if albedo > 1e-3 { value * albedo } else { value }
+}
+
+// Luminance variance in 3x3 window of pixels with similar features
+fn spatial_variance(width: usize, height: usize, lighting: &[Color], features: &DenoiseFeatures) -> Vec<f32> {
+    let mut variance = vec![0.0; lighting.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let center = y * width + x;
+            let (mut sum, mut sum_sqr, mut sum_weight) = (0.0, 0.0, 0.0);
+            for py in y.saturating_sub(1)..(y + 2).min(height) {
+                for px in x.saturating_sub(1)..(x + 2).min(width) {
+                    let index = py * width + px;
+                    let weight = edge_weight(center, index, features);
+                    let lum = lighting[index].luminance();
+                    sum += weight * lum;
+                    sum_sqr += weight * lum * lum;
+                    sum_weight += weight;
+                }
+            }
+            let mean = sum / sum_weight;
+            variance[center] = (sum_sqr / sum_weight - mean * mean).max(0.0);
+        }
+    }
+    variance
+}
+
+fn edge_weight(center: usize, index: usize, features: &DenoiseFeatures) -> f32 {
+    let (nc, ni) = (features.normal[center], features.normal[index]);
+    // background has zero normal, it is filtered only with other background pixels
+    let w_normal = if nc.length_sqr() == 0.0 && ni.length_sqr() == 0.0 {
+        1.0
+    } else {
+        nc.dot(ni).max(0.0).powf(SIGMA_NORMAL)
+    };
+
+    let depth_c = features.depth[center];
+    let w_depth = (-(depth_c - features.depth[index]).abs() / (SIGMA_DEPTH * depth_c.max(1e-3))).exp();
+
+    let a = features.albedo[center];
+    let b = features.albedo[index];
+    let albedo_diff = (a.red - b.red).abs() + (a.green - b.green).abs() + (a.blue - b.blue).abs();
+    let w_albedo = (-albedo_diff / SIGMA_ALBEDO).exp();
+    w_normal * w_depth * w_albedo
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn denoise_flat_region() {
+        let (width, height) = (32, 32);
+        let n = width * height;
+        // noisy constant image on two planes with different albedo
+        let mut color = Vec::with_capacity(n);
+        let mut albedo = Vec::with_capacity(n);
+        for y in 0..height {
+            for x in 0..width {
+                let a = if x < 16 { 0.8 } else { 0.2 };
+                let noise = if (x * 7 + y * 13) % 5 == 0 { 1.5 } else { 0.875 };
+                albedo.push(Color { red: a, green: a, blue: a });
+                color.push(Color { red: a * noise, green: a * noise, blue: a * noise });
+            }
+        }
+        let normal = vec![f32x3(0.0, 0.0, -1.0); n];
+        let depth = vec![2.0; n];
+        let variance = vec![0.05; n];
+        let features = DenoiseFeatures { albedo: &albedo, normal: &normal, depth: &depth, variance: &variance };
+        let result = denoise(width, height, &color, &features);
+
+        let error = |img: &[Color]| -> f32 {
+            img.iter().zip(albedo.iter()).map(|(c, a)| (c.red - a.red).abs()).sum::<f32>() / n as f32
+        };
+        assert!(error(&result) < 0.5 * error(&color));
+        // edge between albedos is preserved
+        assert!((result[16 * width + 15].red - 0.8).abs() < 0.15);
+        assert!((result[16 * width + 16].red - 0.2).abs() < 0.05);
+    }
+}
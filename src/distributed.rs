@@ -0,0 +1,401 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::checkpoint::{fnv1a_hash, read_u32, read_u64, write_u32, write_u64};
+use crate::img_sampling::Tile;
+use crate::json::parse_json_str;
+use crate::renderer::{TileData, render_tile};
+use crate::sampler::create_sampler;
+use crate::scene::SceneData;
+
+
+// Coordinator and worker exchange messages over TCP, each message starts with its tag
+// and values are little endian like in checkpoint files.
+const MSG_SCENE: u32 = 1;
+const MSG_READY: u32 = 2;
+const MSG_ERROR: u32 = 3;
+const MSG_JOB: u32 = 4;
+const MSG_RESULT: u32 = 5;
+const MSG_CLOSE: u32 = 6;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// worker parses scene and builds BVH before it answers
+const SCENE_TIMEOUT: Duration = Duration::from_secs(600);
+// worker that doesn't return tile in time is treated as disconnected
+const TILE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Length prefixes come from the network, so they are limited before anything is allocated
+const MAX_SCENE_LEN: u64 = 16 << 20;
+const MAX_TEXT_LEN: u64 = 64 << 10;
+
+// Scene as it is shipped to workers. Workers parse the same JSON and move it to the same
+// frame of animation, so scene hashes of coordinator and workers must match. Scene files
+// don't reference external files, shipping of meshes or textures is not supported.
+#[derive(Debug, Clone)]
+pub struct SceneSource {
+    pub json: String,
+    pub frame: Option<usize>
+}
+
+impl SceneSource {
+    pub fn new(json: String) -> SceneSource {
+        SceneSource { json, frame: None }
+    }
+
+    pub fn from_file(filename: &str) -> Result<SceneSource, Box<dyn Error>> {
+        Ok(SceneSource::new(fs::read_to_string(filename)?))
+    }
+
+    fn hash(&self) -> u64 {
+        fnv1a_hash(self.json.as_bytes()) ^ self.frame.map_or(0, |frame| frame as u64 + 1)
+    }
+}
+
+enum Message {
+    Scene(SceneSource),
+    Ready { nthreads: usize, scene_hash: u64 },
+    Error(String),
+    Job { tile: Tile, sample_index: usize, sequence: usize },
+    Result { sequence: usize, data: TileData },
+    Close
+}
+
+fn write_bytes(w: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn read_bytes(r: &mut dyn Read, max_len: u64) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Message is too long."))
+    }
+    let mut bytes = vec![0u8; len as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_string(r: &mut dyn Read, max_len: u64) -> io::Result<String> {
+    String::from_utf8(read_bytes(r, max_len)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_message(w: &mut dyn Write, message: &Message) -> io::Result<()> {
+    match message {
+        Message::Scene(source) => {
+            write_u32(w, MSG_SCENE)?;
+            write_bytes(w, source.json.as_bytes())?;
+            write_u64(w, source.frame.map_or(u64::MAX, |frame| frame as u64))?;
+        },
+        Message::Ready { nthreads, scene_hash } => {
+            write_u32(w, MSG_READY)?;
+            write_u32(w, *nthreads as u32)?;
+            write_u64(w, *scene_hash)?;
+        },
+        Message::Error(text) => {
+            write_u32(w, MSG_ERROR)?;
+            write_bytes(w, text.as_bytes())?;
+        },
+        Message::Job { tile, sample_index, sequence } => {
+            write_u32(w, MSG_JOB)?;
+            for value in [tile.startx, tile.starty, tile.endx, tile.endy, *sample_index] {
+                write_u32(w, value as u32)?;
+            }
+            write_u64(w, *sequence as u64)?;
+        },
+        Message::Result { sequence, data } => {
+            write_u32(w, MSG_RESULT)?;
+            write_u64(w, *sequence as u64)?;
+            data.write_raw(w)?;
+        },
+        Message::Close => write_u32(w, MSG_CLOSE)?
+    }
+    w.flush()
+}
+
+fn read_message(r: &mut dyn Read) -> io::Result<Message> {
+    let message = match read_u32(r)? {
+        MSG_SCENE => {
+            let mut source = SceneSource::new(read_string(r, MAX_SCENE_LEN)?);
+            let frame = read_u64(r)?;
+            source.frame = if frame == u64::MAX { None } else { Some(frame as usize) };
+            Message::Scene(source)
+        },
+        MSG_READY => Message::Ready { nthreads: read_u32(r)? as usize, scene_hash: read_u64(r)? },
+        MSG_ERROR => Message::Error(read_string(r, MAX_TEXT_LEN)?),
+        MSG_JOB => {
+            let tile = Tile {
+                startx: read_u32(r)? as usize,
+                starty: read_u32(r)? as usize,
+                endx: read_u32(r)? as usize,
+                endy: read_u32(r)? as usize
+            };
+            let sample_index = read_u32(r)? as usize;
+            Message::Job { tile, sample_index, sequence: read_u64(r)? as usize }
+        },
+        MSG_RESULT => Message::Result { sequence: read_u64(r)? as usize, data: TileData::read_raw(r)? },
+        MSG_CLOSE => Message::Close,
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown message {}.", tag)))
+    };
+    Ok(message)
+}
+
+// Connection of coordinator to one thread of remote worker
+pub struct WorkerConnection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>
+}
+
+impl WorkerConnection {
+    // Sends scene to worker and returns connection with number of threads of worker
+    pub fn connect(address: &str, source: &SceneSource,
+                   scene_hash: u64) -> Result<(WorkerConnection, usize), Box<dyn Error>> {
+        let addr = match address.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(format!("Unknown worker address {}.", address).into())
+        };
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(SCENE_TIMEOUT))?;
+        let mut connection = WorkerConnection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream)
+        };
+        write_message(&mut connection.writer, &Message::Scene(source.clone()))?;
+        let nthreads = match read_message(&mut connection.reader)? {
+            Message::Ready { nthreads, scene_hash: worker_hash } => {
+                if worker_hash != scene_hash {
+                    return Err(format!("Worker {} parsed scene with different hash.", address).into())
+                }
+                nthreads.max(1)
+            },
+            Message::Error(text) => return Err(format!("Worker {}: {}", address, text).into()),
+            _ => return Err(format!("Worker {} sent unexpected message.", address).into())
+        };
+        connection.writer.get_ref().set_read_timeout(Some(TILE_TIMEOUT))?;
+        Ok((connection, nthreads))
+    }
+
+    pub(crate) fn render_tile(&mut self, tile: &Tile, sample_index: usize,
+                              sequence: usize) -> Result<TileData, Box<dyn Error>> {
+        write_message(&mut self.writer, &Message::Job { tile: *tile, sample_index, sequence })?;
+        match read_message(&mut self.reader)? {
+            Message::Result { sequence: result_sequence, data } if result_sequence == sequence => {
+                if !data.is_inside(tile) {
+                    return Err("Worker returned samples outside of tile.".into())
+                }
+                Ok(data)
+            },
+            Message::Error(text) => Err(text.into()),
+            _ => Err("Worker sent unexpected message.".into())
+        }
+    }
+
+    pub fn close(mut self) {
+        let _result = write_message(&mut self.writer, &Message::Close);
+        let _result = self.writer.get_ref().shutdown(Shutdown::Both);
+    }
+}
+
+// Worker keeps the last prepared scene, so connections for all threads of the
+// coordinator share it and the scene is parsed only once
+type SceneCache = Arc<Mutex<Option<(u64, Arc<SceneData>)>>>;
+
+// Listens for coordinators, used by worker command. Anyone who can connect gets the worker
+// to render, so it listens on other interfaces than loopback only when asked to.
+pub fn run_worker(listen: &str, port: u16) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind((listen, port))?;
+    serve(listener, num_cpus::get());
+    Ok(())
+}
+
+// Each connection renders tiles in its own thread, coordinator opens one connection
+// for each of nthreads. Runs as long as listener accepts connections.
+pub fn serve(listener: TcpListener, nthreads: usize) {
+    let cache: SceneCache = Arc::new(Mutex::new(None));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue
+        };
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || {
+            let _result = handle_connection(stream, nthreads, &cache);
+        });
+    }
+}
+
+fn handle_connection(stream: TcpStream, nthreads: usize, cache: &SceneCache) -> Result<(), Box<dyn Error>> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let source = match read_message(&mut reader)? {
+        Message::Scene(source) => source,
+        _ => return Err("Expected scene from coordinator.".into())
+    };
+    let scene_data = match load_scene(&source, cache) {
+        Ok(scene_data) => scene_data,
+        Err(err) => {
+            write_message(&mut writer, &Message::Error(err.to_string()))?;
+            return Err(err)
+        }
+    };
+    write_message(&mut writer, &Message::Ready { nthreads, scene_hash: scene_data.get_scene_hash() })?;
+
+    let spp = scene_data.get_samples_per_pixel();
+    let mut sampler = create_sampler(scene_data.get_sampler_type(), spp, scene_data.get_seed());
+    loop {
+        match read_message(&mut reader)? {
+            Message::Job { tile, sample_index, sequence } => {
+                let (width, height) = scene_data.image_size();
+                if tile.startx >= tile.endx || tile.starty >= tile.endy || tile.endx > width || tile.endy > height {
+                    write_message(&mut writer, &Message::Error("Tile is outside of image.".to_string()))?;
+                    return Err("Tile is outside of image.".into())
+                }
+                let data = render_tile(&tile, sample_index, &scene_data, sampler.as_mut());
+                write_message(&mut writer, &Message::Result { sequence, data })?;
+            },
+            Message::Close => return Ok(()),
+            _ => return Err("Unexpected message from coordinator.".into())
+        }
+    }
+}
+
+fn load_scene(source: &SceneSource, cache: &SceneCache) -> Result<Arc<SceneData>, Box<dyn Error>> {
+    let key = source.hash();
+    let mut cache = cache.lock().unwrap();
+    if let Some((hash, scene_data)) = cache.as_ref() {
+        if *hash == key {
+            return Ok(Arc::clone(scene_data))
+        }
+    }
+    let mut scene_data = parse_json_str(&source.json)?;
+    if let Some(frame) = source.frame {
+        scene_data.set_frame(frame);
+    }
+    scene_data.prepare();
+    let scene_data = Arc::new(scene_data);
+    *cache = Some((key, Arc::clone(&scene_data)));
+    Ok(scene_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::Renderer2;
+
+    const SCENE: &str = r#"{
+        "global": { "resolution": [40, 24], "spp": 4, "nthreads": 1, "rendering": "direct_lighting" },
+        "camera": { "eye": [0.0, 0.0, -5.0], "lookat": [0.0, 0.0, 0.0], "hfov": 60.0 },
+        "materials": [ { "name": "white", "type": "matte", "diffuse": [0.8, 0.8, 0.8] } ],
+        "shapes": [ { "type": "sphere", "position": [0.0, 0.0, 0.0], "radius": 1.0, "material": "white" } ],
+        "lights": [ { "type": "point", "intensity": [20.0, 20.0, 20.0], "position": [0.0, 3.0, -3.0] } ]
+    }"#;
+
+    fn start_worker(nthreads: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, nthreads));
+        address
+    }
+
+    // Worker that renders n_tiles tiles and disconnects after delay when it gets the next one
+    fn start_failing_worker(n_tiles: usize, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = BufWriter::new(stream);
+                let mut scene_data = match read_message(&mut reader).unwrap() {
+                    Message::Scene(source) => parse_json_str(&source.json).unwrap(),
+                    _ => panic!("Expected scene")
+                };
+                scene_data.prepare();
+                write_message(&mut writer, &Message::Ready { nthreads: 1, scene_hash: scene_data.get_scene_hash() }).unwrap();
+                let mut sampler = create_sampler(scene_data.get_sampler_type(), scene_data.get_samples_per_pixel(), scene_data.get_seed());
+                for _ in 0..n_tiles {
+                    if let Ok(Message::Job { tile, sample_index, sequence }) = read_message(&mut reader) {
+                        let data = render_tile(&tile, sample_index, &scene_data, sampler.as_mut());
+                        write_message(&mut writer, &Message::Result { sequence, data }).unwrap();
+                    }
+                }
+                let _job = read_message(&mut reader);
+                thread::sleep(delay);
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn message_length_is_limited() {
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, MSG_SCENE).unwrap();
+        write_u64(&mut bytes, 1 << 32).unwrap();
+        assert_eq!(read_message(&mut bytes.as_slice()).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &Message::Error("x".repeat(MAX_TEXT_LEN as usize + 1))).unwrap();
+        assert!(read_message(&mut bytes.as_slice()).is_err());
+    }
+
+    fn render(mut ren: Renderer2) -> Renderer2 {
+        while !ren.render(Duration::from_millis(100)) {}
+        ren
+    }
+
+    #[test]
+    fn render_with_workers() {
+        let local = render(Renderer2::new(parse_json_str(SCENE).unwrap()));
+
+        let source = SceneSource::new(SCENE.to_string());
+        let mut ren = Renderer2::new(parse_json_str(SCENE).unwrap());
+        assert_eq!(ren.add_worker(&start_worker(2), &source).unwrap(), 2);
+        assert_eq!(ren.add_worker(&start_failing_worker(0, Duration::ZERO), &source).unwrap(), 1);
+        let ren = render(ren);
+
+        // tiles of lost worker are rendered by other threads with the same samples
+        assert_eq!(ren.lost_workers(), 1);
+        assert_eq!(ren.progress().samples, local.progress().samples);
+        let colors = ren.pixel_buffer().colors();
+        let local_colors = local.pixel_buffer().colors();
+        assert!(colors.iter().zip(local_colors.iter()).all(|(a, b)| a.red == b.red && a.green == b.green && a.blue == b.blue));
+
+        let address = start_worker(1);
+        let mut ren = Renderer2::new(parse_json_str(SCENE).unwrap());
+        assert!(ren.add_worker(&address, &SceneSource::new("{".to_string())).is_err());
+        let other = SceneSource::new(SCENE.replace("\"spp\": 4", "\"spp\": 8"));
+        assert!(ren.add_worker(&address, &other).is_err());
+    }
+
+    #[test]
+    fn resume_after_lost_worker() {
+        let local = render(Renderer2::new(parse_json_str(SCENE).unwrap()));
+
+        // worker is lost after render call stopped dispatching, so its tile and tiles
+        // finished after it are not written when checkpoint is saved
+        let source = SceneSource::new(SCENE.to_string());
+        let mut ren = Renderer2::new(parse_json_str(SCENE).unwrap());
+        ren.add_worker(&start_failing_worker(2, Duration::from_millis(300)), &source).unwrap();
+        assert!(!ren.render(Duration::from_millis(100)));
+        assert_eq!(ren.lost_workers(), 1);
+        let path = std::env::temp_dir().join("rs_tracer_lost_worker.ckpt");
+        ren.save_checkpoint(&path).unwrap();
+        let progress = crate::checkpoint::read_checkpoint_header(&path).unwrap().progress;
+        assert!(!progress.unfinished_jobs.is_empty());
+
+        let mut ren = Renderer2::new(parse_json_str(SCENE).unwrap());
+        ren.resume(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let ren = render(ren);
+        assert_eq!(ren.progress().samples, local.progress().samples);
+        let colors = ren.pixel_buffer().colors();
+        let local_colors = local.pixel_buffer().colors();
+        assert!(colors.iter().zip(local_colors.iter()).all(|(a, b)| a.red == b.red && a.green == b.green && a.blue == b.blue));
+    }
+}
@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::path::Path;
+
+extern crate exr;
+use exr::prelude::{AnyChannel, AnyChannels, AttributeValue, Encoding, FlatSamples, Image, ImageAttributes,
+                   IntegerBounds, Layer, LayerAttributes, SmallVec, Text, Vec2, WritableImage, f16};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExrPrecision {
+    Half,
+    Float
+}
+
+pub enum ChannelData {
+    Float(Vec<f32>),
+    Uint(Vec<u32>)
+}
+
+// Channel of multi-layer EXR image. Layers are grouped by channel name prefix
+// (e.g. "albedo.R"), channels without prefix belong to main RGBA layer.
+pub struct ExrChannel {
+    pub name: String,
+    pub data: ChannelData
+}
+
+impl ExrChannel {
+    pub fn float(name: &str, data: Vec<f32>) -> ExrChannel {
+        ExrChannel { name: name.to_string(), data: ChannelData::Float(data) }
+    }
+
+    pub fn uint(name: &str, data: Vec<u32>) -> ExrChannel {
+        ExrChannel { name: name.to_string(), data: ChannelData::Uint(data) }
+    }
+}
+
+pub enum ExrAttribute {
+    Text(String),
+    Float(f32),
+    Int(i32)
+}
+
+// Position of written pixels in full image when only part of image is written. It becomes
+// data window of EXR image while display window covers full image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataWindow {
+    pub x: usize,
+    pub y: usize,
+    pub full_width: usize,
+    pub full_height: usize
+}
+
+// Writes channels as one scan line image, float channels are stored with given precision
+// while integer channels (ids, sample counts) are always stored as 32 bit unsigned integers.
+pub fn write_exr<P: AsRef<Path>>(path: P, width: usize, height: usize, channels: Vec<ExrChannel>,
+                                 precision: ExrPrecision, attributes: &[(String, ExrAttribute)]) -> Result<(), Box<dyn Error>> {
+    write_exr_window(path, width, height, None, channels, precision, attributes)
+}
+
+pub fn write_exr_window<P: AsRef<Path>>(path: P, width: usize, height: usize, window: Option<DataWindow>, channels: Vec<ExrChannel>,
+                                        precision: ExrPrecision, attributes: &[(String, ExrAttribute)]) -> Result<(), Box<dyn Error>> {
+
+    let mut list = SmallVec::new();
+    for channel in channels {
+        let samples = match channel.data {
+            ChannelData::Float(data) => match precision {
+                ExrPrecision::Half => FlatSamples::F16(data.into_iter().map(f16::from_f32).collect()),
+                ExrPrecision::Float => FlatSamples::F32(data)
+            },
+            ChannelData::Uint(data) => FlatSamples::U32(data)
+        };
+        if samples.len() != width * height {
+            return Err(format!("EXR channel {} has wrong size.", channel.name).into());
+        }
+        list.push(AnyChannel::new(channel.name.as_str(), samples));
+    }
+
+    let mut layer_attributes = LayerAttributes {
+        software_name: Some(Text::from("rs_tracer")),
+        ..Default::default()
+    };
+    for (name, value) in attributes {
+        let value = match value {
+            ExrAttribute::Text(text) => AttributeValue::Text(Text::from(text.as_str())),
+            ExrAttribute::Float(value) => AttributeValue::F32(*value),
+            ExrAttribute::Int(value) => AttributeValue::I32(*value)
+        };
+        layer_attributes.other.insert(Text::from(name.as_str()), value);
+    }
+
+    let display_window = match window {
+        Some(window) => {
+            layer_attributes.layer_position = Vec2(window.x as i32, window.y as i32);
+            IntegerBounds::new((0, 0), (window.full_width, window.full_height))
+        },
+        None => IntegerBounds::new((0, 0), (width, height))
+    };
+    let layer = Layer::new((width, height), layer_attributes, Encoding::SMALL_LOSSLESS, AnyChannels::sort(list));
+    Image::new(ImageAttributes::new(display_window), layer).write().to_file(path)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exr::prelude::{Vec2, read_all_flat_layers_from_file};
+
+    #[test]
+    fn write_layers() {
+        let path = std::env::temp_dir().join("test_layers.exr");
+        let channels = vec![
+            ExrChannel::float("R", vec![1.0; 12]),
+            ExrChannel::float("G", vec![0.5; 12]),
+            ExrChannel::float("B", vec![0.25; 12]),
+            ExrChannel::float("depth.Z", (0..12).map(|i| i as f32).collect()),
+            ExrChannel::uint("samples.count", vec![16; 12])
+        ];
+        let attributes = [("renderTime".to_string(), ExrAttribute::Float(1.5))];
+        write_exr(&path, 4, 3, channels, ExrPrecision::Half, &attributes).unwrap();
+
+        let image = read_all_flat_layers_from_file(&path).unwrap();
+        let layer = &image.layer_data[0];
+        assert_eq!(layer.size, Vec2(4, 3));
+        let names: Vec<String> = layer.channel_data.list.iter().map(|c| c.name.to_string()).collect();
+        assert_eq!(names, ["B", "G", "R", "depth.Z", "samples.count"]);
+        let depth = &layer.channel_data.list[3].sample_data;
+        assert!(matches!(depth, FlatSamples::F16(_)));
+        assert_eq!(depth.value_by_flat_index(5).to_f32(), 5.0);
+        assert!(matches!(layer.channel_data.list[4].sample_data, FlatSamples::U32(_)));
+        assert!(matches!(layer.attributes.other.get(&Text::from("renderTime")), Some(AttributeValue::F32(_))));
+    }
+}
@@ -0,0 +1,127 @@
+use std::f32;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterType {
+    Box,
+    Gaussian { sigma: f32 },
+    Mitchell { b: f32, c: f32 },
+    Lanczos { tau: f32 },
+    BlackmanHarris
+}
+
+// Maximum radius of filter in pixels
+pub const MAX_FILTER_RADIUS: f32 = 7.0;
+
+// Separable pixel reconstruction filter, value is f(dx) * f(dy) for offsets
+// from pixel center in range [-radius, radius].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Filter {
+    filter_type: FilterType,
+    radius: f32
+}
+
+impl Filter {
+    pub fn new(filter_type: FilterType, radius: f32) -> Filter {
+        Filter { filter_type, radius: radius.clamp(0.5, MAX_FILTER_RADIUS) }
+    }
+
+    pub fn default_radius(filter_type: &FilterType) -> f32 {
+        match filter_type {
+            FilterType::Box => 0.5,
+            FilterType::Gaussian { .. } => 1.5,
+            FilterType::Mitchell { .. } => 2.0,
+            FilterType::Lanczos { .. } => 2.0,
+            FilterType::BlackmanHarris => 1.5
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn filter_type(&self) -> &FilterType {
+        &self.filter_type
+    }
+
+    // Box filter with radius 0.5 adds each sample only to the pixel that contains it
+    pub fn is_single_pixel(&self) -> bool {
+        matches!(self.filter_type, FilterType::Box) && self.radius <= 0.5
+    }
+
+    pub fn eval_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        let r = self.radius;
+        if x > r {
+            return 0.0
+        }
+        match self.filter_type {
+            FilterType::Box => 1.0,
+            FilterType::Gaussian { sigma } => {
+                let inv = 1.0 / (2.0 * sigma * sigma);
+                ((-x * x * inv).exp() - (-r * r * inv).exp()).max(0.0)
+            },
+            FilterType::Mitchell { b, c } => {
+                // Mitchell-Netravali is defined on [-2, 2]
+                let x = 2.0 * x / r;
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x +
+                     (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) * (1.0 / 6.0)
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x +
+                     (6.0 - 2.0 * b)) * (1.0 / 6.0)
+                }
+            },
+            FilterType::Lanczos { tau } => sinc(x) * sinc(x / tau),
+            FilterType::BlackmanHarris => {
+                let t = (x + r) / (2.0 * r);
+                let pi = f32::consts::PI;
+                0.35875 - 0.48829 * (2.0 * pi * t).cos() + 0.14128 * (4.0 * pi * t).cos() -
+                    0.01168 * (6.0 * pi * t).cos()
+            }
+        }
+    }
+
+    pub fn eval(&self, dx: f32, dy: f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(FilterType::Box, 0.5)
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 {
+        return 1.0
+    }
+    let px = f32::consts::PI * x;
+    px.sin() / px
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_values() {
+        let filters = [
+            Filter::new(FilterType::Box, 0.5),
+            Filter::new(FilterType::Gaussian { sigma: 0.5 }, 1.5),
+            Filter::new(FilterType::Mitchell { b: 1.0 / 3.0, c: 1.0 / 3.0 }, 2.0),
+            Filter::new(FilterType::Lanczos { tau: 2.0 }, 2.0),
+            Filter::new(FilterType::BlackmanHarris, 1.5)
+        ];
+        for filter in filters.iter() {
+            // peak at center, zero outside of radius and symmetric
+            let center = filter.eval(0.0, 0.0);
+            assert!(center > 0.0);
+            assert!(filter.eval_1d(0.3) <= filter.eval_1d(0.0));
+            assert_eq!(filter.eval_1d(0.7), filter.eval_1d(-0.7));
+            assert_eq!(filter.eval(filter.radius() + 0.01, 0.0), 0.0);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::pcg::PCGRng;
+use crate::sampler::Sampler;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Tile {
@@ -13,30 +13,35 @@ pub struct ImageSample {
     pub x: usize,
     pub y: usize,
     pub xp: f32,
-    pub yp: f32
+    pub yp: f32,
+    pub tp: f32
 }
 
 pub struct ImageSampler {
     tile: Tile,
+    sample_index: usize,
     curx: usize,
     cury: usize
 }
 
 impl ImageSampler {
-    pub fn new(tile: Tile) -> Self {
-        ImageSampler { tile, curx: tile.startx, cury: tile.starty }
+    pub fn new(tile: Tile, sample_index: usize) -> Self {
+        ImageSampler { tile, sample_index, curx: tile.startx, cury: tile.starty }
     }
 
-    pub fn next(&mut self, rng: &mut PCGRng) -> Option<ImageSample> {
+    pub fn next(&mut self, sampler: &mut dyn Sampler) -> Option<ImageSample> {
         if self.cury == self.tile.endy {
             return None
         }
 
+        sampler.start_pixel_sample(self.curx, self.cury, self.sample_index);
+        let (xp, yp) = sampler.get_2d();
         let img_sample = ImageSample {
             x: self.curx,
             y: self.cury,
-            xp: rng.rnd_f32(),
-            yp: rng.rnd_f32()
+            xp,
+            yp,
+            tp: sampler.get_1d()
         };
 
         self.curx += 1;
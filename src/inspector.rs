@@ -0,0 +1,232 @@
+use std::fmt;
+
+use crate::pixel_buffer::{Color, PixelBuffer};
+use crate::render::heat_color;
+use crate::scene::SceneData;
+use crate::tonemap::srgb_oetf;
+use crate::vec::f32x3;
+
+
+// Views of preview window that replace beauty buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    Normal,
+    Albedo,
+    Depth,
+    SampleHeatmap
+}
+
+impl DebugView {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DebugView::Normal => "normals",
+            DebugView::Albedo => "albedo",
+            DebugView::Depth => "depth",
+            DebugView::SampleHeatmap => "sample heatmap"
+        }
+    }
+
+    // Heatmap is taken from pixel statistics, other views from primary hits
+    pub fn needs_hits(&self) -> bool {
+        *self != DebugView::SampleHeatmap
+    }
+}
+
+// First hit of ray through pixel center
+#[derive(Debug, Clone, Copy)]
+pub struct PixelHit {
+    pub shape_id: usize,
+    pub material_id: usize,
+    pub position: f32x3,
+    pub normal: f32x3,
+    pub depth: f32,
+    pub albedo: Color
+}
+
+// Pixel (x, y) is in pixel buffer coordinates, row 0 is top of image.
+// Ray is traced at shutter open, so hit can differ from samples of moving shapes.
+pub fn trace_pixel(scene_data: &SceneData, x: usize, y: usize) -> Option<PixelHit> {
+    let (_width, height) = scene_data.image_size();
+    let ray = scene_data.generate_ray(x, height - 1 - y, 0.5, 0.5, 0.0);
+    scene_data.intersect(&ray, 1e30).map(|sp| PixelHit {
+        shape_id: sp.shape_id,
+        material_id: sp.material_id(),
+        position: sp.hitpoint,
+        normal: sp.normal,
+        depth: sp.t,
+        albedo: scene_data.get_albedo(&sp)
+    })
+}
+
+// Hit of pixel from accumulated AOVs, which average samples of the pixel that hit geometry.
+// Only pixels without stored AOVs are traced. Ids are those of the first hit of pixel.
+pub fn pixel_hit(scene_data: &SceneData, pixel_buffer: &PixelBuffer, x: usize, y: usize) -> Option<PixelHit> {
+    let aov = match pixel_buffer.stored_aov(x, y) {
+        Some(aov) => aov,
+        None => return trace_pixel(scene_data, x, y)
+    };
+    if aov.shape_id == 0 {
+        return None
+    }
+    Some(PixelHit {
+        shape_id: aov.shape_id as usize - 1,
+        material_id: aov.material_id as usize - 1,
+        position: aov.position,
+        normal: aov.normal,
+        depth: aov.depth,
+        albedo: aov.albedo
+    })
+}
+
+// Hits of all pixels in pixel buffer order
+pub fn pixel_hits(scene_data: &SceneData, pixel_buffer: &PixelBuffer) -> Vec<Option<PixelHit>> {
+    let (width, height) = pixel_buffer.size();
+    (0..width * height).map(|index| pixel_hit(scene_data, pixel_buffer, index % width, index / width)).collect()
+}
+
+pub struct PixelInfo {
+    pub x: usize,
+    pub y: usize,
+    pub hit: Option<PixelHit>,
+    pub material_name: String,
+    pub radiance: Color,
+    pub samples: u32,
+    pub variance: f32
+}
+
+pub fn inspect_pixel(scene_data: &SceneData, pixel_buffer: &PixelBuffer, x: usize, y: usize) -> PixelInfo {
+    let hit = pixel_hit(scene_data, pixel_buffer, x, y);
+    let material_name = match &hit {
+        Some(hit) => scene_data.get_material_name(hit.material_id).to_string(),
+        None => String::new()
+    };
+    let stats = pixel_buffer.get_stats(x, y);
+    PixelInfo {
+        x, y, hit, material_name,
+        radiance: pixel_buffer.get_pixel(x, y).get_color(),
+        samples: stats.count,
+        variance: stats.variance()
+    }
+}
+
+impl fmt::Display for PixelInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Pixel ({}, {})", self.x, self.y)?;
+        match &self.hit {
+            Some(hit) => {
+                let name = if self.material_name.is_empty() { "-" } else { self.material_name.as_str() };
+                writeln!(f, "  shape {} material {} ({})", hit.shape_id, hit.material_id, name)?;
+                writeln!(f, "  position ({:.4}, {:.4}, {:.4}) depth {:.4}",
+                    hit.position.0, hit.position.1, hit.position.2, hit.depth)?;
+                writeln!(f, "  normal ({:.4}, {:.4}, {:.4})", hit.normal.0, hit.normal.1, hit.normal.2)?;
+            },
+            None => writeln!(f, "  no hit")?
+        }
+        writeln!(f, "  radiance ({:.4}, {:.4}, {:.4})", self.radiance.red, self.radiance.green, self.radiance.blue)?;
+        write!(f, "  samples {} variance {:.6}", self.samples, self.variance)
+    }
+}
+
+fn rgb(red: f32, green: f32, blue: f32) -> Color {
+    Color { red, green, blue }
+}
+
+fn pack_rgb(color: Color) -> u32 {
+    let quantize = |v: f32| (v.clamp(0.0, 1.0) * 255.0 + 0.5) as u32;
+    (quantize(color.red) << 16) | (quantize(color.green) << 8) | quantize(color.blue)
+}
+
+// Pixels of debug view in display format of window. Hits are required for views
+// other than heatmap. Depth is scaled by farthest hit, near surfaces are bright.
+pub fn debug_view_rgb_vector(view: DebugView, hits: &[Option<PixelHit>], pixel_buffer: &PixelBuffer) -> Vec<u32> {
+    let (width, height) = pixel_buffer.size();
+    match view {
+        DebugView::Normal => hits.iter().map(|hit| match hit {
+            Some(hit) => pack_rgb(rgb(0.5 * hit.normal.0 + 0.5, 0.5 * hit.normal.1 + 0.5, 0.5 * hit.normal.2 + 0.5)),
+            None => 0
+        }).collect(),
+        DebugView::Albedo => hits.iter().map(|hit| match hit {
+            Some(hit) => pack_rgb(rgb(srgb_oetf(hit.albedo.red), srgb_oetf(hit.albedo.green), srgb_oetf(hit.albedo.blue))),
+            None => 0
+        }).collect(),
+        DebugView::Depth => {
+            let max_depth = hits.iter().flatten().map(|hit| hit.depth).fold(0.0, f32::max);
+            hits.iter().map(|hit| match hit {
+                Some(hit) => {
+                    let v = 1.0 - 0.9 * hit.depth / max_depth.max(1e-6);
+                    pack_rgb(rgb(v, v, v))
+                },
+                None => 0
+            }).collect()
+        },
+        DebugView::SampleHeatmap => {
+            let counts: Vec<u32> = (0..width * height).map(|i| pixel_buffer.get_stats(i % width, i / width).count).collect();
+            let max_count = counts.iter().copied().max().unwrap_or(0).max(1);
+            counts.iter().map(|count| pack_rgb(heat_color(*count as f32 / max_count as f32))).collect()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::{AovSample, AovType};
+    use crate::builder::SceneBuilder;
+    use crate::traits::Zero;
+
+    #[test]
+    fn inspect_and_debug_views() {
+        let mut scene_data = SceneBuilder::new()
+            .resolution(16, 16)
+            .camera(f32x3(0.0, 0.0, -5.0), f32x3(0.0, 0.0, 0.0), 60.0)
+            .matte("red", rgb(0.8, 0.1, 0.1))
+            .sphere(f32x3(0.0, 0.0, 0.0), 1.0, "red")
+            .build().unwrap();
+        scene_data.prepare();
+        let mut pixel_buffer = PixelBuffer::new(16, 16);
+        pixel_buffer.add_sample(8.5, 8.5, rgb(1.0, 0.5, 0.25), 1.0, scene_data.get_filter());
+        pixel_buffer.add_sample(8.5, 8.5, rgb(1.0, 0.5, 0.25), 1.0, scene_data.get_filter());
+
+        let info = inspect_pixel(&scene_data, &pixel_buffer, 8, 8);
+        let hit = info.hit.unwrap();
+        assert_eq!(hit.shape_id, 0);
+        assert_eq!(info.material_name, "red");
+        assert!((hit.depth - 4.0).abs() < 0.05);
+        assert!(hit.normal.2 < -0.9);
+        assert_eq!(info.samples, 2);
+        assert!((info.radiance.green - 0.5).abs() < 1e-4);
+        assert!(info.to_string().contains("material 0 (red)"));
+        assert!(inspect_pixel(&scene_data, &pixel_buffer, 0, 0).hit.is_none());
+
+        let hits = pixel_hits(&scene_data, &pixel_buffer);
+        let depth = debug_view_rgb_vector(DebugView::Depth, &hits, &pixel_buffer);
+        assert_eq!(depth[0], 0);
+        assert!(depth[8 * 16 + 8] > 0);
+        let heatmap = debug_view_rgb_vector(DebugView::SampleHeatmap, &hits, &pixel_buffer);
+        assert_eq!(heatmap[8 * 16 + 8], 0xff0000);
+        assert_eq!(heatmap[0], 0x0000ff);
+
+        // stored AOVs are used instead of tracing, pixels without samples are traced
+        pixel_buffer.set_aovs(&[AovType::Depth]);
+        let mut aov = AovSample::zero();
+        aov.depth = 7.0;
+        aov.shape_id = 1;
+        aov.material_id = 1;
+        pixel_buffer.add_aov(0.5, 0.5, &aov);
+        pixel_buffer.add_aov(8.5, 8.5, &AovSample::zero());
+        assert_eq!(pixel_hit(&scene_data, &pixel_buffer, 0, 0).unwrap().depth, 7.0);
+        assert!(pixel_hit(&scene_data, &pixel_buffer, 8, 8).is_none());
+        assert!((pixel_hit(&scene_data, &pixel_buffer, 8, 7).unwrap().depth - 4.0).abs() < 0.1);
+        assert_eq!(inspect_pixel(&scene_data, &pixel_buffer, 0, 0).material_name, "red");
+
+        // edge pixel whose first sample missed reports the hit and its depth, not the average with misses
+        let mut edge = aov;
+        edge.depth = 4.5;
+        pixel_buffer.add_aov(4.5, 8.5, &AovSample::zero());
+        pixel_buffer.add_aov(4.5, 8.5, &edge);
+        pixel_buffer.add_aov(4.5, 8.5, &AovSample::zero());
+        let hit = pixel_hit(&scene_data, &pixel_buffer, 4, 8).unwrap();
+        assert_eq!((hit.shape_id, hit.depth), (0, 4.5));
+    }
+}
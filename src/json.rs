@@ -1,262 +1,1194 @@
-use std::{error::Error, fs, collections::HashMap};
-use crate::{scene::{SceneData, RenderingAlgorithm}, pixel_buffer::{TMOType, Color}, vec::f32x3, materials::{MatteMaterial, MatteEmissiveMaterial}, shapes::{Sphere, Shape, Triangle}, lights::PointLight};
+use std::{error::Error, fmt, fs, collections::HashMap};
+use crate::render::DebugMode;
+use crate::{scene::{Acceleration, BSDFInterface, Crop, CropOutput, SceneData, RenderingAlgorithm}, pixel_buffer::Color, vec::f32x3, materials::{MatteMaterial, MatteEmissiveMaterial}, shapes::{Sphere, Shape, Triangle, GeometryInterface}, lights::PointLight};
+use crate::transform::{AnimatedTransform, Interpolation, Keyframe, Quaternion};
+use crate::camera::{CameraKeyframe, CameraTrack};
+use crate::sampler::SamplerType;
+use crate::filter::{Filter, FilterType};
+use crate::exr_output::ExrPrecision;
+use crate::aov::AovType;
+use crate::lpe::Lpe;
+use crate::tonemap::{TMOType, ToneMapping};
+use crate::checkpoint::fnv1a_hash;
+use crate::postprocess::{Bloom, Glare, PostProcess, Vignette};
+use crate::traits::Zero;
 use serde_json::Value;
 
 
 pub fn parse_json_file(filename: &str) -> Result<SceneData, Box<dyn Error>> {
     let contents = fs::read_to_string(filename)?;
-    let val:Value = serde_json::from_str(&contents)?;
+    parse_json_str(&contents)
+}
+
+// Sets values at dotted paths (e.g. global.crop or shapes[2].radius) of scene file, missing
+// objects are created. Used for command line overrides, so overridden scene is parsed and
+// hashed as a whole.
+pub fn apply_json_overrides(contents: &str, overrides: &[(String, Value)]) -> Result<String, Box<dyn Error>> {
+    let mut root: Value = serde_json::from_str(contents)?;
+    for (path, value) in overrides {
+        *json_value_at(&mut root, path)? = value.clone();
+    }
+    Ok(serde_json::to_string_pretty(&root)?)
+}
+
+pub fn set_json_value(contents: &str, path: &str, value: Value) -> Result<String, Box<dyn Error>> {
+    apply_json_overrides(contents, &[(path.to_string(), value)])
+}
+
+fn json_value_at<'a>(root: &'a mut Value, path: &str) -> Result<&'a mut Value, Box<dyn Error>> {
+    let mut node = root;
+    for part in path.split('.') {
+        let (key, indices) = match part.find('[') {
+            Some(pos) => (&part[..pos], &part[pos..]),
+            None => (part, "")
+        };
+        if !key.is_empty() {
+            let object = match node.as_object_mut() {
+                Some(object) => object,
+                None => return Err(format!("Field: {} - Value can be set only inside of objects.", path).into())
+            };
+            node = object.entry(key.to_string()).or_insert_with(|| Value::Object(Default::default()));
+        }
+        for index in indices.split('[').skip(1) {
+            let index = match index.strip_suffix(']').and_then(|index| index.parse::<usize>().ok()) {
+                Some(index) => index,
+                None => return Err(format!("Field: {} - Invalid array index.", path).into())
+            };
+            node = match node.as_array_mut().and_then(|array| array.get_mut(index)) {
+                Some(item) => item,
+                None => return Err(format!("Field: {} - Index {} is outside of array.", path, index).into())
+            };
+        }
+    }
+    Ok(node)
+}
+
+// Problem of scene file at JSON path, e.g. shapes[17].radius. Position is line and column
+// in source, missing fields are reported at position of their parent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub path: String,
+    pub message: String,
+    pub position: Option<(usize, usize)>
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "Field: {} (line {}, column {}) - {}", self.path, line, column, self.message),
+            None => write!(f, "Field: {} - {}", self.path, self.message)
+        }
+    }
+}
+
+impl Error for FieldError {}
+
+fn field_error(path: &str, message: &str) -> Box<dyn Error> {
+    Box::new(FieldError { path: path.to_string(), message: message.to_string(), position: None })
+}
+
+// All errors found in scene file, one per line. Warnings are listed too, unknown key is often
+// a typo of field that is reported as missing.
+#[derive(Debug)]
+pub struct SceneErrors {
+    pub errors: Vec<FieldError>,
+    pub warnings: Vec<FieldError>
+}
+
+impl fmt::Display for SceneErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors = self.errors.iter().map(|error| error.to_string());
+        let warnings = self.warnings.iter().map(|warning| format!("Warning: {}", warning));
+        let lines: Vec<String> = errors.chain(warnings).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Error for SceneErrors {}
+
+// Errors and warnings collected while parsing, so all problems of file are reported at once.
+// Field that can't be parsed is skipped and parsing continues with the next one.
+#[derive(Default)]
+struct Diagnostics {
+    errors: Vec<FieldError>,
+    warnings: Vec<FieldError>
+}
+
+impl Diagnostics {
+    fn check<T>(&mut self, result: Result<T, Box<dyn Error>>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                let error = match err.downcast::<FieldError>() {
+                    Ok(error) => *error,
+                    Err(err) => FieldError { path: String::new(), message: err.to_string(), position: None }
+                };
+                self.errors.push(error);
+                None
+            }
+        }
+    }
+
+    fn error(&mut self, path: &str, message: &str) {
+        self.check::<()>(Err(field_error(path, message)));
+    }
+
+    // Keys that are not read by parser are ignored, typos get suggestion of known key
+    fn check_keys(&mut self, section: &Value, path: &str, known: &[&str]) {
+        for key in section.as_object().into_iter().flat_map(|object| object.keys()) {
+            if known.contains(&key.as_str()) {
+                continue
+            }
+            let message = match known.iter().find(|known| is_typo(key, known)) {
+                Some(known) => format!("Unknown key {}, did you mean {}?", key, known),
+                None => format!("Unknown key {} is ignored.", key)
+            };
+            self.warnings.push(FieldError { path: field_path(path, key), message, position: None });
+        }
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == *cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn is_typo(key: &str, known: &str) -> bool {
+    let distance = edit_distance(key, known);
+    distance <= 2 && 2 * distance < known.len()
+}
+
+fn field_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        return key.to_string()
+    }
+    format!("{}.{}", path, key)
+}
+
+fn index_path(path: &str, index: usize) -> String {
+    format!("{}[{}]", path, index)
+}
+
+// Line and column of every key and array item of JSON source by its path. Source is
+// scanned only when there is something to report and only after it was parsed, so it is valid.
+struct SourceScanner<'a> {
+    source: &'a str,
+    pos: usize,
+    line: usize,
+    line_start: usize,
+    positions: HashMap<String, (usize, usize)>
+}
+
+impl<'a> SourceScanner<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if !byte.is_ascii_whitespace() {
+                break
+            }
+            if byte == b'\n' {
+                self.line += 1;
+                self.line_start = self.pos + 1;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn position(&self) -> (usize, usize) {
+        (self.line, self.source[self.line_start..self.pos].chars().count() + 1)
+    }
+
+    // Keys are located at their name, array items and root at their value
+    fn mark(&mut self, path: &str, position: (usize, usize)) {
+        self.positions.entry(path.to_string()).or_insert(position);
+    }
+
+    fn string(&mut self) -> String {
+        let start = self.pos;
+        self.pos += 1;
+        while let Some(byte) = self.peek() {
+            self.pos += if byte == b'\\' { 2 } else { 1 };
+            if byte == b'"' {
+                break
+            }
+        }
+        let end = self.pos.min(self.source.len());
+        serde_json::from_str(&self.source[start..end]).unwrap_or_default()
+    }
+
+    fn value(&mut self, path: &str) {
+        self.skip_whitespace();
+        self.mark(path, self.position());
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b'"') => {
+                            let position = self.position();
+                            let key_path = field_path(path, &self.string());
+                            self.mark(&key_path, position);
+                            self.skip_whitespace();
+                            self.pos += 1;
+                            self.value(&key_path);
+                        },
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            break
+                        },
+                        _ => break
+                    }
+                }
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                let mut index = 0;
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => {
+                            self.pos += 1;
+                            index += 1;
+                        },
+                        Some(b']') => {
+                            self.pos += 1;
+                            break
+                        },
+                        None => break,
+                        _ => self.value(&index_path(path, index))
+                    }
+                }
+            },
+            Some(b'"') => {
+                self.string();
+            },
+            _ => {
+                while let Some(byte) = self.peek() {
+                    if byte.is_ascii_whitespace() || matches!(byte, b',' | b'}' | b']') {
+                        break
+                    }
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
+
+fn source_positions(source: &str) -> HashMap<String, (usize, usize)> {
+    let mut scanner = SourceScanner { source, pos: 0, line: 1, line_start: 0, positions: HashMap::new() };
+    scanner.value("");
+    scanner.positions
+}
+
+// Missing field is located at its closest parent that is in the source
+fn locate(positions: &HashMap<String, (usize, usize)>, path: &str) -> Option<(usize, usize)> {
+    let mut path = path;
+    loop {
+        if let Some(position) = positions.get(path) {
+            return Some(*position)
+        }
+        path = &path[..path.rfind(['.', '['])?];
+    }
+}
+
+pub fn parse_json_str(contents: &str) -> Result<SceneData, Box<dyn Error>> {
+    let (scene_data, _warnings) = parse_json_str_with_warnings(contents)?;
+    Ok(scene_data)
+}
+
+// Warnings are unknown keys that were ignored. Errors of all fields are returned together
+// as SceneErrors.
+pub fn parse_json_str_with_warnings(contents: &str) -> Result<(SceneData, Vec<FieldError>), Box<dyn Error>> {
+    let val:Value = serde_json::from_str(contents)?;
+    let mut diagnostics = Diagnostics::default();
+    let scene_data = parse_scene(&mut diagnostics, &val);
+    let Diagnostics { mut errors, mut warnings } = diagnostics;
+    if !errors.is_empty() || !warnings.is_empty() {
+        let positions = source_positions(contents);
+        for error in errors.iter_mut().chain(warnings.iter_mut()) {
+            error.position = locate(&positions, &error.path);
+        }
+        // reported in order of source
+        errors.sort_by_key(|error| error.position);
+        warnings.sort_by_key(|warning| warning.position);
+    }
+    if !errors.is_empty() {
+        return Err(Box::new(SceneErrors { errors, warnings }))
+    }
+    Ok((scene_data, warnings))
+}
+
+const SCENE_KEYS: [&str; 5] = ["global", "camera", "materials", "shapes", "lights"];
+
+fn parse_scene(d: &mut Diagnostics, val: &Value) -> SceneData {
     let mut scene_data = SceneData::default();
+    scene_data.set_scene_hash(scene_hash(val));
+    scene_data.set_image_hash(image_hash(val));
+    if !val.is_object() {
+        d.error("", "Object with scene sections expected.");
+        return scene_data
+    }
+    d.check_keys(val, "", &SCENE_KEYS);
     let global = &val["global"];
     if !global.is_null() {
-        parse_global(&mut scene_data, global)?;
+        parse_global(d, &mut scene_data, global, "global");
     }
     let camera = &val["camera"];
     if !camera.is_null() {
-        parse_camera(&mut scene_data, camera)?;
+        parse_camera(d, &mut scene_data, camera, "camera");
     }
     let mut mtrs: HashMap<String, usize> = HashMap::new();
     let materials = &val["materials"];
     if !materials.is_null() {
-        let map = parse_materials(&mut scene_data, materials)?;
+        let map = parse_materials(d, &mut scene_data, materials, "materials");
         mtrs.extend(map)
     }
     let shapes = &val["shapes"];
     if !shapes.is_null() {
-        parse_shapes(&mut scene_data, shapes, &mtrs)?;
+        parse_shapes(d, &mut scene_data, shapes, &mtrs, "shapes");
     }
     let lights = &val["lights"];
     if !lights.is_null() {
-        parse_lights(&mut scene_data, lights)?;
+        parse_lights(d, &mut scene_data, lights, "lights");
     }
     scene_data.create_area_lights();
+    // light groups are known only after lights and materials are parsed
+    if !global["lpes"].is_null() {
+        let lpes = parse_lpes(d, &global["lpes"], "global.lpes", scene_data.get_light_groups());
+        // other algorithms don't record light paths, their buffers would stay black
+        if !matches!(scene_data.rendering_algorithm, RenderingAlgorithm::PathTracer) {
+            d.error("global.lpes", "Light path expressions are supported only by path rendering algorithm.");
+        }
+        scene_data.set_lpes(lpes);
+    }
+    scene_data
+}
 
-    Ok(scene_data)
+// Settings that don't change accumulated pixels (output, threads, display transform)
+// are left out of hash, so they can be changed when render is resumed from checkpoint.
+const UNHASHED_GLOBAL_KEYS: [&str; 14] = [
+    "output", "nthreads", "checkpoint", "checkpoint_interval", "tonemap", "exposure",
+    "white_balance", "post_process", "exr_precision", "time_limit", "noise_target", "frames",
+    "crop_output", "acceleration"
+];
+
+// Settings that change which samples are taken but not the image they converge to,
+// renders that differ only in them can be merged
+const SAMPLING_GLOBAL_KEYS: [&str; 6] = ["seed", "spp", "min_spp", "noise_threshold", "sampler", "crop"];
+
+fn hash_without(val: &Value, keys: &[&str]) -> u64 {
+    let mut val = val.clone();
+    if let Some(global) = val.get_mut("global").and_then(|global| global.as_object_mut()) {
+        for key in keys {
+            global.remove(*key);
+        }
+    }
+    // keys of objects are sorted, so formatting and order of keys in file don't matter
+    fnv1a_hash(val.to_string().as_bytes())
+}
+
+fn scene_hash(val: &Value) -> u64 {
+    hash_without(val, &UNHASHED_GLOBAL_KEYS)
+}
+
+fn image_hash(val: &Value) -> u64 {
+    hash_without(val, &[&UNHASHED_GLOBAL_KEYS[..], &SAMPLING_GLOBAL_KEYS[..]].concat())
 }
 
-fn parse_lights(scene_data: &mut SceneData, section: &Value) -> Result<(), Box<dyn Error>> {
+fn parse_lpes(d: &mut Diagnostics, section: &Value, path: &str, light_groups: &[String]) -> Vec<Lpe> {
+    let mut lpes = Vec::new();
+    let exprs = match section.as_object() {
+        Some(exprs) => exprs,
+        None => {
+            d.error(path, "Object with names and light path expressions expected.");
+            return lpes
+        }
+    };
+    for (name, expr) in exprs.iter() {
+        let path = field_path(path, name);
+        let lpe = parse_string(expr, &path)
+            .and_then(|expr| Lpe::parse(name, &expr, light_groups).map_err(|err| field_error(&path, &err.to_string())));
+        if let Some(lpe) = d.check(lpe) {
+            lpes.push(lpe);
+        }
+    }
+    lpes
+}
+
+fn parse_lights(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, path: &str) {
     let lights = match section.as_array() {
         Some(lights) => lights,
-        None => return Err("List of lights expected!".into())
+        None => return d.error(path, "List of lights expected.")
     };
-    for light in lights.iter() {
-        parse_light(scene_data, light)?;
+    for (index, light) in lights.iter().enumerate() {
+        parse_light(d, scene_data, light, &index_path(path, index));
     }
-    Ok(())
 }
 
-fn parse_light(scene_data: &mut SceneData, section: &Value) -> Result<(), Box<dyn Error>> {
-    let typ = parse_string(&section["type"], "light->type")?;
+const POINT_LIGHT_KEYS: [&str; 4] = ["type", "intensity", "position", "light_group"];
+
+fn parse_light(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, path: &str) {
+    let typ = match d.check(parse_string(&section["type"], &field_path(path, "type"))) {
+        Some(typ) => typ,
+        None => return
+    };
     match typ.as_str() {
-        "point" => parse_point_light(scene_data, section)?,
-        _ => return Err(format!("Unknown light type {}", typ).into())
+        "point" => parse_point_light(d, scene_data, section, path),
+        _ => d.error(&field_path(path, "type"), &format!("Unknown light type {}, expected point.", typ))
     };
-    Ok(())
 }
 
-fn parse_point_light(scene_data: &mut SceneData, section: &Value) -> Result<(), Box<dyn Error>> {
-    let intensity = parse_color(&section["intensity"], "light->intensity")?;
-    let position = parse_f32x3(&section["position"], "light->position")?;
-    let light = PointLight::new(intensity, position);
-    scene_data.add_light(Box::new(light));
-    Ok(())
+fn parse_point_light(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, path: &str) {
+    d.check_keys(section, path, &POINT_LIGHT_KEYS);
+    let intensity = d.check(parse_color(&section["intensity"], &field_path(path, "intensity")));
+    let position = d.check(parse_f32x3(&section["position"], &field_path(path, "position")));
+    let mut light_group = None;
+    if !section["light_group"].is_null() {
+        if let Some(group) = d.check(parse_string(&section["light_group"], &field_path(path, "light_group"))) {
+            light_group = Some(scene_data.add_light_group(&group));
+        }
+    }
+    if let (Some(intensity), Some(position)) = (intensity, position) {
+        let mut light = PointLight::new(intensity, position);
+        light.set_light_group(light_group);
+        scene_data.add_light(Box::new(light));
+    }
 }
 
-fn parse_shapes(scene_data: &mut SceneData, section: &Value, map: &HashMap<String, usize>) -> Result<(), Box<dyn Error>> {
+fn parse_shapes(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, map: &HashMap<String, usize>, path: &str) {
     let shapes = match section.as_array() {
         Some(shapes) => shapes,
-        None => return Err("List of shapes expected!".into())
+        None => return d.error(path, "List of shapes expected.")
     };
-    for shape in shapes.iter() {
-        parse_shape(scene_data, shape, map)?;
+    for (index, shape) in shapes.iter().enumerate() {
+        parse_shape(d, scene_data, shape, map, &index_path(path, index));
     }
-    Ok(())
 }
 
-fn parse_shape(scene_data: &mut SceneData, section: &Value, map: &HashMap<String, usize>) -> Result<(), Box<dyn Error>> {
-    let typ = parse_string(&section["type"], "shape->type")?;
-    match typ.as_str() {
-        "sphere" => parse_sphere_shape(scene_data, section, map)?,
-        "triangle" => parse_triangle_shape(scene_data, section, map)?,
-        _ => return Err(format!("Unknown shape type {}", typ).into())
+const SHAPE_KEYS: [&str; 5] = ["type", "material", "transform", "motion", "interpolation"];
+const SPHERE_KEYS: [&str; 2] = ["position", "radius"];
+const TRIANGLE_KEYS: [&str; 3] = ["v1", "v2", "v3"];
+const KEYFRAME_KEYS: [&str; 4] = ["time", "translate", "rotate", "scale"];
+
+// All fields of shape are checked, shape is added only if all of them are valid
+fn parse_shape(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, map: &HashMap<String, usize>, path: &str) {
+    let typ = d.check(parse_string(&section["type"], &field_path(path, "type")));
+    let material_id = d.check(parse_material_id(section, map, path));
+    let geometry: Option<Box<dyn GeometryInterface + Send + Sync>> = match typ.as_deref() {
+        Some("sphere") => {
+            d.check_keys(section, path, &[&SHAPE_KEYS[..], &SPHERE_KEYS[..]].concat());
+            parse_sphere_shape(d, section, path).map(|sphere| Box::new(sphere) as _)
+        },
+        Some("triangle") => {
+            d.check_keys(section, path, &[&SHAPE_KEYS[..], &TRIANGLE_KEYS[..]].concat());
+            parse_triangle_shape(d, section, path).map(|triangle| Box::new(triangle) as _)
+        },
+        Some(typ) => {
+            d.error(&field_path(path, "type"), &format!("Unknown shape type {}, expected sphere or triangle.", typ));
+            None
+        },
+        None => None
     };
-    Ok(())
+    if section["transform"].is_object() {
+        d.check_keys(&section["transform"], &field_path(path, "transform"), &KEYFRAME_KEYS);
+    }
+    for (index, key) in section["motion"].as_array().into_iter().flatten().enumerate() {
+        d.check_keys(key, &index_path(&field_path(path, "motion"), index), &KEYFRAME_KEYS);
+    }
+    let transform = d.check(parse_shape_transform(section, path));
+    if let (Some(geometry), Some(material_id), Some(transform)) = (geometry, material_id, transform) {
+        let shape = match transform {
+            Some(transform) => Shape::with_transform(geometry, material_id, transform),
+            None => Shape::new(geometry, material_id)
+        };
+        scene_data.add_shape(shape);
+    }
 }
 
-fn parse_material_id(scene_data: &mut SceneData, section: &Value, map: &HashMap<String, usize>) -> Result<usize, Box<dyn Error>> {
-    let mat_name = parse_string(&section["material"], "shape:material:name")?;
+fn parse_material_id(section: &Value, map: &HashMap<String, usize>, path: &str) -> Result<usize, Box<dyn Error>> {
+    let path = field_path(path, "material");
+    let mat_name = parse_string(&section["material"], &path)?;
     let material_id = match map.get(&mat_name) {
         Some(material_id) => material_id,
-        None => return Err(format!("Material {} doesn't exist", mat_name).into())
+        None => return Err(field_error(&path, &format!("Material {} doesn't exist.", mat_name)))
     };
     Ok(*material_id)
 }
 
-fn parse_sphere_shape(scene_data: &mut SceneData, section: &Value, map: &HashMap<String, usize>) -> Result<(), Box<dyn Error>> {
-    let material_id = parse_material_id(scene_data, section, map)?;
-    let postion = parse_f32x3(&section["position"], "shape->position")?;
-    let radius = parse_f32(&section["radius"], "shape->radius")?;
-    let sphere = Sphere::new(postion, radius);
-    scene_data.add_shape(Shape::new(Box::new(sphere), material_id));
-    Ok(())
+// Shape without transform and motion has None
+fn parse_shape_transform(section: &Value, path: &str) -> Result<Option<AnimatedTransform>, Box<dyn Error>> {
+    if !section["transform"].is_null() && !section["motion"].is_null() {
+        return Err(field_error(path, "Shape can have either transform or motion, not both."))
+    }
+    if !section["transform"].is_null() {
+        let keyframe = parse_keyframe(&section["transform"], &field_path(path, "transform"))?;
+        return Ok(Some(AnimatedTransform::new(vec![keyframe])))
+    }
+    if !section["motion"].is_null() {
+        let motion_path = field_path(path, "motion");
+        let keys = match section["motion"].as_array() {
+            Some(keys) => keys,
+            None => return Err(field_error(&motion_path, "List of keyframes expected."))
+        };
+        let mut keyframes = Vec::new();
+        for (index, key) in keys.iter().enumerate() {
+            let key_path = index_path(&motion_path, index);
+            if key["time"].is_null() {
+                return Err(field_error(&field_path(&key_path, "time"), "Keyframe time expected."))
+            }
+            let keyframe = parse_keyframe(key, &key_path)?;
+            // scale interpolated between opposite signs would pass through zero
+            let s0 = keyframes.first().map_or(keyframe.scale, |k: &Keyframe| k.scale);
+            if s0.0 * keyframe.scale.0 < 0.0 || s0.1 * keyframe.scale.1 < 0.0 || s0.2 * keyframe.scale.2 < 0.0 {
+                return Err(field_error(&field_path(&key_path, "scale"), "Scale of keyframes can't change sign."))
+            }
+            keyframes.push(keyframe);
+        }
+        let interpolation = parse_interpolation(&section["interpolation"], &field_path(path, "interpolation"))?;
+        return Ok(Some(AnimatedTransform::with_interpolation(keyframes, interpolation)))
+    }
+    Ok(None)
 }
 
-fn parse_triangle_shape(scene_data: &mut SceneData, section: &Value, map: &HashMap<String, usize>) -> Result<(), Box<dyn Error>> {
-    let material_id = parse_material_id(scene_data, section, map)?;
-    let v1 = parse_f32x3(&section["v1"], "triangle->v1")?;
-    let v2 = parse_f32x3(&section["v2"], "triangle->v2")?;
-    let v3 = parse_f32x3(&section["v3"], "triangle->v3")?;
-    let tri = Triangle::new(v1, v2, v3);
-    scene_data.add_shape(Shape::new(Box::new(tri), material_id));
-    Ok(())
+fn parse_interpolation(section: &Value, path: &str) -> Result<Interpolation, Box<dyn Error>> {
+    if section.is_null() {
+        return Ok(Interpolation::Linear)
+    }
+    let name = parse_string(section, path)?;
+    match name.as_str() {
+        "linear" => Ok(Interpolation::Linear),
+        "catmull_rom" => Ok(Interpolation::CatmullRom),
+        "bezier" => Ok(Interpolation::Bezier),
+        _ => Err(field_error(path, &format!("Unknown interpolation {}, expected linear, catmull_rom or bezier.", name)))
+    }
+}
+
+fn parse_keyframe(section: &Value, path: &str) -> Result<Keyframe, Box<dyn Error>> {
+    let mut time = 0.0;
+    if !section["time"].is_null() {
+        time = parse_f32(&section["time"], &field_path(path, "time"))?;
+    }
+    let mut translation = f32x3(0.0, 0.0, 0.0);
+    if !section["translate"].is_null() {
+        translation = parse_f32x3(&section["translate"], &field_path(path, "translate"))?;
+    }
+    let mut rotation = Quaternion::identity();
+    if !section["rotate"].is_null() {
+        // axis and angle in degrees
+        let rot = parse_f32_array::<4>(&section["rotate"], &field_path(path, "rotate"))?;
+        rotation = Quaternion::from_axis_angle(f32x3(rot[0], rot[1], rot[2]), rot[3]);
+    }
+    let mut scale = f32x3(1.0, 1.0, 1.0);
+    if !section["scale"].is_null() {
+        let scale_path = field_path(path, "scale");
+        if section["scale"].is_number() {
+            let s = parse_f32(&section["scale"], &scale_path)?;
+            scale = f32x3(s, s, s);
+        } else {
+            scale = parse_f32x3(&section["scale"], &scale_path)?;
+        }
+        // zero scale has no inverse transformation
+        if scale.0 == 0.0 || scale.1 == 0.0 || scale.2 == 0.0 {
+            return Err(expected(&section["scale"], &scale_path, "Non zero scale"))
+        }
+    }
+    Ok(Keyframe::new(time, translation, rotation, scale))
+}
+
+fn parse_sphere_shape(d: &mut Diagnostics, section: &Value, path: &str) -> Option<Sphere> {
+    let position = d.check(parse_f32x3(&section["position"], &field_path(path, "position")));
+    let radius = d.check(parse_f32(&section["radius"], &field_path(path, "radius")));
+    Some(Sphere::new(position?, radius?))
+}
+
+fn parse_triangle_shape(d: &mut Diagnostics, section: &Value, path: &str) -> Option<Triangle> {
+    let v1 = d.check(parse_f32x3(&section["v1"], &field_path(path, "v1")));
+    let v2 = d.check(parse_f32x3(&section["v2"], &field_path(path, "v2")));
+    let v3 = d.check(parse_f32x3(&section["v3"], &field_path(path, "v3")));
+    Some(Triangle::new(v1?, v2?, v3?))
 }
 
-fn parse_materials(scene_data: &mut SceneData, section: &Value) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+fn parse_materials(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, path: &str) -> HashMap<String, usize> {
+    let mut map = HashMap::new();
     let mtrs = match section.as_array() {
         Some(mtrs) => mtrs,
-        None => return Err("List of materials expected.".into())
+        None => {
+            d.error(path, "List of materials expected.");
+            return map
+        }
     };
-    let mut map = HashMap::new();
-    for mat in mtrs.iter() {
-        let name = parse_string(&mat["name"], "material->name")?;
-        let material_id = parse_material(scene_data, mat, &name)?;
+    for (index, mat) in mtrs.iter().enumerate() {
+        let path = index_path(path, index);
+        let name = match d.check(parse_string(&mat["name"], &field_path(&path, "name"))) {
+            Some(name) => name,
+            None => continue
+        };
         if map.contains_key(&name) {
-            return Err(format!("Material {} allread exist!", name).into())
+            d.error(&field_path(&path, "name"), &format!("Material {} is defined twice.", name));
+            continue
+        }
+        // material with errors is replaced, so shapes that use it don't report it as missing
+        let material = parse_material(d, mat, &path)
+            .unwrap_or_else(|| Box::new(MatteMaterial::new(Color::zero())));
+        let material_id = scene_data.add_material(material);
+        scene_data.set_material_name(material_id, &name);
+        if mat["type"] == "matte_emissive" && !mat["light_group"].is_null() {
+            if let Some(group) = d.check(parse_string(&mat["light_group"], &field_path(&path, "light_group"))) {
+                let light_group = scene_data.add_light_group(&group);
+                scene_data.set_material_light_group(material_id, light_group);
+            }
         }
         map.insert(name, material_id);
     }
-    Ok(map)
+    map
 }
 
-fn parse_material(scene_data: &mut SceneData, section: &Value, name: &str) -> Result<usize, Box<dyn Error>> {
-    let typ = parse_string(&section["type"], "material->type")?;
-    let material_id = match typ.as_str() {
-        "matte" => parse_matte_material(scene_data, section, name)?,
-        "matte_emissive" => parse_matte_emissive_material(scene_data, section, name)?,
-        _ => return Err(format!("Unknown material type {}", typ).into())
-    };
-    Ok(material_id)
+const MATTE_KEYS: [&str; 3] = ["name", "type", "diffuse"];
+const MATTE_EMISSIVE_KEYS: [&str; 5] = ["name", "type", "diffuse", "emission", "light_group"];
+
+fn parse_material(d: &mut Diagnostics, section: &Value, path: &str) -> Option<Box<dyn BSDFInterface + Send + Sync>> {
+    let typ = d.check(parse_string(&section["type"], &field_path(path, "type")))?;
+    match typ.as_str() {
+        "matte" => {
+            d.check_keys(section, path, &MATTE_KEYS);
+            let color = d.check(parse_color(&section["diffuse"], &field_path(path, "diffuse")))?;
+            Some(Box::new(MatteMaterial::new(color)))
+        },
+        "matte_emissive" => {
+            d.check_keys(section, path, &MATTE_EMISSIVE_KEYS);
+            let color = d.check(parse_color(&section["diffuse"], &field_path(path, "diffuse")));
+            let emission = d.check(parse_color(&section["emission"], &field_path(path, "emission")));
+            Some(Box::new(MatteEmissiveMaterial::new(color?, emission?)))
+        },
+        _ => {
+            d.error(&field_path(path, "type"), &format!("Unknown material type {}, expected matte or matte_emissive.", typ));
+            None
+        }
+    }
 }
 
-fn parse_matte_material(scene_data: &mut SceneData, section: &Value, name: &str) -> Result<usize, Box<dyn Error>> {
-    let color = parse_color(&section["diffuse"], &format!("material:{}:diffuse", name))?;
-    let material_id = scene_data.add_material(Box::new(MatteMaterial::new(color)));
-    Ok(material_id)
+// Keys of global section in order in which they are applied, crop needs resolution and
+// exposure and white balance change tone mapping. Light path expressions are parsed last.
+const GLOBAL_KEYS: [&str; 27] = [
+    "resolution", "crop", "crop_output", "spp", "noise_threshold", "time_limit", "noise_target",
+    "min_spp", "rendering", "tonemap", "exposure", "white_balance", "exr_precision", "aovs",
+    "denoise", "checkpoint", "checkpoint_interval", "post_process", "sampler", "filter", "seed",
+    "output", "nthreads", "frames", "fps", "lpes", "acceleration"
+];
+
+fn parse_global(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, path: &str) {
+    if !section.is_object() {
+        return d.error(path, "Object with global settings expected.")
+    }
+    d.check_keys(section, path, &GLOBAL_KEYS);
+    for key in GLOBAL_KEYS {
+        if key == "lpes" || section[key].is_null() {
+            continue
+        }
+        let result = parse_global_key(d, scene_data, key, &section[key], &field_path(path, key));
+        d.check(result);
+    }
 }
 
-fn parse_matte_emissive_material(scene_data: &mut SceneData, section: &Value, name: &str) -> Result<usize, Box<dyn Error>> {
-    let color = parse_color(&section["diffuse"], &format!("material:{}:diffuse", name))?;
-    let emission = parse_color(&section["emission"], &format!("material:{}:emission", name))?;
-    let material_id = scene_data.add_material(Box::new(MatteEmissiveMaterial::new(color, emission)));
-    Ok(material_id)
+fn parse_global_key(d: &mut Diagnostics, scene_data: &mut SceneData, key: &str, value: &Value, path: &str) -> Result<(), Box<dyn Error>> {
+    match key {
+        "resolution" => {
+            let (width, height) = parse_resolution(value, path)?;
+            scene_data.set_image_size(width, height);
+        },
+        "crop" => {
+            let crop = parse_crop(value, path, scene_data.image_size())?;
+            scene_data.set_crop(Some(crop));
+        },
+        "crop_output" => {
+            let output = parse_string(value, path)?;
+            match output.as_str() {
+                "cropped" => scene_data.set_crop_output(CropOutput::Cropped),
+                "full" => scene_data.set_crop_output(CropOutput::FullSize),
+                _ => return Err(field_error(path, &format!("Unknown crop output {}, expected cropped or full.", output)))
+            }
+        },
+        "acceleration" => {
+            let acceleration = parse_string(value, path)?;
+            match acceleration.as_str() {
+                "bvh" => scene_data.set_acceleration(Acceleration::Bvh),
+                "list" => scene_data.set_acceleration(Acceleration::List),
+                _ => return Err(field_error(path, &format!("Unknown acceleration {}, expected bvh or list.", acceleration)))
+            }
+        },
+        "spp" => scene_data.set_samples_per_pixel(parse_usize(value, path)?),
+        "noise_threshold" => scene_data.set_noise_threshold(Some(parse_f32(value, path)?)),
+        "time_limit" => scene_data.set_time_limit(Some(parse_f32(value, path)?)),
+        "noise_target" => scene_data.set_noise_target(Some(parse_f32(value, path)?)),
+        "min_spp" => scene_data.set_min_samples_per_pixel(parse_usize(value, path)?),
+        "rendering" => {
+            let alg = parse_string(value, path)?;
+            match alg.as_str() {
+                "ambient" => scene_data.set_rendering_algorithm(RenderingAlgorithm::AmbientOcclusion),
+                "direct_lighting" => scene_data.set_rendering_algorithm(RenderingAlgorithm::DirectLighting),
+                "path" => scene_data.set_rendering_algorithm(RenderingAlgorithm::PathTracer),
+                "shading_normals" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::ShadingNormal)),
+                "geometric_normals" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::GeometricNormal)),
+                "uv" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::Uv)),
+                "barycentrics" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::Barycentrics)),
+                "depth" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::Depth)),
+                "material_id" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::MaterialId)),
+                "shape_id" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::ShapeId)),
+                "node_visits" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::NodeVisits)),
+                "primitive_tests" => scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::PrimitiveTests)),
+                _ => return Err(field_error(path, &format!("Unknown rendering algorithm {}.", alg)))
+            }
+        },
+        "tonemap" => {
+            let tmo = parse_string(value, path)?;
+            let tmo_type = match tmo.as_str() {
+                "linear" => TMOType::Linear,
+                "gamma" => TMOType::Gamma,
+                "srgb" => TMOType::Srgb,
+                "reinhard" => TMOType::Reinhard,
+                "aces" => TMOType::Aces,
+                "hable" | "filmic" => TMOType::Hable,
+                "agx" => TMOType::Agx,
+                _ => return Err(field_error(path, &format!("Unknown tone mapping operator {}.", tmo)))
+            };
+            scene_data.set_tone_mapping(ToneMapping::new(tmo_type));
+        },
+        "exposure" => {
+            let mut tone_mapping = *scene_data.get_tone_mapping();
+            tone_mapping.set_exposure(parse_f32(value, path)?);
+            scene_data.set_tone_mapping(tone_mapping);
+        },
+        "white_balance" => {
+            let temperature = parse_f32(value, path)?;
+            if !(1000.0..=40000.0).contains(&temperature) {
+                return Err(field_error(path, &format!("Temperature {}K out of range [1000, 40000].", temperature)))
+            }
+            let mut tone_mapping = *scene_data.get_tone_mapping();
+            tone_mapping.set_white_balance(temperature);
+            scene_data.set_tone_mapping(tone_mapping);
+        },
+        "exr_precision" => {
+            let precision = parse_string(value, path)?;
+            match precision.as_str() {
+                "half" => scene_data.set_exr_precision(ExrPrecision::Half),
+                "float" => scene_data.set_exr_precision(ExrPrecision::Float),
+                _ => return Err(field_error(path, &format!("Unknown EXR precision {}, expected half or float.", precision)))
+            }
+        },
+        "aovs" => {
+            let names = match value.as_array() {
+                Some(names) => names,
+                None => return Err(field_error(path, "Array of AOV names expected."))
+            };
+            let mut aovs = Vec::new();
+            for (index, name) in names.iter().enumerate() {
+                let name_path = index_path(path, index);
+                let name = parse_string(name, &name_path)?;
+                match AovType::from_name(&name) {
+                    Some(aov) => aovs.push(aov),
+                    None => return Err(field_error(&name_path, &format!("Unknown AOV {}.", name)))
+                }
+            }
+            scene_data.set_aovs(aovs);
+        },
+        "denoise" => scene_data.set_denoise(parse_bool(value, path)?),
+        "checkpoint" => scene_data.set_checkpoint_file(Some(parse_string(value, path)?)),
+        "checkpoint_interval" => scene_data.set_checkpoint_interval(parse_f32(value, path)?),
+        "post_process" => {
+            let post_process = parse_post_process(d, value, path)?;
+            scene_data.set_post_process(post_process);
+        },
+        "sampler" => {
+            let sampler = parse_string(value, path)?;
+            match sampler.as_str() {
+                "independent" => scene_data.set_sampler_type(SamplerType::Independent),
+                "stratified" => scene_data.set_sampler_type(SamplerType::Stratified),
+                "halton" => scene_data.set_sampler_type(SamplerType::Halton),
+                "sobol" => scene_data.set_sampler_type(SamplerType::Sobol),
+                _ => return Err(field_error(path, &format!("Unknown sampler {}.", sampler)))
+            }
+        },
+        "filter" => {
+            let filter = parse_filter(d, value, path)?;
+            scene_data.set_filter(filter);
+        },
+        "seed" => scene_data.set_seed(parse_usize(value, path)? as u64),
+        "output" => scene_data.set_output_file(parse_string(value, path)?),
+        "nthreads" => scene_data.set_nthreads(parse_usize(value, path)?),
+        "frames" => {
+            let frames = match value.as_array() {
+                Some(frames) if frames.len() == 2 => frames,
+                _ => return Err(field_error(path, "First and last frame expected."))
+            };
+            let first = parse_usize(&frames[0], &index_path(path, 0))?;
+            let last = parse_usize(&frames[1], &index_path(path, 1))?;
+            if last < first {
+                return Err(field_error(path, "Last frame must not be before first frame."))
+            }
+            scene_data.set_frames(Some((first, last)));
+        },
+        "fps" => {
+            let fps = parse_f32(value, path)?;
+            if fps <= 0.0 {
+                return Err(field_error(path, "Positive number of frames per second expected."))
+            }
+            scene_data.set_fps(fps);
+        },
+        _ => {}
+    }
+    Ok(())
 }
 
-fn parse_global(scene_data: &mut SceneData, section: &Value) -> Result<(), Box<dyn Error>> {
-    if !section["resolution"].is_null() {
-        let (width, height) = parse_resolution(&section["resolution"])?;
-        scene_data.set_image_size(width, height);
+const FILTER_KEYS: [&str; 2] = ["type", "radius"];
+
+// Filter is given either by name or as object with type, radius and filter parameters
+fn parse_filter(d: &mut Diagnostics, section: &Value, path: &str) -> Result<Filter, Box<dyn Error>> {
+    let typ = match section.as_str() {
+        Some(typ) => typ.to_string(),
+        None => parse_string(&section["type"], &field_path(path, "type"))?
+    };
+    let param = |name: &str, default: f32| -> Result<f32, Box<dyn Error>> {
+        if section[name].is_null() {
+            return Ok(default)
+        }
+        parse_f32(&section[name], &field_path(path, name))
+    };
+    let (filter_type, params): (FilterType, &[&str]) = match typ.as_str() {
+        "box" => (FilterType::Box, &[]),
+        "gaussian" => (FilterType::Gaussian { sigma: param("sigma", 0.5)? }, &["sigma"]),
+        "mitchell" => (FilterType::Mitchell { b: param("b", 1.0 / 3.0)?, c: param("c", 1.0 / 3.0)? }, &["b", "c"]),
+        "lanczos" => (FilterType::Lanczos { tau: param("tau", 2.0)? }, &["tau"]),
+        "blackman_harris" => (FilterType::BlackmanHarris, &[]),
+        _ => return Err(field_error(path, &format!("Unknown filter {}.", typ)))
+    };
+    d.check_keys(section, path, &[&FILTER_KEYS[..], params].concat());
+    let radius = param("radius", Filter::default_radius(&filter_type))?;
+    Ok(Filter::new(filter_type, radius))
+}
+
+const POST_PROCESS_KEYS: [&str; 4] = ["bloom", "glare", "vignette", "dither"];
+const BLOOM_KEYS: [&str; 5] = ["enabled", "threshold", "intensity", "radius", "levels"];
+const GLARE_KEYS: [&str; 6] = ["enabled", "threshold", "intensity", "blades", "length", "angle"];
+const VIGNETTE_KEYS: [&str; 2] = ["enabled", "strength"];
+
+// Each effect is enabled with true (default parameters) or object with parameters,
+// object can disable effect with "enabled": false
+fn parse_post_process(d: &mut Diagnostics, section: &Value, path: &str) -> Result<PostProcess, Box<dyn Error>> {
+    if !section.is_object() {
+        return Err(field_error(path, "Object with post processing effects expected."))
     }
-    if !section["spp"].is_null() {
-        let spp = parse_usize(&section["spp"], "spp")?;
-        scene_data.set_samples_per_pixel(spp);
+    d.check_keys(section, path, &POST_PROCESS_KEYS);
+    for (name, keys) in [("bloom", &BLOOM_KEYS[..]), ("glare", &GLARE_KEYS[..]), ("vignette", &VIGNETTE_KEYS[..])] {
+        d.check_keys(&section[name], &field_path(path, name), keys);
     }
-    if !section["rendering"].is_null() {
-        let alg = parse_string(&section["rendering"], "rendering")?;
-        match alg.as_str() {
-            "ambient" => scene_data.set_rendering_algorithm(RenderingAlgorithm::AmbientOcclusion),
-            "direct_lighting" => scene_data.set_rendering_algorithm(RenderingAlgorithm::DirectLighting),
-            "path" => scene_data.set_rendering_algorithm(RenderingAlgorithm::PathTracer),
-            _ => return Err(format!("Unknown rendering algorithm: {}", alg).into())
+    let enabled = |name: &str| -> Result<bool, Box<dyn Error>> {
+        let effect = &section[name];
+        let effect_path = field_path(path, name);
+        if effect.is_null() {
+            return Ok(false)
         }
-    }
-    if !section["tonemap"].is_null() {
-        let tmo = parse_string(&section["tonemap"], "tonemap")?;
-        match tmo.as_str() {
-            "linear" => scene_data.set_tmo_type(TMOType::Linear),
-            "gamma" => scene_data.set_tmo_type(TMOType::Gamma),
-            "reinhard" => scene_data.set_tmo_type(TMOType::Reinhard),
-            _ => return Err(format!("Unknown tone mapping operator: {}", tmo).into())
+        if effect.is_boolean() {
+            return parse_bool(effect, &effect_path)
+        }
+        if !effect.is_object() {
+            return Err(field_error(&effect_path, "Boolean or object expected."))
+        }
+        if effect["enabled"].is_null() {
+            return Ok(true)
         }
+        parse_bool(&effect["enabled"], &field_path(&effect_path, "enabled"))
+    };
+    let param = |name: &str, field: &str, default: f32| -> Result<f32, Box<dyn Error>> {
+        let value = &section[name][field];
+        if value.is_null() {
+            return Ok(default)
+        }
+        parse_f32(value, &field_path(&field_path(path, name), field))
+    };
+    let count = |name: &str, field: &str, default: usize| -> Result<usize, Box<dyn Error>> {
+        let value = &section[name][field];
+        if value.is_null() {
+            return Ok(default)
+        }
+        parse_usize(value, &field_path(&field_path(path, name), field))
+    };
+
+    let mut post_process = PostProcess::default();
+    if enabled("bloom")? {
+        let d = Bloom::default();
+        post_process.bloom = Some(Bloom {
+            threshold: param("bloom", "threshold", d.threshold)?,
+            intensity: param("bloom", "intensity", d.intensity)?,
+            radius: param("bloom", "radius", d.radius)?,
+            levels: count("bloom", "levels", d.levels)?
+        });
+    }
+    if enabled("glare")? {
+        let d = Glare::default();
+        post_process.glare = Some(Glare {
+            threshold: param("glare", "threshold", d.threshold)?,
+            intensity: param("glare", "intensity", d.intensity)?,
+            blades: count("glare", "blades", d.blades)?,
+            length: param("glare", "length", d.length)?,
+            angle: param("glare", "angle", d.angle)?
+        });
     }
-    if !section["output"].is_null() {
-        let output = parse_string(&section["output"], "output")?;
-        scene_data.set_output_file(output);
+    if enabled("vignette")? {
+        let d = Vignette::default();
+        let strength = param("vignette", "strength", d.strength)?;
+        if !(0.0..=1.0).contains(&strength) {
+            return Err(field_error(&field_path(path, "vignette.strength"), "Value in range [0, 1] expected."))
+        }
+        post_process.vignette = Some(Vignette { strength });
     }
-    if !section["nthreads"].is_null() {
-        let nthreads = parse_usize(&section["nthreads"], "nthreads")?;
-        scene_data.set_nthreads(nthreads);
+    if !section["dither"].is_null() {
+        post_process.dither = parse_bool(&section["dither"], &field_path(path, "dither"))?;
     }
-
-    Ok(())
+    Ok(post_process)
 }
 
-fn parse_camera(scene_data: &mut SceneData, section: &Value) -> Result<(), Box<dyn Error>> {
+const CAMERA_KEYS: [&str; 8] = ["eye", "lookat", "hfov", "vp_distance", "shutter_open", "shutter_close", "keyframes", "interpolation"];
+const CAMERA_KEYFRAME_KEYS: [&str; 4] = ["time", "eye", "lookat", "hfov"];
+
+fn parse_camera(d: &mut Diagnostics, scene_data: &mut SceneData, section: &Value, path: &str) {
+    if !section.is_object() {
+        return d.error(path, "Object with camera settings expected.")
+    }
+    d.check_keys(section, path, &CAMERA_KEYS);
     if !section["eye"].is_null() {
-        let eye = parse_f32x3(&section["eye"], "camera->eye")?;
-        scene_data.set_camera_pos(eye);
+        if let Some(eye) = d.check(parse_f32x3(&section["eye"], &field_path(path, "eye"))) {
+            scene_data.set_camera_pos(eye);
+        }
     }
     if !section["lookat"].is_null() {
-        let look_at = parse_f32x3(&section["lookat"], "camera->lookat")?;
-        scene_data.set_camera_look_at(look_at);
+        if let Some(look_at) = d.check(parse_f32x3(&section["lookat"], &field_path(path, "lookat"))) {
+            scene_data.set_camera_look_at(look_at);
+        }
     }
     if !section["hfov"].is_null() {
-        let hfov = parse_f32(&section["hfov"], "camera->hfov")?;
-        scene_data.set_camera_horizontal_fov(hfov);
+        if let Some(hfov) = d.check(parse_f32(&section["hfov"], &field_path(path, "hfov"))) {
+            scene_data.set_camera_horizontal_fov(hfov);
+        }
     }
     if !section["vp_distance"].is_null() {
-        let dist = parse_f32(&section["vp_distance"], "camera->vp_distance")?;
-        scene_data.set_camera_view_plane_distance(dist);
+        if let Some(dist) = d.check(parse_f32(&section["vp_distance"], &field_path(path, "vp_distance"))) {
+            scene_data.set_camera_view_plane_distance(dist);
+        }
+    }
+    if !section["shutter_open"].is_null() || !section["shutter_close"].is_null() {
+        let shutter_open = d.check(parse_f32(&section["shutter_open"], &field_path(path, "shutter_open")));
+        let shutter_close = d.check(parse_f32(&section["shutter_close"], &field_path(path, "shutter_close")));
+        if let (Some(shutter_open), Some(shutter_close)) = (shutter_open, shutter_close) {
+            if shutter_close < shutter_open {
+                d.error(&field_path(path, "shutter_close"), "Shutter must not close before it opens.");
+            } else {
+                scene_data.set_camera_shutter(shutter_open, shutter_close);
+            }
+        }
+    }
+    if !section["keyframes"].is_null() {
+        let keys_path = field_path(path, "keyframes");
+        let keys = match section["keyframes"].as_array() {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => return d.error(&keys_path, "List of keyframes expected.")
+        };
+        let mut keyframes = Vec::new();
+        for (index, key) in keys.iter().enumerate() {
+            let key_path = index_path(&keys_path, index);
+            d.check_keys(key, &key_path, &CAMERA_KEYFRAME_KEYS);
+            if let Some(keyframe) = d.check(parse_camera_keyframe(key, &key_path)) {
+                keyframes.push(keyframe);
+            }
+        }
+        if keyframes.iter().any(|k| k.hfov.is_some()) && keyframes.iter().any(|k| k.hfov.is_none()) {
+            d.error(&keys_path, "Either all keyframes or none must have hfov.");
+        }
+        let interpolation = d.check(parse_interpolation(&section["interpolation"], &field_path(path, "interpolation")));
+        if let (Some(interpolation), true) = (interpolation, keyframes.len() == keys.len()) {
+            scene_data.set_camera_track(Some(CameraTrack::new(keyframes, interpolation)));
+        }
     }
-    Ok(())
 }
 
-fn parse_resolution(section: &Value) -> Result<(usize, usize), Box<dyn Error>> {
-    let width = parse_usize(&section[0], "resolution")?;
-    let height = parse_usize(&section[1], "resolution")?;
-    Ok((width, height))
+fn parse_camera_keyframe(key: &Value, path: &str) -> Result<CameraKeyframe, Box<dyn Error>> {
+    let time = parse_f32(&key["time"], &field_path(path, "time"))?;
+    let eye = parse_f32x3(&key["eye"], &field_path(path, "eye"))?;
+    let look_at = parse_f32x3(&key["lookat"], &field_path(path, "lookat"))?;
+    let hfov = match key["hfov"].is_null() {
+        true => None,
+        false => Some(parse_f32(&key["hfov"], &field_path(path, "hfov"))?)
+    };
+    Ok(CameraKeyframe { time, eye, look_at, hfov })
 }
 
-fn parse_f32x3(section: &Value, field_name: &str) -> Result<f32x3, Box<dyn Error>> {
-    let val1 = parse_f32(&section[0], field_name)?;
-    let val2 = parse_f32(&section[1], field_name)?;
-    let val3 = parse_f32(&section[2], field_name)?;
-    if !&section[3].is_null() {
-        return Err(format!("Field: {} - Exactly 3 values expected!", field_name).into())
+fn parse_resolution(section: &Value, path: &str) -> Result<(usize, usize), Box<dyn Error>> {
+    match section.as_array() {
+        Some(values) if values.len() == 2 => {
+            let width = parse_usize(&values[0], &index_path(path, 0))?;
+            let height = parse_usize(&values[1], &index_path(path, 1))?;
+            Ok((width, height))
+        },
+        _ => Err(field_error(path, &format!("Width and height expected, found {}.", section)))
     }
-    Ok(f32x3(val1, val2, val3))
 }
 
-fn parse_color(section: &Value, field_name: &str) -> Result<Color, Box<dyn Error>> {
-    let red = parse_f32(&section[0], field_name)?;
-    let green = parse_f32(&section[1], field_name)?;
-    let blue = parse_f32(&section[2], field_name)?;
-    if !&section[3].is_null() {
-        return Err(format!("Field: {} - Exactly 3 values expected!", field_name).into())
+// Integer values are pixels, otherwise values are fractions of resolution in [0, 1]
+fn parse_crop(section: &Value, path: &str, (width, height): (usize, usize)) -> Result<Crop, Box<dyn Error>> {
+    let values = match section.as_array() {
+        Some(values) if values.len() == 4 => values,
+        _ => return Err(field_error(path, "Exactly 4 values [x0, y0, x1, y1] expected."))
+    };
+    if values.iter().all(|value| value.is_u64()) {
+        let window = [0, 1, 2, 3].map(|i| values[i].as_u64().unwrap_or(0) as usize);
+        if window[0] >= window[2] || window[1] >= window[3] || window[0] >= width || window[1] >= height {
+            return Err(field_error(path, "Crop window is empty or outside of image."))
+        }
+        return Ok(Crop::Pixels(window))
     }
-    Ok(Color{red, green, blue})
+    let window = parse_f32_array::<4>(section, path)?;
+    if window.iter().any(|value| !(0.0..=1.0).contains(value)) {
+        return Err(field_error(path, "Normalized values must be in range [0, 1]."))
+    }
+    if window[0] >= window[2] || window[1] >= window[3] {
+        return Err(field_error(path, "Crop window is empty."))
+    }
+    Ok(Crop::Normalized(window))
+}
 
+// Message of value with wrong type, missing fields are read as null
+fn expected(section: &Value, path: &str, what: &str) -> Box<dyn Error> {
+    match section {
+        Value::Null => field_error(path, &format!("{} expected, field is missing.", what)),
+        _ => field_error(path, &format!("{} expected, found {}.", what, section))
+    }
+}
+
+fn parse_f32_array<const N: usize>(section: &Value, path: &str) -> Result<[f32; N], Box<dyn Error>> {
+    let values = match section.as_array() {
+        Some(values) if values.len() == N => values,
+        _ => return Err(expected(section, path, &format!("Array of {} numbers", N)))
+    };
+    let mut result = [0.0; N];
+    for (index, value) in values.iter().enumerate() {
+        result[index] = parse_f32(value, &index_path(path, index))?;
+    }
+    Ok(result)
+}
+
+fn parse_f32x3(section: &Value, path: &str) -> Result<f32x3, Box<dyn Error>> {
+    let [x, y, z] = parse_f32_array::<3>(section, path)?;
+    Ok(f32x3(x, y, z))
+}
+
+fn parse_color(section: &Value, path: &str) -> Result<Color, Box<dyn Error>> {
+    let [red, green, blue] = parse_f32_array::<3>(section, path)?;
+    Ok(Color{red, green, blue})
 }
 
-fn parse_usize(section: &Value, field_name: &str) -> Result<usize, Box<dyn Error>> {
+fn parse_usize(section: &Value, path: &str) -> Result<usize, Box<dyn Error>> {
     let val = match section.as_u64() {
         Some(val) => val as usize,
-        None => return Err(format!("Field: {}", field_name).into())
+        None => return Err(expected(section, path, "Non negative integer"))
     };
     Ok(val)
 }
 
-fn parse_string(section: &Value, field_name: &str) -> Result<String, Box<dyn Error>> {
+fn parse_string(section: &Value, path: &str) -> Result<String, Box<dyn Error>> {
     let val = match section.as_str() {
         Some(val) => val,
-        None => return Err(format!("Field: {}", field_name).into())
+        None => return Err(expected(section, path, "String"))
     };
     Ok(val.to_string())
 }
 
-fn parse_f32(section: &Value, field_name: &str) -> Result<f32, Box<dyn Error>> {
+fn parse_bool(section: &Value, path: &str) -> Result<bool, Box<dyn Error>> {
+    let val = match section.as_bool() {
+        Some(val) => val,
+        None => return Err(expected(section, path, "Boolean"))
+    };
+    Ok(val)
+}
+
+fn parse_f32(section: &Value, path: &str) -> Result<f32, Box<dyn Error>> {
     let val = match section.as_f64() {
         Some(val) => val as f32,
-        None => return Err(format!("Field: {}", field_name).into())
+        None => return Err(expected(section, path, "Number"))
     };
     Ok(val)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scene_errors_with_paths() {
+        let json = r#"{
+    "global": {"spp": "many", "resolution": [64, 48], "noise_treshold": 0.1},
+    "materials": [
+        {"name": "red", "type": "matte", "diffuse": [1, 0, 0]},
+        {"name": "lamp", "type": "matte_emissive", "diffuse": [1, 1, 1], "emission": [1, 1]}
+    ],
+    "shapes": [
+        {"type": "sphere", "material": "red", "position": [0, 0, 0], "radius": 1},
+        {"type": "sphere", "material": "lamp", "position": [0, 0, 0], "radious": 1},
+        {"type": "sphere", "material": "blue", "position": [0, 0, 0], "radius": 1}
+    ],
+    "info": "comment"
+}"#;
+        let err = parse_json_str(json).err().unwrap();
+        let scene_errors = err.downcast_ref::<SceneErrors>().unwrap();
+        let errors = &scene_errors.errors;
+        let find = |path: &str| errors.iter().find(|error| error.path == path).unwrap_or_else(|| panic!("Missing error {}", path));
+        assert_eq!(errors.len(), 4);
+        assert_eq!(find("global.spp").position, Some((2, 16)));
+        assert!(find("global.spp").message.contains("found \"many\""));
+        assert_eq!(find("materials[1].emission").position, Some((5, 74)));
+        // missing field is located at its shape
+        assert_eq!(find("shapes[1].radius").position, Some((9, 9)));
+        assert!(find("shapes[2].material").message.contains("blue"));
+        assert!(err.to_string().contains("Field: global.spp (line 2, column 16) - Non negative integer expected"));
+        assert_eq!(scene_errors.warnings.len(), 3);
+        assert!(err.to_string().contains("Warning: Field: shapes[1].radious (line 9, column 71) - Unknown key radious, did you mean radius?"));
+
+        let fixed = json.replace("\"many\"", "4").replace("[1, 1]}", "[1, 1, 1]}").replace("radious", "radius").replace("blue", "red");
+        let (scene_data, warnings) = parse_json_str_with_warnings(&fixed).unwrap();
+        assert_eq!(scene_data.shape_count(), 3);
+        let messages: Vec<String> = warnings.iter().map(|warning| warning.to_string()).collect();
+        assert_eq!(messages, vec![
+            "Field: global.noise_treshold (line 2, column 50) - Unknown key noise_treshold, did you mean noise_threshold?",
+            "Field: info (line 12, column 5) - Unknown key info is ignored."
+        ]);
+
+        let flat = r#"{"materials": [{"name": "red", "type": "matte", "diffuse": [1, 0, 0]}],
+            "shapes": [{"type": "sphere", "material": "red", "position": [0, 0, 0], "radius": 1, "transform": {"scale": [1, 0, 1]}}]}"#;
+        let err = parse_json_str(flat).err().unwrap();
+        assert!(err.to_string().contains("Field: shapes[0].transform.scale (line 2, column 112) - Non zero scale expected, found [1,0,1]."));
+        let mirrored = flat.replace(r#""transform": {"scale": [1, 0, 1]}"#,
+            r#""motion": [{"time": 0, "scale": 1}, {"time": 1, "scale": [1, -1, 1]}]"#);
+        let err = parse_json_str(&mirrored).err().unwrap();
+        assert!(err.to_string().contains("shapes[0].motion[1].scale"));
+        assert!(err.to_string().contains("Scale of keyframes can't change sign."));
+
+        let lpes = r#"{"global": {"rendering": "ambient", "lpes": {"direct": "CDL"}}}"#;
+        let err = parse_json_str(lpes).err().unwrap();
+        assert!(err.to_string().contains("Field: global.lpes (line 1, column 37) - Light path expressions are supported only by path rendering algorithm."));
+        assert!(parse_json_str(&lpes.replace("ambient", "path")).is_ok());
+    }
+}
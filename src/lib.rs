@@ -0,0 +1,43 @@
+pub mod pixel_buffer;
+pub mod traits;
+pub mod scene;
+pub mod scene_info;
+pub mod renderer;
+pub mod pcg;
+pub mod vec;
+pub mod ray;
+pub mod camera;
+pub mod camera_control;
+pub mod inspector;
+pub mod img_sampling;
+pub mod shapes;
+pub mod render;
+pub mod onb;
+pub mod materials;
+pub mod lights;
+pub mod json;
+pub mod bbox;
+pub mod bvh;
+pub mod transform;
+pub mod sampler;
+pub mod filter;
+pub mod exr_output;
+pub mod aov;
+pub mod lpe;
+pub mod denoise;
+pub mod tonemap;
+pub mod postprocess;
+pub mod checkpoint;
+pub mod builder;
+pub mod session;
+pub mod progress;
+pub mod distributed;
+pub mod cli;
+
+pub use builder::SceneBuilder;
+pub use json::parse_json_file;
+pub use pixel_buffer::Color;
+pub use scene::{RenderingAlgorithm, SceneData};
+pub use progress::{CancelToken, Progress, ProgressMonitor};
+pub use session::{RenderSession, RenderStatus, RenderedImage};
+pub use vec::f32x3;
@@ -1,4 +1,4 @@
-use crate::pcg::PCGRng;
+use crate::sampler::Sampler;
 use crate::ray::offset_ray_origin;
 use crate::vec::f32x3;
 use crate::pixel_buffer::Color;
@@ -7,17 +7,22 @@ use crate::scene::{LightInterface, LightSample, SceneData};
 
 pub struct PointLight {
     intensity: Color,
-    position: f32x3
+    position: f32x3,
+    light_group: Option<usize>
 }
 
 impl PointLight {
     pub fn new(intensity: Color, position: f32x3) -> PointLight {
-        PointLight { intensity, position }
+        PointLight { intensity, position, light_group: None }
+    }
+
+    pub fn set_light_group(&mut self, light_group: Option<usize>) {
+        self.light_group = light_group
     }
 }
 
 impl LightInterface for PointLight {
-    fn illuminate(&self, hit: f32x3, _scene_data: &SceneData, _rng: &mut PCGRng) -> Option<LightSample> {
+    fn illuminate(&self, hit: f32x3, _time: f32, _scene_data: &SceneData, _sampler: &mut dyn Sampler) -> Option<LightSample> {
         let direction_to_light = self.position - hit;
         let wi = direction_to_light.normalize();
         let intensity = self.intensity * direction_to_light.length_sqr().recip();
@@ -30,6 +35,14 @@ impl LightInterface for PointLight {
     fn is_delta_light(&self) -> bool {
         true
     }
+
+    fn name(&self) -> &'static str {
+        "point"
+    }
+
+    fn light_group(&self, _scene_data: &SceneData) -> Option<usize> {
+        self.light_group
+    }
 }
 
 pub struct AreaLight {
@@ -47,11 +60,16 @@ impl LightInterface for AreaLight {
         true
     }
 
-    fn illuminate(&self, hit: f32x3, scene_data: &SceneData, rng: &mut PCGRng) -> Option<LightSample> {
-        let shp_sample = match scene_data.generate_shape_sample(self.shape_id, hit, rng) {
-            Some(shp_sample) => shp_sample,
-            None => return None
-        };
+    fn name(&self) -> &'static str {
+        "area"
+    }
+
+    fn light_group(&self, scene_data: &SceneData) -> Option<usize> {
+        scene_data.shape_light_group(self.shape_id)
+    }
+
+    fn illuminate(&self, hit: f32x3, time: f32, scene_data: &SceneData, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let shp_sample = scene_data.generate_shape_sample(self.shape_id, hit, time, sampler)?;
 
         let position = offset_ray_origin(shp_sample.position, shp_sample.normal);
         let pdfa = shp_sample.pdfa;
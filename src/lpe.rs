@@ -0,0 +1,224 @@
+use std::error::Error;
+
+use crate::pixel_buffer::Color;
+use crate::traits::Zero;
+
+
+// Events along light path, path starts at camera and ends at light.
+// Light event carries light group of the light if it has one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LpeEvent {
+    Camera,
+    Diffuse,
+    Specular,
+    Light(Option<usize>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Atom {
+    Camera,
+    Diffuse,
+    Specular,
+    Light,
+    LightGroup(usize),
+    Any,
+    Class(Vec<Atom>)
+}
+
+impl Atom {
+    fn matches(&self, event: &LpeEvent) -> bool {
+        match (self, event) {
+            (Atom::Any, _) => true,
+            (Atom::Camera, LpeEvent::Camera) => true,
+            (Atom::Diffuse, LpeEvent::Diffuse) => true,
+            (Atom::Specular, LpeEvent::Specular) => true,
+            (Atom::Light, LpeEvent::Light(_)) => true,
+            (Atom::LightGroup(group), LpeEvent::Light(Some(light_group))) => group == light_group,
+            (Atom::Class(atoms), event) => atoms.iter().any(|atom| atom.matches(event)),
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    atom: Atom,
+    min: usize,
+    max: usize
+}
+
+// Light path expression, a small regular expression over path events.
+// C camera, D diffuse, S specular, L light, L'name' light from light group,
+// . any event, [DS] one of events, postfix *, + and ? for repetition.
+// Example: "CDL" direct diffuse, "CD.+L" indirect diffuse, "C.*L'key'" key light.
+#[derive(Debug, Clone)]
+pub struct Lpe {
+    name: String,
+    elements: Vec<Element>
+}
+
+impl Lpe {
+    pub fn parse(name: &str, expression: &str, light_groups: &[String]) -> Result<Lpe, Box<dyn Error>> {
+        let error = |msg: &str| -> Box<dyn Error> {
+            format!("Light path expression {} ({}): {}", name, expression, msg).into()
+        };
+        let chars: Vec<char> = expression.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut elements: Vec<Element> = Vec::new();
+        let mut pos = 0;
+        let mut in_class = false;
+        let mut class = Vec::new();
+        while pos < chars.len() {
+            let c = chars[pos];
+            pos += 1;
+            let atom = match c {
+                'C' => Atom::Camera,
+                'D' => Atom::Diffuse,
+                'S' => Atom::Specular,
+                '.' => Atom::Any,
+                'L' => {
+                    if pos < chars.len() && chars[pos] == '\'' {
+                        let end = match chars[pos + 1..].iter().position(|c| *c == '\'') {
+                            Some(end) => pos + 1 + end,
+                            None => return Err(error("missing closing quote"))
+                        };
+                        let group: String = chars[pos + 1..end].iter().collect();
+                        pos = end + 1;
+                        match light_groups.iter().position(|name| *name == group) {
+                            Some(index) => Atom::LightGroup(index),
+                            None => return Err(error(&format!("unknown light group {}", group)))
+                        }
+                    } else {
+                        Atom::Light
+                    }
+                },
+                '[' if !in_class => {
+                    in_class = true;
+                    continue;
+                },
+                ']' if in_class => {
+                    in_class = false;
+                    Atom::Class(std::mem::take(&mut class))
+                },
+                '*' | '+' | '?' if !in_class => {
+                    let last = match elements.last_mut() {
+                        Some(last) if last.min == 1 && last.max == 1 => last,
+                        _ => return Err(error("nothing to repeat"))
+                    };
+                    match c {
+                        '*' => { last.min = 0; last.max = usize::MAX },
+                        '+' => last.max = usize::MAX,
+                        _ => last.min = 0
+                    }
+                    continue;
+                },
+                _ => return Err(error(&format!("unexpected character {}", c)))
+            };
+            if in_class {
+                class.push(atom);
+            } else {
+                elements.push(Element { atom, min: 1, max: 1 });
+            }
+        }
+        if in_class {
+            return Err(error("missing ]"));
+        }
+        Ok(Lpe { name: name.to_string(), elements })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn matches(&self, events: &[LpeEvent]) -> bool {
+        self.match_from(0, events)
+    }
+
+    fn match_from(&self, index: usize, events: &[LpeEvent]) -> bool {
+        let element = match self.elements.get(index) {
+            Some(element) => element,
+            None => return events.is_empty()
+        };
+        let mut count = 0;
+        while count < element.max && count < events.len() && element.atom.matches(&events[count]) {
+            count += 1;
+        }
+        // greedy with backtracking, paths are short
+        (element.min..=count).rev().any(|n| self.match_from(index + 1, &events[n..]))
+    }
+}
+
+// Routes contributions of one camera path to buffers whose expressions match path events
+pub struct LpeRecorder<'a> {
+    lpes: &'a [Lpe],
+    events: Vec<LpeEvent>,
+    colors: Vec<Color>
+}
+
+impl<'a> LpeRecorder<'a> {
+    pub fn new(lpes: &'a [Lpe]) -> LpeRecorder<'a> {
+        LpeRecorder { lpes, events: Vec::with_capacity(16), colors: vec![Color::zero(); lpes.len()] }
+    }
+
+    pub fn start_path(&mut self) {
+        self.events.clear();
+        self.events.push(LpeEvent::Camera);
+        self.colors.iter_mut().for_each(|color| *color = Color::zero());
+    }
+
+    pub fn push_event(&mut self, event: LpeEvent) {
+        if !self.lpes.is_empty() {
+            self.events.push(event);
+        }
+    }
+
+    // Contribution of light reached from current path
+    pub fn add(&mut self, color: Color, light: LpeEvent) {
+        if self.lpes.is_empty() {
+            return;
+        }
+        self.events.push(light);
+        for (lpe, acum) in self.lpes.iter().zip(self.colors.iter_mut()) {
+            if lpe.matches(&self.events) {
+                *acum += color;
+            }
+        }
+        self.events.pop();
+    }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_expressions() {
+        let groups = vec!["key".to_string(), "fill".to_string()];
+        let lpe = |expr| Lpe::parse("test", expr, &groups).unwrap();
+        let key = LpeEvent::Light(Some(0));
+        let path = |events: &[LpeEvent]| {
+            let mut path = vec![LpeEvent::Camera];
+            path.extend_from_slice(events);
+            path
+        };
+        let direct = path(&[LpeEvent::Diffuse, key]);
+        let indirect = path(&[LpeEvent::Diffuse, LpeEvent::Diffuse, LpeEvent::Light(None)]);
+        let emission = path(&[key]);
+
+        assert!(lpe("CDL").matches(&direct));
+        assert!(!lpe("CDL").matches(&indirect));
+        assert!(lpe("CD.+L").matches(&indirect));
+        assert!(!lpe("CD.+L").matches(&direct));
+        assert!(lpe("CL").matches(&emission));
+        assert!(lpe("C.*L'key'").matches(&direct));
+        assert!(!lpe("C.*L'key'").matches(&indirect));
+        assert!(lpe("C[DS]+L").matches(&indirect));
+        assert!(lpe("CD?L").matches(&emission));
+        assert!(Lpe::parse("test", "CL'rim'", &groups).is_err());
+        assert!(Lpe::parse("test", "*CL", &groups).is_err());
+    }
+}
@@ -1,97 +1,425 @@
+use std::{time::{Instant, Duration}, env};
+use std::error::Error;
+use std::io::{self, Write};
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
 
-pub mod pixel_buffer;
-pub mod traits;
-pub mod scene;
-pub mod renderer;
-pub mod pcg;
-pub mod vec;
-pub mod ray;
-pub mod camera;
-pub mod img_sampling;
-pub mod shapes;
-pub mod render;
-pub mod onb;
-pub mod materials;
-pub mod lights;
-pub mod json;
-pub mod bbox;
-pub mod bvh;
+use minifb::{Window, WindowOptions, Key, KeyRepeat, MouseButton, MouseMode};
+use rs_tracer::renderer::Renderer2;
+use rs_tracer::json::parse_json_str_with_warnings;
+use rs_tracer::cli::{Command, SceneArgs, USAGE, parse_args};
+use rs_tracer::scene::SceneData;
+use rs_tracer::scene_info::SceneInfo;
+use rs_tracer::distributed::{SceneSource, run_worker};
+use rs_tracer::camera_control::CameraControl;
+use rs_tracer::inspector::{DebugView, PixelHit, debug_view_rgb_vector, inspect_pixel, pixel_hits};
 
-use std::{time::{Instant, Duration}, env};
+// Settings of command line that are used when renderer is created
+struct Options {
+    scene_json: String,
+    resume: Option<String>,
+    workers: Vec<String>
+}
+
+fn create_renderer(scene_data: SceneData, options: &Options) -> Result<Renderer2, Box<dyn Error>> {
+    let frame = scene_data.get_frame();
+    let mut ren = Renderer2::new(scene_data);
+    if let Some(path) = &options.resume {
+        if let Err(err) = ren.resume(path) {
+            return Err(format!("Problem resuming from checkpoint {}: {}", path, err).into())
+        }
+        println!("Resumed from checkpoint {}", path);
+    }
+    if !options.workers.is_empty() {
+        // workers get scene with command line overrides applied
+        let mut source = SceneSource::new(options.scene_json.clone());
+        source.frame = frame;
+        // unreachable worker is skipped, rendering continues with the others
+        for address in options.workers.iter() {
+            match ren.add_worker(address, &source) {
+                Ok(nthreads) => println!("Connected to worker {} with {} threads", address, nthreads),
+                Err(err) => eprintln!("Problem connecting to worker {}: {}", address, err)
+            }
+        }
+    }
+    Ok(ren)
+}
 
-use minifb::{Window, WindowOptions, Key};
-use renderer::{Renderer, Renderer2};
-use json::parse_json_file;
-use scene::SceneData;
+fn report_lost_workers(ren: &Renderer2) {
+    if ren.lost_workers() > 0 {
+        eprintln!("Lost {} worker connections, their tiles were rendered again", ren.lost_workers());
+    }
+}
 
-fn run_in_console(scene_data: SceneData) {
+fn write_checkpoint(ren: &mut Renderer2) -> Result<(), Box<dyn Error>> {
+    match ren.checkpoint() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Problem writing checkpoint: {}", err).into())
+    }
+}
+
+fn save_output(ren: &Renderer2) -> Result<(), Box<dyn Error>> {
+    match ren.save() {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("Problem saving output image {}: {}", ren.scene_data().get_output_file(), err).into())
+    }
+}
+
+// Returns scene for next frame of animation
+fn run_in_console(scene_data: SceneData, options: &Options) -> Result<SceneData, Box<dyn Error>> {
     let prepare_time = Instant::now();
     //let mut ren = Renderer::new(scene_data);
-    let mut ren = Renderer2::new(scene_data);
+    let mut ren = create_renderer(scene_data, options)?;
     let prepare_time = Instant::now() - prepare_time;
     println!("Prepare time {}", prepare_time.as_millis());
     let start_time = Instant::now();
     loop {
         let is_finished = ren.render(Duration::from_millis(500));
+        write_checkpoint(&mut ren)?;
+        // progress line is overwritten in place
+        print!("\r{}  ", ren.progress());
+        let _r = io::stdout().flush();
         if is_finished { break; }
     }
+    println!();
+    report_lost_workers(&ren);
 
     let render_time = Instant::now() - start_time;
     println!("Rendering time {}", render_time.as_millis());
-    let _result = ren.save();
+    if let Some(reason) = ren.stop_reason() {
+        println!("Stopped by {:?} after {} spp", reason, ren.rendered_passes());
+    }
+    save_output(&ren)?;
+    Ok(ren.into_scene_data())
+}
+
+// Combines checkpoints of the same scene rendered with different seeds, sample counts or
+// crop windows and saves the result as output of the scene
+fn merge(scene_data: SceneData, checkpoints: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut ren = Renderer2::new(scene_data);
+    for path in checkpoints {
+        match ren.merge_checkpoint(path) {
+            Ok(header) => println!("Merged {} - {} samples", path, header.progress.samples),
+            Err(err) => return Err(format!("Problem merging checkpoint {}: {}", path, err).into())
+        }
+    }
+    save_output(&ren)?;
+    println!("Merged image saved to {}", ren.scene_data().get_output_file());
+    Ok(())
+}
+
+// Writes image of checkpoint without rendering, e.g. to look at interrupted render
+fn convert(scene_data: SceneData, checkpoint: &str) -> Result<(), Box<dyn Error>> {
+    let mut ren = Renderer2::new(scene_data);
+    if let Err(err) = ren.resume(checkpoint) {
+        return Err(format!("Problem reading checkpoint {}: {}", checkpoint, err).into())
+    }
+    save_output(&ren)?;
+    println!("{} - {}", ren.progress(), ren.scene_data().get_output_file());
+    Ok(())
 }
 
-fn run_in_window(scene_data: SceneData) {
+// Renders scene without writing output or checkpoints and reports throughput of each run
+fn bench(scene: &SceneArgs, runs: usize) -> Result<(), Box<dyn Error>> {
+    let scene_json = scene.load()?;
+    for run in 1..=runs {
+        let mut scene_data = parse_scene(&scene_json, &scene.file)?;
+        scene_data.set_checkpoint_file(None);
+        let start_time = Instant::now();
+        let mut ren = Renderer2::new(scene_data);
+        while !ren.render(Duration::from_millis(500)) {}
+        let progress = ren.progress();
+        let time = start_time.elapsed().as_secs_f64();
+        println!("Run {}: {:.3} s, {:.3} Msamples/s, {:.3} Mrays/s", run, time,
+            progress.samples as f64 / time / 1e6, progress.rays as f64 / time / 1e6);
+    }
+    Ok(())
+}
+
+// Summary of scene before long render, errors of scene file are reported all at once
+fn info(scene: &SceneArgs) -> Result<(), Box<dyn Error>> {
+    let scene_json = scene.load()?;
+    let mut scene_data = parse_scene(&scene_json, &scene.file)?;
     let (width, height) = scene_data.image_size();
-    let mut window = Window::new(
-        "Tracer - ESC to exit",
-        width,
-        height,
-        WindowOptions::default()
-    ).unwrap_or_else(|e| {
-        panic!("{}", e);
-    });
+    println!("Resolution {}x{}", width, height);
+    println!("Samples per pixel {}", scene_data.get_samples_per_pixel());
+    println!("Rendering {:?}", scene_data.rendering_algorithm);
+    println!("Threads {}", scene_data.get_nthreads());
+    println!("Output {}", scene_data.get_output_file());
+    println!("Scene hash {:016x}", scene_data.get_scene_hash());
+    print!("{}", SceneInfo::new(&mut scene_data));
+    Ok(())
+}
+
+const CONTROLS_HELP: &str = "Controls: left drag orbit, right drag pan, wheel dolly, WASD/QE fly, +/- field of view, \
+P print camera, J save camera, I toggle inspect (left click prints pixel), \
+1 beauty, 2 normals, 3 albedo, 4 depth, 5 sample heatmap, ESC exit";
+
+fn create_window(width: usize, height: usize) -> Result<Window, Box<dyn Error>> {
+    let mut window = match Window::new("Tracer - ESC to exit", width, height, WindowOptions::default()) {
+        Ok(window) => window,
+        Err(err) => return Err(format!("Problem creating window: {}", err).into())
+    };
+    window.limit_update_rate(Some(Duration::from_millis(16)));
+    println!("{}", CONTROLS_HELP);
+    Ok(window)
+}
+
+// Moves camera according to keyboard and mouse, returns true if camera was changed.
+// Mouse movement is relative to image width, flying speed to distance of look at point.
+// In inspect mode left button is used for picking pixels instead of orbiting.
+fn navigate(window: &Window, control: &mut CameraControl, last_mouse: &mut Option<(f32, f32)>, dt: f32, width: usize, inspect: bool) -> bool {
+    let before = *control;
+    let key = |k: Key| if window.is_key_down(k) { 1.0 } else { 0.0 };
+    let speed = control.distance().max(0.1) * dt;
+    let forward = key(Key::W) - key(Key::S);
+    let right = key(Key::D) - key(Key::A);
+    let up = key(Key::E) - key(Key::Q);
+    if forward != 0.0 || right != 0.0 || up != 0.0 {
+        control.fly(forward * speed, right * speed, up * speed);
+    }
+    let zoom = key(Key::Minus) + key(Key::NumPadMinus) - key(Key::Equal) - key(Key::NumPadPlus);
+    if zoom != 0.0 {
+        control.zoom(30.0 * zoom * dt);
+    }
+
+    let orbit = !inspect && window.get_mouse_down(MouseButton::Left);
+    let pan = window.get_mouse_down(MouseButton::Right) || window.get_mouse_down(MouseButton::Middle);
+    let position = window.get_mouse_pos(MouseMode::Discard);
+    if let (Some((x, y)), Some((last_x, last_y))) = (position, *last_mouse) {
+        let dx = (x - last_x) / width as f32;
+        let dy = (y - last_y) / width as f32;
+        if orbit {
+            control.orbit(-180.0 * dx, 180.0 * dy);
+        } else if pan {
+            control.pan(-dx, dy);
+        }
+    }
+    *last_mouse = if orbit || pan { position } else { None };
+    if let Some((_x, scroll)) = window.get_scroll_wheel() {
+        if scroll != 0.0 {
+            control.dolly(0.9f32.powf(scroll));
+        }
+    }
+    *control != before
+}
 
+// Number keys select view, returns true if view was changed
+fn select_view(window: &Window, view: &mut Option<DebugView>) -> bool {
+    let keys = [
+        (Key::Key1, None),
+        (Key::Key2, Some(DebugView::Normal)),
+        (Key::Key3, Some(DebugView::Albedo)),
+        (Key::Key4, Some(DebugView::Depth)),
+        (Key::Key5, Some(DebugView::SampleHeatmap))
+    ];
+    for (key, selected) in keys {
+        if window.is_key_pressed(key, KeyRepeat::No) && *view != selected {
+            *view = selected;
+            return true
+        }
+    }
+    false
+}
+
+// Primary hits for geometric views are read from AOVs as they accumulate. Without stored
+// AOVs they are traced once and reused until camera moves.
+fn preview_buffer(ren: &Renderer2, view: Option<DebugView>, hits: &mut Option<Vec<Option<PixelHit>>>) -> Vec<u32> {
+    match view {
+        None => ren.to_rgb_vector(),
+        Some(view) => {
+            if view.needs_hits() && (hits.is_none() || ren.pixel_buffer().has_aovs()) {
+                *hits = Some(pixel_hits(ren.scene_data(), ren.pixel_buffer()));
+            }
+            debug_view_rgb_vector(view, hits.as_deref().unwrap_or(&[]), ren.pixel_buffer())
+        }
+    }
+}
+
+// Camera is saved next to output image, e.g. image.png -> image_camera.json
+fn save_camera(control: &CameraControl, scene_data: &SceneData) {
+    let output = scene_data.get_output_file();
+    let path = Path::new(&output);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("output");
+    let camera_path = path.with_file_name(format!("{}_camera.json", stem));
+    match fs::write(&camera_path, format!("{{\n{}\n}}\n", control.to_json(scene_data))) {
+        Ok(()) => println!("Camera saved to {}", camera_path.display()),
+        Err(err) => eprintln!("Problem saving camera {}: {}", camera_path.display(), err)
+    }
+}
+
+// Returns scene for next frame of animation, None when window was closed. Moving camera
+// restarts rendering and keeps window open after rendering is finished.
+fn run_in_window(window: &mut Window, scene_data: SceneData, options: &Options) -> Result<Option<SceneData>, Box<dyn Error>> {
+    let (width, height) = scene_data.image_size();
     //let mut ren = Renderer::new(scene_data);
-    let mut ren = Renderer2::new(scene_data);
+    let mut ren = create_renderer(scene_data, options)?;
     let start_time = Instant::now();
+    let mut is_finished = false;
+    let mut control = CameraControl::from_scene(ren.scene_data());
+    let mut last_mouse = None;
+    let mut interactive = false;
+    let mut last_update = Instant::now();
+    let mut inspect = false;
+    let mut was_clicked = false;
+    let mut view = None;
+    let mut hits = None;
+    let mut title = String::new();
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        let is_finished = ren.render(Duration::from_millis(500));
-        let buffer = ren.to_rgb_vector();
+        let dt = last_update.elapsed().as_secs_f32().min(0.25);
+        last_update = Instant::now();
+        let moved = navigate(window, &mut control, &mut last_mouse, dt, width, inspect);
+        if moved {
+            interactive = true;
+            hits = None;
+            ren.update_scene(|scene_data| control.apply(scene_data));
+        }
+        let view_changed = select_view(window, &mut view);
+        if view_changed {
+            interactive = true;
+        }
+        if window.is_key_pressed(Key::I, KeyRepeat::No) {
+            inspect = !inspect;
+            interactive = true;
+            println!("Inspect mode {}", if inspect { "on" } else { "off" });
+        }
+        let clicked = inspect && window.get_mouse_down(MouseButton::Left);
+        if clicked && !was_clicked {
+            if let Some((x, y)) = window.get_mouse_pos(MouseMode::Discard) {
+                let (x, y) = ((x as usize).min(width - 1), (y as usize).min(height - 1));
+                println!("{}", inspect_pixel(ren.scene_data(), ren.pixel_buffer(), x, y));
+            }
+        }
+        was_clicked = clicked;
+        // finished image is displayed already, window only handles input
+        let displayed = is_finished && !moved;
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            println!("{}", control.to_json(ren.scene_data()));
+        }
+        if window.is_key_pressed(Key::J, KeyRepeat::No) {
+            save_camera(&control, ren.scene_data());
+        }
+        if !displayed {
+            is_finished = ren.render(Duration::from_millis(100));
+            write_checkpoint(&mut ren)?;
+            title = format!("Tracer - ESC to exit - {}", ren.progress());
+        } else if !view_changed {
+            window.update();
+            continue;
+        }
+        match view {
+            Some(view) => window.set_title(&format!("{} - {}", title, view.name())),
+            None => window.set_title(&title)
+        }
+        let buffer = preview_buffer(&ren, view, &mut hits);
         let _r = window.update_with_buffer(&buffer, width, height);
-        if is_finished { break; }
+        if is_finished && !interactive { break; }
     }
+    report_lost_workers(&ren);
     let render_time = Instant::now() - start_time;
     println!("Rendering time {}", render_time.as_millis());
-    let _result = ren.save();
+    save_output(&ren)?;
+    if !is_finished {
+        return Ok(None)
+    }
+    Ok(Some(ren.into_scene_data()))
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Missing scene description file");
-        return;
+// Unknown keys of scene file are printed as warnings, rendering continues without them
+fn parse_scene(scene_json: &str, file: &str) -> Result<SceneData, Box<dyn Error>> {
+    match parse_json_str_with_warnings(scene_json) {
+        Ok((scene_data, warnings)) => {
+            for warning in warnings.iter() {
+                eprintln!("Warning: {}", warning);
+            }
+            Ok(scene_data)
+        },
+        Err(err) => Err(format!("Problem parsing input file {}:\n{}", file, err).into())
     }
+}
 
-    let scene_data = match parse_json_file(&args[1]) {
-        Err(err) => {
-            eprintln!("Problem parsing input file {}: {}", &args[1], err);
-            return;
-        },
-        Ok(scene) => scene
+fn load_scene(scene: &SceneArgs) -> Result<SceneData, Box<dyn Error>> {
+    parse_scene(&scene.load()?, &scene.file)
+}
+
+fn render(scene: &SceneArgs, console: bool, resume: Option<String>, workers: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let scene_json = scene.load()?;
+    let mut scene_data = parse_scene(&scene_json, &scene.file)?;
+    if resume.is_some() && scene_data.get_frames().is_some() {
+        return Err("Animation can't be resumed from single checkpoint".into())
+    }
+    // resumed render keeps writing checkpoints to the same file unless scene sets other one
+    if let Some(path) = &resume {
+        if scene_data.get_checkpoint_file().is_none() {
+            scene_data.set_checkpoint_file(Some(path.clone()));
+        }
+    }
+
+    let options = Options { scene_json, resume, workers };
+    let (width, height) = scene_data.image_size();
+    let mut window = if console { None } else { Some(create_window(width, height)?) };
+    // frames of animation reuse the same scene, only camera and time change between them
+    let frames: Vec<Option<usize>> = match scene_data.get_frames() {
+        Some((first, last)) => (first..=last).map(Some).collect(),
+        None => vec![None]
     };
+    for frame in frames {
+        if let Some(frame) = frame {
+            scene_data.set_frame(frame);
+            println!("Frame {} - {}", frame, scene_data.get_output_file());
+        }
+        scene_data = match window.as_mut() {
+            Some(window) => match run_in_window(window, scene_data, &options)? {
+                Some(scene_data) => scene_data,
+                None => break
+            },
+            None => run_in_console(scene_data, &options)?
+        };
+    }
+    Ok(())
+}
 
-    let mut console = false;
-    for a in args {
-        if a == "--console" {
-            console = true;
+fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Render { scene, console, resume, workers } => render(&scene, console, resume, workers),
+        Command::Info(scene) => info(&scene),
+        Command::Validate(scene) => {
+            load_scene(&scene)?;
+            println!("{} is valid", scene.file);
+            Ok(())
+        },
+        Command::Convert { scene, checkpoint } => convert(load_scene(&scene)?, &checkpoint),
+        Command::Bench { scene, runs } => bench(&scene, runs),
+        Command::Merge { scene, checkpoints } => merge(load_scene(&scene)?, &checkpoints),
+        Command::Worker { port, listen } => {
+            println!("Worker listening on {} port {}", listen, port);
+            match run_worker(&listen, port) {
+                Ok(()) => Ok(()),
+                Err(err) => Err(format!("Problem running worker: {}", err).into())
+            }
+        },
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
         }
     }
+}
 
-    if console {
-        run_in_console(scene_data);
-    } else {
-        run_in_window(scene_data);
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(2)
+        }
+    };
+    match run(command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
     }
-    
 }
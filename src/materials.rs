@@ -3,6 +3,7 @@ use crate::onb::ONB;
 use crate::pixel_buffer::Color;
 use crate::vec::f32x3;
 use crate::scene::{BSDFInterface, BSDFEvalSample, BSDFSample};
+use crate::sampler::Sampler;
 use std::f32;
 
 pub struct MatteMaterial {
@@ -16,15 +17,18 @@ impl MatteMaterial {
 }
 
 impl BSDFInterface for MatteMaterial {
-    fn eval(&self, wo: f32x3, normal: f32x3, wi: f32x3) -> Option<BSDFEvalSample> {
+    fn albedo(&self) -> Color {
+        self.reflectance
+    }
+
+    fn eval(&self, _wo: f32x3, normal: f32x3, wi: f32x3) -> Option<BSDFEvalSample> {
         let color = self.reflectance * f32::consts::FRAC_1_PI;
         let pdfw = normal.dot(wi).abs() * f32::consts::FRAC_1_PI;
         Some(BSDFEvalSample{color, pdfw})
     }
 
-    fn sample(&self, wo: f32x3, normal: f32x3, rng: &mut crate::pcg::PCGRng) -> Option<crate::scene::BSDFSample> {
-        let u1 = rng.rnd_f32();
-        let u2 = rng.rnd_f32();
+    fn sample(&self, _wo: f32x3, normal: f32x3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let (u1, u2) = sampler.get_2d();
         let term1 = 2.0 * f32::consts::PI * u1;
         let term2 = (1.0 - u2).sqrt();
         let x = term1.cos() * term2;
@@ -53,15 +57,18 @@ impl MatteEmissiveMaterial {
 }
 
 impl BSDFInterface for MatteEmissiveMaterial {
-    fn eval(&self, wo: f32x3, normal: f32x3, wi: f32x3) -> Option<BSDFEvalSample> {
+    fn albedo(&self) -> Color {
+        self.reflectance
+    }
+
+    fn eval(&self, _wo: f32x3, normal: f32x3, wi: f32x3) -> Option<BSDFEvalSample> {
         let color = self.reflectance * f32::consts::FRAC_1_PI;
         let pdfw = normal.dot(wi).abs() * f32::consts::FRAC_1_PI;
         Some(BSDFEvalSample{color, pdfw})
     }
 
-    fn sample(&self, wo: f32x3, normal: f32x3, rng: &mut crate::pcg::PCGRng) -> Option<crate::scene::BSDFSample> {
-        let u1 = rng.rnd_f32();
-        let u2 = rng.rnd_f32();
+    fn sample(&self, _wo: f32x3, normal: f32x3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let (u1, u2) = sampler.get_2d();
         let term1 = 2.0 * f32::consts::PI * u1;
         let term2 = (1.0 - u2).sqrt();
         let x = term1.cos() * term2;
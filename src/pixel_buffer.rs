@@ -1,7 +1,17 @@
+use crate::aov::{AovPixel, AovSample, AovType, aov_channels};
+use crate::checkpoint::{read_color, read_f32, read_u32, read_u64, write_color, write_f32, write_u32, write_u64};
+use crate::denoise::{DenoiseFeatures, denoise};
+use crate::exr_output::{DataWindow, ExrAttribute, ExrChannel, ExrPrecision, write_exr_window};
+use crate::filter::{Filter, MAX_FILTER_RADIUS};
+use crate::postprocess::PostProcess;
+use crate::tonemap::ToneMapping;
 use crate::traits::{Zero, One};
+use crate::vec::f32x3;
+use std::io::{self, Read, Write};
 use std::ops::{Add, AddAssign, Div, Mul};
 use std::path::Path;
 use std::error::Error;
+use std::mem;
 
 extern crate image;
 
@@ -13,12 +23,11 @@ pub struct Color {
 }
 
 impl Color {
-    fn to_rgb8(self) -> [u8; 3] {
-        [
-            (self.red * 256.0) as u8,
-            (self.green * 256.0) as u8,
-            (self.blue * 256.0) as u8,
-        ]
+    // Display values in [0, 1] are clamped and rounded to nearest 8-bit value,
+    // dither is offset in range [-0.5, 0.5) added before rounding
+    fn to_rgb8(self, dither: f32) -> [u8; 3] {
+        let quantize = |v: f32| (v.clamp(0.0, 1.0) * 255.0 + 0.5 + dither).clamp(0.0, 255.0) as u8;
+        [quantize(self.red), quantize(self.green), quantize(self.blue)]
     }
 
     pub fn luminance(self) -> f32 {
@@ -101,24 +110,56 @@ impl AddAssign for Color {
     }
 }
 
+// Weight sum below this fraction of sample count is not reliable, e.g. with negative lobes
+// of Mitchell or Lanczos filter it can be close to zero or negative at low spp
+const MIN_RELATIVE_WEIGHT: f32 = 1e-3;
+
+// Filtered sums of samples together with their unweighted sums, which are used when
+// filter weights cancel out
 #[derive(Debug, Clone, Copy)]
 pub struct PixelData {
     pub color: Color,
+    pub alpha: f32,
     pub weight: f32,
+    pub unweighted_color: Color,
+    pub unweighted_alpha: f32,
+    pub count: f32
 }
 
 impl PixelData {
-    fn get_color(&self) -> Color {
-        if self.weight > 0.0 {
+    // Sample that contributes to pixel with filter weight
+    pub fn new(color: Color, alpha: f32, weight: f32) -> PixelData {
+        PixelData { color: color * weight, alpha: alpha * weight, weight, unweighted_color: color, unweighted_alpha: alpha, count: 1.0 }
+    }
+
+    pub fn has_reliable_weight(&self) -> bool {
+        self.weight > MIN_RELATIVE_WEIGHT * self.count
+    }
+
+    pub fn get_color(&self) -> Color {
+        if self.has_reliable_weight() {
             return self.color * (1.0 / self.weight);
         }
+        if self.count > 0.0 {
+            return self.unweighted_color * (1.0 / self.count);
+        }
         Color::zero()
     }
+
+    pub fn get_alpha(&self) -> f32 {
+        if self.has_reliable_weight() {
+            return self.alpha / self.weight;
+        }
+        if self.count > 0.0 {
+            return self.unweighted_alpha / self.count;
+        }
+        0.0
+    }
 }
 
 impl Zero for PixelData {
     fn zero() -> Self {
-        PixelData { color: Color::zero(), weight: 0.0 }
+        PixelData { color: Color::zero(), alpha: 0.0, weight: 0.0, unweighted_color: Color::zero(), unweighted_alpha: 0.0, count: 0.0 }
     }
 }
 
@@ -127,30 +168,101 @@ impl AddAssign for PixelData {
     fn add_assign(&mut self, rhs: Self) {
         *self = Self {
             color: self.color + rhs.color,
+            alpha: self.alpha + rhs.alpha,
             weight: self.weight + rhs.weight,
+            unweighted_color: self.unweighted_color + rhs.unweighted_color,
+            unweighted_alpha: self.unweighted_alpha + rhs.unweighted_alpha,
+            count: self.count + rhs.count
         }
     }
 }
 
-pub enum TMOType {
-    Linear,
-    Gamma,
-    Reinhard,
+// Running mean and variance of sample luminance in a pixel (Welford's algorithm)
+#[derive(Debug, Clone, Copy)]
+pub struct PixelStats {
+    pub count: u32,
+    pub mean: f32,
+    pub m2: f32
 }
 
-fn tone_map(tmo_type: &TMOType, color: Color) -> Color {
-    fn apply_gamma_correction(col: Color, gamma: f32) -> Color {
-        Color { red: col.red.powf(gamma), green: col.green.powf(gamma), blue: col.blue.powf(gamma) }
+impl PixelStats {
+    pub fn add(&mut self, value: f32) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (value - self.mean);
     }
 
-    let gamma: f32 = 1.0 / 2.2;
+    // Statistics of union of both sample sets (Chan et al. parallel algorithm)
+    pub fn merge(&mut self, other: &PixelStats) {
+        if other.count == 0 {
+            return
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        let (na, nb) = (self.count as f32, other.count as f32);
+        self.mean += delta * nb / count as f32;
+        self.m2 += other.m2 + delta * delta * na * nb / count as f32;
+        self.count = count;
+    }
+
+    pub fn variance(&self) -> f32 {
+        if self.count < 2 {
+            return 0.0
+        }
+        self.m2 / (self.count - 1) as f32
+    }
 
-    match tmo_type {
-        TMOType::Linear => color,
-        TMOType::Gamma => apply_gamma_correction(color, gamma),
-        TMOType::Reinhard => {
-            let col = color / (Color::one() + color);
-            apply_gamma_correction(col, gamma)
+    // Variance of pixel estimate, i.e. of mean of samples
+    pub fn estimate_variance(&self) -> f32 {
+        self.variance() / self.count.max(1) as f32
+    }
+
+    // Standard error of pixel estimate relative to its value
+    pub fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY
+        }
+        self.estimate_variance().sqrt() / self.mean.max(0.01)
+    }
+}
+
+impl Zero for PixelStats {
+    fn zero() -> Self {
+        PixelStats { count: 0, mean: 0.0, m2: 0.0 }
+    }
+}
+
+// Calls add for all pixels whose centers are inside of filter radius around image position
+// (fx, fy) with filter weight. Pixel (x, y) has center at (x + 0.5, y + 0.5).
+fn splat<F: FnMut(usize, usize, f32)>(fx: f32, fy: f32, width: usize, height: usize, filter: &Filter, mut add: F) {
+    let radius = filter.radius();
+    let x0 = (fx - 0.5 - radius).ceil().max(0.0) as usize;
+    let x1 = ((fx - 0.5 + radius).floor() as i64).min(width as i64 - 1);
+    let y0 = (fy - 0.5 - radius).ceil().max(0.0) as usize;
+    let y1 = ((fy - 0.5 + radius).floor() as i64).min(height as i64 - 1);
+    if x1 < x0 as i64 || y1 < y0 as i64 {
+        return;
+    }
+    let (x1, y1) = (x1 as usize, y1 as usize);
+
+    // filter is separable, so weights are computed once per row and column
+    const MAX_TAPS: usize = 2 * MAX_FILTER_RADIUS as usize + 2;
+    let mut wx = [0.0f32; MAX_TAPS];
+    let mut wy = [0.0f32; MAX_TAPS];
+    for x in x0..=x1 {
+        wx[x - x0] = filter.eval_1d(x as f32 + 0.5 - fx);
+    }
+    for y in y0..=y1 {
+        wy[y - y0] = filter.eval_1d(y as f32 + 0.5 - fy);
+    }
+
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let weight = wx[x - x0] * wy[y - y0];
+            if weight != 0.0 {
+                add(x, y, weight);
+            }
         }
     }
 }
@@ -158,48 +270,378 @@ fn tone_map(tmo_type: &TMOType, color: Color) -> Color {
 pub struct PixelBuffer {
     width: usize,
     height: usize,
-    pixels: Vec<PixelData>
+    pixels: Vec<PixelData>,
+    stats: Vec<PixelStats>,
+    aov_types: Vec<AovType>,
+    aov_pixels: Vec<AovPixel>,
+    lpe_names: Vec<String>,
+    lpe_colors: Vec<Color>,
+    unweighted_lpe_colors: Vec<Color>,
+    denoise: bool,
+    // denoised colors shown in preview, updated only after spp passes
+    denoised: Option<Vec<Color>>,
+    post_process: PostProcess,
+    data_window: Option<DataWindow>
 }
 
 impl PixelBuffer {
     pub fn new(width: usize, height: usize) -> PixelBuffer {
-        PixelBuffer { width, height, pixels: vec![PixelData::zero(); width * height] }
+        PixelBuffer {
+            width,
+            height,
+            pixels: vec![PixelData::zero(); width * height],
+            stats: vec![PixelStats::zero(); width * height],
+            aov_types: Vec::new(),
+            aov_pixels: Vec::new(),
+            lpe_names: Vec::new(),
+            lpe_colors: Vec::new(),
+            unweighted_lpe_colors: Vec::new(),
+            denoise: false,
+            denoised: None,
+            post_process: PostProcess::default(),
+            data_window: None
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    // Bytes of pixel storage allocated for image, used to report memory before rendering
+    pub fn estimate_memory(width: usize, height: usize, aov_pixels: bool, lpes: usize) -> usize {
+        let mut pixel_size = mem::size_of::<PixelData>() + mem::size_of::<PixelStats>() + 2 * lpes * mem::size_of::<Color>();
+        if aov_pixels {
+            pixel_size += mem::size_of::<AovPixel>();
+        }
+        width * height * pixel_size
+    }
+
+    pub fn set_post_process(&mut self, post_process: PostProcess) {
+        self.post_process = post_process
+    }
+
+    // Denoiser needs albedo, normal and depth, so AOV storage is allocated even if AOVs
+    // are not written.
+    pub fn set_denoise(&mut self, denoise: bool) {
+        self.denoise = denoise;
+        if denoise && self.aov_pixels.is_empty() {
+            self.aov_pixels = vec![AovPixel::zero(); self.width * self.height];
+        }
+    }
+
+    // Light path expression buffers, colors of all buffers are stored together for each pixel
+    pub fn set_lpes(&mut self, names: Vec<String>) {
+        self.lpe_colors = vec![Color::zero(); names.len() * self.width * self.height];
+        self.unweighted_lpe_colors = self.lpe_colors.clone();
+        self.lpe_names = names;
+    }
+
+    pub fn lpe_count(&self) -> usize {
+        self.lpe_names.len()
+    }
+
+    // Normalized the same way as beauty in PixelData::get_color, so buffers keep summing to it
+    pub fn get_lpe_color(&self, x: usize, y: usize, lpe: usize) -> Color {
+        let pdata = &self.pixels[y * self.width + x];
+        let index = (y * self.width + x) * self.lpe_names.len() + lpe;
+        if pdata.has_reliable_weight() {
+            return self.lpe_colors[index] * (1.0 / pdata.weight);
+        }
+        if pdata.count > 0.0 {
+            return self.unweighted_lpe_colors[index] * (1.0 / pdata.count);
+        }
+        Color::zero()
+    }
+
+    // Storage for geometric AOVs is allocated only when some of them is requested
+    pub fn set_aovs(&mut self, aov_types: &[AovType]) {
+        self.aov_types = aov_types.to_vec();
+        self.aov_pixels.clear();
+        if self.denoise || aov_types.iter().any(|aov| aov.is_geometric()) {
+            self.aov_pixels = vec![AovPixel::zero(); self.width * self.height];
+        }
+    }
+
+    pub fn aov_types(&self) -> &[AovType] {
+        &self.aov_types
+    }
+
+    // AOVs are not filtered, sample is added only to the pixel that contains it
+    pub fn add_aov(&mut self, fx: f32, fy: f32, sample: &AovSample) {
+        if self.aov_pixels.is_empty() {
+            return;
+        }
+        let px = (fx as usize).min(self.width - 1);
+        let py = (fy as usize).min(self.height - 1);
+        self.aov_pixels[py * self.width + px].add(sample);
+    }
+
+    pub fn has_aovs(&self) -> bool {
+        !self.aov_pixels.is_empty()
+    }
+
+    // None when AOVs are not stored or pixel has no samples yet
+    pub fn stored_aov(&self, x: usize, y: usize) -> Option<AovSample> {
+        match self.aov_pixels.get(y * self.width + x) {
+            Some(pixel) if !pixel.is_empty() => Some(pixel.get()),
+            _ => None
+        }
+    }
+
+    pub fn get_aov(&self, x: usize, y: usize) -> AovSample {
+        match self.aov_pixels.get(y * self.width + x) {
+            Some(pixel) => pixel.get(),
+            None => AovSample::zero()
+        }
     }
 
     pub fn add_pixel(&mut self, x: usize, y: usize, pixel: &PixelData) {
         self.pixels[y * self.width + x] += *pixel;
     }
 
-    fn save_as_rgb8<P: AsRef<Path>>(&self, path: P, tmo_type: &TMOType) -> Result<(), Box<dyn Error>> {
+    // Sample at continuous image position (fx, fy) is splatted to all pixels whose centers
+    // are inside of filter radius. Pixel (x, y) has center at (x + 0.5, y + 0.5).
+    // Variance statistics are gathered only in the pixel that contains the sample.
+    // Alpha is coverage of the sample, 1 when primary ray hit geometry.
+    pub fn add_sample(&mut self, fx: f32, fy: f32, color: Color, alpha: f32, filter: &Filter) {
+        let px = (fx as usize).min(self.width - 1);
+        let py = (fy as usize).min(self.height - 1);
+        self.stats[py * self.width + px].add(color.luminance());
+
+        if filter.is_single_pixel() {
+            self.add_pixel(px, py, &PixelData::new(color, alpha, 1.0));
+            return;
+        }
 
-        let output: Vec<u8> = self.pixels.iter().flat_map(|pdata: &PixelData| {
-            tone_map(tmo_type, pdata.get_color()).to_rgb8()
-        }).collect();
+        let (width, pixels) = (self.width, &mut self.pixels);
+        splat(fx, fy, self.width, self.height, filter, |x, y, weight| {
+            pixels[y * width + x] += PixelData::new(color, alpha, weight);
+        });
+    }
 
-        let result = image::save_buffer(path,
-            &output[0..output.len()],
-            self.width as u32,
-            self.height as u32,
-            image::ColorType::Rgb8);
+    // LPE colors of sample are splatted with the same weights as beauty, so buffers sum to it
+    pub fn add_lpe_sample(&mut self, fx: f32, fy: f32, colors: &[Color], filter: &Filter) {
+        let n = self.lpe_names.len();
+        if n == 0 {
+            return;
+        }
+        let width = self.width;
+        let (lpe_colors, unweighted_lpe_colors) = (&mut self.lpe_colors, &mut self.unweighted_lpe_colors);
+        let mut add = |x: usize, y: usize, weight: f32| {
+            let offset = (y * width + x) * n;
+            for (acum, color) in lpe_colors[offset..offset + n].iter_mut().zip(colors) {
+                *acum += *color * weight;
+            }
+            for (acum, color) in unweighted_lpe_colors[offset..offset + n].iter_mut().zip(colors) {
+                *acum += *color;
+            }
+        };
+        if filter.is_single_pixel() {
+            add((fx as usize).min(self.width - 1), (fy as usize).min(self.height - 1), 1.0);
+            return;
+        }
+        splat(fx, fy, self.width, self.height, filter, add);
+    }
 
-        match result {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err.into())
+    pub fn get_pixel(&self, x: usize, y: usize) -> PixelData {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn get_stats(&self, x: usize, y: usize) -> PixelStats {
+        self.stats[y * self.width + x]
+    }
+
+    // Largest relative error of pixels inside of region [x0, x1) x [y0, y1)
+    pub fn max_relative_error(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> f32 {
+        let mut max_error: f32 = 0.0;
+        for y in y0..y1 {
+            for x in x0..x1 {
+                max_error = max_error.max(self.stats[y * self.width + x].relative_error());
+            }
         }
+        max_error
     }
 
-    fn save_as_exr<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+    // Estimate of image noise, pixels with less than two samples count as fully noisy
+    pub fn mean_relative_error(&self) -> f32 {
+        self.mean_relative_error_in(0, 0, self.width, self.height)
+    }
 
-        let output: Vec<u8> = self.pixels.iter().flat_map(|pdata: &PixelData| {
-            let c = pdata.get_color();
-            [c.red.to_le_bytes(), c.green.to_le_bytes(), c.blue.to_le_bytes()].concat()
-        }).collect();
+    // Estimate of noise inside of region [x0, x1) x [y0, y1)
+    pub fn mean_relative_error_in(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> f32 {
+        let mut sum = 0.0;
+        for y in y0..y1 {
+            for x in x0..x1 {
+                sum += self.stats[y * self.width + x].relative_error().min(1.0);
+            }
+        }
+        sum / ((x1 - x0) * (y1 - y0)).max(1) as f32
+    }
+
+    // Adds accumulation of other render of the same image, e.g. with other seed or region.
+    // Colors are weighted sums, so sums of both buffers give correctly weighted average.
+    pub fn merge(&mut self, other: &PixelBuffer) -> Result<(), Box<dyn Error>> {
+        if other.size() != self.size() {
+            return Err(format!("Resolution {}x{} of merged pixels differs from {}x{}.",
+                other.width, other.height, self.width, self.height).into())
+        }
+        if other.aov_pixels.len() != self.aov_pixels.len() || other.lpe_names != self.lpe_names {
+            return Err("AOVs or light path expressions of merged pixels differ.".into())
+        }
+        for (pdata, other) in self.pixels.iter_mut().zip(other.pixels.iter()) {
+            *pdata += *other;
+        }
+        for (stats, other) in self.stats.iter_mut().zip(other.stats.iter()) {
+            stats.merge(other);
+        }
+        for (pixel, other) in self.aov_pixels.iter_mut().zip(other.aov_pixels.iter()) {
+            pixel.merge(other);
+        }
+        for (color, other) in self.lpe_colors.iter_mut().zip(other.lpe_colors.iter()) {
+            *color += *other;
+        }
+        for (color, other) in self.unweighted_lpe_colors.iter_mut().zip(other.unweighted_lpe_colors.iter()) {
+            *color += *other;
+        }
+        Ok(())
+    }
+
+    // Copy of region [x0, x1) x [y0, y1). Cropped copy has size of region and remembers its
+    // position in full image, otherwise size stays the same and pixels outside are cleared.
+    pub fn region(&self, x0: usize, y0: usize, x1: usize, y1: usize, cropped: bool) -> PixelBuffer {
+        let (width, height) = if cropped { (x1 - x0, y1 - y0) } else { (self.width, self.height) };
+        let (offset_x, offset_y) = if cropped { (x0, y0) } else { (0, 0) };
+        let mut buffer = PixelBuffer::new(width, height);
+        buffer.set_denoise(self.denoise);
+        buffer.set_aovs(&self.aov_types);
+        buffer.set_lpes(self.lpe_names.clone());
+        buffer.post_process = self.post_process;
+        if cropped {
+            buffer.data_window = Some(DataWindow { x: x0, y: y0, full_width: self.width, full_height: self.height });
+        }
+        let n = self.lpe_names.len();
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let src = y * self.width + x;
+                let dst = (y - offset_y) * width + x - offset_x;
+                buffer.pixels[dst] = self.pixels[src];
+                buffer.stats[dst] = self.stats[src];
+                if !self.aov_pixels.is_empty() {
+                    buffer.aov_pixels[dst] = self.aov_pixels[src];
+                }
+                buffer.lpe_colors[dst * n..(dst + 1) * n].copy_from_slice(&self.lpe_colors[src * n..(src + 1) * n]);
+                buffer.unweighted_lpe_colors[dst * n..(dst + 1) * n].copy_from_slice(&self.unweighted_lpe_colors[src * n..(src + 1) * n]);
+            }
+        }
+        buffer
+    }
+
+    // Raw accumulated values of all buffers, used for checkpoints
+    pub fn write_raw(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_u32(w, self.width as u32)?;
+        write_u32(w, self.height as u32)?;
+        write_u64(w, self.aov_pixels.len() as u64)?;
+        write_u64(w, self.lpe_names.len() as u64)?;
+        for (pdata, stats) in self.pixels.iter().zip(self.stats.iter()) {
+            write_color(w, &pdata.color)?;
+            write_f32(w, pdata.alpha)?;
+            write_f32(w, pdata.weight)?;
+            write_color(w, &pdata.unweighted_color)?;
+            write_f32(w, pdata.unweighted_alpha)?;
+            write_f32(w, pdata.count)?;
+            write_u32(w, stats.count)?;
+            write_f32(w, stats.mean)?;
+            write_f32(w, stats.m2)?;
+        }
+        for pixel in self.aov_pixels.iter() {
+            pixel.write_raw(w)?;
+        }
+        for color in self.lpe_colors.iter().chain(self.unweighted_lpe_colors.iter()) {
+            write_color(w, color)?;
+        }
+        Ok(())
+    }
+
+    // Layout of buffers (resolution, AOVs, LPEs) must be the same as in written buffer
+    pub fn read_raw(&mut self, r: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let width = read_u32(r)? as usize;
+        let height = read_u32(r)? as usize;
+        if width != self.width || height != self.height {
+            return Err(format!("Resolution {}x{} of stored pixels differs from {}x{}.",
+                width, height, self.width, self.height).into())
+        }
+        let n_aov_pixels = read_u64(r)? as usize;
+        let n_lpes = read_u64(r)? as usize;
+        if n_aov_pixels != self.aov_pixels.len() || n_lpes != self.lpe_names.len() {
+            return Err("AOVs or light path expressions of stored pixels differ.".into())
+        }
+        for (pdata, stats) in self.pixels.iter_mut().zip(self.stats.iter_mut()) {
+            *pdata = PixelData {
+                color: read_color(r)?, alpha: read_f32(r)?, weight: read_f32(r)?,
+                unweighted_color: read_color(r)?, unweighted_alpha: read_f32(r)?, count: read_f32(r)?
+            };
+            *stats = PixelStats { count: read_u32(r)?, mean: read_f32(r)?, m2: read_f32(r)? };
+        }
+        for pixel in self.aov_pixels.iter_mut() {
+            *pixel = AovPixel::read_raw(r)?;
+        }
+        for color in self.lpe_colors.iter_mut().chain(self.unweighted_lpe_colors.iter_mut()) {
+            *color = read_color(r)?;
+        }
+        Ok(())
+    }
+
+    // Final colors of pixels, denoised if denoising is enabled
+    pub fn colors(&self) -> Vec<Color> {
+        let colors: Vec<Color> = self.pixels.iter().map(|pdata| pdata.get_color()).collect();
+        if !self.denoise {
+            return colors;
+        }
+        let aovs: Vec<AovSample> = self.aov_pixels.iter().map(|pixel| pixel.get()).collect();
+        let albedo: Vec<Color> = aovs.iter().map(|aov| aov.albedo).collect();
+        let normal: Vec<f32x3> = aovs.iter().map(|aov| aov.normal).collect();
+        let depth: Vec<f32> = aovs.iter().map(|aov| aov.depth).collect();
+        let variance: Vec<f32> = self.stats.iter().map(|st| st.estimate_variance()).collect();
+        let features = DenoiseFeatures { albedo: &albedo, normal: &normal, depth: &depth, variance: &variance };
+        denoise(self.width, self.height, &colors, &features)
+    }
+
+    pub fn alphas(&self) -> Vec<f32> {
+        self.pixels.iter().map(|pdata| pdata.get_alpha()).collect()
+    }
+
+    // Denoising takes much longer than preview refresh, so preview shows result of the last
+    // update. Without update it shows colors that are not denoised.
+    pub fn update_denoised(&mut self) {
+        if self.denoise {
+            self.denoised = Some(self.colors());
+        }
+    }
+
+    // Post processed and tone mapped 8-bit values, EXR output keeps radiance without post effects
+    pub fn display_rgb8(&self, tone_mapping: &ToneMapping) -> Vec<[u8; 3]> {
+        self.rgb8_of(self.colors(), tone_mapping)
+    }
+
+    fn rgb8_of(&self, mut colors: Vec<Color>, tone_mapping: &ToneMapping) -> Vec<[u8; 3]> {
+        if self.post_process.is_enabled() {
+            self.post_process.apply(self.width, self.height, &mut colors);
+        }
+        colors.iter().enumerate().map(|(index, color)| {
+            let dither = self.post_process.dither_offset(index % self.width, index / self.width);
+            tone_mapping.apply(*color).to_rgb8(dither)
+        }).collect()
+    }
+
+    fn save_as_rgb8<P: AsRef<Path>>(&self, path: P, tone_mapping: &ToneMapping) -> Result<(), Box<dyn Error>> {
+
+        let output: Vec<u8> = self.display_rgb8(tone_mapping).into_iter().flatten().collect();
 
         let result = image::save_buffer(path,
             &output[0..output.len()],
             self.width as u32,
             self.height as u32,
-            image::ColorType::Rgb32F);
+            image::ColorType::Rgb8);
 
         match result {
             Ok(_) => Ok(()),
@@ -207,20 +649,72 @@ impl PixelBuffer {
         }
     }
 
-    pub fn save<P: AsRef<Path>>(&self, path: P, tmo_type: &TMOType) -> Result<(), Box<dyn Error>> {
+    // Each AOV and LPE is separate layer, luminance variance of pixel estimate and number
+    // of samples are taken from pixel statistics.
+    pub fn aov_exr_channels(&self) -> Vec<ExrChannel> {
+        let mut channels = Vec::new();
+        for (index, name) in self.lpe_names.iter().enumerate() {
+            let colors: Vec<Color> = (0..self.width * self.height)
+                .map(|i| self.get_lpe_color(i % self.width, i / self.width, index)).collect();
+            channels.push(ExrChannel::float(&format!("{}.R", name), colors.iter().map(|c| c.red).collect()));
+            channels.push(ExrChannel::float(&format!("{}.G", name), colors.iter().map(|c| c.green).collect()));
+            channels.push(ExrChannel::float(&format!("{}.B", name), colors.iter().map(|c| c.blue).collect()));
+        }
+        for aov_type in self.aov_types.iter() {
+            match aov_type {
+                AovType::Variance => channels.push(
+                    ExrChannel::float("variance.Y", self.stats.iter().map(|st| st.estimate_variance()).collect())),
+                AovType::SampleCount => channels.push(
+                    ExrChannel::uint("samples.count", self.stats.iter().map(|st| st.count).collect())),
+                _ => channels.extend(aov_channels(*aov_type, &self.aov_pixels))
+            }
+        }
+        channels
+    }
+
+    // Beauty with alpha in main layer followed by AOV layers
+    pub fn exr_channels(&self) -> Vec<ExrChannel> {
+        let colors = self.colors();
+        let mut channels = vec![
+            ExrChannel::float("R", colors.iter().map(|c| c.red).collect()),
+            ExrChannel::float("G", colors.iter().map(|c| c.green).collect()),
+            ExrChannel::float("B", colors.iter().map(|c| c.blue).collect()),
+            ExrChannel::float("A", self.pixels.iter().map(|pdata| pdata.get_alpha()).collect())
+        ];
+        channels.extend(self.aov_exr_channels());
+        channels
+    }
+
+    pub fn save_as_exr<P: AsRef<Path>>(&self, path: P, precision: ExrPrecision,
+                                       attributes: &[(String, ExrAttribute)]) -> Result<(), Box<dyn Error>> {
+        write_exr_window(path, self.width, self.height, self.data_window, self.exr_channels(), precision, attributes)
+    }
+
+    // AOVs without beauty, used when main image is not EXR
+    pub fn save_aovs<P: AsRef<Path>>(&self, path: P, precision: ExrPrecision,
+                                     attributes: &[(String, ExrAttribute)]) -> Result<(), Box<dyn Error>> {
+        write_exr_window(path, self.width, self.height, self.data_window, self.aov_exr_channels(), precision, attributes)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P, tone_mapping: &ToneMapping) -> Result<(), Box<dyn Error>> {
         let ext = Path::new(path.as_ref()).extension();
         match ext {
             None => Err("There is no filename.".into()),
             Some(os_str) => match os_str.to_str() {
-                Some("exr") => self.save_as_exr(path),
-                _ => self.save_as_rgb8(path, tmo_type)
+                Some("exr") => self.save_as_exr(path, ExrPrecision::Float, &[]),
+                _ => self.save_as_rgb8(path, tone_mapping)
             }
         }
     }
 
-    pub fn to_rgb_vector(&self, tmo_type: &TMOType) -> Vec<u32> {
-        let output = self.pixels.iter().map(|pdata: &PixelData| {
-            let v = tone_map(tmo_type, pdata.get_color()).to_rgb8();
+    // Pixels of preview window
+    #[allow(clippy::needless_return)]
+    pub fn to_rgb_vector(&self, tone_mapping: &ToneMapping) -> Vec<u32> {
+        let colors = match &self.denoised {
+            Some(denoised) => denoised.clone(),
+            None => self.pixels.iter().map(|pdata| pdata.get_color()).collect()
+        };
+        let output = self.rgb8_of(colors, tone_mapping).iter().map(|v| {
             return ((v[0] as u32) << 16) | ((v[1] as u32) << 8) | v[2] as u32;
         }).collect();
         output
@@ -232,6 +726,9 @@ impl PixelBuffer {
 mod tests {
 
     use super::*;
+    use crate::exr_output::ChannelData;
+    use crate::filter::FilterType;
+    use crate::tonemap::TMOType;
     use std::mem;
 
     fn fill_rect(buf: &mut PixelBuffer, pdata: &PixelData, x1: usize, x2: usize, y1: usize, y2: usize) {
@@ -243,18 +740,94 @@ mod tests {
     }
 
     # [test]
+    #[allow(clippy::print_with_newline)]
     fn fill_image() {
         print!("Pixel data size {}\n", mem::size_of::<PixelData>());
 
-        let red = PixelData{color: Color { red: 1.0, green: 0.0, blue: 0.0 }, weight: 1.0};
-        let green = PixelData{color: Color { red: 0.0, green: 1.0, blue: 0.0 }, weight: 1.0};
-        let blue = PixelData{color: Color { red: 0.0, green: 0.0, blue: 1.0 }, weight: 1.0};
+        let red = PixelData::new(Color { red: 1.0, green: 0.0, blue: 0.0 }, 1.0, 1.0);
+        let green = PixelData::new(Color { red: 0.0, green: 1.0, blue: 0.0 }, 1.0, 1.0);
+        let blue = PixelData::new(Color { red: 0.0, green: 0.0, blue: 1.0 }, 1.0, 1.0);
         let mut buf = PixelBuffer::new(200, 300);
         fill_rect(&mut buf, &red, 0, 200, 0, 100);
         fill_rect(&mut buf, &green, 0, 200, 100, 200);
         fill_rect(&mut buf, &blue, 0, 200, 200, 300);
-        buf.save("test.exr", &TMOType::Linear);
+        buf.save(std::env::temp_dir().join("test.exr"), &ToneMapping::new(TMOType::Linear)).unwrap();
+
+    }
+
+    # [test]
+    fn splat_sample() {
+        let mut buf = PixelBuffer::new(8, 8);
+        let filter = Filter::new(FilterType::Gaussian { sigma: 0.5 }, 1.5);
+        let color = Color { red: 1.0, green: 2.0, blue: 3.0 };
+        buf.add_sample(4.5, 4.5, color, 1.0, &filter);
+        let center = buf.get_pixel(4, 4);
+        assert!(center.weight > buf.get_pixel(3, 4).weight);
+        assert_eq!(buf.get_pixel(3, 4).weight, buf.get_pixel(5, 4).weight);
+        assert_eq!(buf.get_pixel(6, 4).weight, 0.0);
+        assert!((center.get_color().blue - 3.0).abs() < 1e-5);
+
+        // sample near border must not panic and must stay inside of the image
+        buf.add_sample(0.1, 7.9, color, 1.0, &filter);
+        assert!(buf.get_pixel(0, 7).weight > 0.0);
+
+        // pixel in negative lobe of Mitchell filter falls back to unweighted mean
+        let mut buf = PixelBuffer::new(8, 8);
+        let filter = Filter::new(FilterType::Mitchell { b: 1.0 / 3.0, c: 1.0 / 3.0 }, 2.0);
+        buf.add_sample(5.0, 4.5, color, 1.0, &filter);
+        let lobe = buf.get_pixel(6, 4);
+        assert!(lobe.weight < 0.0);
+        assert_eq!(lobe.get_color().green, 2.0);
+        assert_eq!(lobe.get_alpha(), 1.0);
+        assert!((buf.get_pixel(5, 4).get_color().red - 1.0).abs() < 1e-5);
+    }
 
+    # [test]
+    fn lpes_sum_to_beauty() {
+        let mut buf = PixelBuffer::new(8, 8);
+        buf.set_lpes(vec!["direct".to_string(), "indirect".to_string()]);
+        let filter = Filter::new(FilterType::Mitchell { b: 1.0 / 3.0, c: 1.0 / 3.0 }, 2.0);
+        let direct = Color { red: 1.0, green: 0.5, blue: 0.0 };
+        let indirect = Color { red: 0.0, green: 1.5, blue: 3.0 };
+        buf.add_sample(5.0, 4.5, direct + indirect, 1.0, &filter);
+        buf.add_lpe_sample(5.0, 4.5, &[direct, indirect], &filter);
+        // pixel (6, 4) is in negative lobe, pixel (5, 4) has reliable weight
+        assert!(!buf.get_pixel(6, 4).has_reliable_weight());
+        for x in [5, 6] {
+            let beauty = buf.get_pixel(x, 4).get_color();
+            let sum = buf.get_lpe_color(x, 4, 0) + buf.get_lpe_color(x, 4, 1);
+            assert!((sum.red - beauty.red).abs() < 1e-5 && (sum.green - beauty.green).abs() < 1e-5);
+            assert!((sum.blue - beauty.blue).abs() < 1e-5 && beauty.blue > 2.9);
+        }
+    }
+
+    # [test]
+    fn denoised_preview() {
+        let mut buf = PixelBuffer::new(4, 4);
+        buf.set_denoise(true);
+        let filter = Filter::new(FilterType::Box, 0.5);
+        buf.add_sample(1.5, 1.5, Color { red: 1.0, green: 1.0, blue: 1.0 }, 1.0, &filter);
+        let tone_mapping = ToneMapping::new(TMOType::Linear);
+        buf.to_rgb_vector(&tone_mapping);
+        assert!(buf.denoised.is_none());
+        buf.update_denoised();
+        assert_eq!(buf.denoised.as_ref().map(|colors| colors.len()), Some(16));
+    }
+
+    # [test]
+    fn variance_of_estimate() {
+        let mut buf = PixelBuffer::new(1, 1);
+        buf.set_aovs(&[AovType::Variance]);
+        let filter = Filter::new(FilterType::Box, 0.5);
+        for v in [1.0, 3.0, 1.0, 3.0] {
+            buf.add_sample(0.5, 0.5, Color { red: v, green: v, blue: v }, 1.0, &filter);
+        }
+        // variance of samples is 4/3, of their mean four times smaller
+        let channels = buf.aov_exr_channels();
+        match &channels[0].data {
+            ChannelData::Float(values) => assert!((values[0] - 1.0 / 3.0).abs() < 1e-5),
+            _ => panic!("Variance must be float channel")
+        }
     }
 }
 
@@ -0,0 +1,295 @@
+use std::f32::consts::PI;
+
+use crate::pixel_buffer::Color;
+use crate::traits::Zero;
+
+
+// Glow around bright pixels. Energy above threshold is blurred with gaussians of
+// increasing size (each level doubles the radius) and added back to the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bloom {
+    pub threshold: f32,
+    pub intensity: f32,
+    // standard deviation of first level as fraction of image width
+    pub radius: f32,
+    pub levels: usize
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Bloom { threshold: 1.0, intensity: 0.05, radius: 0.004, levels: 5 }
+    }
+}
+
+// Diffraction streaks of polygonal aperture. Aperture with even number of blades
+// produces as many streaks as there are blades, odd number produces twice as many.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Glare {
+    pub threshold: f32,
+    pub intensity: f32,
+    pub blades: usize,
+    // distance where streak falls to 1/e, as fraction of image width
+    pub length: f32,
+    // rotation of streaks in degrees
+    pub angle: f32
+}
+
+impl Default for Glare {
+    fn default() -> Self {
+        Glare { threshold: 2.0, intensity: 0.02, blades: 6, length: 0.02, angle: 15.0 }
+    }
+}
+
+// Natural cos^4 falloff of lens, strength 1 darkens corners to quarter of center
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vignette {
+    pub strength: f32
+}
+
+impl Default for Vignette {
+    fn default() -> Self {
+        Vignette { strength: 1.0 }
+    }
+}
+
+// Effects applied to linear radiance before tone mapping, and ordered dithering
+// applied to display values before quantization to 8 bits. All effects are disabled by default.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PostProcess {
+    pub bloom: Option<Bloom>,
+    pub glare: Option<Glare>,
+    pub vignette: Option<Vignette>,
+    pub dither: bool
+}
+
+impl PostProcess {
+    pub fn is_enabled(&self) -> bool {
+        self.bloom.is_some() || self.glare.is_some() || self.vignette.is_some()
+    }
+
+    pub fn apply(&self, width: usize, height: usize, colors: &mut [Color]) {
+        let mut glow = vec![Color::zero(); colors.len()];
+        if let Some(bloom) = &self.bloom {
+            add_bloom(width, height, colors, bloom, &mut glow);
+        }
+        if let Some(glare) = &self.glare {
+            add_glare(width, height, colors, glare, &mut glow);
+        }
+        for (color, glow) in colors.iter_mut().zip(glow) {
+            *color += glow;
+        }
+        if let Some(vignette) = &self.vignette {
+            apply_vignette(width, height, colors, vignette);
+        }
+    }
+
+    // Offset added to display value scaled to [0, 255] before rounding
+    pub fn dither_offset(&self, x: usize, y: usize) -> f32 {
+        if !self.dither {
+            return 0.0
+        }
+        bayer_threshold(x, y) - 0.5
+    }
+}
+
+// Threshold in [0, 1) from 8x8 Bayer matrix, lowest bits of coordinates select
+// the most significant 2x2 pattern
+fn bayer_threshold(x: usize, y: usize) -> f32 {
+    let (mut x, mut y) = (x & 7, y & 7);
+    let mut value = 0;
+    for _ in 0..3 {
+        value = (value << 2) | (((x ^ y) & 1) << 1) | (y & 1);
+        x >>= 1;
+        y >>= 1;
+    }
+    (value as f32 + 0.5) / 64.0
+}
+
+// Part of color above threshold, hue is preserved
+fn bright_pass(colors: &[Color], threshold: f32) -> Vec<Color> {
+    colors.iter().map(|color| {
+        let lum = color.luminance();
+        if lum > threshold { *color * ((lum - threshold) / lum) } else { Color::zero() }
+    }).collect()
+}
+
+fn add_bloom(width: usize, height: usize, colors: &[Color], bloom: &Bloom, glow: &mut [Color]) {
+    let bright = bright_pass(colors, bloom.threshold);
+    let sigma = bloom.radius * width as f32;
+    let weight = bloom.intensity / bloom.levels.max(1) as f32;
+    // each level is blurred at half resolution of previous one with the same kernel
+    let (mut level, mut level_width, mut level_height) = (bright, width, height);
+    for index in 0..bloom.levels {
+        if index > 0 {
+            if level_width < 2 || level_height < 2 {
+                break;
+            }
+            (level, level_width, level_height) = downsample(&level, level_width, level_height);
+        }
+        let scale = (1 << index) as f32;
+        let blurred = gaussian_blur(&level, level_width, level_height, (sigma / scale).max(0.5));
+        for y in 0..height {
+            for x in 0..width {
+                let fx = (x as f32 + 0.5) / scale - 0.5;
+                let fy = (y as f32 + 0.5) / scale - 0.5;
+                glow[y * width + x] += bilinear(&blurred, level_width, level_height, fx, fy) * weight;
+            }
+        }
+    }
+}
+
+fn downsample(colors: &[Color], width: usize, height: usize) -> (Vec<Color>, usize, usize) {
+    let (w, h) = (width / 2, height / 2);
+    let mut result = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let i = 2 * y * width + 2 * x;
+            result.push((colors[i] + colors[i + 1] + colors[i + width] + colors[i + width + 1]) * 0.25);
+        }
+    }
+    (result, w, h)
+}
+
+fn gaussian_blur(colors: &[Color], width: usize, height: usize, sigma: f32) -> Vec<Color> {
+    let radius = (3.0 * sigma).ceil() as i32;
+    let kernel: Vec<f32> = (-radius..=radius).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let norm: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|k| k / norm).collect();
+    // pixels outside of image are black, so bright edges don't bleed more than they should
+    let pass = |src: &[Color], dx: usize, dy: usize| -> Vec<Color> {
+        let mut dst = vec![Color::zero(); src.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = Color::zero();
+                for (k, weight) in kernel.iter().enumerate() {
+                    let offset = k as i32 - radius;
+                    let px = x as i32 + offset * dx as i32;
+                    let py = y as i32 + offset * dy as i32;
+                    if px >= 0 && py >= 0 && (px as usize) < width && (py as usize) < height {
+                        sum += src[py as usize * width + px as usize] * *weight;
+                    }
+                }
+                dst[y * width + x] = sum;
+            }
+        }
+        dst
+    };
+    pass(&pass(colors, 1, 0), 0, 1)
+}
+
+fn bilinear(colors: &[Color], width: usize, height: usize, fx: f32, fy: f32) -> Color {
+    let x0 = fx.floor();
+    let y0 = fy.floor();
+    let (tx, ty) = (fx - x0, fy - y0);
+    let fetch = |x: i32, y: i32| -> Color {
+        if x < 0 || y < 0 || x as usize >= width || y as usize >= height {
+            Color::zero()
+        } else {
+            colors[y as usize * width + x as usize]
+        }
+    };
+    let (x0, y0) = (x0 as i32, y0 as i32);
+    fetch(x0, y0) * ((1.0 - tx) * (1.0 - ty)) + fetch(x0 + 1, y0) * (tx * (1.0 - ty)) +
+        fetch(x0, y0 + 1) * ((1.0 - tx) * ty) + fetch(x0 + 1, y0 + 1) * (tx * ty)
+}
+
+// Each streak is exponential falloff along its direction, built with a few passes of
+// 4 taps whose spacing grows 4x per pass (Kawase streak filter).
+fn add_glare(width: usize, height: usize, colors: &[Color], glare: &Glare, glow: &mut [Color]) {
+    let bright = bright_pass(colors, glare.threshold);
+    let blades = glare.blades.max(2);
+    // is_multiple_of needs Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    let streaks = if blades % 2 == 0 { blades } else { 2 * blades };
+    let length = (glare.length * width as f32).max(1.0);
+    let decay = (-1.0 / length).exp();
+    let mut passes = 1;
+    while 4f32.powi(passes) < 4.0 * length {
+        passes += 1;
+    }
+    let weight = glare.intensity / streaks as f32;
+    for streak in 0..streaks {
+        let angle = glare.angle.to_radians() + 2.0 * PI * streak as f32 / streaks as f32;
+        let (dx, dy) = (angle.cos(), angle.sin());
+        let mut current = bright.clone();
+        let mut total = 1.0;
+        for pass in 0..passes {
+            let step = 4f32.powi(pass);
+            let taps: Vec<f32> = (0..4).map(|s| decay.powf(step * s as f32)).collect();
+            total *= taps.iter().sum::<f32>();
+            let mut next = vec![Color::zero(); current.len()];
+            for y in 0..height {
+                for x in 0..width {
+                    let mut sum = Color::zero();
+                    for (s, tap) in taps.iter().enumerate() {
+                        let offset = step * s as f32;
+                        sum += bilinear(&current, width, height, x as f32 - dx * offset, y as f32 - dy * offset) * *tap;
+                    }
+                    next[y * width + x] = sum;
+                }
+            }
+            current = next;
+        }
+        for (g, c) in glow.iter_mut().zip(current) {
+            *g += c * (weight / total);
+        }
+    }
+}
+
+fn apply_vignette(width: usize, height: usize, colors: &mut [Color], vignette: &Vignette) {
+    let half_diagonal = ((width * width + height * height) as f32).sqrt() * 0.5;
+    for y in 0..height {
+        for x in 0..width {
+            let px = (x as f32 + 0.5 - width as f32 * 0.5) / half_diagonal;
+            let py = (y as f32 + 0.5 - height as f32 * 0.5) / half_diagonal;
+            // corner of image is 45 degrees off axis
+            let cos2 = 1.0 / (1.0 + px * px + py * py);
+            let falloff = cos2 * cos2;
+            let index = y * width + x;
+            colors[index] = colors[index] * (1.0 - vignette.strength * (1.0 - falloff));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_process_effects() {
+        let (width, height) = (64, 64);
+        let center = 32 * width + 32;
+        let mut colors = vec![Color { red: 0.1, green: 0.1, blue: 0.1 }; width * height];
+        colors[center] = Color { red: 100.0, green: 100.0, blue: 100.0 };
+
+        let bloom = PostProcess { bloom: Some(Bloom { radius: 0.02, ..Bloom::default() }), ..PostProcess::default() };
+        let mut result = colors.clone();
+        bloom.apply(width, height, &mut result);
+        assert!(result[32 * width + 35].red > 0.11);
+        assert!((result[0].red - 0.1).abs() < 1e-4);
+
+        // four blades give horizontal and vertical streaks
+        let glare = Glare { blades: 4, angle: 0.0, length: 0.1, intensity: 0.1, ..Glare::default() };
+        let glare = PostProcess { glare: Some(glare), ..PostProcess::default() };
+        let mut result = colors.clone();
+        glare.apply(width, height, &mut result);
+        assert!(result[32 * width + 42].red > 0.1 + 1e-3);
+        assert!(result[42 * width + 32].red > 0.1 + 1e-3);
+        assert!(result[42 * width + 42].red < result[32 * width + 42].red);
+
+        let vignette = PostProcess { vignette: Some(Vignette::default()), ..PostProcess::default() };
+        let mut result = colors.clone();
+        vignette.apply(width, height, &mut result);
+        assert!(result[0].red < 0.03);
+        assert!((result[32 * width + 31].red - 0.1).abs() < 1e-3);
+
+        // every threshold of Bayer matrix is used once
+        let mut thresholds: Vec<f32> = (0..64).map(|i| bayer_threshold(i % 8, i / 8)).collect();
+        thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for (i, t) in thresholds.iter().enumerate() {
+            assert!((t - (i as f32 + 0.5) / 64.0).abs() < 1e-6);
+        }
+        assert_eq!(PostProcess::default().dither_offset(3, 5), 0.0);
+    }
+}
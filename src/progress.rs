@@ -0,0 +1,112 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+
+// Cancels rendering from any thread, clones share the same state. Renderer stops
+// dispatching tiles as soon as it sees cancellation and closes its threads.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// Snapshot of rendering progress. Rates are per second of rendering time.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Progress {
+    pub tiles_done: usize,
+    // upper bound while rendering, adaptive sampling and stop conditions can finish sooner
+    pub tiles_total: usize,
+    // current spp pass starting from 1 and number of passes
+    pub pass: usize,
+    pub passes: usize,
+    pub samples: u64,
+    pub rays: u64,
+    pub samples_per_second: f64,
+    pub rays_per_second: f64,
+    pub elapsed: Duration,
+    // estimated remaining time, unknown until first tile is done
+    pub eta: Option<Duration>
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f32 {
+        if self.tiles_total == 0 {
+            return 1.0
+        }
+        self.tiles_done as f32 / self.tiles_total as f32
+    }
+}
+
+// Latest progress shared with other threads, e.g. for polling from UI thread
+#[derive(Debug, Clone, Default)]
+pub struct ProgressMonitor {
+    progress: Arc<Mutex<Progress>>
+}
+
+impl ProgressMonitor {
+    pub fn new() -> ProgressMonitor {
+        ProgressMonitor::default()
+    }
+
+    pub fn get(&self) -> Progress {
+        *self.progress.lock().unwrap()
+    }
+
+    pub fn set(&self, progress: Progress) {
+        *self.progress.lock().unwrap() = progress;
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// One line summary used for console progress
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let eta = match self.eta {
+            Some(eta) => format_duration(eta),
+            None => "-".to_string()
+        };
+        write!(f, "pass {}/{} tiles {}/{} ({:.1}%) {:.2} Msamples/s {:.2} Mrays/s elapsed {} ETA {}",
+            self.pass, self.passes, self.tiles_done, self.tiles_total, 100.0 * self.fraction(),
+            self.samples_per_second * 1e-6, self.rays_per_second * 1e-6, format_duration(self.elapsed), eta)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_line() {
+        let progress = Progress {
+            tiles_done: 25, tiles_total: 100, pass: 2, passes: 8, samples: 1_000_000, rays: 3_000_000,
+            samples_per_second: 500_000.0, rays_per_second: 1_500_000.0,
+            elapsed: Duration::from_secs(2), eta: Some(Duration::from_secs(3725))
+        };
+        assert_eq!(progress.to_string(),
+            "pass 2/8 tiles 25/100 (25.0%) 0.50 Msamples/s 1.50 Mrays/s elapsed 0:00:02 ETA 1:02:05");
+        let token = CancelToken::new();
+        let shared = token.clone();
+        shared.cancel();
+        assert!(token.is_cancelled());
+    }
+}
@@ -4,19 +4,21 @@ use crate::vec::f32x3;
 pub struct Ray {
     pub origin: f32x3,
     pub direction: f32x3,
-    pub inv_dir: f32x3
+    pub inv_dir: f32x3,
+    pub time: f32
 }
 
 impl Ray {
-    pub fn new(origin: f32x3, direction: f32x3) -> Ray {
+    pub fn new(origin: f32x3, direction: f32x3, time: f32) -> Ray {
         let inv_dir = f32x3(1.0 / direction.0,
                                    1.0 / direction.1,
                                    1.0 / direction.2);
-        Ray { origin, direction, inv_dir }
+        Ray { origin, direction, inv_dir, time }
     }
 }
 
 
+#[allow(clippy::needless_late_init)]
 pub fn offset_ray_origin(hit: f32x3, normal: f32x3) -> f32x3 {
 
     const fn int_scale() -> f32 {256.0}
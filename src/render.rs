@@ -1,6 +1,8 @@
 use crate::ray::{Ray, offset_ray_origin};
+use crate::lpe::{LpeEvent, LpeRecorder};
+use crate::bvh::TraversalStats;
 use crate::scene::{SceneData, ShadingPoint};
-use crate::pcg::PCGRng;
+use crate::sampler::Sampler;
 use crate::pixel_buffer::Color;
 use crate::traits::{Zero, One};
 use crate::vec::f32x3;
@@ -19,21 +21,19 @@ pub fn sample_hemisphere(normal: f32x3, u1: f32, u2: f32) -> (f32x3, f32) {
     (direction, pdfw)
 }
 
-pub fn ambient_occlusion(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color {
-
-   if let Some(sp) = scene_data.intersect(ray, 1e30) {
-        let (direction,pdfw) = sample_hemisphere(sp.normal, rng.rnd_f32(), rng.rnd_f32());
-        let new_origin = offset_ray_origin(sp.hitpoint, sp.normal);
-        let shadow_ray = Ray::new(new_origin, direction);
-        let result = scene_data.intersect(&shadow_ray, 1e30);
-        if result.is_none() {
-            return Color::one() * f32::consts::FRAC_1_PI * sp.normal.dot(direction) * pdfw.recip();
-        } else {
-            return Color::zero();
-        }
-   } else {
-       return Color::zero();
-   }
+// Integrators get shading point of primary ray, primary intersection is done by caller.
+
+pub fn ambient_occlusion(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData, sampler: &mut dyn Sampler) -> Color {
+    let (u1, u2) = sampler.get_2d();
+    let (direction,pdfw) = sample_hemisphere(sp.normal, u1, u2);
+    let new_origin = offset_ray_origin(sp.hitpoint, sp.normal);
+    let shadow_ray = Ray::new(new_origin, direction, ray.time);
+    let result = scene_data.intersect(&shadow_ray, 1e30);
+    if result.is_none() {
+        Color::one() * f32::consts::FRAC_1_PI * sp.normal.dot(direction) * pdfw.recip()
+    } else {
+        Color::zero()
+    }
 }
 
 // pub fn direct_lighting(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color {
@@ -140,13 +140,14 @@ fn balance_heuristic(pdfa: f32, pdfb: f32) -> f32 {
     pdfa / (pdfa + pdfb)
 }
 
-pub fn direct_sample_light(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color {
+#[allow(clippy::needless_borrow)]
+pub fn direct_sample_light(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData, sampler: &mut dyn Sampler) -> Color {
     let wo = -ray.direction;
     let nlights = scene_data.lights.len();
-    let light_id = ((rng.rnd_f32() * nlights as f32) as usize).clamp(0, nlights - 1);
+    let light_id = ((sampler.get_1d() * nlights as f32) as usize).clamp(0, nlights - 1);
     let light = &scene_data.lights[light_id];
 
-    let lgt_sample = match light.illuminate(sp.hitpoint, scene_data, rng) {
+    let lgt_sample = match light.illuminate(sp.hitpoint, sp.time, scene_data, sampler) {
         Some(lgt_sample) => lgt_sample,
         None => return Color::zero()
     };
@@ -162,7 +163,7 @@ pub fn direct_sample_light(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData,
         let bsdf_value = bs.color * sp.normal.dot(wi);
         let lgt_value = lgt_sample.intensity * lgt_sample.cos_theta;
         let new_origin = offset_ray_origin(sp.hitpoint, sp.normal);
-        if scene_data.visible(new_origin, lgt_sample.position) {
+        if scene_data.visible(new_origin, lgt_sample.position, sp.time) {
             let light_pdf = light_picking_pdf * lgt_sample.pdfa;
             let mut weight = 1.0;
             if !light.is_delta_light() {
@@ -176,17 +177,18 @@ pub fn direct_sample_light(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData,
 }
 
 
-pub fn direct_sample_bsdf(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color {
+#[allow(clippy::needless_borrow)]
+pub fn direct_sample_bsdf(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData, sampler: &mut dyn Sampler) -> Color {
    
     let wo = -ray.direction;
     let origin = offset_ray_origin(sp.hitpoint, sp.normal);
 
-    let bs = match scene_data.sample_bsdf(&sp, wo, rng) {
+    let bs = match scene_data.sample_bsdf(&sp, wo, sampler) {
         Some(bs) => bs,
         None => return Color::zero()
     };
 
-    let shadow_ray = Ray::new(origin, bs.direction);
+    let shadow_ray = Ray::new(origin, bs.direction, sp.time);
 
     let lgt_sp= match scene_data.intersect(&shadow_ray, 1e30) {
         Some(lgt_sp) => lgt_sp,
@@ -217,30 +219,26 @@ pub fn direct_sample_bsdf(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData,
 }
 
 
-pub fn direct_lighting(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color {
-
-    let sp = match scene_data.intersect(ray, 1e30) {
-        Some(sp) => sp,
-        None => return Color::zero()
-    };
-
+pub fn direct_lighting(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData, sampler: &mut dyn Sampler) -> Color {
     let mut acum_color = scene_data.get_emission(sp.shape_id);
-    acum_color += direct_sample_light(&sp, ray, scene_data, rng);
-    acum_color += direct_sample_bsdf(&sp, ray, scene_data, rng);
+    acum_color += direct_sample_light(sp, ray, scene_data, sampler);
+    acum_color += direct_sample_bsdf(sp, ray, scene_data, sampler);
 
     acum_color
 }
 
-fn pick_random_light(scene_data: &SceneData, rng: &mut PCGRng) -> (usize, f32) {
+fn pick_random_light(scene_data: &SceneData, sampler: &mut dyn Sampler) -> (usize, f32) {
     let nlights = scene_data.lights.len();
-    let light_id = ((rng.rnd_f32() * nlights as f32) as usize).clamp(0, nlights - 1);
+    let light_id = ((sampler.get_1d() * nlights as f32) as usize).clamp(0, nlights - 1);
     let light_picking_pdf = 1.0 / nlights as f32;
     (light_id, light_picking_pdf)
 }
 
-fn explicit_direct_lighting(sp: &ShadingPoint, wo: f32x3, scene_data: &SceneData, rng: &mut PCGRng) -> Color {
-    let (light_id, light_picking_pdf) = pick_random_light(scene_data, rng);
-    if let Some(lgt_sample) = scene_data.lights[light_id].illuminate(sp.hitpoint, scene_data, rng) {
+// Returns contribution and index of sampled light
+#[allow(clippy::needless_borrow)]
+fn explicit_direct_lighting(sp: &ShadingPoint, wo: f32x3, scene_data: &SceneData, sampler: &mut dyn Sampler) -> (Color, usize) {
+    let (light_id, light_picking_pdf) = pick_random_light(scene_data, sampler);
+    if let Some(lgt_sample) = scene_data.lights[light_id].illuminate(sp.hitpoint, sp.time, scene_data, sampler) {
         let wi = lgt_sample.wi;
         if wi.dot(sp.normal) > 0.0 && wo.dot(sp.normal) > 0.0 {
             let len_sqr = (sp.hitpoint - lgt_sample.position).length_sqr();
@@ -248,30 +246,31 @@ fn explicit_direct_lighting(sp: &ShadingPoint, wo: f32x3, scene_data: &SceneData
                 let bsdf_value = bs_eval.color * sp.normal.dot(wi).abs();
                 let lgt_value = lgt_sample.intensity * lgt_sample.cos_theta;
                 let new_origin = offset_ray_origin(sp.hitpoint, sp.normal);
-                if scene_data.visible(new_origin, lgt_sample.position) {
+                if scene_data.visible(new_origin, lgt_sample.position, sp.time) {
                     let light_pdf = lgt_sample.pdfa * light_picking_pdf;
                     let bs_pdfa = bs_eval.pdfw * lgt_sample.cos_theta * len_sqr.recip();
                     let mut weight = 1.0;
                     if !scene_data.lights[light_id].is_delta_light() {
                         weight = balance_heuristic(light_pdf, bs_pdfa);
                     }
-                    return weight * lgt_value * bsdf_value * (len_sqr * light_pdf).recip();
+                    return (weight * lgt_value * bsdf_value * (len_sqr * light_pdf).recip(), light_id);
                 }
             }
         }
     }
-    Color::zero()
+    (Color::zero(), light_id)
 }
 
+// Each contribution is also routed to light path expression buffers of the recorder,
+// the path events are camera, scattering event at each vertex and light.
+pub fn path_tracer(sp: &ShadingPoint, ray: &Ray, scene_data: &SceneData, sampler: &mut dyn Sampler, lpe: &mut LpeRecorder) -> Color {
 
-pub fn path_tracer(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color {
-
-    let mut sp = match scene_data.intersect(ray, 1e30) {
-        Some(sp) => sp,
-        None => return Color::zero()
-    };
-
+    let mut sp = *sp;
     let mut acum_color = scene_data.get_emission(sp.shape_id);
+    lpe.start_path();
+    if scene_data.is_emissive(sp.shape_id) {
+        lpe.add(acum_color, LpeEvent::Light(scene_data.shape_light_group(sp.shape_id)));
+    }
 
     let mut depth = 1;
     let max_depth = 10;
@@ -281,10 +280,14 @@ pub fn path_tracer(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color
     let use_mis = true;
 
     loop {
+        lpe.push_event(scene_data.scatter_event(&sp));
         if use_mis {
-            acum_color += path * explicit_direct_lighting(&sp, wo, scene_data, rng);
+            let (color, light_id) = explicit_direct_lighting(&sp, wo, scene_data, sampler);
+            let contribution = path * color;
+            acum_color += contribution;
+            lpe.add(contribution, LpeEvent::Light(scene_data.lights[light_id].light_group(scene_data)));
         }
-        let bs = match scene_data.sample_bsdf(&sp, wo, rng) {
+        let bs = match scene_data.sample_bsdf(&sp, wo, sampler) {
             Some(bs) => bs,
             None => break
         };
@@ -297,7 +300,7 @@ pub fn path_tracer(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color
         path = path * bs.color * (cos_theta / bs.pdfw);
 
         let origin = offset_ray_origin(sp.hitpoint, normal);
-        let ray = Ray::new(origin, wi);
+        let ray = Ray::new(origin, wi, sp.time);
 
         sp = match scene_data.intersect(&ray, 1e30) {
             Some(sp) => sp,
@@ -313,14 +316,18 @@ pub fn path_tracer(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color
                         let pdfw = pdfa * (hitpoint - sp.hitpoint).length_sqr() * cos_theta.recip();
                         let light_picking_pdf = 1.0 / scene_data.lights.len() as f32;
                         let weight = balance_heuristic(bs.pdfw, pdfw * light_picking_pdf);
-                        acum_color += weight * path * emission;
+                        let contribution = weight * path * emission;
+                        acum_color += contribution;
+                        lpe.add(contribution, LpeEvent::Light(scene_data.shape_light_group(sp.shape_id)));
                         break
                     }
                 }
 
             } else {
                 if wi.dot(normal) > 0.0 && wo.dot(normal) > 0.0 {
-                    acum_color += path * scene_data.get_emission(sp.shape_id);
+                    let contribution = path * scene_data.get_emission(sp.shape_id);
+                    acum_color += contribution;
+                    lpe.add(contribution, LpeEvent::Light(scene_data.shape_light_group(sp.shape_id)));
                     break
                 }
             }
@@ -341,3 +348,65 @@ pub fn path_tracer(ray: &Ray, scene_data: &SceneData, rng: &mut PCGRng) -> Color
     acum_color
 
 }
+
+// Debug integrators show scene data instead of lighting. Normals and coordinates are
+// mapped to [0, 1], depth is distance along the ray, traversal costs are relative to
+// the number of bounding volumes or shapes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugMode {
+    ShadingNormal,
+    GeometricNormal,
+    Uv,
+    Barycentrics,
+    Depth,
+    MaterialId,
+    ShapeId,
+    NodeVisits,
+    PrimitiveTests
+}
+
+// Blue for low values through green to red for high values, value is in [0, 1]
+pub fn heat_color(value: f32) -> Color {
+    let v = value.clamp(0.0, 1.0);
+    if v < 0.5 {
+        Color { red: 0.0, green: 2.0 * v, blue: 1.0 - 2.0 * v }
+    } else {
+        Color { red: 2.0 * v - 1.0, green: 2.0 - 2.0 * v, blue: 0.0 }
+    }
+}
+
+// Stable pseudo random color, neighbouring ids get different colors
+pub fn id_color(id: usize) -> Color {
+    let mut h = (id as u64 + 1).wrapping_mul(0x9E3779B97F4A7C15);
+    h = (h ^ (h >> 31)).wrapping_mul(0xBF58476D1CE4E5B9);
+    h ^= h >> 29;
+    let channel = |shift: u64| 0.2 + 0.8 * ((h >> shift) & 0xFF) as f32 / 255.0;
+    Color { red: channel(0), green: channel(8), blue: channel(16) }
+}
+
+fn vector_color(v: f32x3) -> Color {
+    Color { red: 0.5 * v.0 + 0.5, green: 0.5 * v.1 + 0.5, blue: 0.5 * v.2 + 0.5 }
+}
+
+// Traversal costs are shown also for rays that miss, other modes are black there
+pub fn debug_integrator(mode: DebugMode, sp: Option<&ShadingPoint>, stats: &TraversalStats, scene_data: &SceneData) -> Color {
+    let relative = |count: u32, total: usize| count as f32 / total.max(1) as f32;
+    match (mode, sp) {
+        (DebugMode::NodeVisits, _) => heat_color(relative(stats.node_visits, scene_data.bounding_volume_count())),
+        (DebugMode::PrimitiveTests, _) => heat_color(relative(stats.primitive_tests, scene_data.shape_count())),
+        (_, None) => Color::zero(),
+        (DebugMode::ShadingNormal, Some(sp)) => vector_color(sp.normal),
+        (DebugMode::GeometricNormal, Some(sp)) => vector_color(scene_data.geometric_normal(sp)),
+        (DebugMode::Uv, Some(sp)) => {
+            let (u, v) = scene_data.get_uv(sp);
+            Color { red: u, green: v, blue: 0.0 }
+        },
+        (DebugMode::Barycentrics, Some(sp)) => match scene_data.get_barycentrics(sp) {
+            Some(b) => Color { red: b.0, green: b.1, blue: b.2 },
+            None => Color::zero()
+        },
+        (DebugMode::Depth, Some(sp)) => Color::one() * sp.t,
+        (DebugMode::MaterialId, Some(sp)) => id_color(sp.material_id()),
+        (DebugMode::ShapeId, Some(sp)) => id_color(sp.shape_id)
+    }
+}
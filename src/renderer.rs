@@ -1,59 +1,205 @@
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::path::Path;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::sync::{Arc, mpsc};
 use std::thread;
 use std::mem::drop;
+use std::path::Path;
 use std::time::{Duration, Instant};
 
-use crate::pcg::PCGRng;
-use crate::pixel_buffer::{Color, PixelBuffer, PixelData, TMOType};
-use crate::ray::Ray;
-use crate::scene::{SceneData, RenderingAlgorithm};
+use serde_json::json;
+
+use crate::aov::AovSample;
+use crate::checkpoint::{CheckpointHeader, RenderProgress, read_checkpoint, read_checkpoint_pixels, write_checkpoint};
+use crate::checkpoint::{read_color, read_f32, read_u32, read_u64, write_color, write_f32, write_u32, write_u64};
+use crate::distributed::{SceneSource, WorkerConnection};
+use crate::lpe::LpeRecorder;
+use crate::exr_output::ExrAttribute;
+use crate::sampler::{Sampler, create_sampler};
+use crate::traits::Zero;
+use crate::pixel_buffer::{Color, PixelBuffer};
+use crate::progress::{CancelToken, Progress};
+use crate::scene::{CropOutput, CropWindow, SceneData, RenderingAlgorithm, traced_rays};
 use crate::img_sampling::{Tile, ImageSampler};
-use crate::render::{ambient_occlusion, direct_lighting, path_tracer};
+use crate::render::{ambient_occlusion, debug_integrator, direct_lighting, path_tracer};
 
 
 #[derive(Debug, Clone, Copy)]
 pub struct PixelSample {
     x: usize,
     y: usize,
-    color: Color
+    xp: f32,
+    yp: f32,
+    color: Color,
+    alpha: f32,
+    aov: AovSample
 }
 
+impl PixelSample {
+    // Continuous position in pixel buffer. Image y axis goes up while pixel buffer rows
+    // go down, so offset inside pixel is mirrored and kept inside of the same pixel.
+    fn film_position(&self, height: usize) -> (f32, f32) {
+        let fx = self.x as f32 + self.xp;
+        let fy = (height - self.y - 1) as f32 + (1.0 - self.yp).min(1.0 - f32::EPSILON);
+        (fx, fy)
+    }
+}
 
-fn render_tile(tile: &Tile, scene_data: &SceneData, rng: &mut PCGRng) -> Vec<PixelSample> {
+
+pub(crate) fn render_tile(tile: &Tile, sample_index: usize, scene_data: &SceneData, sampler: &mut dyn Sampler) -> TileData {
     let capacity = (tile.endx - tile.startx) * (tile.endy - tile.starty);
     let mut samples = Vec::with_capacity(capacity);
+    let mut lpe = LpeRecorder::new(scene_data.get_lpes());
+    let mut lpe_colors = Vec::with_capacity(capacity * scene_data.get_lpes().len());
+    let start_rays = traced_rays();
 
-    let mut img_sampler = ImageSampler::new(*tile);
-    while let Some(sample) = img_sampler.next(rng) {
-        let ray = scene_data.generate_ray(sample.x, sample.y, sample.xp, sample.yp);
-        let color = match scene_data.rendering_algorithm {
-            RenderingAlgorithm::AmbientOcclusion => ambient_occlusion(&ray, scene_data, rng),
-            RenderingAlgorithm::DirectLighting => direct_lighting(&ray, scene_data, rng),
-            RenderingAlgorithm::PathTracer => path_tracer(&ray, scene_data, rng)
+    let mut img_sampler = ImageSampler::new(*tile, sample_index);
+    while let Some(sample) = img_sampler.next(sampler) {
+        let ray = scene_data.generate_ray(sample.x, sample.y, sample.xp, sample.yp, sample.tp);
+        let (hit, stats) = scene_data.intersect_with_stats(&ray, 1e30);
+        let (color, alpha, aov) = match hit {
+            Some(sp) => {
+                let color = match scene_data.rendering_algorithm {
+                    RenderingAlgorithm::AmbientOcclusion => ambient_occlusion(&sp, &ray, scene_data, sampler),
+                    RenderingAlgorithm::DirectLighting => direct_lighting(&sp, &ray, scene_data, sampler),
+                    RenderingAlgorithm::PathTracer => path_tracer(&sp, &ray, scene_data, sampler, &mut lpe),
+                    RenderingAlgorithm::Debug(mode) => debug_integrator(mode, Some(&sp), &stats, scene_data)
+                };
+                let aov = AovSample {
+                    albedo: scene_data.get_albedo(&sp),
+                    normal: sp.normal,
+                    depth: sp.t,
+                    position: sp.hitpoint,
+                    shape_id: sp.shape_id as u32 + 1,
+                    material_id: sp.material_id() as u32 + 1
+                };
+                (color, 1.0, aov)
+            },
+            None => {
+                lpe.start_path();
+                let color = match scene_data.rendering_algorithm {
+                    RenderingAlgorithm::Debug(mode) => debug_integrator(mode, None, &stats, scene_data),
+                    _ => Color::zero()
+                };
+                (color, 0.0, AovSample::zero())
+            }
         };
-        samples.push(PixelSample { x: sample.x, y: sample.y, color });
+        samples.push(PixelSample { x: sample.x, y: sample.y, xp: sample.xp, yp: sample.yp, color, alpha, aov });
+        lpe_colors.extend_from_slice(lpe.colors());
     }
-    samples
+    TileData { samples, lpe_colors, rays: traced_rays() - start_rays }
 }
 
+// Light path expression colors of all samples are stored in one vector,
+// sample i has colors [i * n, (i + 1) * n) where n is number of expressions.
 pub struct TileData {
-    samples: Vec<PixelSample>
+    samples: Vec<PixelSample>,
+    lpe_colors: Vec<Color>,
+    rays: u64
+}
+
+// Tiles rendered by remote workers are sent in this form
+impl TileData {
+    pub(crate) fn write_raw(&self, w: &mut dyn Write) -> io::Result<()> {
+        write_u64(w, self.samples.len() as u64)?;
+        for sample in self.samples.iter() {
+            write_u32(w, sample.x as u32)?;
+            write_u32(w, sample.y as u32)?;
+            write_f32(w, sample.xp)?;
+            write_f32(w, sample.yp)?;
+            write_color(w, &sample.color)?;
+            write_f32(w, sample.alpha)?;
+            sample.aov.write_raw(w)?;
+        }
+        write_u64(w, self.lpe_colors.len() as u64)?;
+        for color in self.lpe_colors.iter() {
+            write_color(w, color)?;
+        }
+        write_u64(w, self.rays)
+    }
+
+    pub(crate) fn read_raw(r: &mut dyn Read) -> io::Result<TileData> {
+        let n_samples = read_u64(r)? as usize;
+        let mut samples = Vec::with_capacity(n_samples.min(1 << 16));
+        for _ in 0..n_samples {
+            let x = read_u32(r)? as usize;
+            let y = read_u32(r)? as usize;
+            let xp = read_f32(r)?;
+            let yp = read_f32(r)?;
+            let color = read_color(r)?;
+            let alpha = read_f32(r)?;
+            let aov = AovSample::read_raw(r)?;
+            samples.push(PixelSample { x, y, xp, yp, color, alpha, aov });
+        }
+        let n_colors = read_u64(r)? as usize;
+        let mut lpe_colors = Vec::with_capacity(n_colors.min(1 << 16));
+        for _ in 0..n_colors {
+            lpe_colors.push(read_color(r)?);
+        }
+        let rays = read_u64(r)?;
+        Ok(TileData { samples, lpe_colors, rays })
+    }
+
+    // Samples outside of image would be splatted out of pixel buffer
+    pub(crate) fn is_inside(&self, tile: &Tile) -> bool {
+        self.samples.iter().all(|s| s.x >= tile.startx && s.x < tile.endx && s.y >= tile.starty && s.y < tile.endy)
+    }
+}
+
+// Samples are splatted to neighbouring pixels, possibly across tile borders.
+fn write_tile_data(pixel_buffer: &mut PixelBuffer, data: &TileData, scene_data: &SceneData) {
+    let (_width, height) = scene_data.image_size();
+    let filter = scene_data.get_filter();
+    let n_lpes = scene_data.get_lpes().len();
+    for (index, sample) in data.samples.iter().enumerate() {
+        let (fx, fy) = sample.film_position(height);
+        pixel_buffer.add_sample(fx, fy, sample.color, sample.alpha, filter);
+        pixel_buffer.add_aov(fx, fy, &sample.aov);
+        if n_lpes > 0 {
+            pixel_buffer.add_lpe_sample(fx, fy, &data.lpe_colors[index * n_lpes..(index + 1) * n_lpes], filter);
+        }
+    }
+}
+
+fn create_pixel_buffer(scene_data: &SceneData) -> PixelBuffer {
+    let (width, height) = scene_data.image_size();
+    let mut pixel_buffer = PixelBuffer::new(width, height);
+    pixel_buffer.set_denoise(scene_data.get_denoise());
+    pixel_buffer.set_post_process(*scene_data.get_post_process());
+    pixel_buffer.set_aovs(scene_data.get_aovs());
+    pixel_buffer.set_lpes(scene_data.get_lpes().iter().map(|lpe| lpe.name().to_string()).collect());
+    pixel_buffer
 }
 
 fn create_tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    create_region_tiles(0, 0, width, height, tile_size)
+}
+
+fn create_region_tiles(startx: usize, starty: usize, endx: usize, endy: usize, tile_size: usize) -> Vec<Tile> {
     let mut tiles = Vec::new();
-    for y in (0..height).step_by(tile_size) {
-        for x in (0..width).step_by(tile_size) {
-            let endx = (x + tile_size).min(width);
-            let endy = (y + tile_size).min(height);
-            tiles.push(Tile{startx: x, starty: y, endx, endy});
+    for y in (starty..endy).step_by(tile_size) {
+        for x in (startx..endx).step_by(tile_size) {
+            tiles.push(Tile{startx: x, starty: y, endx: (x + tile_size).min(endx), endy: (y + tile_size).min(endy)});
         }
     }
     tiles
 }
 
+// Tiles cover crop window extended by reach of filter, so pixels at border of window get
+// samples from outside of it too. Tile rows go up while crop rows go down.
+fn create_scene_tiles(scene_data: &SceneData) -> Vec<Tile> {
+    let (width, height) = scene_data.image_size();
+    let window = match scene_data.get_crop_window() {
+        Some(window) => window,
+        None => return create_tiles(width, height, 16)
+    };
+    let filter = scene_data.get_filter();
+    let margin = if filter.is_single_pixel() { 0 } else { (filter.radius() - 0.5).max(0.0).ceil() as usize };
+    create_region_tiles(window.x0.saturating_sub(margin), (height - window.y1).saturating_sub(margin),
+        (window.x1 + margin).min(width), (height - window.y0 + margin).min(height), 16)
+}
+
 pub struct Renderer {
     scene_data: Arc<SceneData>,
 
@@ -70,13 +216,14 @@ impl Renderer {
     pub fn new(mut sc_data: SceneData) -> Renderer {
         let (width, height) = sc_data.image_size();
         sc_data.prepare();
+        let pixel_buffer = create_pixel_buffer(&sc_data);
         Renderer {
             scene_data: Arc::new(sc_data),
             renderig_in_progress: false,
             tiles: Arc::new(create_tiles(width, height, 16)),
             threads: Vec::new(),
             receiver: None,
-            pixel_buffer: PixelBuffer::new(width, height),
+            pixel_buffer,
             n_tiles_processed: 0
         }
     }
@@ -93,11 +240,12 @@ impl Renderer {
             let sc_data = Arc::clone(&self.scene_data);
 
             let handle = thread::spawn (move || {
-                let mut rng = PCGRng::new(0xf123456789012345, 1000 * thread_id as u64);
+                let spp = sc_data.get_samples_per_pixel();
+                let mut sampler = create_sampler(sc_data.get_sampler_type(), spp, sc_data.get_seed());
                     for tile in tiles.iter().skip(thread_id).step_by(n_actual_threads) {
-                        for _n in 0..sc_data.get_samples_per_pixel() {
-                            let samples = render_tile(tile, &sc_data, &mut rng);
-                            sender.send(TileData {samples});
+                        for n in 0..spp {
+                            let data = render_tile(tile, n, &sc_data, sampler.as_mut());
+                            let _result = sender.send(data);
                     }
                 }
             });
@@ -106,6 +254,7 @@ impl Renderer {
         drop(tx);
     }
 
+    #[allow(clippy::needless_return, clippy::bool_comparison)]
     pub fn render(&mut self, timeout: Duration) -> bool {
         if self.n_tiles_processed == self.tiles.len() * self.scene_data.get_samples_per_pixel() {
             return true;
@@ -122,11 +271,7 @@ impl Renderer {
             loop {
                 let data = rx.recv().unwrap();
 
-                let (_width, height) = self.scene_data.image_size();
-                for sample in data.samples {
-                    let pdata = PixelData{color: sample.color, weight: 1.0};
-                    self.pixel_buffer.add_pixel(sample.x, height - sample.y - 1, &pdata);
-                }
+                write_tile_data(&mut self.pixel_buffer, &data, &self.scene_data);
 
                 self.n_tiles_processed += 1;
                 if self.n_tiles_processed == self.tiles.len() * self.scene_data.get_samples_per_pixel() {
@@ -147,62 +292,137 @@ impl Renderer {
     }
 
     pub fn save(&self) -> Result<(), Box<dyn Error>> {
-        self.pixel_buffer.save(self.scene_data.get_output_file(), self.scene_data.get_tmo_type())
+        self.pixel_buffer.save(self.scene_data.get_output_file(), self.scene_data.get_tone_mapping())
     }
 
     pub fn to_rgb_vector(&self) -> Vec<u32> {
-        self.pixel_buffer.to_rgb_vector(self.scene_data.get_tmo_type())
+        self.pixel_buffer.to_rgb_vector(self.scene_data.get_tone_mapping())
     }
 
 }
 
 pub struct TileData2 {
-    samples: Vec<PixelSample>,
-    thread_id: usize
+    data: TileData,
+    thread_id: usize,
+    sequence: usize
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TileJob {
+    tile: Tile,
+    tile_index: usize,
+    sample_index: usize,
+    sequence: usize
 }
 
 enum Job {
-    Tile(Tile),
+    Tile(TileJob),
     Close
 }
 
+// Thread of remote worker sends back its job when connection to worker is lost
+enum TileMessage {
+    Done(TileData2),
+    Failed { thread_id: usize, job: TileJob }
+}
+
+// Reason why rendering stopped before all samples per pixel were rendered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    TimeLimit,
+    NoiseTarget,
+    Cancelled
+}
+
 pub struct Renderer2 {
     scene_data: Arc<SceneData>,
 
     renderig_in_progress: bool,
     tiles: Vec<Tile>,
     threads: Vec<thread::JoinHandle<()>>,
-    receiver: mpsc::Receiver<TileData2>,
+    receiver: mpsc::Receiver<TileMessage>,
     pixel_buffer: PixelBuffer,
     n_tiles_processed: usize,
+    n_tiles_dispatched: usize,
+    pending: BTreeMap<usize, TileData2>,
     senders: Vec<mpsc::Sender<Job>>,
+    thread_alive: Vec<bool>,
+
+    // connections to remote workers are used by threads created at the start of rendering
+    workers: Vec<WorkerConnection>,
+    // jobs of lost workers and unfinished jobs of checkpoint, they are dispatched again
+    // with the same sequence number
+    retry_jobs: Vec<TileJob>,
+    // jobs that are not written to pixel buffer yet, by sequence number
+    unfinished_jobs: BTreeMap<usize, TileJob>,
+    lost_workers: usize,
 
+    // tiles that are rendered in current spp pass
+    current_pass: usize,
+    pass_tiles: Vec<usize>,
+    pass_position: usize,
+    all_passes_dispatched: bool,
+    // passes included in denoised preview
+    denoised_passes: usize,
+    // seeds and windows of merged checkpoints
+    merged: Vec<(u64, CropWindow)>,
+
+    render_time: Duration,
+    call_start: Instant,
+    last_checkpoint: Instant,
+    stop_reason: Option<StopReason>,
+    cancel_token: CancelToken,
+    n_samples: u64,
+    n_rays: u64
 }
 
 impl Renderer2 {
 
     pub fn new(mut sc_data: SceneData) -> Renderer2 {
-        let (width, height) = sc_data.image_size();
         sc_data.prepare();
+        let tiles = create_scene_tiles(&sc_data);
+        let pixel_buffer = create_pixel_buffer(&sc_data);
+        let pass_tiles = (0..tiles.len()).collect();
         // ah, when reciever is in Option than he borrow self and I can't use write_samples method
-        let (_tx, reciver): (mpsc::Sender<TileData2>, mpsc::Receiver<TileData2>) = mpsc::channel();
+        let (_tx, reciver): (mpsc::Sender<TileMessage>, mpsc::Receiver<TileMessage>) = mpsc::channel();
         Renderer2 {
+            all_passes_dispatched: sc_data.get_samples_per_pixel() == 0,
             scene_data: Arc::new(sc_data),
             renderig_in_progress: false,
-            tiles: create_tiles(width, height, 16),
+            tiles,
             threads: Vec::new(),
             receiver: reciver,
-            pixel_buffer: PixelBuffer::new(width, height),
+            pixel_buffer,
             n_tiles_processed: 0,
-            senders: Vec::new()
+            n_tiles_dispatched: 0,
+            pending: BTreeMap::new(),
+            senders: Vec::new(),
+            thread_alive: Vec::new(),
+            workers: Vec::new(),
+            retry_jobs: Vec::new(),
+            unfinished_jobs: BTreeMap::new(),
+            lost_workers: 0,
+            current_pass: 0,
+            pass_tiles,
+            pass_position: 0,
+            denoised_passes: 0,
+            merged: Vec::new(),
+            render_time: Duration::ZERO,
+            call_start: Instant::now(),
+            last_checkpoint: Instant::now(),
+            stop_reason: None,
+            cancel_token: CancelToken::new(),
+            n_samples: 0,
+            n_rays: 0
         }
     }
 
     fn create_threads(&mut self) {
         let n_actual_threads = self.tiles.len().min(self.scene_data.get_nthreads());
-        let (tx, reciver): (mpsc::Sender<TileData2>, mpsc::Receiver<TileData2>) = mpsc::channel();
+        let (tx, reciver): (mpsc::Sender<TileMessage>, mpsc::Receiver<TileMessage>) = mpsc::channel();
 
         self.receiver = reciver;
+        self.thread_alive.clear();
 
         for thread_id in 0..n_actual_threads {
             let sender = tx.clone();
@@ -210,23 +430,72 @@ impl Renderer2 {
             let (tx_job, rec_job): (mpsc::Sender<Job>, mpsc::Receiver<Job>) = mpsc::channel();
 
             let handle = thread::spawn (move || {
-                let mut rng = PCGRng::new(0xf123456789012345, 1000 * thread_id as u64);
-                loop {
-                    let job = rec_job.recv().unwrap();
-                    let tile = match job {
-                        Job::Tile(tile) => tile,
-                        Job::Close => break
-                    };
-                    let samples = render_tile(&tile, &sc_data, &mut rng);
-                    sender.send(TileData2 {samples, thread_id}).unwrap();
+                let spp = sc_data.get_samples_per_pixel();
+                let mut sampler = create_sampler(sc_data.get_sampler_type(), spp, sc_data.get_seed());
+                while let Ok(Job::Tile(job)) = rec_job.recv() {
+                    let data = render_tile(&job.tile, job.sample_index, &sc_data, sampler.as_mut());
+                    // renderer was dropped while rendering
+                    if sender.send(TileMessage::Done(TileData2 {data, thread_id, sequence: job.sequence})).is_err() {
+                        break;
+                    }
+                }
+            });
+            self.threads.push(handle);
+            self.senders.push(tx_job);
+            self.thread_alive.push(true);
+        }
+
+        // remote worker thread forwards jobs over its connection
+        for mut connection in self.workers.drain(..) {
+            let thread_id = self.threads.len();
+            let sender = tx.clone();
+            let (tx_job, rec_job): (mpsc::Sender<Job>, mpsc::Receiver<Job>) = mpsc::channel();
+
+            let handle = thread::spawn (move || {
+                while let Ok(Job::Tile(job)) = rec_job.recv() {
+                    match connection.render_tile(&job.tile, job.sample_index, job.sequence) {
+                        Ok(data) => {
+                            if sender.send(TileMessage::Done(TileData2 {data, thread_id, sequence: job.sequence})).is_err() {
+                                break;
+                            }
+                        },
+                        Err(_) => {
+                            let _result = sender.send(TileMessage::Failed { thread_id, job });
+                            return;
+                        }
+                    }
                 }
+                connection.close();
             });
             self.threads.push(handle);
             self.senders.push(tx_job);
+            self.thread_alive.push(true);
         }
         drop(tx);
     }
 
+    // Connects to worker started with --worker. Worker accepts one connection for each of
+    // its threads, each connection gets the scene and checks that it has the same hash.
+    // Returns number of worker threads. Must be called before rendering starts.
+    pub fn add_worker(&mut self, address: &str, source: &SceneSource) -> Result<usize, Box<dyn Error>> {
+        if self.renderig_in_progress {
+            return Err("Workers can be added only before rendering starts.".into())
+        }
+        let scene_hash = self.scene_data.get_scene_hash();
+        let (connection, nthreads) = WorkerConnection::connect(address, source, scene_hash)?;
+        self.workers.push(connection);
+        for _ in 1..nthreads {
+            let (connection, _nthreads) = WorkerConnection::connect(address, source, scene_hash)?;
+            self.workers.push(connection);
+        }
+        Ok(nthreads)
+    }
+
+    // Number of worker connections that were lost while rendering
+    pub fn lost_workers(&self) -> usize {
+        self.lost_workers
+    }
+
     fn shutdown_threads(&mut self) {
         for sender in self.senders.iter() {
             let _result = sender.send(Job::Close);
@@ -238,8 +507,253 @@ impl Renderer2 {
         self.senders.clear();
     }
 
+    fn is_finished(&self) -> bool {
+        self.all_passes_dispatched && self.n_tiles_processed == self.n_tiles_dispatched
+    }
+
+    // Relative error of tile, tiles are in image space and pixel buffer rows are flipped
+    fn tile_error(&self, tile: &Tile) -> f32 {
+        let (_width, height) = self.scene_data.image_size();
+        self.pixel_buffer.max_relative_error(tile.startx, height - tile.endy, tile.endx, height - tile.starty)
+    }
+
+    // Selects tiles for next spp pass. In adaptive mode only tiles whose error is above
+    // noise threshold are rendered again, so decision is made only when all samples of
+    // previous pass are written. Returns false when there is nothing more to render.
+    fn start_next_pass(&mut self) -> bool {
+        let next_pass = self.current_pass + 1;
+        if next_pass >= self.scene_data.get_samples_per_pixel() {
+            return false;
+        }
+        if let Some(reason) = self.stop_condition() {
+            self.stop_reason = Some(reason);
+            return false;
+        }
+        if let Some(threshold) = self.scene_data.get_noise_threshold() {
+            if next_pass >= self.scene_data.get_min_samples_per_pixel() {
+                let pass_tiles: Vec<usize> = (0..self.tiles.len())
+                    .filter(|index| self.tile_error(&self.tiles[*index]) > threshold)
+                    .collect();
+                if pass_tiles.is_empty() {
+                    return false;
+                }
+                self.pass_tiles = pass_tiles;
+            }
+        }
+        self.current_pass = next_pass;
+        self.pass_position = 0;
+        true
+    }
+
+    // Time limit and noise target are checked only when all tiles of current pass are written,
+    // so every pixel gets the same number of passes. Next pass is not started when it
+    // is expected to end after time limit.
+    fn stop_condition(&self) -> Option<StopReason> {
+        let completed_passes = self.current_pass + 1;
+        if let Some(time_limit) = self.scene_data.get_time_limit() {
+            let elapsed = (self.render_time + self.call_start.elapsed()).as_secs_f32();
+            let pass_time = elapsed / completed_passes as f32;
+            if elapsed + pass_time > time_limit {
+                return Some(StopReason::TimeLimit)
+            }
+        }
+        if let Some(noise_target) = self.scene_data.get_noise_target() {
+            let error = match self.scene_data.get_crop_window() {
+                Some(w) => self.pixel_buffer.mean_relative_error_in(w.x0, w.y0, w.x1, w.y1),
+                None => self.pixel_buffer.mean_relative_error()
+            };
+            if error <= noise_target {
+                return Some(StopReason::NoiseTarget)
+            }
+        }
+        None
+    }
+
+    fn waiting_for_pass(&self) -> bool {
+        let sc = &self.scene_data;
+        let adaptive = sc.get_noise_threshold().is_some() && self.current_pass + 1 >= sc.get_min_samples_per_pixel();
+        let limited = sc.get_time_limit().is_some() || sc.get_noise_target().is_some();
+        (adaptive || limited) && self.n_tiles_processed < self.n_tiles_dispatched
+    }
+
+    // Number of accumulated tiles and expected total number of tiles for all spp passes.
+    // Adaptive sampling and stop conditions can end rendering sooner, so total is upper bound
+    // until rendering is finished. Cancelled rendering keeps expected total.
+    pub fn tiles_progress(&self) -> (usize, usize) {
+        if self.is_finished() && self.stop_reason != Some(StopReason::Cancelled) {
+            return (self.n_tiles_processed, self.n_tiles_processed)
+        }
+        let spp = self.scene_data.get_samples_per_pixel();
+        let remaining_passes = spp.saturating_sub(self.current_pass + 1);
+        let remaining = self.pass_tiles.len() - self.pass_position + remaining_passes * self.pass_tiles.len();
+        (self.n_tiles_processed, self.n_tiles_dispatched + remaining)
+    }
+
+    // Progress counters are updated when tiles are written, so progress can be polled
+    // between calls of render
+    pub fn progress(&self) -> Progress {
+        let (tiles_done, tiles_total) = self.tiles_progress();
+        let elapsed = self.render_time;
+        let secs = elapsed.as_secs_f64();
+        let rate = |count: u64| if secs > 0.0 { count as f64 / secs } else { 0.0 };
+        let eta = if self.is_finished() {
+            Some(Duration::ZERO)
+        } else if tiles_done > 0 {
+            Some(elapsed.mul_f64((tiles_total - tiles_done) as f64 / tiles_done as f64))
+        } else {
+            None
+        };
+        Progress {
+            tiles_done,
+            tiles_total,
+            pass: self.rendered_passes(),
+            passes: self.scene_data.get_samples_per_pixel(),
+            samples: self.n_samples,
+            rays: self.n_rays,
+            samples_per_second: rate(self.n_samples),
+            rays_per_second: rate(self.n_rays),
+            elapsed,
+            eta
+        }
+    }
+
+    // Token can be cancelled from other thread while render is running
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn set_cancel_token(&mut self, cancel_token: CancelToken) {
+        self.cancel_token = cancel_token
+    }
+
+    // Stops rendering threads with Close job, tiles that are already being rendered are
+    // still written, so pixel buffer and progress are consistent after cancel.
+    pub fn cancel(&mut self) {
+        if self.renderig_in_progress {
+            self.shutdown_threads();
+            while let Ok(message) = self.receiver.try_recv() {
+                if let TileMessage::Done(data) = message {
+                    self.commit_tile(data);
+                }
+            }
+            self.renderig_in_progress = false;
+        }
+        if !self.is_finished() {
+            self.stop_reason = Some(StopReason::Cancelled);
+        }
+        self.all_passes_dispatched = true;
+    }
+
+    pub fn pixel_buffer(&self) -> &PixelBuffer {
+        &self.pixel_buffer
+    }
+
+    pub fn scene_data(&self) -> &SceneData {
+        &self.scene_data
+    }
+
+    // Changes scene and starts rendering from the beginning with empty pixel buffer, e.g. when
+    // camera is moved in preview window. Tiles in flight are discarded. Remote workers have
+    // their own copy of the scene, so they are not used after restart. Scene hash no longer
+    // describes the scene file, so checkpoints are not written either.
+    pub fn update_scene<F: FnOnce(&mut SceneData)>(&mut self, update: F) {
+        if self.renderig_in_progress {
+            self.shutdown_threads();
+            while self.receiver.try_recv().is_ok() {}
+            self.renderig_in_progress = false;
+        }
+        self.workers.clear();
+        let scene_data = Arc::get_mut(&mut self.scene_data).expect("Scene is still used by rendering threads");
+        update(scene_data);
+        scene_data.set_checkpoint_file(None);
+        scene_data.prepare();
+        self.tiles = create_scene_tiles(scene_data);
+        self.pixel_buffer = create_pixel_buffer(scene_data);
+        self.all_passes_dispatched = scene_data.get_samples_per_pixel() == 0;
+        self.n_tiles_processed = 0;
+        self.n_tiles_dispatched = 0;
+        self.pending.clear();
+        self.retry_jobs.clear();
+        self.unfinished_jobs.clear();
+        self.current_pass = 0;
+        self.pass_tiles = (0..self.tiles.len()).collect();
+        self.pass_position = 0;
+        self.denoised_passes = 0;
+        self.merged.clear();
+        self.render_time = Duration::ZERO;
+        self.stop_reason = None;
+        self.n_samples = 0;
+        self.n_rays = 0;
+    }
+
+    // Returns prepared scene, e.g. for next frame of animation. Rendering is stopped.
+    pub fn into_scene_data(mut self) -> SceneData {
+        if self.renderig_in_progress {
+            self.shutdown_threads();
+        }
+        match Arc::try_unwrap(self.scene_data) {
+            Ok(scene_data) => scene_data,
+            Err(_) => panic!("Scene is still used by rendering threads")
+        }
+    }
+
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    // Number of spp passes that were started, all of them are rendered when rendering is finished
+    pub fn rendered_passes(&self) -> usize {
+        if self.n_tiles_dispatched == 0 { 0 } else { self.current_pass + 1 }
+    }
+
+    // Jobs are numbered sequentially in the order of dispatching. Returns false if there is
+    // no job available at the moment.
+    fn dispatch_job(&mut self, thread_id: usize) -> bool {
+        if let Some(job) = self.retry_jobs.pop() {
+            let _res = self.senders[thread_id].send(Job::Tile(job));
+            return true;
+        }
+        if self.all_passes_dispatched {
+            return false;
+        }
+        // tiles finished ahead of slow one wait in pending, so their number is bounded
+        if self.n_tiles_dispatched - self.n_tiles_processed >= 2 * self.threads.len() {
+            return false;
+        }
+        if self.pass_position == self.pass_tiles.len() {
+            if self.waiting_for_pass() {
+                return false;
+            }
+            if !self.start_next_pass() {
+                self.all_passes_dispatched = true;
+                return false;
+            }
+        }
+        let sequence = self.n_tiles_dispatched;
+        let tile_index = self.pass_tiles[self.pass_position];
+        let job = TileJob{tile: self.tiles[tile_index], tile_index, sample_index: self.current_pass, sequence};
+        self.unfinished_jobs.insert(sequence, job);
+        let _res = self.senders[thread_id].send(Job::Tile(job));
+        self.pass_position += 1;
+        self.n_tiles_dispatched += 1;
+        true
+    }
+
+    // Tiles are written in the order in which they were dispatched, regardless of which
+    // thread finished first, so accumulation is the same for any number of threads.
+    fn commit_tile(&mut self, data: TileData2) {
+        self.pending.insert(data.sequence, data);
+        while let Some(data) = self.pending.remove(&self.n_tiles_processed) {
+            self.write_samples(&data);
+            self.unfinished_jobs.remove(&self.n_tiles_processed);
+            self.n_tiles_processed += 1;
+        }
+    }
+
+    #[allow(clippy::needless_return, clippy::bool_comparison)]
     pub fn render(&mut self, timeout: Duration) -> bool {
-        if self.n_tiles_processed == self.tiles.len() * self.scene_data.get_samples_per_pixel() {
+        self.update_denoised_preview();
+        if self.is_finished() {
             return true;
         }
 
@@ -248,82 +762,233 @@ impl Renderer2 {
             self.renderig_in_progress = true;
         }
 
-        // state variables
-        let start_time = Instant::now();
-        let mut current_tile = self.n_tiles_processed % self.tiles.len();
-        let mut current_spp = self.n_tiles_processed / self.tiles.len();
-        let mut thread_id = 0;
+        self.call_start = Instant::now();
+        let start_time = self.call_start;
         let mut n_tiles_in_progress = 0;
+        let mut idle_threads: Vec<usize> = (0..self.threads.len()).rev().filter(|id| self.thread_alive[*id]).collect();
 
         loop {
-            let _res = self.senders[thread_id].send(Job::Tile(self.tiles[current_tile]));
-            current_tile += 1;
-            thread_id += 1;
-            n_tiles_in_progress += 1;
-            self.n_tiles_processed += 1;
-            if thread_id == self.threads.len() {
-                break;
-            }
-            if current_tile == self.tiles.len() {
-                current_tile = 0;
-                current_spp += 1;
-                if current_spp == self.scene_data.get_samples_per_pixel() {
-                    break;
+            let render_time = Instant::now() - start_time;
+            if render_time <= timeout && !self.cancel_token.is_cancelled() {
+                while let Some(&thread_id) = idle_threads.last() {
+                    if !self.dispatch_job(thread_id) {
+                        break;
+                    }
+                    idle_threads.pop();
+                    n_tiles_in_progress += 1;
                 }
             }
-        }
-
-        loop {
-            let data = self.receiver.recv().unwrap();
-            n_tiles_in_progress -= 1;
-            let render_time = Instant::now() - start_time;
-            if current_spp == self.scene_data.get_samples_per_pixel() || render_time > timeout {
-                self.write_samples(&data);
+            if n_tiles_in_progress == 0 {
                 break;
             }
 
-            if current_tile == self.tiles.len() {
-                current_tile = 0;
-                current_spp += 1;
-                if current_spp == self.scene_data.get_samples_per_pixel() {
-                    self.write_samples(&data);
-                    break;
+            n_tiles_in_progress -= 1;
+            match self.receiver.recv().unwrap() {
+                TileMessage::Done(data) => {
+                    idle_threads.push(data.thread_id);
+                    self.commit_tile(data);
+                },
+                TileMessage::Failed { thread_id, job } => {
+                    self.thread_alive[thread_id] = false;
+                    self.lost_workers += 1;
+                    self.retry_jobs.push(job);
                 }
             }
-
-            let _res = self.senders[data.thread_id].send(Job::Tile(self.tiles[current_tile]));
-            current_tile += 1;
-            n_tiles_in_progress += 1;
-            self.n_tiles_processed += 1;
-
-            self.write_samples(&data);
         }
+        self.render_time += Instant::now() - start_time;
 
-        for _i in 0..n_tiles_in_progress {
-            let data = self.receiver.recv().unwrap();
-            self.write_samples(&data);
+        if self.cancel_token.is_cancelled() && !self.is_finished() {
+            self.cancel();
+            return true;
         }
-        if self.n_tiles_processed == self.tiles.len() * self.scene_data.get_samples_per_pixel() {
+        if self.is_finished() {
             self.renderig_in_progress = false;
             self.shutdown_threads();
+            self.update_denoised_preview();
             return true;
         }
+        self.update_denoised_preview();
         return false;
     }
 
+    // Preview is denoised when spp pass is started, so previous pass is (nearly) accumulated,
+    // and when rendering is finished
+    fn update_denoised_preview(&mut self) {
+        let passes = if self.is_finished() { self.rendered_passes() } else { self.current_pass };
+        if passes != self.denoised_passes {
+            self.pixel_buffer.update_denoised();
+            self.denoised_passes = passes;
+        }
+    }
+
+    // Splatting across tile borders is safe because only this thread writes to the pixel
+    // buffer and tiles are written in order.
     fn write_samples(&mut self, data: &TileData2) {
-        let (_width, height) = self.scene_data.image_size();
-        for sample in data.samples.iter() {
-            let pdata = PixelData{color: sample.color, weight: 1.0};
-            self.pixel_buffer.add_pixel(sample.x, height - sample.y - 1, &pdata);
+        write_tile_data(&mut self.pixel_buffer, &data.data, &self.scene_data);
+        self.n_samples += data.data.samples.len() as u64;
+        self.n_rays += data.data.rays;
+    }
+
+    // Progress is consistent only between calls of render, when no tile is in flight
+    fn checkpoint_progress(&self) -> RenderProgress {
+        RenderProgress {
+            n_tiles_processed: self.n_tiles_processed,
+            current_pass: self.current_pass,
+            pass_tiles: self.pass_tiles.clone(),
+            pass_position: self.pass_position,
+            unfinished_jobs: self.unfinished_jobs.values().map(|job| (job.tile_index, job.sample_index)).collect(),
+            all_passes_dispatched: self.all_passes_dispatched,
+            render_time: self.render_time,
+            samples: self.n_samples,
+            rays: self.n_rays
         }
     }
+
+    pub fn save_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let sc = &self.scene_data;
+        let (width, height) = sc.image_size();
+        let header = CheckpointHeader {
+            scene_hash: sc.get_scene_hash(),
+            image_hash: sc.get_image_hash(),
+            seed: sc.get_seed(),
+            window: sc.get_crop_window().unwrap_or(CropWindow { x0: 0, y0: 0, x1: width, y1: height }),
+            progress: self.checkpoint_progress()
+        };
+        write_checkpoint(path, &header, &self.pixel_buffer)
+    }
+
+    // Writes checkpoint if checkpoint file is set and checkpoint interval elapsed or
+    // rendering is finished. Returns true if checkpoint was written.
+    pub fn checkpoint(&mut self) -> Result<bool, Box<dyn Error>> {
+        let path = match self.scene_data.get_checkpoint_file() {
+            Some(path) => path,
+            None => return Ok(false)
+        };
+        let interval = Duration::from_secs_f32(self.scene_data.get_checkpoint_interval().max(0.0));
+        if !self.is_finished() && self.last_checkpoint.elapsed() < interval {
+            return Ok(false)
+        }
+        self.save_checkpoint(path)?;
+        self.last_checkpoint = Instant::now();
+        Ok(true)
+    }
+
+    // Continues rendering from checkpoint, must be called before rendering starts.
+    // Checkpoint of different scene is refused.
+    pub fn resume<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        if self.renderig_in_progress || self.n_tiles_dispatched > 0 {
+            return Err("Rendering can be resumed only before it starts.".into())
+        }
+        let progress = read_checkpoint(path, self.scene_data.get_scene_hash(), &mut self.pixel_buffer)?;
+        if progress.pass_tiles.iter().any(|tile| *tile >= self.tiles.len()) ||
+            progress.unfinished_jobs.iter().any(|(tile, _)| *tile >= self.tiles.len()) ||
+            progress.pass_position > progress.pass_tiles.len() {
+            return Err("Checkpoint has invalid progress counters.".into())
+        }
+        self.n_tiles_processed = progress.n_tiles_processed;
+        // unfinished jobs get the next sequence numbers, so they are written in the same order
+        for (i, (tile_index, sample_index)) in progress.unfinished_jobs.into_iter().enumerate() {
+            let sequence = progress.n_tiles_processed + i;
+            let job = TileJob{tile: self.tiles[tile_index], tile_index, sample_index, sequence};
+            self.unfinished_jobs.insert(sequence, job);
+            self.retry_jobs.insert(0, job);
+        }
+        self.n_tiles_dispatched = progress.n_tiles_processed + self.unfinished_jobs.len();
+        self.current_pass = progress.current_pass;
+        self.pass_tiles = progress.pass_tiles;
+        self.pass_position = progress.pass_position;
+        // stop conditions are evaluated again, so render stopped by time limit can be extended
+        self.all_passes_dispatched = self.scene_data.get_samples_per_pixel() == 0;
+        self.render_time = progress.render_time;
+        self.n_samples = progress.samples;
+        self.n_rays = progress.rays;
+        Ok(())
+    }
+
+    // Adds pixels of checkpoint rendered from the same image with other seed, sample count or
+    // crop window. Merged pixels are saved as rendered ones, rendering must not be started.
+    // Checkpoints with the same seed have the same samples, so their windows must not overlap.
+    pub fn merge_checkpoint<P: AsRef<Path>>(&mut self, path: P) -> Result<CheckpointHeader, Box<dyn Error>> {
+        if self.renderig_in_progress || self.n_tiles_dispatched > 0 {
+            return Err("Checkpoints can be merged only before rendering starts.".into())
+        }
+        let mut pixel_buffer = create_pixel_buffer(&self.scene_data);
+        let header = read_checkpoint_pixels(path, self.scene_data.get_image_hash(), &mut pixel_buffer)?;
+        let overlaps = |w: &CropWindow| w.x0 < header.window.x1 && header.window.x0 < w.x1 &&
+            w.y0 < header.window.y1 && header.window.y0 < w.y1;
+        if self.merged.iter().any(|(seed, window)| *seed == header.seed && overlaps(window)) {
+            return Err(format!("Checkpoint has the same seed {} as other merged checkpoint, its samples would be counted twice.", header.seed).into())
+        }
+        self.merged.push((header.seed, header.window));
+        self.pixel_buffer.merge(&pixel_buffer)?;
+        self.render_time += header.progress.render_time;
+        self.n_samples += header.progress.samples;
+        self.n_rays += header.progress.rays;
+        Ok(header)
+    }
+
+    // Render settings and elapsed time stored in header of EXR output
+    fn exr_attributes(&self) -> Vec<(String, ExrAttribute)> {
+        let sc = &self.scene_data;
+        let (width, height) = sc.image_size();
+        let text = |name: &str, value: String| (name.to_string(), ExrAttribute::Text(value));
+        let mut attributes = vec![
+            text("resolution", format!("{}x{}", width, height)),
+            ("spp".to_string(), ExrAttribute::Int(sc.get_samples_per_pixel() as i32)),
+            text("sampler", format!("{:?}", sc.get_sampler_type())),
+            text("seed", sc.get_seed().to_string()),
+            text("filter", format!("{:?} radius {}", sc.get_filter().filter_type(), sc.get_filter().radius())),
+            text("algorithm", format!("{:?}", sc.rendering_algorithm)),
+            ("renderedSpp".to_string(), ExrAttribute::Int(self.rendered_passes() as i32)),
+            ("renderTime".to_string(), ExrAttribute::Float(self.render_time.as_secs_f32()))
+        ];
+        if let Some(threshold) = sc.get_noise_threshold() {
+            attributes.push(("noiseThreshold".to_string(), ExrAttribute::Float(threshold)));
+        }
+        if let Some(w) = sc.get_crop_window() {
+            attributes.push(text("cropWindow", format!("{} {} {} {}", w.x0, w.y0, w.x1, w.y1)));
+        }
+        attributes
+    }
+
+    // Pixels that are written to output, only crop window is kept when crop is set
+    fn output_buffer(&self) -> Option<PixelBuffer> {
+        let w = self.scene_data.get_crop_window()?;
+        let cropped = self.scene_data.get_crop_output() == CropOutput::Cropped;
+        Some(self.pixel_buffer.region(w.x0, w.y0, w.x1, w.y1, cropped))
+    }
+
     pub fn save(&self) -> Result<(), Box<dyn Error>> {
-        self.pixel_buffer.save(self.scene_data.get_output_file(), self.scene_data.get_tmo_type())
+        let output = self.scene_data.get_output_file();
+        let precision = self.scene_data.get_exr_precision();
+        let path = Path::new(&output);
+        let region = self.output_buffer();
+        let pixel_buffer = region.as_ref().unwrap_or(&self.pixel_buffer);
+        if path.extension().and_then(|ext| ext.to_str()) == Some("exr") {
+            return pixel_buffer.save_as_exr(path, precision, &self.exr_attributes())
+        }
+        pixel_buffer.save(path, self.scene_data.get_tone_mapping())?;
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("output");
+        // AOVs of LDR image are written next to it, e.g. image.png -> image_aovs.exr
+        if !pixel_buffer.aov_types().is_empty() || pixel_buffer.lpe_count() > 0 {
+            let aov_path = path.with_file_name(format!("{}_aovs.exr", stem));
+            pixel_buffer.save_aovs(aov_path, precision, &self.exr_attributes())?;
+        }
+        // LDR images have no header for data window, so it is described in image_crop.json
+        if let Some(w) = self.scene_data.get_crop_window() {
+            let (width, height) = self.scene_data.image_size();
+            let crop = json!({
+                "resolution": [width, height],
+                "crop": [w.x0, w.y0, w.x1, w.y1],
+                "cropped": self.scene_data.get_crop_output() == CropOutput::Cropped
+            });
+            fs::write(path.with_file_name(format!("{}_crop.json", stem)), format!("{:#}\n", crop))?;
+        }
+        Ok(())
     }
 
     pub fn to_rgb_vector(&self) -> Vec<u32> {
-        self.pixel_buffer.to_rgb_vector(self.scene_data.get_tmo_type())
+        self.pixel_buffer.to_rgb_vector(self.scene_data.get_tone_mapping())
     }
 }
 
@@ -331,7 +996,373 @@ impl Renderer2 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::pixel_buffer::TMOType;
+    use crate::aov::AovType;
+    use crate::tonemap::{TMOType, ToneMapping};
+    use crate::lights::PointLight;
+    use crate::filter::{Filter, FilterType};
+    use crate::lpe::Lpe;
+    use crate::materials::{MatteMaterial, MatteEmissiveMaterial};
+    use crate::sampler::SamplerType;
+    use crate::shapes::{Shape, Sphere};
+    use crate::camera::{CameraKeyframe, CameraTrack};
+    use crate::transform::{AnimatedTransform, Interpolation, Keyframe, Quaternion};
+    use crate::vec::f32x3;
+    use crate::render::{DebugMode, id_color};
+    use crate::scene::Crop;
+    use crate::builder::SceneBuilder;
+
+    fn sphere_scene(nthreads: usize, sampler_type: SamplerType) -> SceneData {
+        let mut scene_data = SceneData::default();
+        scene_data.set_image_size(40, 30);
+        scene_data.set_samples_per_pixel(4);
+        scene_data.set_nthreads(nthreads);
+        scene_data.set_sampler_type(sampler_type);
+        scene_data.set_seed(7);
+        scene_data.set_rendering_algorithm(RenderingAlgorithm::PathTracer);
+        scene_data.set_camera_horizontal_fov(60.0);
+        let material_id = scene_data.add_material(Box::new(MatteMaterial::new(Color{red: 0.8, green: 0.5, blue: 0.2})));
+        scene_data.add_shape(Shape::new(Box::new(Sphere::new(f32x3(0.0, 0.0, 5.0), 1.5)), material_id));
+        scene_data.add_light(Box::new(PointLight::new(Color{red: 5.0, green: 5.0, blue: 5.0}, f32x3(2.0, 2.0, 2.0))));
+        scene_data.create_area_lights();
+        scene_data
+    }
+
+    fn render_scene(scene_data: SceneData) -> Renderer2 {
+        let mut ren = Renderer2::new(scene_data);
+        while !ren.render(Duration::from_millis(1)) {}
+        ren
+    }
+
+    fn render_sphere(nthreads: usize, sampler_type: SamplerType) -> Renderer2 {
+        render_scene(sphere_scene(nthreads, sampler_type))
+    }
+
+    #[test]
+    fn deterministic_render () {
+        for sampler_type in [SamplerType::Independent, SamplerType::Sobol] {
+            let ren1 = render_sphere(1, sampler_type);
+            let ren2 = render_sphere(5, sampler_type);
+            for y in 0..30 {
+                for x in 0..40 {
+                    let p1 = ren1.pixel_buffer.get_pixel(x, y);
+                    let p2 = ren2.pixel_buffer.get_pixel(x, y);
+                    assert_eq!(p1.color.red.to_bits(), p2.color.red.to_bits());
+                    assert_eq!(p1.color.green.to_bits(), p2.color.green.to_bits());
+                    assert_eq!(p1.color.blue.to_bits(), p2.color.blue.to_bits());
+                    assert_eq!(p1.weight.to_bits(), p2.weight.to_bits());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resume_from_checkpoint () {
+        let path = std::env::temp_dir().join("rs_tracer_resume_test.ckpt");
+        let scene = |scene_hash| {
+            let mut scene_data = sphere_scene(2, SamplerType::Sobol);
+            scene_data.set_samples_per_pixel(16);
+            scene_data.set_aovs(vec![AovType::Albedo]);
+            scene_data.set_scene_hash(scene_hash);
+            scene_data
+        };
+        let mut ren = Renderer2::new(scene(11));
+        ren.render(Duration::from_millis(1));
+        ren.save_checkpoint(&path).unwrap();
+
+        let mut resumed = Renderer2::new(scene(11));
+        resumed.resume(&path).unwrap();
+        while !resumed.render(Duration::from_millis(1)) {}
+        let full = render_scene(scene(11));
+        for y in 0..30 {
+            for x in 0..40 {
+                let p1 = full.pixel_buffer.get_pixel(x, y);
+                let p2 = resumed.pixel_buffer.get_pixel(x, y);
+                assert_eq!(p1.color.red.to_bits(), p2.color.red.to_bits());
+                assert_eq!(p1.weight.to_bits(), p2.weight.to_bits());
+                assert_eq!(full.pixel_buffer.get_stats(x, y).count, resumed.pixel_buffer.get_stats(x, y).count);
+                assert_eq!(full.pixel_buffer.get_aov(x, y).albedo.red, resumed.pixel_buffer.get_aov(x, y).albedo.red);
+            }
+        }
+
+        // checkpoint of other scene is refused
+        assert!(Renderer2::new(scene(12)).resume(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn limited_render () {
+        let mut scene_data = sphere_scene(3, SamplerType::Sobol);
+        scene_data.set_samples_per_pixel(64);
+        scene_data.set_time_limit(Some(0.0));
+        let ren = render_scene(scene_data);
+        assert_eq!(ren.stop_reason(), Some(StopReason::TimeLimit));
+        assert_eq!(ren.rendered_passes(), 1);
+
+        let mut scene_data = sphere_scene(3, SamplerType::Sobol);
+        scene_data.set_samples_per_pixel(64);
+        scene_data.set_noise_target(Some(0.005));
+        let ren = render_scene(scene_data);
+        assert_eq!(ren.stop_reason(), Some(StopReason::NoiseTarget));
+        let passes = ren.rendered_passes();
+        assert!(passes > 1 && passes < 64, "{}", passes);
+        assert!(ren.pixel_buffer.mean_relative_error() <= 0.005);
+        // whole passes are rendered, so all pixels have the same number of samples
+        for y in 0..30 {
+            for x in 0..40 {
+                assert_eq!(ren.pixel_buffer.get_stats(x, y).count as usize, passes);
+            }
+        }
+    }
+
+    #[test]
+    fn adaptive_render () {
+        let adaptive_scene = |nthreads| {
+            let mut scene_data = sphere_scene(nthreads, SamplerType::Sobol);
+            scene_data.set_image_size(80, 60);
+            scene_data.set_samples_per_pixel(64);
+            scene_data.set_min_samples_per_pixel(4);
+            scene_data.set_noise_threshold(Some(0.01));
+            scene_data
+        };
+        let ren1 = render_scene(adaptive_scene(1));
+        let ren2 = render_scene(adaptive_scene(3));
+
+        // empty background converges after minimum number of samples
+        assert_eq!(ren1.pixel_buffer.get_stats(0, 0).count, 4);
+        let mut total = 0;
+        for y in 0..60 {
+            for x in 0..80 {
+                let count = ren1.pixel_buffer.get_stats(x, y).count;
+                assert!((4..=64).contains(&count));
+                assert_eq!(count, ren2.pixel_buffer.get_stats(x, y).count);
+                let (p1, p2) = (ren1.pixel_buffer.get_pixel(x, y), ren2.pixel_buffer.get_pixel(x, y));
+                assert_eq!(p1.color.red.to_bits(), p2.color.red.to_bits());
+                total += count;
+            }
+        }
+        assert!(total < 64 * 80 * 60);
+        assert!(total > 4 * 80 * 60);
+    }
+
+    #[test]
+    fn render_aovs () {
+        let mut scene_data = sphere_scene(2, SamplerType::Independent);
+        scene_data.set_aovs(vec![AovType::Depth, AovType::ShapeId, AovType::Normal]);
+        let ren = render_scene(scene_data);
+
+        let center = ren.pixel_buffer.get_aov(20, 15);
+        assert_eq!(center.shape_id, 1);
+        assert!((center.depth - 3.5).abs() < 0.05);
+        assert!(center.normal.2 < -0.9);
+        assert_eq!(ren.pixel_buffer.get_aov(0, 0).shape_id, 0);
+        let channels = ren.pixel_buffer.aov_exr_channels();
+        assert_eq!(channels.len(), 5);
+    }
+
+    #[test]
+    fn render_lpes () {
+        let mut scene_data = sphere_scene(2, SamplerType::Independent);
+        let material_id = scene_data.add_material(Box::new(MatteEmissiveMaterial::new(
+            Color{red: 0.5, green: 0.5, blue: 0.5}, Color{red: 2.0, green: 2.0, blue: 2.0})));
+        let group = scene_data.add_light_group("fill");
+        scene_data.set_material_light_group(material_id, group);
+        scene_data.add_shape(Shape::new(Box::new(Sphere::new(f32x3(-2.0, 1.0, 6.0), 1.0)), material_id));
+        scene_data.create_area_lights();
+        scene_data.set_filter(Filter::new(FilterType::Gaussian { sigma: 0.5 }, 1.5));
+        let groups = scene_data.get_light_groups().to_vec();
+        let exprs = [("direct", "CDL"), ("indirect", "CD.+L"), ("specular", "CS.*L"), ("emission", "CL"), ("fill", "C.*L'fill'")];
+        let lpes = exprs.iter().map(|(name, expr)| Lpe::parse(name, expr, &groups).unwrap()).collect();
+        scene_data.set_lpes(lpes);
+        let ren = render_scene(scene_data);
+
+        let buf = &ren.pixel_buffer;
+        let mut fill_sum = 0.0;
+        for y in 0..30 {
+            for x in 0..40 {
+                let beauty = buf.get_pixel(x, y).get_color();
+                let sum = (0..4).fold(Color::zero(), |acum, i| acum + buf.get_lpe_color(x, y, i));
+                assert!((beauty.red - sum.red).abs() <= 1e-4 * beauty.red.max(1.0));
+                assert_eq!(buf.get_lpe_color(x, y, 2).red, 0.0);
+                fill_sum += buf.get_lpe_color(x, y, 4).red;
+            }
+        }
+        assert!(fill_sum > 0.0);
+    }
+
+    #[test]
+    fn render_animation () {
+        let mut scene_data = sphere_scene(2, SamplerType::Independent);
+        let key = |time: f32, x: f32| CameraKeyframe { time, eye: f32x3(x, 0.0, 0.0), look_at: f32x3(x, 0.0, 5.0), hfov: None };
+        scene_data.set_camera_track(Some(CameraTrack::new(vec![key(0.0, 0.0), key(1.0, 3.0)], Interpolation::CatmullRom)));
+        scene_data.set_frames(Some((0, 24)));
+        scene_data.set_output_file("out_###.png".to_string());
+        scene_data.set_frame(0);
+        let first_hash = scene_data.get_scene_hash();
+        let ren = render_scene(scene_data);
+        assert!(ren.pixel_buffer.get_pixel(20, 15).color.red > 0.0);
+
+        // only camera moves, so bounds of shapes are reused
+        let mut scene_data = ren.into_scene_data();
+        scene_data.set_frame(24);
+        assert!(!scene_data.needs_prepare());
+        assert_eq!(scene_data.get_output_file(), "out_024.png");
+        assert_ne!(scene_data.get_scene_hash(), first_hash);
+        let ren = render_scene(scene_data);
+        assert_eq!(ren.pixel_buffer.get_pixel(20, 15).color.red, 0.0);
+
+        let mut scene_data = ren.into_scene_data();
+        let keyframes = vec![
+            Keyframe::new(0.0, f32x3(0.0, 0.0, 0.0), Quaternion::identity(), f32x3(1.0, 1.0, 1.0)),
+            Keyframe::new(1.0, f32x3(0.0, 2.0, 0.0), Quaternion::identity(), f32x3(1.0, 1.0, 1.0))
+        ];
+        let sphere = Box::new(Sphere::new(f32x3(-2.0, 0.0, 5.0), 0.5));
+        scene_data.add_shape(Shape::with_transform(sphere, 0, AnimatedTransform::with_interpolation(keyframes, Interpolation::Bezier)));
+        scene_data.prepare();
+        scene_data.set_frame(12);
+        assert!(scene_data.needs_prepare());
+        assert_eq!(crate::scene::frame_file_name("anim.png", 3), "anim_0003.png");
+    }
+
+    #[test]
+    fn render_debug_modes () {
+        let render_mode = |mode: DebugMode| {
+            let mut scene_data = sphere_scene(1, SamplerType::Independent);
+            scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(mode));
+            let ren = render_scene(scene_data);
+            (ren.pixel_buffer.get_pixel(20, 15).get_color(), ren.pixel_buffer.get_pixel(0, 0).get_color())
+        };
+        let (center, corner) = render_mode(DebugMode::Depth);
+        assert!((center.red - 3.5).abs() < 0.1 && corner.red == 0.0);
+        let (center, _corner) = render_mode(DebugMode::ShadingNormal);
+        assert!(center.blue < 0.1 && (center.green - 0.5).abs() < 0.1);
+        let (center, _corner) = render_mode(DebugMode::ShapeId);
+        assert!((center.green - id_color(0).green).abs() < 1e-4);
+        // ray that hits the sphere tests it, corner ray misses its bounding box
+        let (center, corner) = render_mode(DebugMode::PrimitiveTests);
+        assert!(center.red > corner.red && corner.blue > 0.99);
+
+        // with second sphere behind the first BVH has root and two leaves, center ray visits all
+        // of them and corner ray only the root
+        let mut scene_data = sphere_scene(1, SamplerType::Independent);
+        let material_id = scene_data.get_shape(0).material_id;
+        scene_data.add_shape(Shape::new(Box::new(Sphere::new(f32x3(0.0, 0.0, 8.0), 1.0)), material_id));
+        scene_data.set_rendering_algorithm(RenderingAlgorithm::Debug(DebugMode::NodeVisits));
+        let ren = render_scene(scene_data);
+        let (center, corner) = (ren.pixel_buffer.get_pixel(20, 15).get_color(), ren.pixel_buffer.get_pixel(0, 0).get_color());
+        assert!(center.red > 0.99 && corner.red < 0.01);
+        assert!((corner.green - 2.0 / 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn render_crop () {
+        let mut scene_data = sphere_scene(2, SamplerType::Independent);
+        scene_data.set_crop(Some(Crop::Pixels([10, 5, 30, 20])));
+        scene_data.set_crop_output(CropOutput::Cropped);
+        let output = std::env::temp_dir().join("test_crop.exr");
+        scene_data.set_output_file(output.to_str().unwrap().to_string());
+        let ren = render_scene(scene_data);
+        assert_eq!(ren.pixel_buffer.get_pixel(20, 12).weight, 4.0);
+        assert_eq!(ren.pixel_buffer.get_pixel(0, 0).weight, 0.0);
+        assert_eq!(ren.pixel_buffer.get_pixel(39, 29).weight, 0.0);
+        ren.save().unwrap();
+        let image = exr::prelude::read_all_flat_layers_from_file(&output).unwrap();
+        assert_eq!(image.attributes.display_window.size, exr::prelude::Vec2(40, 30));
+        assert_eq!(image.layer_data[0].size, exr::prelude::Vec2(20, 15));
+        assert_eq!(image.layer_data[0].attributes.layer_position, exr::prelude::Vec2(10, 5));
+
+        let region = ren.pixel_buffer.region(10, 5, 30, 20, false);
+        assert_eq!(region.size(), (40, 30));
+        assert_eq!(region.get_pixel(10, 5).weight, ren.pixel_buffer.get_pixel(10, 5).weight);
+
+        // normalized crop given on command line is resolved against resolution
+        let json = r#"{"global": {"resolution": [40, 30]}}"#;
+        let json = crate::json::set_json_value(json, "global.crop", serde_json::json!([0.25, 0.0, 0.5, 0.5])).unwrap();
+        let scene_data = crate::json::parse_json_str(&json).unwrap();
+        assert_eq!(scene_data.get_crop_window(), Some(CropWindow { x0: 10, y0: 0, x1: 20, y1: 15 }));
+        assert!(crate::json::parse_json_str(r#"{"global": {"resolution": [40, 30], "crop": [50, 0, 60, 10]}}"#).is_err());
+    }
+
+    #[test]
+    fn merge_checkpoints () {
+        let render_part = |seed: u64, crop: Option<Crop>, name: &str| {
+            let mut scene_data = sphere_scene(1, SamplerType::Independent);
+            scene_data.set_seed(seed);
+            scene_data.set_crop(crop);
+            let ren = render_scene(scene_data);
+            let path = std::env::temp_dir().join(name);
+            ren.save_checkpoint(&path).unwrap();
+            (ren, path)
+        };
+        let (ren1, path1) = render_part(1, None, "test_merge1.ckpt");
+        let (ren2, path2) = render_part(2, Some(Crop::Pixels([0, 0, 20, 30])), "test_merge2.ckpt");
+        let (_ren3, path3) = render_part(2, Some(Crop::Pixels([20, 0, 40, 30])), "test_merge3.ckpt");
+
+        let mut ren = Renderer2::new(sphere_scene(1, SamplerType::Independent));
+        assert_eq!(ren.merge_checkpoint(&path1).unwrap().progress.samples, 40 * 30 * 4);
+        let header = ren.merge_checkpoint(&path2).unwrap();
+        assert_eq!((header.seed, header.window), (2, CropWindow { x0: 0, y0: 0, x1: 20, y1: 30 }));
+        let (p1, p2, merged) = (ren1.pixel_buffer.get_pixel(5, 15), ren2.pixel_buffer.get_pixel(5, 15), ren.pixel_buffer.get_pixel(5, 15));
+        assert_eq!(merged.weight, 8.0);
+        assert!((merged.get_color().red - 0.5 * (p1.get_color().red + p2.get_color().red)).abs() < 1e-5);
+        assert_eq!(ren.pixel_buffer.get_stats(5, 15).count, 8);
+        assert_eq!(ren.pixel_buffer.get_pixel(35, 15).weight, 4.0);
+
+        // the same seed is merged only when windows don't overlap, other samples would be the same
+        assert!(ren.merge_checkpoint(&path1).is_err());
+        ren.merge_checkpoint(&path3).unwrap();
+        assert_eq!(ren.pixel_buffer.get_pixel(35, 15).weight, 8.0);
+
+        let mut other = sphere_scene(1, SamplerType::Independent);
+        other.set_image_hash(1);
+        assert!(Renderer2::new(other).merge_checkpoint(&path1).is_err());
+        for path in [path1, path2, path3] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn builder_scene_hashes () {
+        let build = |seed: u64, radius: f32| SceneBuilder::new()
+            .resolution(40, 30)
+            .seed(seed)
+            .matte("white", Color { red: 0.8, green: 0.8, blue: 0.8 })
+            .sphere(f32x3(0.0, 0.0, 5.0), radius, "white")
+            .build().unwrap();
+        let scene_data = build(1, 1.0);
+        assert_ne!(scene_data.get_image_hash(), 0);
+        assert_ne!(scene_data.get_scene_hash(), scene_data.get_image_hash());
+        assert_eq!(build(1, 1.0).get_scene_hash(), scene_data.get_scene_hash());
+        // other seed renders the same image, other radius doesn't
+        assert_eq!(build(2, 1.0).get_image_hash(), scene_data.get_image_hash());
+        assert_ne!(build(2, 1.0).get_scene_hash(), scene_data.get_scene_hash());
+        assert_ne!(build(1, 2.0).get_image_hash(), scene_data.get_image_hash());
+    }
+
+    #[test]
+    fn restart_with_updated_scene () {
+        let mut ren = Renderer2::new(sphere_scene(2, SamplerType::Sobol));
+        ren.render(Duration::from_millis(1));
+        ren.update_scene(|scene_data| {
+            scene_data.set_camera_pos(f32x3(0.5, 0.0, 0.0));
+            scene_data.set_samples_per_pixel(2);
+        });
+        assert_eq!(ren.progress().tiles_done, 0);
+        while !ren.render(Duration::from_millis(1)) {}
+
+        let mut scene_data = sphere_scene(2, SamplerType::Sobol);
+        scene_data.set_camera_pos(f32x3(0.5, 0.0, 0.0));
+        scene_data.set_samples_per_pixel(2);
+        let fresh = render_scene(scene_data);
+        assert_eq!(ren.progress().samples, 40 * 30 * 2);
+        for y in 0..30 {
+            for x in 0..40 {
+                let p1 = fresh.pixel_buffer.get_pixel(x, y);
+                let p2 = ren.pixel_buffer.get_pixel(x, y);
+                assert_eq!(p1.color.red.to_bits(), p2.color.red.to_bits());
+                assert_eq!(p1.weight.to_bits(), p2.weight.to_bits());
+            }
+        }
+    }
 
     #[test]
     fn render_tiles () {
@@ -343,6 +1374,6 @@ mod tests {
         }
         let render_time = Instant::now() - start_time;
         println!("Render time {}", render_time.as_millis());
-        ren.pixel_buffer.save("test.jpg", &TMOType::Linear);
+        ren.pixel_buffer.save(std::env::temp_dir().join("test.jpg"), &ToneMapping::new(TMOType::Linear)).unwrap();
     }
 }
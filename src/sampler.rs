@@ -0,0 +1,362 @@
+use crate::pcg::PCGRng;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerType {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol
+}
+
+// Sampler generates sample values for one pixel sample. Each call to get_1d or get_2d
+// consumes next dimension(s) so calls must be made in same order for every sample.
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: usize);
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+// All samplers derive their random stream only from pixel, sample index and seed,
+// so rendered image doesn't depend on number of threads or order of tiles.
+pub fn create_sampler(sampler_type: &SamplerType, samples_per_pixel: usize, seed: u64) -> Box<dyn Sampler + Send> {
+    match sampler_type {
+        SamplerType::Independent => Box::new(IndependentSampler::new(seed)),
+        SamplerType::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel, seed)),
+        SamplerType::Halton => Box::new(HaltonSampler::new(seed)),
+        SamplerType::Sobol => Box::new(SobolSampler::new(seed))
+    }
+}
+
+fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    let mut h = 0x9e3779b97f4a7c15u64;
+    for v in values {
+        h = mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15).wrapping_add(h << 6).wrapping_add(h >> 2));
+    }
+    h
+}
+
+fn u32_to_f32(v: u32) -> f32 {
+    let val = f32::from_bits(0x33800000); // 0x1p-24f, 2^-24
+    (v >> 8) as f32 * val
+}
+
+// Random permutation of index in range 0..n (Kensler, Correlated Multi-Jittered Sampling)
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break
+        }
+    }
+    (i.wrapping_add(seed)) % n
+}
+
+// Hash based Owen scrambling (Burley, Practical Hash-based Owen Scrambling)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
+
+
+pub struct IndependentSampler {
+    seed: u64,
+    rng: PCGRng
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        IndependentSampler { seed, rng: PCGRng::new(0xf123456789012345, seed) }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: usize) {
+        let pixel_hash = hash(&[x as u64, y as u64, self.seed]);
+        self.rng = PCGRng::new(hash(&[pixel_hash, sample_index as u64]), pixel_hash);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.rnd_f32()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.rng.rnd_f32(), self.rng.rnd_f32())
+    }
+}
+
+
+// Jittered sampling where strata of every dimension are randomly permuted between samples.
+// Samples with index beyond samples per pixel fall back to uniform random numbers.
+pub struct StratifiedSampler {
+    samples_per_pixel: usize,
+    seed: u64,
+    pixel_hash: u64,
+    sample_index: usize,
+    dimension: u64,
+    rng: PCGRng
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> Self {
+        StratifiedSampler { samples_per_pixel: samples_per_pixel.max(1), seed, pixel_hash: 0, sample_index: 0, dimension: 0, rng: PCGRng::new(0, 0) }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: usize) {
+        self.pixel_hash = hash(&[x as u64, y as u64, self.seed]);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+        self.rng = PCGRng::new(hash(&[self.pixel_hash, sample_index as u64]), self.pixel_hash);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let n = self.samples_per_pixel;
+        let seed = hash(&[self.pixel_hash, self.dimension]) as u32;
+        self.dimension += 1;
+        if self.sample_index >= n {
+            return self.rng.rnd_f32();
+        }
+        let stratum = permutation_element(self.sample_index as u32, n as u32, seed);
+        ((stratum as f32 + self.rng.rnd_f32()) / n as f32).min(1.0 - f32::EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let n = self.samples_per_pixel;
+        let nx = (n as f32).sqrt().ceil() as usize;
+        let ny = n.div_ceil(nx);
+        let seed = hash(&[self.pixel_hash, self.dimension]) as u32;
+        self.dimension += 2;
+        if self.sample_index >= n {
+            return (self.rng.rnd_f32(), self.rng.rnd_f32());
+        }
+        let stratum = permutation_element(self.sample_index as u32, (nx * ny) as u32, seed) as usize;
+        let sx = stratum % nx;
+        let sy = stratum / nx;
+        let u = (sx as f32 + self.rng.rnd_f32()) / nx as f32;
+        let v = (sy as f32 + self.rng.rnd_f32()) / ny as f32;
+        (u.min(1.0 - f32::EPSILON), v.min(1.0 - f32::EPSILON))
+    }
+}
+
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311
+];
+
+// Radical inverse where every digit is permuted with hash based permutation that
+// depends on digit prefix (Owen scrambling)
+fn owen_scrambled_radical_inverse(base: u32, mut index: u64, seed: u64) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut reversed_digits: u64 = 0;
+    let mut digit_index = 0u64;
+    while 1.0 - inv_base_m < 1.0 && digit_index < 64 {
+        let next = index / base as u64;
+        let digit_value = (index - next * base as u64) as u32;
+        let digit_hash = hash(&[seed, reversed_digits, digit_index]) as u32;
+        let digit_value = permutation_element(digit_value, base, digit_hash);
+        reversed_digits = reversed_digits * base as u64 + digit_value as u64;
+        inv_base_m *= inv_base;
+        index = next;
+        digit_index += 1;
+        if inv_base_m < 1e-9 {
+            break
+        }
+    }
+    ((reversed_digits as f64 * inv_base_m) as f32).min(1.0 - f32::EPSILON)
+}
+
+// Halton sequence with per pixel Owen scrambling. Dimensions beyond prime table use
+// random numbers.
+pub struct HaltonSampler {
+    seed: u64,
+    pixel_hash: u64,
+    sample_index: usize,
+    dimension: usize,
+    rng: PCGRng
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        HaltonSampler { seed, pixel_hash: 0, sample_index: 0, dimension: 0, rng: PCGRng::new(0, 0) }
+    }
+
+    fn sample_dimension(&mut self, dimension: usize) -> f32 {
+        if dimension >= PRIMES.len() {
+            return self.rng.rnd_f32();
+        }
+        let seed = hash(&[self.pixel_hash, dimension as u64]);
+        owen_scrambled_radical_inverse(PRIMES[dimension], self.sample_index as u64, seed)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: usize) {
+        self.pixel_hash = hash(&[x as u64, y as u64, self.seed]);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+        self.rng = PCGRng::new(hash(&[self.pixel_hash, sample_index as u64]), self.pixel_hash);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let val = self.sample_dimension(self.dimension);
+        self.dimension += 1;
+        val
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let u = self.sample_dimension(self.dimension);
+        let v = self.sample_dimension(self.dimension + 1);
+        self.dimension += 2;
+        (u, v)
+    }
+}
+
+
+fn sobol_dim0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+fn sobol_dim1(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut v = 1u32 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Owen scrambled Sobol sampler. Every 1D or 2D request uses first two Sobol dimensions
+// with independently scrambled (shuffled) sample index and scrambled values, so
+// dimensions are padded and decorrelated (Burley 2020).
+pub struct SobolSampler {
+    seed: u64,
+    pixel_hash: u64,
+    sample_index: u32,
+    dimension: u64
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        SobolSampler { seed, pixel_hash: 0, sample_index: 0, dimension: 0 }
+    }
+
+    fn shuffled_index(&self, dim_hash: u64) -> u32 {
+        nested_uniform_scramble(self.sample_index, dim_hash as u32)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: usize) {
+        self.pixel_hash = hash(&[x as u64, y as u64, self.seed]);
+        self.sample_index = sample_index as u32;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dim_hash = hash(&[self.pixel_hash, self.dimension]);
+        self.dimension += 1;
+        let index = self.shuffled_index(dim_hash);
+        u32_to_f32(nested_uniform_scramble(sobol_dim0(index), (dim_hash >> 32) as u32))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dim_hash = hash(&[self.pixel_hash, self.dimension]);
+        self.dimension += 2;
+        let index = self.shuffled_index(dim_hash);
+        let seed = mix_bits(dim_hash);
+        let u = nested_uniform_scramble(sobol_dim0(index), seed as u32);
+        let v = nested_uniform_scramble(sobol_dim1(index), (seed >> 32) as u32);
+        (u32_to_f32(u), u32_to_f32(v))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_stratification(sampler: &mut dyn Sampler, n: usize) {
+        // every 1D and 2D stratum must contain exactly one sample
+        let mut strata_1d = vec![0; n];
+        let nx = (n as f32).sqrt() as usize;
+        let mut strata_2d = vec![0; nx * nx];
+        for i in 0..n {
+            sampler.start_pixel_sample(3, 7, i);
+            let u = sampler.get_1d();
+            let (v1, v2) = sampler.get_2d();
+            assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v1) && (0.0..1.0).contains(&v2));
+            strata_1d[(u * n as f32) as usize] += 1;
+            strata_2d[(v2 * nx as f32) as usize * nx + (v1 * nx as f32) as usize] += 1;
+        }
+        assert!(strata_1d.iter().all(|&c| c == 1));
+        assert!(strata_2d.iter().all(|&c| c == n / (nx * nx)));
+    }
+
+    #[test]
+    fn stratified_sampler() {
+        check_stratification(&mut StratifiedSampler::new(16, 0), 16);
+    }
+
+    #[test]
+    fn sobol_sampler() {
+        check_stratification(&mut SobolSampler::new(0), 64);
+    }
+
+    #[test]
+    fn halton_sampler() {
+        let mut sampler = HaltonSampler::new(0);
+        let mut strata = [0; 8];
+        for i in 0..8 {
+            sampler.start_pixel_sample(1, 2, i);
+            strata[(sampler.get_1d() * 8.0) as usize] += 1;
+        }
+        assert!(strata.iter().all(|&c| c == 1));
+    }
+}
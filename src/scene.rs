@@ -1,18 +1,40 @@
+use std::cell::Cell;
 use std::default::Default;
 
-use crate::bvh::{BVHPrimitive, build_bottom_up_bvh, BVH};
-use crate::camera::PinholeCamera;
+use crate::aov::AovType;
+use crate::bvh::{BVH, BVHPrimitive, BVHStats, TraversalStats, TRAVERSAL_COST, INTERSECTION_COST, build_median_split_bvh, hit_probability};
+use crate::camera::{CameraTrack, PinholeCamera};
+use crate::exr_output::ExrPrecision;
+use crate::filter::Filter;
 use crate::lights::AreaLight;
-use crate::pcg::PCGRng;
-use crate::pixel_buffer::{Color, TMOType};
+use crate::lpe::{Lpe, LpeEvent};
+use crate::sampler::{Sampler, SamplerType};
+use crate::pixel_buffer::Color;
+use crate::postprocess::PostProcess;
+use crate::tonemap::ToneMapping;
 use crate::traits::Zero;
 use crate::vec::{f32x3, f64x3};
 use crate::ray::Ray;
+use crate::render::DebugMode;
 use crate::shapes::{GeometryInterface, Shape};
 use crate::bbox::AABB;
 
 extern crate num_cpus;
 
+thread_local! {
+    static TRACED_RAYS: Cell<u64> = const { Cell::new(0) };
+}
+
+fn count_ray() {
+    TRACED_RAYS.with(|rays| rays.set(rays.get() + 1));
+}
+
+// Number of rays traced by current thread, thread local counter avoids contention
+// between rendering threads
+pub fn traced_rays() -> u64 {
+    TRACED_RAYS.with(|rays| rays.get())
+}
+
 pub struct BSDFEvalSample {
     pub color: Color,
     pub pdfw: f32
@@ -26,7 +48,13 @@ pub struct BSDFSample {
 
 pub trait BSDFInterface {
     fn eval(&self, wo: f32x3, normal: f32x3, wi: f32x3) -> Option<BSDFEvalSample>;
-    fn sample(&self, wo: f32x3, normal: f32x3, rng: &mut PCGRng) -> Option<BSDFSample>;
+    fn sample(&self, wo: f32x3, normal: f32x3, sampler: &mut dyn Sampler) -> Option<BSDFSample>;
+    fn albedo(&self) -> Color {
+        Color::zero()
+    }
+    fn is_specular(&self) -> bool {
+        false
+    }
     fn is_emissive(&self) -> bool {
         false
     }
@@ -50,17 +78,24 @@ pub struct ShapeSample {
 }
 
 pub trait LightInterface {
-    fn illuminate(&self, hit: f32x3, scene_data: &SceneData, rng: &mut PCGRng) -> Option<LightSample>;
+    fn illuminate(&self, hit: f32x3, time: f32, scene_data: &SceneData, sampler: &mut dyn Sampler) -> Option<LightSample>;
     fn is_delta_light(&self) -> bool;
+    // Kind of light for scene statistics
+    fn name(&self) -> &'static str;
     fn is_area_light(&self) -> bool {
         false
     }
+    fn light_group(&self, _scene_data: &SceneData) -> Option<usize> {
+        None
+    }
 }
 
+#[derive(Debug)]
 pub enum RenderingAlgorithm {
     AmbientOcclusion,
     DirectLighting,
-    PathTracer
+    PathTracer,
+    Debug(DebugMode)
 }
 
 pub struct SceneData {
@@ -68,24 +103,102 @@ pub struct SceneData {
     height: usize,
     nthreads: usize,
     samples_per_pixel: usize,
+    min_samples_per_pixel: usize,
+    noise_threshold: Option<f32>,
+    time_limit: Option<f32>,
+    noise_target: Option<f32>,
     camera: PinholeCamera,
     shapes: Vec<Shape<Box<dyn GeometryInterface + Send + Sync>>>,
     materials: Vec<Box<dyn BSDFInterface + Send + Sync>>,
+    material_light_groups: Vec<Option<usize>>,
+    material_names: Vec<String>,
+    light_groups: Vec<String>,
+    lpes: Vec<Lpe>,
     pub lights: Vec<Box<dyn LightInterface + Send + Sync>>,
     pub rendering_algorithm: RenderingAlgorithm,
     output: String,
-    tmo_type: TMOType,
+    checkpoint_file: Option<String>,
+    checkpoint_interval: f32,
+    scene_hash: u64,
+    image_hash: u64,
+    tone_mapping: ToneMapping,
+    exr_precision: ExrPrecision,
+    aovs: Vec<AovType>,
+    denoise: bool,
+    post_process: PostProcess,
+    sampler_type: SamplerType,
+    seed: u64,
+    filter: Filter,
+    frames: Option<(usize, usize)>,
+    fps: f32,
+    frame: Option<usize>,
+    camera_track: Option<CameraTrack>,
+    crop: Option<Crop>,
+    crop_output: CropOutput,
+    acceleration: Acceleration,
 
     bbox_shapes: Vec<AABB>,
-    bvh: Option<BVH>
+    bvh: Option<BVH>,
+    // shutter interval for which bounds of shapes were computed
+    prepared: Option<(f32, f32)>
+}
+
+// Region of image that is rendered, in pixels or as fractions of resolution.
+// Rows are counted from top of image like in output file, ends are exclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Crop {
+    Pixels([usize; 4]),
+    Normalized([f32; 4])
+}
+
+// Crop resolved to pixels of image, window is never empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropWindow {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize
 }
 
+impl CropWindow {
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+}
+
+// Cropped output has size of crop window, full size output has pixels outside of it cleared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CropOutput {
+    Cropped,
+    FullSize
+}
+
+// Structure used to find shapes hit by ray. Flat list tests box of every shape and is
+// kept for comparing traversal costs with BVH.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acceleration {
+    List,
+    Bvh
+}
+
+#[derive(Clone, Copy)]
 pub struct ShadingPoint {
     pub t: f32,
     pub hitpoint: f32x3,
     pub normal: f32x3,
     material_id: usize,
-    pub shape_id: usize
+    pub shape_id: usize,
+    pub time: f32
+}
+
+impl ShadingPoint {
+    pub fn material_id(&self) -> usize {
+        self.material_id
+    }
 }
 
 impl SceneData {
@@ -106,6 +219,7 @@ impl SceneData {
         self.nthreads = nthreads
     }
 
+    #[allow(clippy::needless_return)]
     pub fn get_samples_per_pixel(&self) -> usize {
         return self.samples_per_pixel
     }
@@ -114,6 +228,42 @@ impl SceneData {
         self.samples_per_pixel = samples_per_pixel;
     }
 
+    pub fn get_min_samples_per_pixel(&self) -> usize {
+        self.min_samples_per_pixel
+    }
+
+    pub fn set_min_samples_per_pixel(&mut self, min_samples_per_pixel: usize) {
+        self.min_samples_per_pixel = min_samples_per_pixel.max(1);
+    }
+
+    // When noise threshold is set, rendering is adaptive and samples per pixel is
+    // the maximum number of samples.
+    pub fn get_noise_threshold(&self) -> Option<f32> {
+        self.noise_threshold
+    }
+
+    pub fn set_noise_threshold(&mut self, noise_threshold: Option<f32>) {
+        self.noise_threshold = noise_threshold;
+    }
+
+    // Rendering stops after spp pass when the next pass would exceed time limit in seconds
+    pub fn get_time_limit(&self) -> Option<f32> {
+        self.time_limit
+    }
+
+    pub fn set_time_limit(&mut self, time_limit: Option<f32>) {
+        self.time_limit = time_limit;
+    }
+
+    // Rendering stops after spp pass when average relative error of pixels is below target
+    pub fn get_noise_target(&self) -> Option<f32> {
+        self.noise_target
+    }
+
+    pub fn set_noise_target(&mut self, noise_target: Option<f32>) {
+        self.noise_target = noise_target;
+    }
+
     pub fn set_rendering_algorithm(&mut self, rendering_algorithm: RenderingAlgorithm) {
         self.rendering_algorithm = rendering_algorithm
     }
@@ -122,16 +272,204 @@ impl SceneData {
         self.output = file_path
     }
 
+    // Output of animation frame is numbered, see frame_file_name
     pub fn get_output_file(&self) -> String {
-        self.output.clone()
+        match self.frame {
+            Some(frame) => frame_file_name(&self.output, frame),
+            None => self.output.clone()
+        }
+    }
+
+    pub fn set_checkpoint_file(&mut self, file_path: Option<String>) {
+        self.checkpoint_file = file_path
+    }
+
+    pub fn get_checkpoint_file(&self) -> Option<String> {
+        match self.frame {
+            Some(frame) => self.checkpoint_file.as_ref().map(|path| frame_file_name(path, frame)),
+            None => self.checkpoint_file.clone()
+        }
+    }
+
+    // Minimal time in seconds between two checkpoints
+    pub fn set_checkpoint_interval(&mut self, interval: f32) {
+        self.checkpoint_interval = interval
+    }
+
+    pub fn get_checkpoint_interval(&self) -> f32 {
+        self.checkpoint_interval
+    }
+
+    // Hash of scene description, checkpoint can be resumed only with the same scene
+    pub fn set_scene_hash(&mut self, scene_hash: u64) {
+        self.scene_hash = scene_hash
+    }
+
+    // Every frame of animation has different hash
+    pub fn get_scene_hash(&self) -> u64 {
+        self.frame_hash(self.scene_hash)
+    }
+
+    // Hash of scene without sampling settings (seed, spp, crop, ...), renders with the same
+    // image hash converge to the same image and can be merged
+    pub fn set_image_hash(&mut self, image_hash: u64) {
+        self.image_hash = image_hash
+    }
+
+    pub fn get_image_hash(&self) -> u64 {
+        self.frame_hash(self.image_hash)
+    }
+
+    fn frame_hash(&self, hash: u64) -> u64 {
+        match self.frame {
+            Some(frame) => hash ^ (frame as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15),
+            None => hash
+        }
+    }
+
+    pub fn set_crop(&mut self, crop: Option<Crop>) {
+        self.crop = crop
+    }
+
+    pub fn get_crop(&self) -> Option<Crop> {
+        self.crop
+    }
+
+    // Crop in pixels of current resolution, clamped to image
+    pub fn get_crop_window(&self) -> Option<CropWindow> {
+        let (width, height) = (self.width, self.height);
+        let [x0, y0, x1, y1] = match self.crop? {
+            Crop::Pixels(window) => window,
+            Crop::Normalized([x0, y0, x1, y1]) => {
+                let floor = |v: f32, size: usize| (v * size as f32).floor() as usize;
+                let ceil = |v: f32, size: usize| (v * size as f32).ceil() as usize;
+                [floor(x0, width), floor(y0, height), ceil(x1, width), ceil(y1, height)]
+            }
+        };
+        let x0 = x0.min(width.max(1) - 1);
+        let y0 = y0.min(height.max(1) - 1);
+        Some(CropWindow { x0, y0, x1: x1.min(width).max(x0 + 1), y1: y1.min(height).max(y0 + 1) })
+    }
+
+    pub fn set_crop_output(&mut self, crop_output: CropOutput) {
+        self.crop_output = crop_output
+    }
+
+    pub fn get_crop_output(&self) -> CropOutput {
+        self.crop_output
+    }
+
+    pub fn set_acceleration(&mut self, acceleration: Acceleration) {
+        self.acceleration = acceleration;
+        self.prepared = None;
+    }
+
+    pub fn get_acceleration(&self) -> Acceleration {
+        self.acceleration
+    }
+
+    // Range of frames of animation including the last one
+    pub fn set_frames(&mut self, frames: Option<(usize, usize)>) {
+        self.frames = frames
+    }
+
+    pub fn get_frames(&self) -> Option<(usize, usize)> {
+        self.frames
+    }
+
+    pub fn set_fps(&mut self, fps: f32) {
+        self.fps = fps
+    }
+
+    pub fn get_fps(&self) -> f32 {
+        self.fps
+    }
+
+    pub fn set_camera_track(&mut self, camera_track: Option<CameraTrack>) {
+        self.camera_track = camera_track
+    }
+
+    pub fn get_frame(&self) -> Option<usize> {
+        self.frame
+    }
+
+    // Moves scene to time of frame, frame starts at frame / fps seconds. Camera is placed
+    // according to its track and shutter opens relative to start of frame.
+    pub fn set_frame(&mut self, frame: usize) {
+        let time = frame as f32 / self.fps;
+        self.frame = Some(frame);
+        self.camera.set_time(time);
+        if let Some(key) = self.camera_track.as_ref().map(|track| track.evaluate(time)) {
+            self.camera.set_position(key.eye);
+            self.camera.set_look_at(key.look_at);
+            if let Some(hfov) = key.hfov {
+                self.set_camera_horizontal_fov(hfov);
+            }
+        }
+    }
+
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping
+    }
+
+    pub fn get_tone_mapping(&self) -> &ToneMapping {
+        &self.tone_mapping
+    }
+
+    pub fn set_exr_precision(&mut self, precision: ExrPrecision) {
+        self.exr_precision = precision
+    }
+
+    pub fn get_exr_precision(&self) -> ExrPrecision {
+        self.exr_precision
+    }
+
+    pub fn set_aovs(&mut self, aovs: Vec<AovType>) {
+        self.aovs = aovs
+    }
+
+    pub fn get_aovs(&self) -> &[AovType] {
+        &self.aovs
+    }
+
+    pub fn set_denoise(&mut self, denoise: bool) {
+        self.denoise = denoise
+    }
+
+    pub fn get_denoise(&self) -> bool {
+        self.denoise
+    }
+
+    pub fn set_post_process(&mut self, post_process: PostProcess) {
+        self.post_process = post_process
     }
 
-    pub fn set_tmo_type(&mut self, tmo_type: TMOType) {
-        self.tmo_type = tmo_type
+    pub fn get_post_process(&self) -> &PostProcess {
+        &self.post_process
     }
 
-    pub fn get_tmo_type(&self) -> &TMOType {
-        &self.tmo_type
+    pub fn set_sampler_type(&mut self, sampler_type: SamplerType) {
+        self.sampler_type = sampler_type
+    }
+
+    pub fn get_sampler_type(&self) -> &SamplerType {
+        &self.sampler_type
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed
+    }
+
+    pub fn get_seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter
+    }
+
+    pub fn get_filter(&self) -> &Filter {
+        &self.filter
     }
 
     pub fn set_camera_pos(&mut self, position: f32x3) {
@@ -146,32 +484,98 @@ impl SceneData {
         self.camera.set_view_plane_distance(view_plane_distance);
     }
 
+    pub fn set_camera_shutter(&mut self, shutter_open: f32, shutter_close: f32) {
+        self.camera.set_shutter(shutter_open, shutter_close);
+    }
+
     pub fn set_camera_horizontal_fov(&mut self, fov: f32) {
         let half_width = self.width as f32 * 0.5;
         let view_plane_distance = half_width / (0.5 * fov).to_radians().tan();
         self.camera.set_view_plane_distance(view_plane_distance);
     }
 
+    pub fn get_camera_pos(&self) -> f32x3 {
+        self.camera.position()
+    }
+
+    pub fn get_camera_look_at(&self) -> f32x3 {
+        self.camera.look_at()
+    }
+
+    pub fn get_camera_shutter(&self) -> (f32, f32) {
+        self.camera.shutter()
+    }
+
+    // horizontal field of view in degrees
+    pub fn get_camera_horizontal_fov(&self) -> f32 {
+        let half_width = self.width as f32 * 0.5;
+        2.0 * (half_width / self.camera.view_plane_distance()).atan().to_degrees()
+    }
+
     fn calculate_image_sample(&self, x: usize, y: usize, xp: f32, yp: f32) -> (f32, f32) {
         let img_x = x as f32 - self.width as f32 * 0.5 + xp;
         let img_y = y as f32 - self.height as f32 * 0.5 + yp;
         (img_x, img_y)
     }
 
-    pub fn generate_ray(&self, x: usize, y: usize, xp: f32, yp: f32) -> Ray {
+    pub fn generate_ray(&self, x: usize, y: usize, xp: f32, yp: f32, tp: f32) -> Ray {
         let (img_x, img_y) = self.calculate_image_sample(x, y, xp, yp);
-        self.camera.generate_ray(img_x, img_y)
+        self.camera.generate_ray(img_x, img_y, tp)
     }
 
     pub fn add_shape(&mut self, shape: Shape<Box<dyn GeometryInterface + Send + Sync>>) {
         self.shapes.push(shape);
+        self.prepared = None;
     }
 
     pub fn add_material(&mut self, material: Box<dyn BSDFInterface + Send + Sync>) -> usize {
         self.materials.push(material);
+        self.material_light_groups.push(None);
+        self.material_names.push(String::new());
         self.materials.len() - 1
     }
 
+    pub fn set_material_name(&mut self, material_id: usize, name: &str) {
+        self.material_names[material_id] = name.to_string();
+    }
+
+    // Empty for materials added without name
+    pub fn get_material_name(&self, material_id: usize) -> &str {
+        &self.material_names[material_id]
+    }
+
+    // Returns index of light group, group is created if it doesn't exist
+    pub fn add_light_group(&mut self, name: &str) -> usize {
+        match self.light_groups.iter().position(|group| group == name) {
+            Some(index) => index,
+            None => {
+                self.light_groups.push(name.to_string());
+                self.light_groups.len() - 1
+            }
+        }
+    }
+
+    pub fn get_light_groups(&self) -> &[String] {
+        &self.light_groups
+    }
+
+    // Emissive shapes with this material belong to the light group
+    pub fn set_material_light_group(&mut self, material_id: usize, light_group: usize) {
+        self.material_light_groups[material_id] = Some(light_group);
+    }
+
+    pub fn shape_light_group(&self, shape_id: usize) -> Option<usize> {
+        self.material_light_groups[self.shapes[shape_id].material_id]
+    }
+
+    pub fn set_lpes(&mut self, lpes: Vec<Lpe>) {
+        self.lpes = lpes
+    }
+
+    pub fn get_lpes(&self) -> &[Lpe] {
+        &self.lpes
+    }
+
     pub fn add_light(&mut self, light: Box<dyn LightInterface + Send + Sync>) {
         self.lights.push(light);
     }
@@ -185,8 +589,8 @@ impl SceneData {
         }
     }
 
-    pub fn generate_shape_sample(&self, shape_id: usize, hit: f32x3, rng: &mut PCGRng) -> Option<ShapeSample> {
-        self.shapes[shape_id].geometry.generate_sample(hit, rng)
+    pub fn generate_shape_sample(&self, shape_id: usize, hit: f32x3, time: f32, sampler: &mut dyn Sampler) -> Option<ShapeSample> {
+        self.shapes[shape_id].generate_sample_at(hit, time, sampler)
     }
 
     pub fn get_emission(&self, shape_id: usize) -> Color {
@@ -194,48 +598,79 @@ impl SceneData {
         self.materials[material_id].emssion()
     }
 
+    pub fn get_albedo(&self, sp: &ShadingPoint) -> Color {
+        self.materials[sp.material_id].albedo()
+    }
+
+    pub fn scatter_event(&self, sp: &ShadingPoint) -> LpeEvent {
+        if self.materials[sp.material_id].is_specular() {
+            LpeEvent::Specular
+        } else {
+            LpeEvent::Diffuse
+        }
+    }
+
     pub fn is_emissive(&self, shape_id: usize) -> bool {
         let material_id = self.shapes[shape_id].material_id;
         self.materials[material_id].is_emissive()
     }
 
+    #[allow(clippy::needless_borrow)]
     pub fn intersect_new(&self, ray: &Ray, tmax: f32) -> Option<ShadingPoint> {
+        count_ray();
         let isect = |prim: usize, origin: f64x3,
                                                  direction: f64x3, tmax: f64| -> Option<f64> {
             let shape = &self.shapes[prim];
-            shape.geometry.intersect(origin, direction, tmax)
+            shape.intersect_at(origin, direction, tmax, ray.time)
         };
 
         if let Some(bvh) = &self.bvh {
-            if let Some(is) = bvh.intersection(&ray, tmax, &isect) {
+            if let Some(is) = bvh.intersection(&ray, tmax, &isect, &mut TraversalStats::default()) {
                 return Some(self.create_shading_point(&ray, is.t, is.primitive))
             }
         }
         None
     }
 
+    #[allow(clippy::needless_return)]
     fn create_shading_point(&self, ray: &Ray, t: f64, shape_id: usize) -> ShadingPoint {
         let shape = &self.shapes[shape_id];
         let hitpoint = ray.origin + t as f32 * ray.direction;
-        let mut normal = shape.geometry.normal(hitpoint);
+        let mut normal = shape.normal_at(hitpoint, ray.time);
         
         if normal.dot(-ray.direction) < 0.0 {
             normal = -normal;
         }
         let material_id = shape.material_id;
-        return ShadingPoint{t: t as f32, hitpoint, normal, material_id, shape_id};
+        return ShadingPoint{t: t as f32, hitpoint, normal, material_id, shape_id, time: ray.time};
     }
 
     pub fn intersect(&self, ray: &Ray, tmax: f32) -> Option<ShadingPoint> {
+        self.intersect_with_stats(ray, tmax).0
+    }
+
+    // Intersection that also reports how much work it took, used by traversal cost view
+    pub fn intersect_with_stats(&self, ray: &Ray, tmax: f32) -> (Option<ShadingPoint>, TraversalStats) {
+        count_ray();
+        let mut stats = TraversalStats::default();
+        if let Some(bvh) = &self.bvh {
+            let isect = |prim: usize, origin: f64x3, direction: f64x3, tmax: f64| -> Option<f64> {
+                self.shapes[prim].intersect_at(origin, direction, tmax, ray.time)
+            };
+            let sp = bvh.intersection(ray, tmax, &isect, &mut stats).map(|is| self.create_shading_point(ray, is.t, is.primitive));
+            return (sp, stats)
+        }
         let origin = f64x3::from(ray.origin);
         let direction = f64x3::from(ray.direction);
         let mut cur_t = tmax as f64;
         let mut cur_shape_index = 0;
         for index in 0..self.bbox_shapes.len() {
             let bbox = &self.bbox_shapes[index];
+            stats.node_visits += 1;
             if bbox.intersection(ray) {
                 let shape = &self.shapes[index];
-                if let Some(t) = shape.geometry.intersect(origin, direction, cur_t) {
+                stats.primitive_tests += 1;
+                if let Some(t) = shape.intersect_at(origin, direction, cur_t, ray.time) {
                     if t < cur_t {
                         cur_t = t;
                         cur_shape_index = index;
@@ -254,37 +689,114 @@ impl SceneData {
         if cur_t != tmax as f64 {
             let shape = &self.shapes[cur_shape_index];
             let hitpoint = ray.origin + cur_t as f32 * ray.direction;
-            let mut normal = shape.geometry.normal(hitpoint);
+            let mut normal = shape.normal_at(hitpoint, ray.time);
             
             if normal.dot(-ray.direction) < 0.0 {
                 normal = -normal;
             }
             let material_id = shape.material_id;
-            return Some(ShadingPoint{t: cur_t as f32, hitpoint, normal, material_id, shape_id: cur_shape_index});
+            return (Some(ShadingPoint{t: cur_t as f32, hitpoint, normal, material_id, shape_id: cur_shape_index, time: ray.time}), stats);
         }
-        None
+        (None, stats)
+    }
+
+    // Bounding volumes that a ray can visit, nodes of BVH or box of every shape in flat list
+    pub fn bounding_volume_count(&self) -> usize {
+        match &self.bvh {
+            Some(bvh) => bvh.node_count(),
+            None => self.bbox_shapes.len()
+        }
+    }
+
+    pub fn shape_count(&self) -> usize {
+        self.shapes.len()
+    }
+
+    pub fn get_shape(&self, shape_id: usize) -> &Shape<Box<dyn GeometryInterface + Send + Sync>> {
+        &self.shapes[shape_id]
+    }
+
+    pub fn material_count(&self) -> usize {
+        self.materials.len()
     }
 
-    pub fn visible_new(&self, p0: f32x3, p1: f32x3) -> bool {
+    pub fn get_material(&self, material_id: usize) -> &(dyn BSDFInterface + Send + Sync) {
+        self.materials[material_id].as_ref()
+    }
+
+    // Bounds of all shapes over shutter interval, valid after prepare
+    pub fn get_bounds(&self) -> Option<AABB> {
+        self.bbox_shapes.iter().copied().reduce(|bounds, bbox| bounds.merge(&bbox))
+    }
+
+    // Statistics of structure used by intersect, without BVH every shape box is tested
+    // by each ray, so the list is one level deep. Valid after prepare.
+    pub fn acceleration_stats(&self) -> BVHStats {
+        if let Some(bvh) = &self.bvh {
+            return bvh.stats()
+        }
+        let mut stats = BVHStats { nodes: self.bbox_shapes.len(), leaves: self.bbox_shapes.len(), ..BVHStats::default() };
+        if let Some(bounds) = self.get_bounds() {
+            stats.depth = 1;
+            stats.sah_cost = self.bbox_shapes.iter()
+                .map(|bbox| TRAVERSAL_COST + INTERSECTION_COST * hit_probability(bbox, &bounds))
+                .sum();
+        }
+        stats
+    }
+
+    // Shapes are intersected as flat list of their boxes when BVH is not built
+    pub fn has_bvh(&self) -> bool {
+        self.bvh.is_some()
+    }
+
+    pub fn acceleration_memory(&self) -> usize {
+        let bvh_memory = self.bvh.as_ref().map_or(0, |bvh| bvh.memory());
+        self.bbox_shapes.len() * std::mem::size_of::<AABB>() + bvh_memory
+    }
+
+    // Surface normal as defined by geometry, not flipped towards the ray like shading normal
+    pub fn geometric_normal(&self, sp: &ShadingPoint) -> f32x3 {
+        self.shapes[sp.shape_id].normal_at(sp.hitpoint, sp.time)
+    }
+
+    pub fn get_uv(&self, sp: &ShadingPoint) -> (f32, f32) {
+        self.shapes[sp.shape_id].uv_at(sp.hitpoint, sp.time)
+    }
+
+    pub fn get_barycentrics(&self, sp: &ShadingPoint) -> Option<f32x3> {
+        self.shapes[sp.shape_id].barycentrics_at(sp.hitpoint, sp.time)
+    }
+
+    pub fn visible_new(&self, p0: f32x3, p1: f32x3, time: f32) -> bool {
+        count_ray();
         let isect = |prim: usize, origin: f64x3,
                                                  direction: f64x3, tmax: f64| -> Option<f64> {
             let shape = &self.shapes[prim];
-            shape.geometry.intersect(origin, direction, tmax)
+            shape.intersect_at(origin, direction, tmax, time)
         };
 
         if let Some(bvh) = &self.bvh {
             let direction = p1 - p0;
             let tmax = direction.length();
-            let ray = Ray::new(p0, direction.normalize());
+            let ray = Ray::new(p0, direction.normalize(), time);
             return bvh.visible(&ray, tmax, &isect);
         }
         true
     }
 
-    pub fn visible(&self, p0: f32x3, p1: f32x3) -> bool {
+    #[allow(clippy::needless_return)]
+    pub fn visible(&self, p0: f32x3, p1: f32x3, time: f32) -> bool {
+        count_ray();
         let direction = p1 - p0;
         let tmax = direction.length();
-        let ray = Ray::new(p0, direction.normalize());
+        let ray = Ray::new(p0, direction.normalize(), time);
+        if let Some(bvh) = &self.bvh {
+            let isect = |prim: usize, origin: f64x3, direction: f64x3, tmax: f64| -> Option<f64> {
+                self.shapes[prim].intersect_at(origin, direction, tmax, time)
+            };
+            return bvh.visible(&ray, tmax, &isect)
+        }
         let origin = f64x3::from(ray.origin);
         let direction = f64x3::from(ray.direction);
 
@@ -292,7 +804,7 @@ impl SceneData {
             let bbox = &self.bbox_shapes[index];
             if bbox.intersection(&ray) {
                 let shape = &self.shapes[index];
-                if let Some(_t) = shape.geometry.intersect(origin, direction, tmax as f64) {
+                if let Some(_t) = shape.intersect_at(origin, direction, tmax as f64, time) {
                     return false
                 }
             }
@@ -305,34 +817,60 @@ impl SceneData {
         material.eval(wo, sp.normal, wi)
     }
 
-    pub fn sample_bsdf(&self, sp: &ShadingPoint, wo: f32x3, rng: &mut PCGRng) -> Option<BSDFSample> {
+    pub fn sample_bsdf(&self, sp: &ShadingPoint, wo: f32x3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
         let material = &self.materials[sp.material_id];
-        material.sample(wo, sp.normal, rng)
+        material.sample(wo, sp.normal, sampler)
     }
 
     pub fn geometry_pdfa(&self, interaction_point: f32x3, sp: &ShadingPoint) -> Option<f32> {
-        self.shapes[sp.shape_id].geometry.pdfa(interaction_point, sp.hitpoint)
+        self.shapes[sp.shape_id].pdfa_at(interaction_point, sp.hitpoint, sp.time)
     }
 
+    // Animated shapes are bounded over their motion during shutter interval. Bounds of static
+    // shapes don't depend on time, so prepared data is reused when only camera moves.
+    // BVH is built over the same bounds.
     pub fn prepare(&mut self) {
+        if !self.needs_prepare() {
+            return
+        }
+        let (time0, time1) = self.camera.shutter_interval();
         self.bbox_shapes.clear();
         for shape in &self.shapes {
-            self.bbox_shapes.push(shape.geometry.bbox());
+            self.bbox_shapes.push(shape.motion_bbox_in(time0, time1));
         }
+        self.prepared = Some((time0, time1));
+        self.bvh = None;
+        if self.acceleration == Acceleration::Bvh && !self.shapes.is_empty() {
+            let prims: Vec<BVHPrimitive> = self.bbox_shapes.iter().enumerate()
+                .map(|(primitive, bbox)| BVHPrimitive { bbox: *bbox, primitive }).collect();
+            self.bvh = Some(build_median_split_bvh(&prims));
+        }
+    }
 
-        // if !self.shapes.is_empty() {
-        //     let mut prims = Vec::new();
-        //     for (index, shape) in self.shapes.iter().enumerate() {
-        //         prims.push(BVHPrimitive{bbox: shape.geometry.bbox(), primitive: index})
-        //     }
-        //     let bvh = build_bottom_up_bvh(&prims);
-        //     self.bvh = Some(bvh);
-        // }
+    pub fn needs_prepare(&self) -> bool {
+        match self.prepared {
+            None => true,
+            Some(interval) => interval != self.camera.shutter_interval() && self.shapes.iter().any(|s| s.is_animated())
+        }
     }
 
 
 }
 
+// File of animation frame. The first run of # in pattern is replaced with frame number padded
+// to its length, e.g. out_####.png -> out_0012.png. Without # the number is appended to file stem.
+pub fn frame_file_name(pattern: &str, frame: usize) -> String {
+    if let Some(start) = pattern.find('#') {
+        let width = pattern[start..].chars().take_while(|c| *c == '#').count();
+        return format!("{}{:0width$}{}", &pattern[..start], frame, &pattern[start + width..], width = width)
+    }
+    let path = std::path::Path::new(pattern);
+    match (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|e| e.to_str())) {
+        (Some(stem), Some(ext)) => path.with_file_name(format!("{}_{:04}.{}", stem, frame, ext)).to_string_lossy().into_owned(),
+        _ => format!("{}_{:04}", pattern, frame)
+    }
+}
+
 impl Default for SceneData {
     fn default() -> Self {
         Self {
@@ -340,15 +878,42 @@ impl Default for SceneData {
             height: 768,
             nthreads: num_cpus::get(),
             samples_per_pixel: 1,
+            min_samples_per_pixel: 8,
+            noise_threshold: None,
+            time_limit: None,
+            noise_target: None,
             camera: PinholeCamera::default(),
             shapes: Vec::new(),
             materials: Vec::new(),
+            material_light_groups: Vec::new(),
+            material_names: Vec::new(),
+            light_groups: Vec::new(),
+            lpes: Vec::new(),
             lights: Vec::new(),
             rendering_algorithm: RenderingAlgorithm::DirectLighting,
             output: "output.png".into(),
-            tmo_type: TMOType::Gamma,
+            checkpoint_file: None,
+            checkpoint_interval: 300.0,
+            scene_hash: 0,
+            image_hash: 0,
+            tone_mapping: ToneMapping::default(),
+            exr_precision: ExrPrecision::Float,
+            aovs: Vec::new(),
+            denoise: false,
+            post_process: PostProcess::default(),
+            sampler_type: SamplerType::Independent,
+            seed: 0,
+            filter: Filter::default(),
+            frames: None,
+            fps: 24.0,
+            frame: None,
+            camera_track: None,
+            crop: None,
+            crop_output: CropOutput::FullSize,
+            acceleration: Acceleration::Bvh,
             bbox_shapes: Vec::new(),
-            bvh: None
+            bvh: None,
+            prepared: None
         }
     }
 }
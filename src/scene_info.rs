@@ -0,0 +1,216 @@
+use std::fmt;
+use std::mem;
+
+use crate::bbox::AABB;
+use crate::bvh::BVHStats;
+use crate::pixel_buffer::PixelBuffer;
+use crate::scene::SceneData;
+use crate::shapes::{GeometryInterface, Shape};
+
+
+// Statistics and problems of scene that can be found before rendering
+pub struct SceneInfo {
+    // counts of primitives and lights by kind, in order of first appearance
+    pub shapes: Vec<(&'static str, usize)>,
+    pub lights: Vec<(&'static str, usize)>,
+    pub bounds: Option<AABB>,
+    pub acceleration: BVHStats,
+    pub flat_acceleration: bool,
+    pub geometry_memory: usize,
+    pub material_memory: usize,
+    pub acceleration_memory: usize,
+    pub image_memory: usize,
+    pub unused_materials: Vec<String>,
+    pub degenerate_triangles: Vec<usize>,
+    pub zero_area_emitters: Vec<usize>
+}
+
+fn count_kind(counts: &mut Vec<(&'static str, usize)>, kind: &'static str) {
+    match counts.iter_mut().find(|(name, _)| *name == kind) {
+        Some((_, count)) => *count += 1,
+        None => counts.push((kind, 1))
+    }
+}
+
+// Zero area gives infinite pdfa when shape is sampled
+fn has_zero_area(area: f32) -> bool {
+    !area.recip().is_finite()
+}
+
+impl SceneInfo {
+    // Scene is prepared, so bounds and acceleration structure are the ones used for rendering
+    pub fn new(scene_data: &mut SceneData) -> SceneInfo {
+        scene_data.prepare();
+        let mut info = SceneInfo {
+            shapes: Vec::new(),
+            lights: Vec::new(),
+            bounds: scene_data.get_bounds(),
+            acceleration: scene_data.acceleration_stats(),
+            flat_acceleration: !scene_data.has_bvh(),
+            geometry_memory: 0,
+            material_memory: 0,
+            acceleration_memory: scene_data.acceleration_memory(),
+            image_memory: 0,
+            unused_materials: Vec::new(),
+            degenerate_triangles: Vec::new(),
+            zero_area_emitters: Vec::new()
+        };
+
+        let (time0, _time1) = scene_data.get_camera_shutter();
+        let mut used = vec![false; scene_data.material_count()];
+        for shape_id in 0..scene_data.shape_count() {
+            let shape = scene_data.get_shape(shape_id);
+            count_kind(&mut info.shapes, shape.name());
+            info.geometry_memory += mem::size_of::<Shape<Box<dyn GeometryInterface + Send + Sync>>>() + mem::size_of_val(shape.geometry.as_ref());
+            used[shape.material_id] = true;
+            let zero_area = has_zero_area(shape.area_at(time0));
+            if zero_area && shape.name() == "triangle" {
+                info.degenerate_triangles.push(shape_id);
+            }
+            if zero_area && scene_data.is_emissive(shape_id) {
+                info.zero_area_emitters.push(shape_id);
+            }
+        }
+        for light in scene_data.lights.iter() {
+            count_kind(&mut info.lights, light.name());
+        }
+        for (material_id, used) in used.iter().enumerate() {
+            info.material_memory += mem::size_of_val(scene_data.get_material(material_id));
+            if !used {
+                let name = scene_data.get_material_name(material_id);
+                info.unused_materials.push(if name.is_empty() { format!("#{}", material_id) } else { name.to_string() });
+            }
+        }
+
+        let (width, height) = scene_data.image_size();
+        let aov_pixels = scene_data.get_denoise() || scene_data.get_aovs().iter().any(|aov| aov.is_geometric());
+        info.image_memory = PixelBuffer::estimate_memory(width, height, aov_pixels, scene_data.get_lpes().len());
+        info
+    }
+
+    pub fn total_memory(&self) -> usize {
+        self.geometry_memory + self.material_memory + self.acceleration_memory + self.image_memory
+    }
+
+    pub fn has_problems(&self) -> bool {
+        !self.unused_materials.is_empty() || !self.degenerate_triangles.is_empty() || !self.zero_area_emitters.is_empty()
+    }
+}
+
+fn format_counts(counts: &[(&'static str, usize)]) -> String {
+    let total: usize = counts.iter().map(|(_, count)| count).sum();
+    let kinds: Vec<String> = counts.iter().map(|(name, count)| format!("{} {}", name, count)).collect();
+    if kinds.is_empty() {
+        return total.to_string()
+    }
+    format!("{} ({})", total, kinds.join(", "))
+}
+
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0)
+    }
+}
+
+// Long lists of shapes are shortened, ids of the first ones are enough to find them
+fn format_ids(ids: &[usize]) -> String {
+    let mut text: Vec<String> = ids.iter().take(10).map(|id| id.to_string()).collect();
+    if ids.len() > 10 {
+        text.push(format!("... {} more", ids.len() - 10));
+    }
+    text.join(", ")
+}
+
+impl fmt::Display for SceneInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Primitives {}", format_counts(&self.shapes))?;
+        writeln!(f, "Lights {}", format_counts(&self.lights))?;
+        if self.lights.iter().any(|(name, _)| *name == "area") {
+            writeln!(f, "  area lights are created for emissive shapes")?;
+        }
+        match &self.bounds {
+            Some(bounds) => writeln!(f, "Bounds ({:.4}, {:.4}, {:.4}) - ({:.4}, {:.4}, {:.4})",
+                bounds.min.0, bounds.min.1, bounds.min.2, bounds.max.0, bounds.max.1, bounds.max.2)?,
+            None => writeln!(f, "Bounds empty")?
+        }
+        if self.flat_acceleration {
+            writeln!(f, "Acceleration flat list of {} shape boxes, SAH cost {:.2}",
+                self.acceleration.nodes, self.acceleration.sah_cost)?;
+        } else {
+            writeln!(f, "Acceleration BVH {} nodes, {} leaves, depth {}, SAH cost {:.2}",
+                self.acceleration.nodes, self.acceleration.leaves, self.acceleration.depth, self.acceleration.sah_cost)?;
+        }
+        writeln!(f, "Memory estimate {} (geometry {}, materials {}, acceleration {}, image {})",
+            format_bytes(self.total_memory()), format_bytes(self.geometry_memory), format_bytes(self.material_memory),
+            format_bytes(self.acceleration_memory), format_bytes(self.image_memory))?;
+        if !self.unused_materials.is_empty() {
+            writeln!(f, "Warning: unused materials {}", self.unused_materials.join(", "))?;
+        }
+        if !self.degenerate_triangles.is_empty() {
+            writeln!(f, "Warning: degenerate triangles with zero area, shapes {}", format_ids(&self.degenerate_triangles))?;
+        }
+        if !self.zero_area_emitters.is_empty() {
+            writeln!(f, "Warning: emitters with zero area, shapes {}", format_ids(&self.zero_area_emitters))?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::SceneBuilder;
+    use crate::pixel_buffer::Color;
+    use crate::scene::Acceleration;
+    use crate::shapes::Sphere;
+    use crate::transform::{AnimatedTransform, Keyframe, Quaternion};
+    use crate::vec::f32x3;
+
+    #[test]
+    fn scene_statistics() {
+        let white = Color { red: 0.8, green: 0.8, blue: 0.8 };
+        let mut scene_data = SceneBuilder::new()
+            .resolution(8, 8)
+            .matte("white", white)
+            .matte("unused", white)
+            .emissive("lamp", white, white)
+            .sphere(f32x3(0.0, 0.0, 0.0), 1.0, "white")
+            .triangle(f32x3(-2.0, -1.0, 0.0), f32x3(2.0, -1.0, 0.0), f32x3(0.0, 3.0, 0.0), "white")
+            .triangle(f32x3(0.0, 0.0, 0.0), f32x3(1.0, 1.0, 1.0), f32x3(2.0, 2.0, 2.0), "lamp")
+            .sphere(f32x3(0.0, 2.0, 0.0), 0.5, "lamp")
+            .point_light(white, f32x3(0.0, 5.0, 0.0))
+            .configure(|scene_data| {
+                // scaled lamp, its normal at center of bbox is not defined
+                let keyframe = Keyframe::new(0.0, f32x3(0.0, -3.0, 0.0), Quaternion::identity(), f32x3(2.0, 2.0, 2.0));
+                let sphere = Box::new(Sphere::new(f32x3(0.0, 0.0, 0.0), 1.0));
+                scene_data.add_shape(Shape::with_transform(sphere, 2, AnimatedTransform::new(vec![keyframe])));
+            })
+            .build().unwrap();
+        let info = SceneInfo::new(&mut scene_data);
+
+        assert_eq!(info.shapes, vec![("sphere", 3), ("triangle", 2)]);
+        assert_eq!(info.lights, vec![("point", 1), ("area", 3)]);
+        let bounds = info.bounds.unwrap();
+        assert_eq!((bounds.min.0, bounds.max.1), (-2.0, 3.0));
+        assert_eq!((info.acceleration.nodes, info.acceleration.leaves, info.acceleration.depth), (9, 5, 4));
+        assert!(info.acceleration.sah_cost > 1.0 && info.acceleration.sah_cost < 18.0);
+        assert!((scene_data.get_shape(4).area_at(0.0) - 16.0 * std::f32::consts::PI).abs() < 1e-3);
+        assert_eq!(info.image_memory, PixelBuffer::estimate_memory(8, 8, false, 0));
+        assert!(info.geometry_memory > 0 && info.total_memory() > info.image_memory);
+        assert_eq!(info.unused_materials, vec!["unused".to_string()]);
+        assert_eq!(info.degenerate_triangles, vec![2]);
+        assert_eq!(info.zero_area_emitters, vec![2]);
+        assert!(info.has_problems());
+        assert!(info.to_string().contains("Primitives 5 (sphere 3, triangle 2)"));
+        assert!(info.to_string().contains("Acceleration BVH 9 nodes, 5 leaves, depth 4"));
+
+        scene_data.set_acceleration(Acceleration::List);
+        let info = SceneInfo::new(&mut scene_data);
+        assert_eq!((info.acceleration.nodes, info.acceleration.depth), (5, 1));
+        assert!(info.acceleration.sah_cost > 5.0 && info.acceleration.sah_cost < 10.0);
+        assert!(info.to_string().contains("Acceleration flat list of 5 shape boxes"));
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use crate::pixel_buffer::Color;
+use crate::progress::ProgressMonitor;
+use crate::renderer::{Renderer2, StopReason};
+use crate::scene::SceneData;
+
+// Types of session API, defined with progress monitor
+pub use crate::progress::{CancelToken, Progress};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderStatus {
+    Finished,
+    Cancelled
+}
+
+// Rendered image in memory. Colors are linear radiance (denoised if denoising is enabled),
+// rgb8 are display values after post processing and tone mapping of the scene.
+#[derive(Debug, Clone)]
+pub struct RenderedImage {
+    pub width: usize,
+    pub height: usize,
+    pub colors: Vec<Color>,
+    pub alpha: Vec<f32>,
+    pub rgb8: Vec<u8>
+}
+
+impl RenderedImage {
+    pub fn get_color(&self, x: usize, y: usize) -> Color {
+        self.colors[y * self.width + x]
+    }
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&Progress) + 'a>;
+
+// Renders scene in calling thread while tiles are rendered by worker threads.
+// Progress callback is called and progress monitor is updated after each update interval.
+pub struct RenderSession<'a> {
+    renderer: Renderer2,
+    monitor: ProgressMonitor,
+    progress_callback: Option<ProgressCallback<'a>>,
+    update_interval: Duration
+}
+
+impl<'a> RenderSession<'a> {
+    pub fn new(scene_data: SceneData) -> RenderSession<'a> {
+        RenderSession {
+            renderer: Renderer2::new(scene_data),
+            monitor: ProgressMonitor::new(),
+            progress_callback: None,
+            update_interval: Duration::from_millis(100)
+        }
+    }
+
+    pub fn on_progress<F: FnMut(&Progress) + 'a>(mut self, callback: F) -> Self {
+        self.progress_callback = Some(Box::new(callback));
+        self
+    }
+
+    pub fn update_interval(mut self, update_interval: Duration) -> Self {
+        self.update_interval = update_interval;
+        self
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.renderer.cancel_token()
+    }
+
+    pub fn progress_monitor(&self) -> ProgressMonitor {
+        self.monitor.clone()
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.renderer.progress()
+    }
+
+    pub fn renderer(&self) -> &Renderer2 {
+        &self.renderer
+    }
+
+    pub fn run(&mut self) -> RenderStatus {
+        loop {
+            let is_finished = self.renderer.render(self.update_interval);
+            let progress = self.progress();
+            self.monitor.set(progress);
+            if let Some(callback) = self.progress_callback.as_mut() {
+                callback(&progress);
+            }
+            if is_finished {
+                break;
+            }
+        }
+        match self.renderer.stop_reason() {
+            Some(StopReason::Cancelled) => RenderStatus::Cancelled,
+            _ => RenderStatus::Finished
+        }
+    }
+
+    pub fn image(&self) -> RenderedImage {
+        let pixel_buffer = self.renderer.pixel_buffer();
+        let (width, height) = pixel_buffer.size();
+        let rgb8 = pixel_buffer.display_rgb8(self.renderer.scene_data().get_tone_mapping());
+        RenderedImage {
+            width,
+            height,
+            colors: pixel_buffer.colors(),
+            alpha: pixel_buffer.alphas(),
+            rgb8: rgb8.into_iter().flatten().collect()
+        }
+    }
+}
+
+// Renders scene to image in memory
+pub fn render(scene_data: SceneData) -> RenderedImage {
+    let mut session = RenderSession::new(scene_data);
+    session.run();
+    session.image()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::SceneBuilder;
+    use crate::scene::RenderingAlgorithm;
+    use crate::vec::f32x3;
+
+    fn builder() -> SceneBuilder {
+        let white = Color { red: 0.8, green: 0.8, blue: 0.8 };
+        SceneBuilder::new()
+            .resolution(32, 24)
+            .samples_per_pixel(4)
+            .threads(2)
+            .algorithm(RenderingAlgorithm::DirectLighting)
+            .camera(f32x3(0.0, 0.0, -5.0), f32x3(0.0, 0.0, 0.0), 60.0)
+            .matte("white", white)
+            .sphere(f32x3(0.0, 0.0, 0.0), 1.0, "white")
+            .point_light(Color { red: 20.0, green: 20.0, blue: 20.0 }, f32x3(0.0, 3.0, -3.0))
+    }
+
+    #[test]
+    fn render_in_memory() {
+        let mut updates = Vec::new();
+        let mut session = RenderSession::new(builder().build().unwrap())
+            .update_interval(Duration::from_millis(1))
+            .on_progress(|progress| updates.push(*progress));
+        assert_eq!(session.run(), RenderStatus::Finished);
+        let image = session.image();
+        drop(session);
+
+        assert_eq!((image.width, image.height), (32, 24));
+        assert_eq!(image.rgb8.len(), 32 * 24 * 3);
+        assert!(image.get_color(16, 12).red > 0.0);
+        assert_eq!(image.alpha[0], 0.0);
+        let last = updates.last().unwrap();
+        assert_eq!(last.tiles_done, last.tiles_total);
+        assert_eq!(last.fraction(), 1.0);
+        assert_eq!((last.pass, last.passes), (4, 4));
+        assert_eq!(last.samples, 32 * 24 * 4);
+        // primary ray and shadow ray for each hit
+        assert!(last.rays > last.samples);
+        assert_eq!(last.eta, Some(Duration::ZERO));
+        assert!(updates.windows(2).all(|w| w[0].tiles_done <= w[1].tiles_done));
+
+        // cancelled before start, nothing is rendered
+        let mut session = RenderSession::new(builder().build().unwrap());
+        session.cancel_token().cancel();
+        assert_eq!(session.run(), RenderStatus::Cancelled);
+        assert_eq!(session.progress().tiles_done, 0);
+
+        // cancelled from callback while rendering, written tiles are kept
+        let scene_data = builder().samples_per_pixel(64).build().unwrap();
+        let session = RenderSession::new(scene_data).update_interval(Duration::from_millis(1));
+        let token = session.cancel_token();
+        let monitor = session.progress_monitor();
+        let mut session = session.on_progress(move |progress| if progress.tiles_done > 0 { token.cancel() });
+        assert_eq!(session.run(), RenderStatus::Cancelled);
+        let progress = monitor.get();
+        assert!(progress.tiles_done > 0 && progress.tiles_done < progress.tiles_total);
+
+        assert!(builder().sphere(f32x3(0.0, 0.0, 0.0), 1.0, "gold").build().is_err());
+    }
+}
@@ -1,13 +1,21 @@
 
-use crate::{vec::{f32x3, f64x3}, pcg::PCGRng, scene::ShapeSample, onb::ONB, bbox::AABB};
+use crate::{vec::{f32x3, f64x3}, sampler::Sampler, scene::ShapeSample, onb::ONB, bbox::AABB};
+use crate::transform::{AnimatedTransform, Transform};
 use std::f32;
 
 pub trait GeometryInterface {
     fn intersect(&self, origin: f64x3, direction: f64x3, tmax: f64) -> Option<f64>;
     fn normal(&self, hitpoint: f32x3) -> f32x3;
-    fn generate_sample(&self, interaction_point: f32x3, rng: &mut PCGRng) -> Option<ShapeSample>;
+    fn generate_sample(&self, interaction_point: f32x3, sampler: &mut dyn Sampler) -> Option<ShapeSample>;
     fn pdfa(&self, interaction_point: f32x3, position: f32x3) -> Option<f32>;
     fn bbox(&self) -> AABB;
+    // Surface parametrization of hitpoint, both coordinates are in [0, 1]
+    fn uv(&self, hitpoint: f32x3) -> (f32, f32);
+    // Weights of vertices for shapes made of triangles
+    fn barycentrics(&self, hitpoint: f32x3) -> Option<f32x3>;
+    // Kind of primitive for scene statistics
+    fn name(&self) -> &'static str;
+    fn area(&self) -> f32;
 }
 
 pub struct Sphere {
@@ -23,6 +31,7 @@ impl Sphere {
 }
 
 impl GeometryInterface for Sphere {
+    #[allow(clippy::needless_return)]
     fn intersect(&self, origin: f64x3, direction: f64x3, tmax: f64) -> Option<f64> {
         let radius = self.radius as f64;
         let tmp = origin - f64x3::from(self.position);
@@ -70,7 +79,7 @@ impl GeometryInterface for Sphere {
     //     Some(ShapeSample{position, pdfa, normal})
     // }
 
-    fn generate_sample(&self, interaction_point: f32x3, rng: &mut PCGRng) -> Option<ShapeSample> {
+    fn generate_sample(&self, interaction_point: f32x3, sampler: &mut dyn Sampler) -> Option<ShapeSample> {
 
         let light_center_dir = self.position - interaction_point;
         let d2 = light_center_dir.dot(light_center_dir);
@@ -83,10 +92,11 @@ impl GeometryInterface for Sphere {
         let onb = ONB::from(light_center_dir * d.recip());
         let cos_theta_max = (1.0 - radius_sqr / d2).sqrt();
         let pdfw = (2.0 * f32::consts::PI * (1.0 - cos_theta_max)).recip();
-        let cos_theta = 1.0 + rng.rnd_f32() * (cos_theta_max - 1.0);
+        let (u1, u2) = sampler.get_2d();
+        let cos_theta = 1.0 + u1 * (cos_theta_max - 1.0);
         let sin2_theta = 1.0 - cos_theta * cos_theta;
         let sin_theta = sin2_theta.sqrt();
-        let phi = 2.0 * f32::consts::PI * rng.rnd_f32();
+        let phi = 2.0 * f32::consts::PI * u2;
         let x = sin_theta * phi.cos();
         let y = sin_theta * phi.sin();
         let z = cos_theta;
@@ -127,6 +137,27 @@ impl GeometryInterface for Sphere {
         AABB::new(min, max)
     }
 
+    // u goes around vertical axis, v from bottom to top
+    fn uv(&self, hitpoint: f32x3) -> (f32, f32) {
+        let dir = (hitpoint - self.position).normalize();
+        let phi = dir.2.atan2(dir.0);
+        let u = if phi < 0.0 { phi + 2.0 * f32::consts::PI } else { phi } * 0.5 * f32::consts::FRAC_1_PI;
+        let v = 1.0 - dir.1.clamp(-1.0, 1.0).acos() * f32::consts::FRAC_1_PI;
+        (u, v)
+    }
+
+    fn barycentrics(&self, _hitpoint: f32x3) -> Option<f32x3> {
+        None
+    }
+
+
+    fn name(&self) -> &'static str {
+        "sphere"
+    }
+
+    fn area(&self) -> f32 {
+        4.0 * f32::consts::PI * self.radius * self.radius
+    }
 }
 
 
@@ -208,15 +239,16 @@ impl GeometryInterface for Triangle {
         (self.v1 - self.v0).cross(self.v2 - self.v0).normalize()
     }
 
-    fn generate_sample(&self, interaction_point: f32x3, rng: &mut PCGRng) -> Option<ShapeSample> {
-        let (u, v, w) = uniform_sample_triangle(rng.rnd_f32(), rng.rnd_f32());
+    fn generate_sample(&self, _interaction_point: f32x3, sampler: &mut dyn Sampler) -> Option<ShapeSample> {
+        let (u1, u2) = sampler.get_2d();
+        let (u, v, w) = uniform_sample_triangle(u1, u2);
         let position = u * self.v0 + v * self.v1 + w * self.v2;
         let area = (self.v1 - self.v0).cross(self.v2 - self.v1).length() * 0.5;
         let pdfa = area.recip();
         Some(ShapeSample{position, pdfa, normal: self.normal(position)})
     }
 
-    fn pdfa(&self, interaction_point: f32x3, position: f32x3) -> Option<f32> {
+    fn pdfa(&self, _interaction_point: f32x3, _position: f32x3) -> Option<f32> {
         let area = (self.v1 - self.v0).cross(self.v2 - self.v1).length() * 0.5;
         let pdfa = area.recip();
         Some(pdfa)
@@ -227,37 +259,226 @@ impl GeometryInterface for Triangle {
         let max = self.v0.max(self.v1).max(self.v2);
         AABB::new(min, max)
     }
+
+    // Triangle has no texture coordinates, weights of v1 and v2 are used
+    fn uv(&self, hitpoint: f32x3) -> (f32, f32) {
+        let weights = self.barycentrics(hitpoint).unwrap_or(f32x3(1.0, 0.0, 0.0));
+        (weights.1, weights.2)
+    }
+
+    fn barycentrics(&self, hitpoint: f32x3) -> Option<f32x3> {
+        let e1 = self.v1 - self.v0;
+        let e2 = self.v2 - self.v0;
+        let p = hitpoint - self.v0;
+        let (d11, d12, d22) = (e1.dot(e1), e1.dot(e2), e2.dot(e2));
+        let (dp1, dp2) = (p.dot(e1), p.dot(e2));
+        let denom = d11 * d22 - d12 * d12;
+        if denom == 0.0 {
+            return None
+        }
+        let b1 = ((d22 * dp1 - d12 * dp2) / denom).clamp(0.0, 1.0);
+        let b2 = ((d11 * dp2 - d12 * dp1) / denom).clamp(0.0, 1.0 - b1);
+        Some(f32x3(1.0 - b1 - b2, b1, b2))
+    }
+
+    fn name(&self) -> &'static str {
+        "triangle"
+    }
+
+    fn area(&self) -> f32 {
+        (self.v1 - self.v0).cross(self.v2 - self.v1).length() * 0.5
+    }
+}
+
+impl<T: GeometryInterface + ?Sized> GeometryInterface for Box<T> {
+    fn intersect(&self, origin: f64x3, direction: f64x3, tmax: f64) -> Option<f64> {
+        (**self).intersect(origin, direction, tmax)
+    }
+
+    fn normal(&self, hitpoint: f32x3) -> f32x3 {
+        (**self).normal(hitpoint)
+    }
+
+    fn generate_sample(&self, interaction_point: f32x3, sampler: &mut dyn Sampler) -> Option<ShapeSample> {
+        (**self).generate_sample(interaction_point, sampler)
+    }
+
+    fn pdfa(&self, interaction_point: f32x3, position: f32x3) -> Option<f32> {
+        (**self).pdfa(interaction_point, position)
+    }
+
+    fn bbox(&self) -> AABB {
+        (**self).bbox()
+    }
+
+    fn uv(&self, hitpoint: f32x3) -> (f32, f32) {
+        (**self).uv(hitpoint)
+    }
+
+    fn barycentrics(&self, hitpoint: f32x3) -> Option<f32x3> {
+        (**self).barycentrics(hitpoint)
+    }
+
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn area(&self) -> f32 {
+        (**self).area()
+    }
 }
 
 pub struct Shape<T> {
     pub geometry: T,
-    pub material_id: usize
+    pub material_id: usize,
+    pub transform: Option<AnimatedTransform>
 }
 
 impl<T> Shape<T> {
     pub fn new(geometry: T, material_id: usize) -> Self {
-        Shape { geometry, material_id }
+        Shape { geometry, material_id, transform: None }
+    }
+
+    pub fn with_transform(geometry: T, material_id: usize, transform: AnimatedTransform) -> Self {
+        Shape { geometry, material_id, transform: Some(transform) }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        match &self.transform {
+            Some(transform) => transform.is_animated(),
+            None => false
+        }
+    }
+
+    fn transform_at(&self, time: f32) -> Option<Transform> {
+        self.transform.as_ref().map(|transform| transform.interpolate(time))
+    }
+}
+
+// Time dependent versions of geometry interface. If shape has transformation
+// geometry is defined in object space and ray or points are transformed at given time.
+impl<T: GeometryInterface> Shape<T> {
+    pub fn intersect_at(&self, origin: f64x3, direction: f64x3, tmax: f64, time: f32) -> Option<f64> {
+        match self.transform_at(time) {
+            None => self.geometry.intersect(origin, direction, tmax),
+            Some(tr) => {
+                // direction is not normalized so t stays the same in world and object space
+                let o = tr.inv_point(f32x3(origin.0 as f32, origin.1 as f32, origin.2 as f32));
+                let d = tr.inv_vector(f32x3(direction.0 as f32, direction.1 as f32, direction.2 as f32));
+                self.geometry.intersect(f64x3::from(o), f64x3::from(d), tmax)
+            }
+        }
+    }
+
+    pub fn normal_at(&self, hitpoint: f32x3, time: f32) -> f32x3 {
+        match self.transform_at(time) {
+            None => self.geometry.normal(hitpoint),
+            Some(tr) => tr.normal(self.geometry.normal(tr.inv_point(hitpoint))).normalize()
+        }
+    }
+
+    pub fn uv_at(&self, hitpoint: f32x3, time: f32) -> (f32, f32) {
+        match self.transform_at(time) {
+            None => self.geometry.uv(hitpoint),
+            Some(tr) => self.geometry.uv(tr.inv_point(hitpoint))
+        }
+    }
+
+    pub fn barycentrics_at(&self, hitpoint: f32x3, time: f32) -> Option<f32x3> {
+        match self.transform_at(time) {
+            None => self.geometry.barycentrics(hitpoint),
+            Some(tr) => self.geometry.barycentrics(tr.inv_point(hitpoint))
+        }
+    }
+
+    pub fn generate_sample_at(&self, interaction_point: f32x3, time: f32, sampler: &mut dyn Sampler) -> Option<ShapeSample> {
+        match self.transform_at(time) {
+            None => self.geometry.generate_sample(interaction_point, sampler),
+            Some(tr) => {
+                let sample = self.geometry.generate_sample(tr.inv_point(interaction_point), sampler)?;
+                let position = tr.point(sample.position);
+                let normal = tr.normal(sample.normal).normalize();
+                let pdfa = sample.pdfa / tr.area_scale(sample.normal);
+                Some(ShapeSample{position, pdfa, normal})
+            }
+        }
+    }
+
+    pub fn pdfa_at(&self, interaction_point: f32x3, position: f32x3, time: f32) -> Option<f32> {
+        match self.transform_at(time) {
+            None => self.geometry.pdfa(interaction_point, position),
+            Some(tr) => {
+                let obj_position = tr.inv_point(position);
+                let pdfa = self.geometry.pdfa(tr.inv_point(interaction_point), obj_position)?;
+                Some(pdfa / tr.area_scale(self.geometry.normal(obj_position)))
+            }
+        }
+    }
+
+    // Exact for triangles and uniformly scaled spheres, area scale is taken at the point where
+    // bbox touches surface on +x side, normal of sphere at its center is not defined
+    pub fn area_at(&self, time: f32) -> f32 {
+        match self.transform_at(time) {
+            None => self.geometry.area(),
+            Some(tr) => {
+                let bbox = self.geometry.bbox();
+                let surface_point = f32x3(bbox.max.0, 0.5 * (bbox.min.1 + bbox.max.1), 0.5 * (bbox.min.2 + bbox.max.2));
+                self.geometry.area() * tr.area_scale(self.geometry.normal(surface_point))
+            }
+        }
+    }
+
+    // bounding box that covers whole motion of the shape
+    pub fn motion_bbox(&self) -> AABB {
+        match &self.transform {
+            None => self.geometry.bbox(),
+            Some(transform) => transform.motion_bounds(&self.geometry.bbox())
+        }
+    }
+
+    // bounding box that covers motion of the shape during time interval
+    pub fn motion_bbox_in(&self, time0: f32, time1: f32) -> AABB {
+        match &self.transform {
+            None => self.geometry.bbox(),
+            Some(transform) => transform.motion_bounds_in(&self.geometry.bbox(), time0, time1)
+        }
     }
 }
 
 impl<T: GeometryInterface + Sync + Send> GeometryInterface for Shape<T> {
     fn intersect(&self, origin: f64x3, direction: f64x3, tmax: f64) -> Option<f64> {
-        self.geometry.intersect(origin, direction, tmax)
+        self.intersect_at(origin, direction, tmax, 0.0)
     }
 
     fn normal(&self, hitpoint: f32x3) -> f32x3 {
-        self.geometry.normal(hitpoint)
+        self.normal_at(hitpoint, 0.0)
     }
 
-    fn generate_sample(&self, interaction_point: f32x3, rng: &mut PCGRng) -> Option<ShapeSample> {
-        self.geometry.generate_sample(interaction_point, rng)
+    fn generate_sample(&self, interaction_point: f32x3, sampler: &mut dyn Sampler) -> Option<ShapeSample> {
+        self.generate_sample_at(interaction_point, 0.0, sampler)
     }
 
     fn pdfa(&self, interaction_point: f32x3, position: f32x3) -> Option<f32> {
-        self.geometry.pdfa(interaction_point, position)
+        self.pdfa_at(interaction_point, position, 0.0)
     }
 
     fn bbox(&self) -> AABB {
-        self.geometry.bbox()
+        self.motion_bbox()
+    }
+
+    fn uv(&self, hitpoint: f32x3) -> (f32, f32) {
+        self.uv_at(hitpoint, 0.0)
+    }
+
+    fn barycentrics(&self, hitpoint: f32x3) -> Option<f32x3> {
+        self.barycentrics_at(hitpoint, 0.0)
+    }
+
+    fn name(&self) -> &'static str {
+        self.geometry.name()
+    }
+
+    fn area(&self) -> f32 {
+        self.area_at(0.0)
     }
 }
@@ -0,0 +1,257 @@
+use crate::pixel_buffer::Color;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TMOType {
+    Linear,
+    Gamma,
+    Srgb,
+    Reinhard,
+    Aces,
+    Hable,
+    Agx
+}
+
+type Matrix3 = [[f32; 3]; 3];
+
+const IDENTITY: Matrix3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// Converts linear scene radiance to display values in range [0, 1]. Exposure (in EV) and
+// white balance are applied before tone mapping operator, display values are sRGB encoded
+// except for Linear and Gamma operators.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMapping {
+    tmo_type: TMOType,
+    exposure: f32,
+    white_balance: Option<f32>,
+    wb_matrix: Matrix3
+}
+
+impl ToneMapping {
+    pub fn new(tmo_type: TMOType) -> ToneMapping {
+        ToneMapping { tmo_type, exposure: 0.0, white_balance: None, wb_matrix: IDENTITY }
+    }
+
+    pub fn tmo_type(&self) -> TMOType {
+        self.tmo_type
+    }
+
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.exposure = exposure
+    }
+
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    // Temperature of illuminant in Kelvins that becomes neutral white (6500K is no change)
+    pub fn set_white_balance(&mut self, temperature: f32) {
+        self.white_balance = Some(temperature);
+        self.wb_matrix = white_balance_matrix(temperature);
+    }
+
+    pub fn white_balance(&self) -> Option<f32> {
+        self.white_balance
+    }
+
+    pub fn apply(&self, color: Color) -> Color {
+        let color = color * 2f32.powf(self.exposure);
+        let color = match self.white_balance {
+            Some(_) => mul(&self.wb_matrix, color),
+            None => color
+        };
+        let color = Color { red: color.red.max(0.0), green: color.green.max(0.0), blue: color.blue.max(0.0) };
+        match self.tmo_type {
+            TMOType::Linear => color,
+            TMOType::Gamma => map(color, |v| v.powf(1.0 / 2.2)),
+            TMOType::Srgb => map(color, srgb_oetf),
+            TMOType::Reinhard => map(color, |v| srgb_oetf(v / (1.0 + v))),
+            TMOType::Aces => map(aces_fitted(color), srgb_oetf),
+            TMOType::Hable => {
+                let white_scale = 1.0 / hable_partial(HABLE_WHITE);
+                map(color, |v| srgb_oetf(hable_partial(v * HABLE_EXPOSURE_BIAS) * white_scale))
+            },
+            TMOType::Agx => map(agx(color), srgb_oetf)
+        }
+    }
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        ToneMapping::new(TMOType::Gamma)
+    }
+}
+
+fn map<F: Fn(f32) -> f32>(color: Color, f: F) -> Color {
+    Color { red: f(color.red), green: f(color.green), blue: f(color.blue) }
+}
+
+fn mul(m: &Matrix3, c: Color) -> Color {
+    Color {
+        red: m[0][0] * c.red + m[0][1] * c.green + m[0][2] * c.blue,
+        green: m[1][0] * c.red + m[1][1] * c.green + m[1][2] * c.blue,
+        blue: m[2][0] * c.red + m[2][1] * c.green + m[2][2] * c.blue
+    }
+}
+
+fn mul_matrix(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+pub fn srgb_oetf(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// ACES RRT and sRGB ODT fit by Stephen Hill
+fn aces_fitted(color: Color) -> Color {
+    const INPUT: Matrix3 = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777]
+    ];
+    const OUTPUT: Matrix3 = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602]
+    ];
+    let rrt_odt_fit = |v: f32| {
+        let a = v * (v + 0.0245786) - 0.000090537;
+        let b = v * (0.983729 * v + 0.432951) + 0.238081;
+        a / b
+    };
+    map(mul(&OUTPUT, map(mul(&INPUT, color), rrt_odt_fit)), |v| v.clamp(0.0, 1.0))
+}
+
+const HABLE_EXPOSURE_BIAS: f32 = 2.0;
+const HABLE_WHITE: f32 = 11.2;
+
+// Uncharted 2 filmic curve by John Hable
+fn hable_partial(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+// AgX base look by Troy Sobotka, with polynomial fit of contrast curve by Benjamin Wrensch.
+// Result is converted back to linear display values.
+fn agx(color: Color) -> Color {
+    const INSET: Matrix3 = [
+        [0.8424791, 0.0784336, 0.07922375],
+        [0.04232824, 0.8784686, 0.07916613],
+        [0.04237565, 0.0784336, 0.879143]
+    ];
+    const OUTSET: Matrix3 = [
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.05289685, 1.151903, -0.09896118],
+        [-0.05297164, -0.09804345, 1.151074]
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+    let contrast = |v: f32| {
+        let v = ((v.max(1e-10).log2() - MIN_EV) / (MAX_EV - MIN_EV)).clamp(0.0, 1.0);
+        let v2 = v * v;
+        let v4 = v2 * v2;
+        15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v - 0.00232
+    };
+    let color = map(mul(&INSET, color), contrast);
+    map(mul(&OUTSET, color), |v| v.clamp(0.0, 1.0).powf(2.2))
+}
+
+// Chromaticity of Planckian locus (Kim et al. 2002 cubic spline approximation)
+fn planckian_xy(temperature: f32) -> (f32, f32) {
+    let t = temperature.clamp(1667.0, 25000.0) as f64;
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+    (x as f32, y as f32)
+}
+
+// Bradford chromatic adaptation from white of given temperature to D65, in linear sRGB
+fn white_balance_matrix(temperature: f32) -> Matrix3 {
+    const SRGB_TO_XYZ: Matrix3 = [
+        [0.4124564, 0.3575761, 0.1804375],
+        [0.2126729, 0.7151522, 0.0721750],
+        [0.0193339, 0.119192, 0.9503041]
+    ];
+    const XYZ_TO_SRGB: Matrix3 = [
+        [3.2404542, -1.5371385, -0.4985314],
+        [-0.969266, 1.8760108, 0.0415560],
+        [0.0556434, -0.2040259, 1.0572252]
+    ];
+    const BRADFORD: Matrix3 = [
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296]
+    ];
+    const BRADFORD_INV: Matrix3 = [
+        [0.9869929, -0.1470543, 0.1599627],
+        [0.4323053, 0.5183603, 0.0492912],
+        [-0.0085287, 0.0400428, 0.9684867]
+    ];
+    let to_lms = |x: f32, y: f32| mul(&BRADFORD, Color { red: x / y, green: 1.0, blue: (1.0 - x - y) / y });
+    let (x, y) = planckian_xy(temperature);
+    let src = to_lms(x, y);
+    let dst = to_lms(0.31271, 0.32902);
+    let scale = [
+        [dst.red / src.red, 0.0, 0.0],
+        [0.0, dst.green / src.green, 0.0],
+        [0.0, 0.0, dst.blue / src.blue]
+    ];
+    let adapt = mul_matrix(&BRADFORD_INV, &mul_matrix(&scale, &BRADFORD));
+    mul_matrix(&XYZ_TO_SRGB, &mul_matrix(&adapt, &SRGB_TO_XYZ))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tone_mapping_operators() {
+        let grey = Color { red: 0.18, green: 0.18, blue: 0.18 };
+        let bright = Color { red: 100.0, green: 100.0, blue: 100.0 };
+        for tmo_type in [TMOType::Srgb, TMOType::Reinhard, TMOType::Aces, TMOType::Hable, TMOType::Agx] {
+            let tm = ToneMapping::new(tmo_type);
+            let (g, b) = (tm.apply(grey), tm.apply(bright));
+            assert!(g.red > 0.1 && g.red < 0.7, "{:?} {}", tmo_type, g.red);
+            assert!(b.red <= 1.0 && b.red > 0.9, "{:?} {}", tmo_type, b.red);
+            assert!(tm.apply(Color { red: 0.0, green: 0.0, blue: 0.0 }).red < 0.05);
+        }
+        assert!((srgb_oetf(0.18) - 0.4614).abs() < 1e-3);
+
+        // one EV doubles linear value
+        let mut tm = ToneMapping::new(TMOType::Linear);
+        tm.set_exposure(1.0);
+        assert!((tm.apply(grey).red - 0.36).abs() < 1e-6);
+
+        // illuminant of given temperature becomes neutral, warm light is cooled down
+        tm.set_exposure(0.0);
+        tm.set_white_balance(6504.0);
+        let c = tm.apply(grey);
+        assert!((c.red - c.blue).abs() < 0.01);
+        tm.set_white_balance(3200.0);
+        let c = tm.apply(grey);
+        assert!(c.blue > c.red);
+    }
+}
@@ -0,0 +1,479 @@
+use crate::bbox::AABB;
+use crate::vec::f32x3;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quaternion {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion { x: 0.0, y: 0.0, z: 0.0, w: 1.0 }
+    }
+
+    // angle is in degrees
+    pub fn from_axis_angle(axis: f32x3, angle: f32) -> Quaternion {
+        let axis = axis.normalize();
+        let half = 0.5 * angle.to_radians();
+        let s = half.sin();
+        Quaternion { x: axis.0 * s, y: axis.1 * s, z: axis.2 * s, w: half.cos() }
+    }
+
+    pub fn dot(self, other: Quaternion) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z + self.w * other.w
+    }
+
+    fn normalize(self) -> Quaternion {
+        let inv_len = 1.0 / self.dot(self).sqrt();
+        Quaternion { x: self.x * inv_len, y: self.y * inv_len, z: self.z * inv_len, w: self.w * inv_len }
+    }
+
+    fn lerp(self, other: Quaternion, t: f32) -> Quaternion {
+        Quaternion {
+            x: self.x + (other.x - self.x) * t,
+            y: self.y + (other.y - self.y) * t,
+            z: self.z + (other.z - self.z) * t,
+            w: self.w + (other.w - self.w) * t
+        }
+    }
+
+    pub fn slerp(self, other: Quaternion, t: f32) -> Quaternion {
+        // take the shorter arc
+        let mut other = other;
+        let mut cos_theta = self.dot(other);
+        if cos_theta < 0.0 {
+            other = Quaternion { x: -other.x, y: -other.y, z: -other.z, w: -other.w };
+            cos_theta = -cos_theta;
+        }
+        if cos_theta > 0.9995 {
+            return self.lerp(other, t).normalize();
+        }
+        let theta = cos_theta.clamp(-1.0, 1.0).acos();
+        let inv_sin = 1.0 / theta.sin();
+        let a = ((1.0 - t) * theta).sin() * inv_sin;
+        let b = (t * theta).sin() * inv_sin;
+        Quaternion {
+            x: a * self.x + b * other.x,
+            y: a * self.y + b * other.y,
+            z: a * self.z + b * other.z,
+            w: a * self.w + b * other.w
+        }
+    }
+
+    // angle between two orientations in radians
+    pub fn angle(self, other: Quaternion) -> f32 {
+        2.0 * self.dot(other).abs().clamp(0.0, 1.0).acos()
+    }
+
+    fn to_matrix(self) -> [[f32; 3]; 3] {
+        let Quaternion { x, y, z, w } = self;
+        [
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - z * w), 2.0 * (x * z + y * w)],
+            [2.0 * (x * y + z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - x * w)],
+            [2.0 * (x * z - y * w), 2.0 * (y * z + x * w), 1.0 - 2.0 * (x * x + y * y)]
+        ]
+    }
+}
+
+
+// Affine transformation, last row of 4x4 matrix is always (0, 0, 0, 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    m: [[f32; 4]; 3],
+    inv: [[f32; 4]; 3]
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        let m = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0]];
+        Transform { m, inv: m }
+    }
+
+    // M = T * R * S, scale components must be non zero so that M can be inverted
+    pub fn from_trs(translation: f32x3, rotation: Quaternion, scale: f32x3) -> Transform {
+        assert!(scale.0 != 0.0 && scale.1 != 0.0 && scale.2 != 0.0, "Zero scale can't be inverted.");
+        let r = rotation.to_matrix();
+        let s = [scale.0, scale.1, scale.2];
+        let t = [translation.0, translation.1, translation.2];
+        let mut m = [[0.0f32; 4]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                m[i][j] = r[i][j] * s[j];
+            }
+            m[i][3] = t[i];
+        }
+
+        // M^-1 = S^-1 * R^T * T^-1
+        let mut inv = [[0.0f32; 4]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                inv[i][j] = r[j][i] / s[i];
+            }
+            inv[i][3] = -(inv[i][0] * t[0] + inv[i][1] * t[1] + inv[i][2] * t[2]);
+        }
+        Transform { m, inv }
+    }
+
+    fn apply_point(m: &[[f32; 4]; 3], p: f32x3) -> f32x3 {
+        f32x3(m[0][0] * p.0 + m[0][1] * p.1 + m[0][2] * p.2 + m[0][3],
+              m[1][0] * p.0 + m[1][1] * p.1 + m[1][2] * p.2 + m[1][3],
+              m[2][0] * p.0 + m[2][1] * p.1 + m[2][2] * p.2 + m[2][3])
+    }
+
+    fn apply_vector(m: &[[f32; 4]; 3], v: f32x3) -> f32x3 {
+        f32x3(m[0][0] * v.0 + m[0][1] * v.1 + m[0][2] * v.2,
+              m[1][0] * v.0 + m[1][1] * v.1 + m[1][2] * v.2,
+              m[2][0] * v.0 + m[2][1] * v.1 + m[2][2] * v.2)
+    }
+
+    pub fn point(&self, p: f32x3) -> f32x3 {
+        Transform::apply_point(&self.m, p)
+    }
+
+    pub fn vector(&self, v: f32x3) -> f32x3 {
+        Transform::apply_vector(&self.m, v)
+    }
+
+    // normals are transformed with inverse transpose, result is not normalized
+    pub fn normal(&self, n: f32x3) -> f32x3 {
+        let inv = &self.inv;
+        f32x3(inv[0][0] * n.0 + inv[1][0] * n.1 + inv[2][0] * n.2,
+              inv[0][1] * n.0 + inv[1][1] * n.1 + inv[2][1] * n.2,
+              inv[0][2] * n.0 + inv[1][2] * n.1 + inv[2][2] * n.2)
+    }
+
+    pub fn inv_point(&self, p: f32x3) -> f32x3 {
+        Transform::apply_point(&self.inv, p)
+    }
+
+    pub fn inv_vector(&self, v: f32x3) -> f32x3 {
+        Transform::apply_vector(&self.inv, v)
+    }
+
+    pub fn determinant(&self) -> f32 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+        m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+        m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    // ratio between world space area and object space area of a surface element with normal n
+    pub fn area_scale(&self, n: f32x3) -> f32 {
+        self.determinant().abs() * self.normal(n).length()
+    }
+
+    pub fn bbox(&self, bbox: &AABB) -> AABB {
+        let mut min = f32x3(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut max = f32x3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for i in 0..8 {
+            let corner = f32x3(if i & 1 == 0 { bbox.min.0 } else { bbox.max.0 },
+                               if i & 2 == 0 { bbox.min.1 } else { bbox.max.1 },
+                               if i & 4 == 0 { bbox.min.2 } else { bbox.max.2 });
+            let p = self.point(corner);
+            min = min.min(p);
+            max = max.max(p);
+        }
+        AABB::new(min, max)
+    }
+}
+
+
+// Interpolation of keyframe values. Catmull-Rom passes through keyframes with smooth tangents.
+// Bezier uses the same handles, but they are flat at the first and last keyframe and at local
+// extremes of each component, so motion eases in and out and never overshoots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    CatmullRom,
+    Bezier
+}
+
+// Tangent per unit of time at keyframe index
+fn tangent(times: &[f32], values: &[f32x3], index: usize, interpolation: Interpolation) -> f32x3 {
+    let last = values.len() - 1;
+    if interpolation == Interpolation::Bezier && (index == 0 || index == last) {
+        return f32x3(0.0, 0.0, 0.0)
+    }
+    let prev = index.saturating_sub(1);
+    let next = (index + 1).min(last);
+    let m = (1.0 / (times[next] - times[prev])) * (values[next] - values[prev]);
+    if interpolation != Interpolation::Bezier {
+        return m
+    }
+    let d0 = values[index] - values[prev];
+    let d1 = values[next] - values[index];
+    let flat = |d0: f32, d1: f32, m: f32| if d0 * d1 <= 0.0 { 0.0 } else { m };
+    f32x3(flat(d0.0, d1.0, m.0), flat(d0.1, d1.1, m.1), flat(d0.2, d1.2, m.2))
+}
+
+// Value between keyframes index and index + 1, t is in [0, 1]. Splines are cubic Hermite
+// curves, which is the same as cubic Bezier with handles at one third of tangents.
+pub fn interpolate_values(times: &[f32], values: &[f32x3], index: usize, t: f32, interpolation: Interpolation) -> f32x3 {
+    let (p0, p1) = (values[index], values[index + 1]);
+    if interpolation == Interpolation::Linear {
+        return p0 + t * (p1 - p0)
+    }
+    let dt = times[index + 1] - times[index];
+    let m0 = tangent(times, values, index, interpolation);
+    let m1 = tangent(times, values, index + 1, interpolation);
+    let (t2, t3) = (t * t, t * t * t);
+    (2.0 * t3 - 3.0 * t2 + 1.0) * p0 + ((t3 - 2.0 * t2 + t) * dt) * m0 +
+        (-2.0 * t3 + 3.0 * t2) * p1 + ((t3 - t2) * dt) * m1
+}
+
+// Derivative of interpolate_values with respect to t
+fn interpolate_derivative(times: &[f32], values: &[f32x3], index: usize, t: f32, interpolation: Interpolation) -> f32x3 {
+    let (p0, p1) = (values[index], values[index + 1]);
+    if interpolation == Interpolation::Linear {
+        return p1 - p0
+    }
+    let dt = times[index + 1] - times[index];
+    let m0 = tangent(times, values, index, interpolation);
+    let m1 = tangent(times, values, index + 1, interpolation);
+    let t2 = t * t;
+    (6.0 * t2 - 6.0 * t) * (p0 - p1) + ((3.0 * t2 - 4.0 * t + 1.0) * dt) * m0 + ((3.0 * t2 - 2.0 * t) * dt) * m1
+}
+
+// Box of values between t0 and t1. Part of cubic segment is a cubic Bezier curve, which
+// lies in convex hull of its control points.
+fn interpolation_hull(times: &[f32], values: &[f32x3], index: usize, t0: f32, t1: f32,
+                      interpolation: Interpolation) -> (f32x3, f32x3) {
+    let h = (t1 - t0) / 3.0;
+    let p0 = interpolate_values(times, values, index, t0, interpolation);
+    let p1 = interpolate_values(times, values, index, t1, interpolation);
+    let c0 = p0 + h * interpolate_derivative(times, values, index, t0, interpolation);
+    let c1 = p1 - h * interpolate_derivative(times, values, index, t1, interpolation);
+    (p0.min(p1).min(c0).min(c1), p0.max(p1).max(c0).max(c1))
+}
+
+// Clamps scale to range of keyframes of segment, splines would overshoot it and
+// could pass through zero scale, which has no inverse
+fn clamp_scale(scale: f32x3, s0: f32x3, s1: f32x3) -> f32x3 {
+    scale.max(s0.min(s1)).min(s0.max(s1))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe {
+    pub time: f32,
+    pub translation: f32x3,
+    pub rotation: Quaternion,
+    pub scale: f32x3
+}
+
+impl Keyframe {
+    pub fn new(time: f32, translation: f32x3, rotation: Quaternion, scale: f32x3) -> Keyframe {
+        Keyframe { time, translation, rotation, scale }
+    }
+
+    fn transform(&self) -> Transform {
+        Transform::from_trs(self.translation, self.rotation, self.scale)
+    }
+}
+
+
+// Transformation that is a function of time. Translation and scale are interpolated
+// between keyframes linearly or with splines, rotation always with slerp. Outside of
+// keyframe range first or last keyframe is used. Scale components of keyframes must not
+// change sign.
+pub struct AnimatedTransform {
+    keyframes: Vec<Keyframe>,
+    transforms: Vec<Transform>,
+    interpolation: Interpolation,
+    // keyframe values for splines
+    times: Vec<f32>,
+    translations: Vec<f32x3>,
+    scales: Vec<f32x3>
+}
+
+impl AnimatedTransform {
+    pub fn new(keyframes: Vec<Keyframe>) -> AnimatedTransform {
+        AnimatedTransform::with_interpolation(keyframes, Interpolation::Linear)
+    }
+
+    pub fn with_interpolation(mut keyframes: Vec<Keyframe>, interpolation: Interpolation) -> AnimatedTransform {
+        if keyframes.is_empty() {
+            keyframes.push(Keyframe::new(0.0, f32x3(0.0, 0.0, 0.0), Quaternion::identity(), f32x3(1.0, 1.0, 1.0)));
+        }
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        let transforms = keyframes.iter().map(|k| k.transform()).collect();
+        let times = keyframes.iter().map(|k| k.time).collect();
+        let translations = keyframes.iter().map(|k| k.translation).collect();
+        let scales = keyframes.iter().map(|k| k.scale).collect();
+        AnimatedTransform { keyframes, transforms, interpolation, times, translations, scales }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.keyframes.len() > 1
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    fn segment(&self, time: f32) -> Option<(usize, f32)> {
+        let n = self.keyframes.len();
+        if n == 1 || time <= self.keyframes[0].time {
+            return None
+        }
+        if time >= self.keyframes[n - 1].time {
+            return None
+        }
+        let index = self.keyframes.partition_point(|k| k.time <= time) - 1;
+        let k0 = &self.keyframes[index];
+        let k1 = &self.keyframes[index + 1];
+        let t = (time - k0.time) / (k1.time - k0.time);
+        Some((index, t))
+    }
+
+    pub fn interpolate(&self, time: f32) -> Transform {
+        let (index, t) = match self.segment(time) {
+            Some(seg) => seg,
+            None => {
+                if time <= self.keyframes[0].time {
+                    return self.transforms[0]
+                }
+                return self.transforms[self.transforms.len() - 1]
+            }
+        };
+        let k0 = &self.keyframes[index];
+        let k1 = &self.keyframes[index + 1];
+        let (translation, scale) = match self.interpolation {
+            Interpolation::Linear => (k0.translation + t * (k1.translation - k0.translation), k0.scale + t * (k1.scale - k0.scale)),
+            interpolation => {
+                let scale = interpolate_values(&self.times, &self.scales, index, t, interpolation);
+                (interpolate_values(&self.times, &self.translations, index, t, interpolation),
+                 clamp_scale(scale, k0.scale, k1.scale))
+            }
+        };
+        let rotation = k0.rotation.slerp(k1.rotation, t);
+        Transform::from_trs(translation, rotation, scale)
+    }
+
+    // Bounding box of the object over the whole motion
+    pub fn motion_bounds(&self, bbox: &AABB) -> AABB {
+        let n = self.keyframes.len();
+        self.motion_bounds_in(bbox, self.keyframes[0].time, self.keyframes[n - 1].time)
+    }
+
+    // Bounding box of the object during time interval. Each keyframe segment is split into
+    // steps and point p of the object moves as T(t) + R(t) * S(t) * p within a step, so the
+    // box of step is the hull of translation spline plus the box of scaled object rotated to
+    // the middle of step, padded by the arc that its corners travel in half of step.
+    pub fn motion_bounds_in(&self, bbox: &AABB, time0: f32, time1: f32) -> AABB {
+        const STEPS: usize = 32;
+        let mut result = self.interpolate(time0).bbox(bbox).merge(&self.interpolate(time1).bbox(bbox));
+
+        for index in 0..self.keyframes.len() - 1 {
+            let k0 = &self.keyframes[index];
+            let k1 = &self.keyframes[index + 1];
+            let (start, end) = (k0.time.max(time0), k1.time.min(time1));
+            if start > end || k1.time <= k0.time {
+                continue;
+            }
+            let (t_start, t_end) = ((start - k0.time) / (k1.time - k0.time), (end - k0.time) / (k1.time - k0.time));
+            let step_angle = k0.rotation.angle(k1.rotation) * (t_end - t_start) / STEPS as f32;
+            for step in 0..STEPS {
+                let t0 = t_start + (t_end - t_start) * step as f32 / STEPS as f32;
+                let t1 = t_start + (t_end - t_start) * (step + 1) as f32 / STEPS as f32;
+                let (t_min, t_max) = interpolation_hull(&self.times, &self.translations, index, t0, t1, self.interpolation);
+                let (s_min, s_max) = interpolation_hull(&self.times, &self.scales, index, t0, t1, self.interpolation);
+                let (s_min, s_max) = (clamp_scale(s_min, k0.scale, k1.scale), clamp_scale(s_max, k0.scale, k1.scale));
+
+                // products of scale and coordinate ranges
+                let mul = |a: f32x3, b: f32x3| f32x3(a.0 * b.0, a.1 * b.1, a.2 * b.2);
+                let products = [mul(s_min, bbox.min), mul(s_min, bbox.max), mul(s_max, bbox.min), mul(s_max, bbox.max)];
+                let scaled = AABB::new(products.iter().fold(products[0], |a, b| a.min(*b)),
+                                       products.iter().fold(products[0], |a, b| a.max(*b)));
+                // distance of the farthest corner from the center of rotation
+                let far = |a: f32, b: f32| a.abs().max(b.abs());
+                let radius = f32x3(far(scaled.min.0, scaled.max.0), far(scaled.min.1, scaled.max.1),
+                                   far(scaled.min.2, scaled.max.2)).length();
+                let pad = radius * 0.5 * step_angle;
+                let pad = f32x3(pad, pad, pad);
+
+                let rotation = k0.rotation.slerp(k1.rotation, 0.5 * (t0 + t1));
+                let rotated = Transform::from_trs(f32x3(0.0, 0.0, 0.0), rotation, f32x3(1.0, 1.0, 1.0)).bbox(&scaled);
+                result = result.merge(&AABB::new(t_min + rotated.min - pad, t_max + rotated.max + pad));
+            }
+        }
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_inverse() {
+        let rot = Quaternion::from_axis_angle(f32x3(0.0, 1.0, 0.0), 90.0);
+        let t = Transform::from_trs(f32x3(1.0, 2.0, 3.0), rot, f32x3(2.0, 2.0, 2.0));
+        let p = f32x3(1.0, 0.0, 0.0);
+        let wp = t.point(p);
+        assert!((wp - f32x3(1.0, 2.0, 1.0)).length() < 1e-5);
+        assert!((t.inv_point(wp) - p).length() < 1e-5);
+        assert!((t.determinant() - 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn keyframe_interpolation() {
+        let k0 = Keyframe::new(0.0, f32x3(0.0, 0.0, 0.0), Quaternion::identity(), f32x3(1.0, 1.0, 1.0));
+        let k1 = Keyframe::new(1.0, f32x3(2.0, 0.0, 0.0), Quaternion::identity(), f32x3(1.0, 1.0, 1.0));
+        let anim = AnimatedTransform::new(vec![k1, k0]);
+        assert!(anim.is_animated());
+        let p = anim.interpolate(0.25).point(f32x3(0.0, 0.0, 0.0));
+        assert!((p - f32x3(0.5, 0.0, 0.0)).length() < 1e-5);
+        let p = anim.interpolate(2.0).point(f32x3(0.0, 0.0, 0.0));
+        assert!((p - f32x3(2.0, 0.0, 0.0)).length() < 1e-5);
+
+        let bbox = AABB::new(f32x3(-1.0, -1.0, -1.0), f32x3(1.0, 1.0, 1.0));
+        let bounds = anim.motion_bounds(&bbox);
+        assert!(bounds.min.0 <= -1.0 && bounds.max.0 >= 3.0);
+        let bounds = anim.motion_bounds_in(&bbox, 0.0, 0.5);
+        assert!(bounds.min.0 <= -1.0 && bounds.max.0 >= 2.0 && bounds.max.0 < 2.1);
+
+        // splines pass through keyframes, Catmull-Rom overshoots and Bezier doesn't
+        let times = [0.0, 1.0, 2.0, 3.0];
+        let values = [f32x3(0.0, 0.0, 0.0), f32x3(1.0, 2.0, 0.0), f32x3(2.0, 2.0, 0.0), f32x3(3.0, 0.0, 0.0)];
+        for interpolation in [Interpolation::Linear, Interpolation::CatmullRom, Interpolation::Bezier] {
+            assert!((interpolate_values(&times, &values, 1, 0.0, interpolation) - values[1]).length() < 1e-6);
+            assert!((interpolate_values(&times, &values, 1, 1.0, interpolation) - values[2]).length() < 1e-6);
+        }
+        assert!(interpolate_values(&times, &values, 1, 0.5, Interpolation::CatmullRom).1 > 2.0);
+        assert!((interpolate_values(&times, &values, 1, 0.5, Interpolation::Bezier).1 - 2.0).abs() < 1e-6);
+        let p = interpolate_values(&times, &values, 0, 0.5, Interpolation::CatmullRom);
+        assert!(p.0 > 0.45 && p.0 < 0.55);
+    }
+
+    #[test]
+    fn spline_motion() {
+        // Catmull-Rom scale would overshoot to negative between the middle keyframes
+        let rot = |angle: f32| Quaternion::from_axis_angle(f32x3(0.0, 0.0, 1.0), angle);
+        let keyframes = vec![
+            Keyframe::new(0.0, f32x3(0.0, 0.0, 0.0), rot(0.0), f32x3(1.0, 1.0, 1.0)),
+            Keyframe::new(1.0, f32x3(1.0, 2.0, 0.0), rot(60.0), f32x3(0.1, 0.1, 0.1)),
+            Keyframe::new(2.0, f32x3(2.0, 2.0, 0.0), rot(150.0), f32x3(0.1, 0.1, 0.1)),
+            Keyframe::new(3.0, f32x3(3.0, 0.0, 0.0), rot(90.0), f32x3(2.0, 1.0, 1.0))
+        ];
+        let anim = AnimatedTransform::with_interpolation(keyframes, Interpolation::CatmullRom);
+        assert!((anim.interpolate(1.5).determinant() - 0.001).abs() < 1e-6);
+
+        // corners of the object at any time are inside of motion bounds
+        let bbox = AABB::new(f32x3(1.0, -0.5, -0.5), f32x3(3.0, 0.5, 0.5));
+        for (time0, time1) in [(0.0, 3.0), (0.3, 1.7), (-1.0, 0.5)] {
+            let bounds = anim.motion_bounds_in(&bbox, time0, time1);
+            for i in 0..=1000 {
+                let time = time0 + (time1 - time0) * i as f32 / 1000.0;
+                let b = anim.interpolate(time).bbox(&bbox);
+                let eps = f32x3(1e-4, 1e-4, 1e-4);
+                assert!(b.min.min(bounds.min - eps) == bounds.min - eps && b.max.max(bounds.max + eps) == bounds.max + eps,
+                        "Object at time {} is outside of motion bounds", time);
+            }
+        }
+    }
+}